@@ -1,739 +1,1327 @@
 // BMP Image format
 
 use std::path::posix::{Path};
-use std::io::File;
-use std::str;
+use std::cmp;
+use std::i32;
+use std::collections::HashMap;
+use std::io::{File, IoResult, IoError, EndOfFile, BufReader, MemWriter};
 use image::*;
 
 static SIGNATURE: &'static str = "BM";
 
+// Colorspace types for v4/v5 headers, stored as four characters in little endian
+static LCS_CALIBRATED_RGB: u32 = 0;
+static LCS_SRGB: u32 = 0x73524742;                // "sRGB"
+static LCS_WINDOWS_COLOR_SPACE: u32 = 0x57696E20; // "Win "
+static PROFILE_LINKED: u32 = 0x4C494E4B;          // "LINK"
+static PROFILE_EMBEDDED: u32 = 0x4D424544;        // "MBED"
+
+// Scales for the fixed point endpoints and gammas
+static FXPT2DOT30: f64 = 1073741824.0;
+static FXPT16DOT16: f64 = 65536.0;
+
+// Never used by a pixel, added to indexed RGB8 palettes of only grays so they
+// don't read back as GRAYSCALE8
+static UNUSED_COLOR: (u8, u8, u8) = (255, 0, 255);
+
 
 /* NOTES:
  * BMP pixels stored as BGR, not RGB8
  * If height is positive, scanlines stored BOTTOM UP --> store pixels starting from bottom row when writing
  * If height is negative, scanliens stored TOP DOWN  --> No flip required to match Image struct pixel array orientation
- * Image_width % 4 = # of bytes for padding per scanline
- * Only v4 an v5 can produce RGBA8 images
+ *   (only uncompressed and BI_BITFIELDS images can be top down)
+ * Scanlines are padded to a multiple of 4 bytes, row_stride() gives the padded size for any bit depth
+ * 1, 4 and 8-bit images are indexed through a color table, packed most significant bit first
+ * Indexed images whose color table is all grays decode as GRAYSCALE8, so RGB8 images of only grays are
+ *   written with an extra unused color to keep them RGB8
+ * 16 and 32-bit BI_BITFIELDS images can put each channel anywhere in the pixel, given by the masks
+ * Only v4 an v5 (or v3 with an alpha mask) can produce RGBA8 images
  */
 
+
+// Everything that can go wrong while reading or writing a BMP image
+#[deriving(Show)]
+pub enum BmpError {
+  BadSignature,                   // First two bytes are not "BM"
+  TruncatedHeader,                // File ended before the headers were fully read
+  UnsupportedCompression(u32),    // Compression type this decoder can't handle
+  UnsupportedBitDepth(u16),       // Bits per pixel this decoder can't handle for the compression type
+  UnsupportedHeader(u32),         // Info header size that doesn't match a known version
+  BadPadding,                     // Non-zero bytes found in scanline padding
+  InvalidDimensions(i32, i32),    // Negative width, or a height that can't be made positive
+  BadProfile,                     // ICC profile offset or size points outside the file
+  IoFailure(IoError),             // Any other error from the underlying file
+}
+
+// Wraps an I/O error so it can be returned with try!
+fn io<T>(result: IoResult<T>) -> Result<T, BmpError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => Err(IoFailure(e))
+  }
+}
+
+// Same as io(), but running out of bytes means the header was cut short
+fn header<T>(result: IoResult<T>) -> Result<T, BmpError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => {
+      if e.kind == EndOfFile {
+        Err(TruncatedHeader)
+      }
+      else {
+        Err(IoFailure(e))
+      }
+    }
+  }
+}
+
+// Number of bytes in a scanline, which is always padded to a multiple of 4.
+// Every reader and writer path gets its padding from here.
+pub fn row_stride(width: uint, bits_per_pixel: uint) -> uint {
+  ((width * bits_per_pixel + 31) / 32) * 4
+}
+
+// Reads count bytes a piece at a time, so a size from the header is only
+// allocated as far as the data actually goes
+fn read_bytes<R: Reader>(image: &mut R, count: uint) -> Result<Vec<u8>, BmpError> {
+  let mut bytes: Vec<u8> = Vec::new();
+  let mut buffer = [0u8, ..4096];
+  while bytes.len() < count {
+    let length = cmp::min(count - bytes.len(), buffer.len());
+    try!(io(image.read_at_least(length, buffer.mut_slice_to(length))));
+    bytes.push_all(buffer.slice_to(length));
+  }
+  Ok(bytes)
+}
+
+// Reads the padding at the end of a scanline, all of which must be zero
+fn read_padding<R: Reader>(image: &mut R, padding: uint) -> Result<(), BmpError> {
+  for _ in range(0, padding) {
+    if try!(io(image.read_byte())) != 0 {
+      return Err(BadPadding);
+    }
+  }
+  Ok(())
+}
+
+
+// Which revision of the info header the file uses, identified by its size
+#[deriving(Show, PartialEq, Clone)]
+pub enum BmpVersion {
+  BmpV2,          // 12 bytes, BITMAPCOREHEADER (also OS/2 1.x)
+  BmpV3,          // 40 bytes, BITMAPINFOHEADER
+  BmpV3Masks,     // 52 bytes, BITMAPV2INFOHEADER, BMPv3 with RGB masks in the header
+  BmpV3Alpha,     // 56 bytes, BITMAPV3INFOHEADER, BMPv3 with RGB and alpha masks in the header
+  BmpV4,          // 108 bytes, BITMAPV4HEADER
+  BmpV5,          // 124 bytes, BITMAPV5HEADER
+}
+
+// Everything stored in the file and info headers, without any pixel data
+#[deriving(Show, Clone)]
+pub struct BmpInfo {
+  pub file_size: u32,               // Total file size in bytes
+  pub offset: u32,                  // Byte where pixel data starts
+  pub header_size: u32,             // Size of the info header in bytes
+  pub version: BmpVersion,
+  pub width: i32,                   // In pixels
+  pub height: i32,                  // In pixels, negative when scanlines are stored top down
+  pub planes: u16,                  // Should always be 1 in BMPs
+  pub bits_per_pixel: u16,
+  pub compression_type: u32,        // 0 = BI_RGB, 1 = BI_RLE8, 2 = BI_RLE4, 3 = BI_BITFIELDS, 6 = BI_ALPHABITFIELDS
+  pub size_of_bitmap: u32,          // May be 0 if uncompressed
+  pub horizontal_resolution: u32,   // In pixels per meter
+  pub vertical_resolution: u32,     // In pixels per meter
+  pub colors_used: u32,             // 0 means the default for the bit depth
+  pub colors_important: u32,        // 0 if all colors are important
+  pub red_mask: u32,                // Masks are 0 unless stored in the file
+  pub green_mask: u32,
+  pub blue_mask: u32,
+  pub alpha_mask: u32,
+  pub cs_type: u32,                 // Colorspace type, v4 and up
+  pub red_endpoint: (i32, i32, i32),    // CIEXYZ endpoints in 2.30 fixed point, v4 and up
+  pub green_endpoint: (i32, i32, i32),
+  pub blue_endpoint: (i32, i32, i32),
+  pub gamma_red: u32,               // Gammas in 16.16 fixed point, v4 and up
+  pub gamma_green: u32,
+  pub gamma_blue: u32,
+  pub intent: u32,                  // Rendering intent, v5 only
+  pub profile_data: u32,            // Offset of the ICC profile from the start of the info header, v5 only
+  pub profile_size: u32,            // Size of the ICC profile in bytes, v5 only
+  pub palette_size: uint,           // Number of color table entries
+}
+
+impl BmpInfo {
+
+  // Whether an embedded or linked ICC profile is stored somewhere in the file
+  pub fn has_profile(&self) -> bool {
+    self.version == BmpV5 && self.profile_size > 0 && (self.cs_type == PROFILE_EMBEDDED || self.cs_type == PROFILE_LINKED)
+  }
+
+  // Number of bytes taken by the file header, info header and any masks stored after it
+  fn header_end(&self) -> uint {
+    let masks = match self.version {
+      BmpV3 if self.compression_type == 3 => 12,
+      BmpV3 if self.compression_type == 6 => 16,
+      _ => 0
+    };
+    14 + self.header_size as uint + masks
+  }
+
+  // Core headers store color table entries as BGR, everything else as BGRX
+  fn palette_entry_size(&self) -> uint {
+    match self.version {
+      BmpV2 => 3,
+      _     => 4
+    }
+  }
+
+}
+
+
+// Reads only the headers of a BMP file
+#[allow(dead_code)]
+pub fn read_bitmap_info(image_path_str: &str) -> Result<BmpInfo, BmpError> {
+  let path = Path::new(image_path_str);
+  let mut file = try!(io(File::open(&path)));
+  decode_bitmap_info(&mut file)
+}
+
+// Reads the file and info headers from any reader, leaving it positioned right after them
+#[allow(dead_code)]
+pub fn decode_bitmap_info<R: Reader>(image: &mut R) -> Result<BmpInfo, BmpError> {
+
+  // Check signature
+  let read_sig = try!(header(image.read_exact(2u)));
+  if read_sig.as_slice() != SIGNATURE.as_bytes() {
+    return Err(BadSignature);
+  }
+
+  let file_size = try!(header(image.read_le_u32()));
+  try!(header(image.read_le_u32()));   // Reserved
+  let offset = try!(header(image.read_le_u32()));
+
+  read_info_header(image, file_size, offset)
+}
+
+// Reads the info header of a device independent bitmap, a BMP file without
+// its file header as found in icons and clipboards. The color table and pixel
+// data are taken to follow right after the header, and the returned offset is
+// where they'd be if the file header were there.
 #[allow(dead_code)]
-#[allow(dead_assignment)]
-pub fn read_bitmap(image_path_str: &str) -> Option<Image>{
+pub fn decode_dib_info<R: Reader>(image: &mut R) -> Result<BmpInfo, BmpError> {
+  let mut info = try!(read_info_header(image, 0, 0));
+  let palette_bytes = if info.bits_per_pixel <= 8 { info.palette_size * info.palette_entry_size() } else { 0 };
+  info.offset = (info.header_end() + palette_bytes) as u32;
+  Ok(info)
+}
+
+// Reads everything after the file header up to the color table
+fn read_info_header<R: Reader>(image: &mut R, file_size: u32, offset: u32) -> Result<BmpInfo, BmpError> {
+
+  let header_size = try!(header(image.read_le_u32()));
+
+  let version = match header_size {
+    12  => BmpV2,
+    40  => BmpV3,
+    52  => BmpV3Masks,
+    56  => BmpV3Alpha,
+    108 => BmpV4,
+    124 => BmpV5,
+    _   => return Err(UnsupportedHeader(header_size))
+  };
+
+  let mut info = BmpInfo {
+    file_size: file_size,
+    offset: offset,
+    header_size: header_size,
+    version: version,
+    width: 0,
+    height: 0,
+    planes: 0,
+    bits_per_pixel: 0,
+    compression_type: 0,
+    size_of_bitmap: 0,
+    horizontal_resolution: 0,
+    vertical_resolution: 0,
+    colors_used: 0,
+    colors_important: 0,
+    red_mask: 0,
+    green_mask: 0,
+    blue_mask: 0,
+    alpha_mask: 0,
+    cs_type: 0,
+    red_endpoint: (0, 0, 0),
+    green_endpoint: (0, 0, 0),
+    blue_endpoint: (0, 0, 0),
+    gamma_red: 0,
+    gamma_green: 0,
+    gamma_blue: 0,
+    intent: 0,
+    profile_data: 0,
+    profile_size: 0,
+    palette_size: 0,
+  };
+
+  // Core headers only store 16-bit dimensions and nothing past the bit depth
+  if info.version == BmpV2 {
+    info.width = try!(header(image.read_le_u16())) as i32;
+    info.height = try!(header(image.read_le_u16())) as i32;
+    info.planes = try!(header(image.read_le_u16()));
+    info.bits_per_pixel = try!(header(image.read_le_u16()));
+  }
+  else {
+    info.width = try!(header(image.read_le_i32()));
+    info.height = try!(header(image.read_le_i32()));
+    info.planes = try!(header(image.read_le_u16()));
+    info.bits_per_pixel = try!(header(image.read_le_u16()));
+    info.compression_type = try!(header(image.read_le_u32()));
+    info.size_of_bitmap = try!(header(image.read_le_u32()));
+    info.horizontal_resolution = try!(header(image.read_le_u32()));
+    info.vertical_resolution = try!(header(image.read_le_u32()));
+    info.colors_used = try!(header(image.read_le_u32()));
+    info.colors_important = try!(header(image.read_le_u32()));
+
+    // BMPv3 keeps its bitfield masks just after the header, later versions inside it
+    let (rgb_masks, alpha_mask) = match info.version {
+      BmpV3      => (info.compression_type == 3 || info.compression_type == 6, info.compression_type == 6),
+      BmpV3Masks => (true, false),
+      _          => (true, true)
+    };
+    if rgb_masks {
+      info.red_mask = try!(header(image.read_le_u32()));
+      info.green_mask = try!(header(image.read_le_u32()));
+      info.blue_mask = try!(header(image.read_le_u32()));
+    }
+    if alpha_mask {
+      info.alpha_mask = try!(header(image.read_le_u32()));
+    }
+    if info.version == BmpV4 || info.version == BmpV5 {
+      info.cs_type = try!(header(image.read_le_u32()));
+      info.red_endpoint = (try!(header(image.read_le_i32())), try!(header(image.read_le_i32())), try!(header(image.read_le_i32())));
+      info.green_endpoint = (try!(header(image.read_le_i32())), try!(header(image.read_le_i32())), try!(header(image.read_le_i32())));
+      info.blue_endpoint = (try!(header(image.read_le_i32())), try!(header(image.read_le_i32())), try!(header(image.read_le_i32())));
+      info.gamma_red = try!(header(image.read_le_u32()));
+      info.gamma_green = try!(header(image.read_le_u32()));
+      info.gamma_blue = try!(header(image.read_le_u32()));
+    }
+    if info.version == BmpV5 {
+      info.intent = try!(header(image.read_le_u32()));
+      info.profile_data = try!(header(image.read_le_u32()));
+      info.profile_size = try!(header(image.read_le_u32()));
+      try!(header(image.read_le_u32()));   // Reserved
+    }
+  }
+
+  info.palette_size = if info.colors_used > 0 {
+    info.colors_used as uint
+  }
+  else if info.bits_per_pixel <= 8 {
+    1u << info.bits_per_pixel as uint
+  }
+  else {
+    0
+  };
+
+  Ok(info)
+}
+
 
+#[allow(dead_code)]
+pub fn read_bitmap(image_path_str: &str) -> Result<Image, BmpError> {
   let path = Path::new(image_path_str);
+  let mut file = try!(io(File::open(&path)));
+  decode_bitmap(&mut file)
+}
+
+// Decodes a BMP image from an in-memory buffer
+#[allow(dead_code)]
+pub fn from_bytes(bytes: &[u8]) -> Result<Image, BmpError> {
+  let mut reader = BufReader::new(bytes);
+  decode_bitmap(&mut reader)
+}
+
+// Decodes a BMP image from any reader, starting at the "BM" signature
+#[allow(dead_code)]
+pub fn decode_bitmap<R: Reader>(image: &mut R) -> Result<Image, BmpError> {
+
+  let info = try!(decode_bitmap_info(image));
+
+  if !info.has_profile() {
+    let mut decoded = try!(decode_pixels(image, &info));
+    decoded.colorimetry = read_colorimetry(&info, &[]);
+    return Ok(decoded);
+  }
+
+  // Profiles are found by offset and usually follow the pixel data, so keep the rest of the file around
+  let rest = try!(io(image.read_to_end()));
+  let mut decoded = try!(decode_pixels(&mut BufReader::new(rest.as_slice()), &info));
+
+  let header_end = info.header_end();
+  let start = 14 + info.profile_data as uint;
+  let end = start + info.profile_size as uint;
+  if start < header_end || end > header_end + rest.len() {
+    return Err(BadProfile);
+  }
+  decoded.colorimetry = read_colorimetry(&info, rest.slice(start - header_end, end - header_end));
+  Ok(decoded)
+}
+
+// Turns the colorspace fields of a v4/v5 header into Colorimetry, given the
+// bytes of any profile it points to
+fn read_colorimetry(info: &BmpInfo, profile: &[u8]) -> Option<Colorimetry> {
+
+  if info.version != BmpV4 && info.version != BmpV5 {
+    return None;
+  }
 
-  let mut file_size: u32 = 0 as u32;      
-  let mut offset: u32 = 0 as u32;
-  let mut header_size: u32 = 0 as u32;      // 40 = BMPv3, 108 = BMPv4, 124 = BMPv5
-  let mut image_width: u32 = 0 as u32;
-  let mut image_height: u32 = 0 as u32;
-  let mut planes: u16 = 0 as u16;
-  let mut bits_per_pixel: u16 = 0 as u16;   // 8 = GRAYSCALE8, 24 = RGB8, 32 = RGBA8
-  let mut compression_type: u32 = 0 as u32;
-  let mut size_of_bitmap: u32 = 0 as u32;   
+  let color_space = if info.cs_type == LCS_CALIBRATED_RGB {
+    CalibratedRGB
+  }
+  else if info.cs_type == LCS_SRGB {
+    SRGB
+  }
+  else if info.cs_type == LCS_WINDOWS_COLOR_SPACE {
+    WindowsColorSpace
+  }
+  else if info.cs_type == PROFILE_EMBEDDED {
+    EmbeddedProfile(Vec::from_slice(profile))
+  }
+  else if info.cs_type == PROFILE_LINKED {
+    // File name in the system code page, null terminated, read as Latin-1
+    LinkedProfile(profile.iter().take_while(|&&c| c != 0).map(|&c| c as char).collect())
+  }
+  else {
+    return None;
+  };
+
+  Some(Colorimetry {
+    color_space: color_space,
+    red: from_fixed_endpoint(info.red_endpoint),
+    green: from_fixed_endpoint(info.green_endpoint),
+    blue: from_fixed_endpoint(info.blue_endpoint),
+    gamma: (info.gamma_red as f64 / FXPT16DOT16, info.gamma_green as f64 / FXPT16DOT16, info.gamma_blue as f64 / FXPT16DOT16),
+    intent: info.intent,
+  })
+}
+
+fn from_fixed_endpoint((x, y, z): (i32, i32, i32)) -> (f64, f64, f64) {
+  (x as f64 / FXPT2DOT30, y as f64 / FXPT2DOT30, z as f64 / FXPT2DOT30)
+}
+
+fn to_fixed_endpoint((x, y, z): (f64, f64, f64)) -> (i32, i32, i32) {
+  ((x * FXPT2DOT30).round() as i32, (y * FXPT2DOT30).round() as i32, (z * FXPT2DOT30).round() as i32)
+}
+
+// Decodes the color table and pixel data following the headers described by info
+#[allow(dead_code)]
+pub fn decode_pixels<R: Reader>(image: &mut R, info: &BmpInfo) -> Result<Image, BmpError> {
 
   let mut image_data_bytes: Vec<u8> = Vec::new();
   let mut buffer: Vec<u8> = Vec::new();
 
+  if info.width < 0 || info.height == i32::MIN {
+    return Err(InvalidDimensions(info.width, info.height));
+  }
+  let top_down = info.height < 0;
+  let image_width = info.width as uint;
+  let image_height = if top_down { -info.height as uint } else { info.height as uint };
+  let bits_per_pixel = info.bits_per_pixel;
+  let compression_type = info.compression_type;
+
+  // Reject anything we can't decode before touching pixel data
+  match compression_type {
+    0 => {
+      if bits_per_pixel != 1 && bits_per_pixel != 4 && bits_per_pixel != 8 && bits_per_pixel != 16 && bits_per_pixel != 24 && bits_per_pixel != 32 {
+        return Err(UnsupportedBitDepth(bits_per_pixel));
+      }
+    },
+    1 => {
+      if bits_per_pixel != 8 {
+        return Err(UnsupportedBitDepth(bits_per_pixel));
+      }
+    },
+    2 => {
+      if bits_per_pixel != 4 {
+        return Err(UnsupportedBitDepth(bits_per_pixel));
+      }
+    },
+    3 | 6 => {
+      if bits_per_pixel != 16 && bits_per_pixel != 32 {
+        return Err(UnsupportedBitDepth(bits_per_pixel));
+      }
+    },
+    _ => {
+      return Err(UnsupportedCompression(compression_type));
+    }
+  }
 
-  match File::open(&path) {
-    Ok(mut image) => {
+  // Color table sits between the headers and the pixel data
+  let table_space = info.offset as int - info.header_end() as int;
+  let entry_size = info.palette_entry_size();
+  let mut palette_entries = 0u;
+  if bits_per_pixel <= 8 && table_space > 0 {
+    palette_entries = cmp::min(info.palette_size, table_space as uint / entry_size);
+  }
+  let palette = try!(read_palette(image, palette_entries, entry_size));
 
-      // Check signature
+  // Skip anything between the headers and the pixel data
+  let remainder = table_space - (palette_entries * entry_size) as int;
+  for _ in range(0, remainder) {
+    try!(header(image.read_byte()));
+  }
 
-      // There's a better way of doing this?
-      let read_sig = image.read_exact(2u).unwrap();
-      let signature = read_sig.as_slice();
-      if !str::eq_slice(str::from_utf8(signature).unwrap(), SIGNATURE) {
-        fail!("Input image is not a valid BMP image");
-      }
+  // Pixels per meter, 0 when the writer didn't know
+  let resolution = if info.horizontal_resolution == 0 && info.vertical_resolution == 0 {
+    None
+  }
+  else {
+    Some(Resolution{horizontal: info.horizontal_resolution as f64, vertical: info.vertical_resolution as f64})
+  };
+
+  // Indexed images pick their color type once the palette is known
+  let mut color_type = match bits_per_pixel {
+    24 => RGB8,
+    32 => RGBA8,
+    _  => GRAYSCALE8
+  };
+
+  // BI_RGB means uncompressed (BGR)
+  if compression_type as uint == 0 {
+
+    // Palette indices, expanded to GRAYSCALE8 or RGB8
+    if bits_per_pixel <= 8 {
+      let indices = try!(unpack_indices(image, image_width as uint, image_height as uint, bits_per_pixel as uint));
+      let (expanded_type, expanded) = expand_palette(indices.as_slice(), &palette);
+      color_type = expanded_type;
+      buffer = expanded;
+    }
 
-      file_size = image.read_le_u32().unwrap();
-      image.read_le_u32().unwrap();   // Reserved
-      offset = image.read_le_u32().unwrap();
-      header_size = image.read_le_u32().unwrap();
-      image_width = image.read_le_u32().unwrap();
-      image_height = image.read_le_u32().unwrap();
-      planes = image.read_le_u16().unwrap();
-      bits_per_pixel = image.read_le_u16().unwrap();
-      compression_type = image.read_le_u32().unwrap();
-      size_of_bitmap = image.read_le_u32().unwrap();
-
-
-      let remainder = offset as int - 14 - 24;    // offset - fileheader size - read bytes
-
-  println!("
-  Total file size (in bytes): {}
-  Pixel data starting byte: {}
-  Header size: {}\t(40 = BMP 3.x, 108 = BMP 4.x, 124 = BMP 5.x)
-  Dimensions: {}px x {}px
-  Number of color planes: {}\t(Should always be 1 in BMPs)
-  Bits per pixel: {}\t(8 = GRAYSCALE8, 24 = RGB8, 32 = RGBA8)
-  Compression type: {}
-  Size of bitmap (in bytes): {}\t(May be 0 if uncompressed)
-
-  Number of bytes left unread in header: {}
-  ",
-  file_size,
-  offset,
-  header_size,
-  image_width,
-  image_height,
-  planes,
-  bits_per_pixel,
-  compression_type,
-  size_of_bitmap,
-  remainder
-  );
-
-
-      for _ in range(0, remainder) {
-        match image.read_byte() {
-          Ok(_)  => {continue},
-          Err(e)    => {fail!("Error reading BMP header: {}", e)}
+    // RGB8
+    if bits_per_pixel as int == 24 {
+      for _ in range(0, image_height) {
+        for _ in range(0, image_width) {
+          let pixel_data = try!(io(image.read_exact(3)));
+          buffer.push(*pixel_data.get(2));  // Red
+          buffer.push(*pixel_data.get(1));  // Green
+          buffer.push(*pixel_data.get(0));  // Blue
         }
+
+        // Padding, all scanlines must be multiple of 4
+        try!(read_padding(image, row_stride(image_width, 24) - image_width * 3));
       }
+    }
+  }
 
-      // BI_RGB means uncompressed (BGR)
-      if compression_type as uint == 0 {
-
-        // GRAYSCALE8
-        if bits_per_pixel as uint == 8 {
-          println!("GRAYSCALE8 Image");
-          for y in range(0, image_height) {
-            for x in range(0, image_width) {
-              match image.read_byte() {
-                Ok(pixel_data) => {
-                  buffer.push(pixel_data);
-                },
-                Err(e)    => {fail!("Error reading BMP pixel at ({}, {}): {}", x, y, e)}
-              }
-            }
 
-            // Padding based on image width, all scanlines must be multiple of 4
-            match image_width % 4 {
-              1 => {
-                match image.read_byte() {
-                  Ok(padding) => {
-                    if padding as uint == 0 {
-                      continue;
-                    }
-                    else {
-                      break;
-                    }
-                  },
-                  Err(e) => {
-                    fail!("Error checking padding at end of scanline: {}", e);
-                  }
-                }
-              },
-              2 => {
-                match image.read_le_u16() {
-                  Ok(padding) => {
-                    if padding as uint == 0 {
-                      continue;
-                    }
-                    else {
-                      break;
-                    }
-                  },
-                  Err(e) => {
-                    fail!("Error checking padding at end of scanline: {}", e);
-                  }
-                }
-              },
-              3 => {
-                match image.read_byte() {
-                  Ok(padding) => {
-                    if padding as uint == 0 {
-                      match image.read_le_u16() {
-                        Ok(padding) => {
-                          if padding as uint == 0 {
-                            continue;
-                          }
-                          else {
-                            break;
-                          }
-                        },
-                        Err(e) => {
-                          fail!("Error checking padding at end of scanline: {}", e);
-                        }
-                      }
-                    }
-                    else {
-                      break;
-                    }
-                  },
-                  Err(e) => {
-                    fail!("Error checking padding at end of scanline: {}", e);
-                  }
-                }
-              },
-              _ => {
-                continue;
-              }
-            }
+  // BI_RLE8 and BI_RLE4 store palette indices as runs, which are expanded through the color table
+  if compression_type == 1 || compression_type == 2 {
+    let indices = try!(decode_rle(image, image_width as uint, image_height as uint, compression_type == 2));
+    let (expanded_type, expanded) = expand_palette(indices.as_slice(), &palette);
+    color_type = expanded_type;
+    buffer = expanded;
+  }
 
-          }
-        }
-        
-        // RGB8
-        if bits_per_pixel as int == 24 {
-          println!("RGB8 Image");
-          for y in range(0, image_height) {
-            for x in range(0, image_width) {
-              match image.read_exact(3) {
-                Ok(mut pixel_data) => {
-                  match pixel_data.pop() {
-                    Some(red) => {buffer.push(red)},
-                    None  => {fail!("Error getting red component for BMP pixel at ({}, {})", x, y)}
-                  }
-                  match pixel_data.pop() {
-                    Some(green) => {buffer.push(green)},
-                    None  => {fail!("Error getting green component for BMP pixel at ({}, {})", x, y)}
-                  }
-                  match pixel_data.pop() {
-                    Some(blue) => {buffer.push(blue)},
-                    None  => {fail!("Error getting blue component for BMP pixel at ({}, {})", x, y)}
-                  }
-                },
-                Err(e)    => {fail!("Error reading BMP pixel at ({}, {}): {}", x, y, e)}
-              }
-            }
 
-            // Padding based on image width, all scanlines must be multiple of 4
-            match image_width % 4 {
-              1 => {
-                match image.read_byte() {
-                  Ok(padding) => {
-                    if padding as uint == 0 {
-                      continue;
-                    }
-                    else {
-                      break;
-                    }
-                  },
-                  Err(e) => {
-                    fail!("Error checking padding at end of scanline: {}", e);
-                  }
-                }
-              },
-              2 => {
-                match image.read_le_u16() {
-                  Ok(padding) => {
-                    if padding as uint == 0 {
-                      continue;
-                    }
-                    else {
-                      break;
-                    }
-                  },
-                  Err(e) => {
-                    fail!("Error checking padding at end of scanline: {}", e);
-                  }
-                }
-              },
-              3 => {
-                match image.read_byte() {
-                  Ok(padding) => {
-                    if padding as uint == 0 {
-                      match image.read_le_u16() {
-                        Ok(padding) => {
-                          if padding as uint == 0 {
-                            continue;
-                          }
-                          else {
-                            break;
-                          }
-                        },
-                        Err(e) => {
-                          fail!("Error checking padding at end of scanline: {}", e);
-                        }
-                      }
-                    }
-                    else {
-                      break;
-                    }
-                  },
-                  Err(e) => {
-                    fail!("Error checking padding at end of scanline: {}", e);
-                  }
-                }
-              },
-              _ => {
-                continue;
-              }
-            }
+  // BI_BITFEILDS means image is uncompressed and components values are stored according to component masks in header.
+  // 16 and 32-bit BI_RGB images use the same layout with fixed 5-5-5 and BGRX masks.
+  if compression_type == 3 || compression_type == 6 || (compression_type == 0 && (bits_per_pixel == 16 || bits_per_pixel == 32)) {
 
-          }
+    // The fourth byte of a 32-bit BI_RGB pixel is officially unused, but often holds alpha anyway
+    let guess_alpha = compression_type == 0 && bits_per_pixel == 32;
+
+    let (red, green, blue, alpha) = if compression_type != 0 && (info.red_mask | info.green_mask | info.blue_mask) != 0 {
+      (info.red_mask, info.green_mask, info.blue_mask, info.alpha_mask)
+    }
+    else if bits_per_pixel == 16 {
+      (0x7C00u32, 0x03E0u32, 0x001Fu32, 0u32)
+    }
+    else if guess_alpha {
+      (0x00FF0000u32, 0x0000FF00u32, 0x000000FFu32, 0xFF000000u32)
+    }
+    else {
+      (0x00FF0000u32, 0x0000FF00u32, 0x000000FFu32, 0u32)
+    };
+
+    let red = ChannelMask::new(red);
+    let green = ChannelMask::new(green);
+    let blue = ChannelMask::new(blue);
+    let alpha = ChannelMask::new(alpha);
+    color_type = if alpha.bits > 0 { RGBA8 } else { RGB8 };
+
+    let row_bytes = image_width as uint * bits_per_pixel as uint / 8;
+    let padding = row_stride(image_width as uint, bits_per_pixel as uint) - row_bytes;
+
+    for _ in range(0, image_height) {
+      for _ in range(0, image_width) {
+        let pixel: u32 = if bits_per_pixel == 16 {
+          try!(io(image.read_le_u16())) as u32
+        }
+        else {
+          try!(io(image.read_le_u32()))
+        };
+
+        buffer.push(red.extract(pixel));
+        buffer.push(green.extract(pixel));
+        buffer.push(blue.extract(pixel));
+        if alpha.bits > 0 {
+          buffer.push(alpha.extract(pixel));
         }
       }
 
+      // Padding, only 16-bit scanlines can need any
+      try!(read_padding(image, padding));
+    }
 
-      // If bits_per_pixel = 16 or 32, then compresion must be 3 
-      // BI_BITFEILDS means image is uncompressed and components values are stored according to component masks in header
-      // - Should be able to identify color channels through masks. Is it common to store masks and data differently than ABGR?
-      if compression_type as int == 3 { 
-        // RGBA8
-        if bits_per_pixel as int == 32 {
-          println!("RGBA8 Image");
-          for y in range(0, image_height) {
-            for x in range(0, image_width) {
-
-              match image.read_exact(4) {
-                Ok(mut pixel_data) => {       
-                  match pixel_data.pop() {
-                    Some(red) => {buffer.push(red)},
-                    None  => {fail!("Error getting red component for BMP pixel at ({}, {})", x, y)}
-                  }
-                  match pixel_data.pop() {
-                    Some(green) => {buffer.push(green)},
-                    None  => {fail!("Error getting green component for BMP pixel at ({}, {})", x, y)}
-                  }
-                  match pixel_data.pop() {
-                    Some(blue) => {buffer.push(blue)},
-                    None  => {fail!("Error getting blue component for BMP pixel at ({}, {})", x, y)}
-                  }
-                  match pixel_data.pop() {
-                    Some(alpha) => {buffer.push(alpha)},
-                    None  => {fail!("Error getting alpha component for BMP pixel at ({}, {})", x, y)}
-                  }                    
-                },
-                Err(e)    => {
-                  fail!("Error reading BMP pixel at ({}, {}): {}", x, y, e)}
-              }
-            }
+    if guess_alpha && !has_alpha(buffer.as_slice()) {
+      buffer = strip_alpha(buffer.as_slice());
+      color_type = RGB8;
+    }
+  }
 
-          }
-        }
-      }
 
-      
-    },
-    Err(e)  => {println!("Error opening file: {}", e)}
+  // Without this scanlines are flipped in image data, top down images are already in order
+  if top_down {
+    image_data_bytes = buffer;
   }
+  else {
+    let row_size = image_width as uint * bytes_per_pixel(color_type);
+    for i in range(0, image_height as uint) {
+      let start_index: uint = (image_height as uint - i - 1) * row_size;
+      let end_index: uint = start_index + row_size; // Off by one as slice function doesn't include last index
 
-  
-  // Without this scanlines are flipped in image data
-  if image_height as int > 0 {
-      for i in range(0, image_height){
-
-        if bits_per_pixel == 8 {
-          let start_index: uint = (image_height as uint - i as uint - 1) * image_width as uint ;  // 3 because RGB8
-          let end_index: uint = start_index + image_width as uint; // Off by one as slice function doesn't include last index
+      let scanline = buffer.slice(start_index, end_index);
+      image_data_bytes.push_all(scanline);
+    }
+  }
 
-          let scanline = buffer.slice(start_index, end_index);
-          image_data_bytes.push_all(scanline);          
-        }
-        if bits_per_pixel == 24 {
-          let start_index: uint = (image_height as uint - i as uint - 1) * image_width as uint * 3;  // 3 because RGB8
-          let end_index: uint = start_index + (image_width as uint * 3); // Off by one as slice function doesn't include last index
+  Ok(Image{width: image_width as uint, height: image_height as uint, color_type: color_type, data: image_data_bytes, colorimetry: None, resolution: resolution})
+}
 
-          let scanline = buffer.slice(start_index, end_index);
-          image_data_bytes.push_all(scanline);
-        }
-        if bits_per_pixel == 32 {
-          let start_index: uint = (image_height as uint - i as uint - 1) * image_width as uint * 4;  // 4 because RGBA8
-          let end_index: uint = start_index + (image_width as uint * 4); // Off by one as slice function doesn't include last index
+// Where one color channel sits inside a 16 or 32-bit BI_BITFIELDS pixel
+struct ChannelMask {
+  mask: u32,
+  shift: uint,    // Position of the lowest set bit
+  bits: uint,     // Number of bits in the channel, 0 if the channel isn't stored
+}
 
-          let scanline = buffer.slice(start_index, end_index);
-          image_data_bytes.push_all(scanline);
-        }          
+impl ChannelMask {
 
+  fn new(mask: u32) -> ChannelMask {
+    let mut shift = 0u;
+    let mut bits = 0u;
+    if mask != 0 {
+      while (mask >> shift) & 1 == 0 {
+        shift += 1;
+      }
+      while shift + bits < 32 && (mask >> (shift + bits)) & 1 == 1 {
+        bits += 1;
       }
+    }
+    ChannelMask{mask: mask, shift: shift, bits: bits}
+  }
+
+  // Pulls the channel out of a pixel and scales it to the full 0-255 range
+  fn extract(&self, pixel: u32) -> u8 {
+    if self.bits == 0 {
+      return 0;
+    }
+
+    let value = (pixel & self.mask) >> self.shift;
+    if self.bits >= 8 {
+      (value >> (self.bits - 8)) as u8
+    }
+    else {
+      let max = (1u32 << self.bits) - 1;
+      ((value * 255 + max / 2) / max) as u8
+    }
   }
 
+}
+
+// Whether the alpha of RGBA8 pixels carries any information. All zero is
+// taken to be unused padding, and all 255 is the same as no alpha at all.
+fn has_alpha(pixels: &[u8]) -> bool {
+  let mut all_zero = true;
+  let mut all_opaque = true;
+  for i in range(0, pixels.len() / 4) {
+    let alpha = pixels[i * 4 + 3];
+    all_zero = all_zero && alpha == 0;
+    all_opaque = all_opaque && alpha == 255;
+  }
+  !all_zero && !all_opaque
+}
+
+// Drops the alpha component of RGBA8 pixels
+fn strip_alpha(pixels: &[u8]) -> Vec<u8> {
+  let mut stripped: Vec<u8> = Vec::with_capacity(pixels.len() / 4 * 3);
+  for i in range(0, pixels.len() / 4) {
+    stripped.push_all(pixels.slice(i * 4, i * 4 + 3));
+  }
+  stripped
+}
 
-  if bits_per_pixel == 8 {
-    Some(Image{width: image_width as uint, height: image_height as uint, color_type: GRAYSCALE8, data: image_data_bytes})
+fn bytes_per_pixel(color_type: ColorType) -> uint {
+  match color_type {
+    GRAYSCALE8 => 1,
+    RGB8       => 3,
+    RGBA8      => 4
   }
-  else if bits_per_pixel == 24 {
-    Some(Image{width: image_width as uint, height: image_height as uint, color_type: RGB8, data: image_data_bytes})
+}
+
+// Reads a color table of BGR or BGRX entries, returned as (red, green, blue)
+fn read_palette<R: Reader>(image: &mut R, entries: uint, entry_size: uint) -> Result<Vec<(u8, u8, u8)>, BmpError> {
+  // Indices only reach 256 entries, and the header's count can claim far more
+  let mut palette: Vec<(u8, u8, u8)> = Vec::with_capacity(cmp::min(entries, 256));
+  for _ in range(0, entries) {
+    let entry = try!(header(image.read_exact(entry_size)));
+    palette.push((*entry.get(2), *entry.get(1), *entry.get(0)));
   }
-  else if bits_per_pixel == 32 {
-    Some(Image{width: image_width as uint, height: image_height as uint, color_type: RGBA8, data: image_data_bytes})
+  Ok(palette)
+}
+
+// Looks every index up in the palette. If every color is a shade of gray the
+// image is returned as GRAYSCALE8, otherwise as RGB8. Indices past the end of
+// the palette are black, and with no palette at all indices are gray levels.
+fn expand_palette(indices: &[u8], palette: &Vec<(u8, u8, u8)>) -> (ColorType, Vec<u8>) {
+
+  if palette.len() == 0 {
+    return (GRAYSCALE8, Vec::from_slice(indices));
+  }
+
+  let mut is_gray = true;
+  for &(r, g, b) in palette.iter() {
+    if r != g || g != b {
+      is_gray = false;
+      break;
+    }
+  }
+
+  let mut expanded: Vec<u8> = Vec::new();
+  for &index in indices.iter() {
+    let (r, g, b) = if (index as uint) < palette.len() {
+      *palette.get(index as uint)
+    }
+    else {
+      (0, 0, 0)
+    };
+
+    if is_gray {
+      expanded.push(r);
+    }
+    else {
+      expanded.push(r);
+      expanded.push(g);
+      expanded.push(b);
+    }
+  }
+
+  if is_gray {
+    (GRAYSCALE8, expanded)
   }
   else {
-    println!("Error writing image as valid colorspace");
-    None
+    (RGB8, expanded)
   }
+}
+
+// Decodes BI_RLE8 (or BI_RLE4 when nibbles is true) pixel data into one palette
+// index per pixel, with scanlines in file order. Pixels skipped by delta or
+// end-of-line escapes are left as index 0, and runs that spill past the edge
+// of the image are clipped. Indices grow as pixels are decoded, and the rest
+// of the image is only filled in once the whole stream has been read.
+fn decode_rle<R: Reader>(image: &mut R, width: uint, height: uint, nibbles: bool) -> Result<Vec<u8>, BmpError> {
+
+  let mut indices: Vec<u8> = Vec::new();
+  let mut x = 0u;
+  let mut y = 0u;
+
+  while y < height {
+    let count = try!(io(image.read_byte())) as uint;
+    let value = try!(io(image.read_byte()));
+
+    // Encoded run, in RLE4 the two nibbles alternate
+    if count > 0 {
+      for i in range(0, count) {
+        let index = if nibbles {
+          if i % 2 == 0 { value >> 4 } else { value & 0x0F }
+        }
+        else {
+          value
+        };
+        if x < width {
+          set_index(&mut indices, x + y * width, index);
+        }
+        x += 1;
+      }
+      continue;
+    }
+
+    match value {
+      // End of line
+      0 => {
+        x = 0;
+        y += 1;
+      },
+
+      // End of bitmap
+      1 => {
+        break;
+      },
+
+      // Delta, move right and up
+      2 => {
+        x += try!(io(image.read_byte())) as uint;
+        y += try!(io(image.read_byte())) as uint;
+      },
+
+      // Absolute mode, the next `value` pixels are stored as-is and padded to a 16-bit boundary
+      length => {
+        let length = length as uint;
+        let bytes = if nibbles { (length + 1) / 2 } else { length };
+        let data = try!(io(image.read_exact(bytes)));
+
+        for i in range(0, length) {
+          let index = if nibbles {
+            let byte = *data.get(i / 2);
+            if i % 2 == 0 { byte >> 4 } else { byte & 0x0F }
+          }
+          else {
+            *data.get(i)
+          };
+          if x < width && y < height {
+            set_index(&mut indices, x + y * width, index);
+          }
+          x += 1;
+        }
 
+        if bytes % 2 == 1 {
+          try!(io(image.read_byte()));
+        }
+      }
+    }
+  }
+
+  let missing = width * height - indices.len();
+  indices.grow(missing, &0u8);
+  Ok(indices)
+}
+
+// Stores a decoded index, growing the indices with 0 up to its position
+fn set_index(indices: &mut Vec<u8>, position: uint, index: u8) {
+  if position >= indices.len() {
+    let missing = position + 1 - indices.len();
+    indices.grow(missing, &0u8);
+  }
+  *indices.get_mut(position) = index;
+}
+
+// Settings for how an image is laid out when it's written
+#[deriving(Show, Clone)]
+pub struct BmpOptions {
+  pub rle: bool,        // Compress 8-bit indexed and GRAYSCALE8 images with BI_RLE8, ignored for other color types
+  pub palette: bool,    // Write RGB8 images with at most 256 colors as indexed images, on by default
+  pub top_down: bool,   // Store scanlines top down with a negative height, ignored for RLE8
+}
+
+impl BmpOptions {
+
+  // Uncompressed output, indexed when the colors fit, the same as write_bitmap
+  pub fn new() -> BmpOptions {
+    BmpOptions{rle: false, palette: true, top_down: false}
+  }
 
 }
 
 #[allow(dead_code)]
-pub fn write_bitmap(image: Image, filename: &str) -> bool {
+pub fn write_bitmap(image: Image, filename: &str) -> Result<(), BmpError> {
+  write_bitmap_with(image, filename, &BmpOptions::new())
+}
 
+#[allow(dead_code)]
+pub fn write_bitmap_with(image: Image, filename: &str, options: &BmpOptions) -> Result<(), BmpError> {
   let path = Path::new(filename);
-  let mut file = File::create(&path);
-  let padding = image.height * (image.width % 4);
+  let mut file = try!(io(File::create(&path)));
+  encode_bitmap_with(&image, &mut file, options)
+}
 
+// Encodes a BMP image into a new in-memory buffer
+#[allow(dead_code)]
+pub fn to_bytes(image: &Image) -> Result<Vec<u8>, BmpError> {
+  to_bytes_with(image, &BmpOptions::new())
+}
+
+#[allow(dead_code)]
+pub fn to_bytes_with(image: &Image, options: &BmpOptions) -> Result<Vec<u8>, BmpError> {
+  let mut writer = MemWriter::new();
+  try!(encode_bitmap_with(image, &mut writer, options));
+  Ok(writer.unwrap())
+}
+
+// Encodes a BMP image to any writer
+#[allow(dead_code)]
+pub fn encode_bitmap<W: Writer>(image: &Image, file: &mut W) -> Result<(), BmpError> {
+  encode_bitmap_with(image, file, &BmpOptions::new())
+}
+
+#[allow(dead_code)]
+pub fn encode_bitmap_with<W: Writer>(image: &Image, file: &mut W, options: &BmpOptions) -> Result<(), BmpError> {
 
   match image.color_type {
 
-    // Save as BMP 4.x
+    // Save indexed through a gray palette
     GRAYSCALE8 => {
 
-      let filesize: u32 = ((image.width * image.height) + padding + 108 + 14) as u32; 
-      let reserved: u32 = 0 as u32;
-      let bitmap_offset: u32 = (122u + 1024u) as u32; // Add size of color palette
-
-      file.write(SIGNATURE.as_bytes()).unwrap();
-      file.write_le_u32(filesize).unwrap();
-      file.write_le_u32(reserved).unwrap();
-      file.write_le_u32(bitmap_offset).unwrap();
-
-
-      let header_size: u32 = 108 as u32;  // Size in bytes
-      let image_width: u32 = image.width as u32;    // In pixels
-      let image_height: u32 = image.height as u32;   // In pixels
-      let planes: u16 = 1 as u16;         // Number of color planes, in BMP this is always 1
-      let bits_per_pixel: u16 = 8 as u16;  // Number of bits per pixel
-
-      file.write_le_u32(header_size).unwrap();
-      file.write_le_u32(image_width).unwrap();
-      file.write_le_u32(image_height).unwrap();
-      file.write_le_u16(planes).unwrap();
-      file.write_le_u16(bits_per_pixel).unwrap();
-
-      let compression_type: u32 = 0 as u32;    // 0 is uncompressed, 1 is RLE algorithm, 2 is 4-bit RLE algorithm
-      let size_of_bitmap: u32 = (image.width * image.height + padding) as u32; // Size in bytes, 0 when uncompressed = 0
-      let horizontal_resolution: u32 = 2835 as u32;  // In pixels per meter
-      let vertical_resolution: u32 = 2835 as u32; // In pixels per meter
-      let colors_used: u32 = 0 as u32;        // Number of colors in palette, 0 if no palette
-      let colors_important: u32 = 0 as u32;   // 0 if all colors are important
-
-      file.write_le_u32(compression_type).unwrap();
-      file.write_le_u32(size_of_bitmap).unwrap();
-      file.write_le_u32(horizontal_resolution).unwrap();
-      file.write_le_u32(vertical_resolution).unwrap();
-      file.write_le_u32(colors_used).unwrap();
-      file.write_le_u32(colors_important).unwrap();
-
-
-      let red_mask: u32 = 0x00000000 as u32; //BGRs when not compressed? This is unclear
-      let green_mask: u32 = 0x00000000 as u32;
-      let blue_mask: u32 = 0x00000000 as u32;
-      let alpha_mask: u32 = 0x00000000 as u32;
-      let cs_type: u32 = 0 as u32;
-      let endpoint_red_x: u32 = 0 as u32;
-      let endpoint_red_y: u32 = 0 as u32;
-      let endpoint_red_z: u32 = 0 as u32;
-      let endpoint_green_x: u32 = 0 as u32;
-      let endpoint_green_y: u32 = 0 as u32;
-      let endpoint_green_z: u32 = 0 as u32;
-      let endpoint_blue_x: u32 = 0 as u32;
-      let endpoint_blue_y: u32 = 0 as u32;
-      let endpoint_blue_z: u32 = 0 as u32;
-      let gamma_red: u32 = 0 as u32;
-      let gamma_green: u32 = 0 as u32;
-      let gamma_blue: u32 = 0 as u32;
-
-      file.write_le_u32(red_mask).unwrap();
-      file.write_le_u32(green_mask).unwrap();
-      file.write_le_u32(blue_mask).unwrap();
-      file.write_le_u32(alpha_mask).unwrap();
-      file.write_le_u32(cs_type).unwrap();
-      file.write_le_u32(endpoint_red_x).unwrap();
-      file.write_le_u32(endpoint_red_y).unwrap();
-      file.write_le_u32(endpoint_red_z).unwrap();
-      file.write_le_u32(endpoint_green_x).unwrap();
-      file.write_le_u32(endpoint_green_y).unwrap();
-      file.write_le_u32(endpoint_green_z).unwrap();
-      file.write_le_u32(endpoint_blue_x).unwrap();
-      file.write_le_u32(endpoint_blue_y).unwrap();
-      file.write_le_u32(endpoint_blue_z).unwrap();
-      file.write_le_u32(gamma_red).unwrap();
-      file.write_le_u32(gamma_green).unwrap();
-      file.write_le_u32(gamma_blue).unwrap();
-
-
-      // GRAYSCALE8 PALETTE
+      let mut palette: Vec<(u8, u8, u8)> = Vec::new();
       for i in range(0u, 256) {
-        file.write_u8(i as u8).unwrap();
-        file.write_u8(i as u8).unwrap();
-        file.write_u8(i as u8).unwrap();
-        file.write_u8(0).unwrap();
+        palette.push((i as u8, i as u8, i as u8));
       }
+      encode_indexed(image, image.data.as_slice(), palette.as_slice(), 8, file, options)
 
+    },
 
-      if compression_type == 0 {
-        for y in range(0, image.height) {
-
-          let bmp_y = image.height - 1 - y;
-
-          for x in range(0, image.width) {
-
-            let index = x + image.width * bmp_y;
-            file.write_u8(*image.data.get(index)).unwrap();
-
-          }
+    // Save as 24-bit BGR
+    RGB8 => {
 
-          // Padding based on image width, scanlines must be multiple of 4
-          match image_width % 4 {
-            1 => {
-              file.write_u8(0).unwrap();
-            },
-            2 => {
-              file.write_u8(0).unwrap();
-              file.write_u8(0).unwrap();
-            },
-            3 => {
-              file.write_u8(0).unwrap();
-              file.write_u8(0).unwrap();
-              file.write_u8(0).unwrap();
-            },
-            _ => {
-              continue;
+      // Few enough colors for an indexed image, at the smallest depth that fits
+      if options.palette {
+        match build_palette(image) {
+          Some((mut palette, indices)) => {
+            if palette.iter().all(|&(r, g, b)| r == g && g == b) {
+              palette.push(UNUSED_COLOR);
             }
-          }
-
+            if palette.len() <= 256 {
+              let bits = if options.rle || palette.len() > 16 { 8 } else if palette.len() > 2 { 4 } else { 1 };
+              return encode_indexed(image, indices.as_slice(), palette.as_slice(), bits, file, options);
+            }
+          },
+          None => {}
         }
       }
-      true
 
-    }
+      let stride = row_stride(image.width, 24);
+      let mut pixel_data: Vec<u8> = Vec::from_elem(stride * image.height, 0u8);
 
+      for y in range(0, image.height) {
 
-    // Save as BMP 4.x
-    RGB8 => {
+        let bmp_y = if options.top_down { y } else { image.height - 1 - y };
 
-      let filesize: u32 = ((image.width * image.height * 3) + padding + 108 + 14) as u32; 
-      let reserved: u32 = 0 as u32;
-      let bitmap_offset: u32 = 122 as u32; // Bitmap 3.x => 54, Bitmap 4.x => 122
-
-      file.write(SIGNATURE.as_bytes()).unwrap();
-      file.write_le_u32(filesize).unwrap();
-      file.write_le_u32(reserved).unwrap();
-      file.write_le_u32(bitmap_offset).unwrap();
-
-
-      let header_size: u32 = 108 as u32;  // Size in bytes
-      let image_width: u32 = image.width as u32;    // In pixels
-      let image_height: u32 = image.height as u32;   // In pixels
-      let planes: u16 = 1 as u16;         // Number of color planes, in BMP this is always 1
-      let bits_per_pixel: u16 = 24 as u16;  // Number of bits per pixel
-
-      file.write_le_u32(header_size).unwrap();
-      file.write_le_u32(image_width).unwrap();
-      file.write_le_u32(image_height).unwrap();
-      file.write_le_u16(planes).unwrap();
-      file.write_le_u16(bits_per_pixel).unwrap();
-
-
-      let compression_type: u32 = 0 as u32;    // 0 is uncompressed, 1 is RLE algorithm, 2 is 4-bit RLE algorithm
-      let size_of_bitmap: u32 = (image.width * image.height * 3 + padding) as u32; // Size in bytes, 0 when uncompressed = 0
-      let horizontal_resolution: u32 = 2835 as u32;  // In pixels per meter
-      let vertical_resolution: u32 = 2835 as u32; // In pixels per meter
-      let colors_used: u32 = 0 as u32;        // Number of colors in palette, 0 if no palette
-      let colors_important: u32 = 0 as u32;   // 0 if all colors are important
-
-      file.write_le_u32(compression_type).unwrap();
-      file.write_le_u32(size_of_bitmap).unwrap();
-      file.write_le_u32(horizontal_resolution).unwrap();
-      file.write_le_u32(vertical_resolution).unwrap();
-      file.write_le_u32(colors_used).unwrap();
-      file.write_le_u32(colors_important).unwrap();
-
-
-      let red_mask: u32 = 0x00FF0000 as u32; //BGRs when not compressed? This is unclear
-      let green_mask: u32 = 0x0000FF00 as u32;
-      let blue_mask: u32 = 0x000000FF as u32;
-      let alpha_mask: u32 = 0x00000000 as u32;
-      let cs_type: u32 = 0 as u32;
-      let endpoint_red_x: u32 = 0 as u32;
-      let endpoint_red_y: u32 = 0 as u32;
-      let endpoint_red_z: u32 = 0 as u32;
-      let endpoint_green_x: u32 = 0 as u32;
-      let endpoint_green_y: u32 = 0 as u32;
-      let endpoint_green_z: u32 = 0 as u32;
-      let endpoint_blue_x: u32 = 0 as u32;
-      let endpoint_blue_y: u32 = 0 as u32;
-      let endpoint_blue_z: u32 = 0 as u32;
-      let gamma_red: u32 = 0 as u32;
-      let gamma_green: u32 = 0 as u32;
-      let gamma_blue: u32 = 0 as u32;
-
-      file.write_le_u32(red_mask).unwrap();
-      file.write_le_u32(green_mask).unwrap();
-      file.write_le_u32(blue_mask).unwrap();
-      file.write_le_u32(alpha_mask).unwrap();
-      file.write_le_u32(cs_type).unwrap();
-      file.write_le_u32(endpoint_red_x).unwrap();
-      file.write_le_u32(endpoint_red_y).unwrap();
-      file.write_le_u32(endpoint_red_z).unwrap();
-      file.write_le_u32(endpoint_green_x).unwrap();
-      file.write_le_u32(endpoint_green_y).unwrap();
-      file.write_le_u32(endpoint_green_z).unwrap();
-      file.write_le_u32(endpoint_blue_x).unwrap();
-      file.write_le_u32(endpoint_blue_y).unwrap();
-      file.write_le_u32(endpoint_blue_z).unwrap();
-      file.write_le_u32(gamma_red).unwrap();
-      file.write_le_u32(gamma_green).unwrap();
-      file.write_le_u32(gamma_blue).unwrap();
-
-
-      if compression_type == 0 {
-        for y in range(0, image.height) {
-
-          let bmp_y = image.height - 1 - y;
-
-          for x in range(0, image.width) {
-            
-            let mut pixel_data: Vec<u8> = image.get_pixel(x,bmp_y);
-            let blue  = pixel_data.pop().unwrap();
-            let green = pixel_data.pop().unwrap();
-            let red   = pixel_data.pop().unwrap();
-
-            file.write_u8(blue).unwrap();
-            file.write_u8(green).unwrap();
-            file.write_u8(red).unwrap();
+        for x in range(0, image.width) {
 
-          }
+          let i = x * 3 + image.width * bmp_y * 3;
+          let j = x * 3 + stride * y;
 
-          // Padding based on image width, scanlines must be multiple of 4
-          match image_width % 4 {
-            1 => {
-              file.write_u8(0).unwrap();
-            },
-            2 => {
-              file.write_u8(0).unwrap();
-              file.write_u8(0).unwrap();
-            },
-            3 => {
-              file.write_u8(0).unwrap();
-              file.write_u8(0).unwrap();
-              file.write_u8(0).unwrap();
-            },
-            _ => {
-              continue;
-            }
-          }
+          // Write BGR, padding is already zeroed
+          *pixel_data.get_mut(j)     = *image.data.get(i + 2);
+          *pixel_data.get_mut(j + 1) = *image.data.get(i + 1);
+          *pixel_data.get_mut(j + 2) = *image.data.get(i);
 
         }
       }
-      true
+
+      let layout = Layout {
+        bits_per_pixel: 24,
+        compression_type: 0,
+        top_down: options.top_down,
+        masks: (0x00FF0000, 0x0000FF00, 0x000000FF, 0x00000000),
+        palette: Vec::new(),
+        pixel_data: pixel_data,
+      };
+      write_layout(image, &layout, file)
 
     },
 
-    // Save as BMP 5.x
+    // Save as 32-bit ABGR, which needs bitfield masks
     RGBA8 => {
 
-      let filesize: u32 = (image.width * image.height * 4 + 124 + 14) as u32; 
-      let reserved: u32 = 0 as u32;
-      let bitmap_offset: u32 = 138 as u32;
-
-      file.write(SIGNATURE.as_bytes()).unwrap();
-      file.write_le_u32(filesize).unwrap();
-      file.write_le_u32(reserved).unwrap();
-      file.write_le_u32(bitmap_offset).unwrap();
-
-
-      let header_size: u32 = 124 as u32;  // Size in bytes
-      let image_width: u32 = image.width as u32;    // In pixels
-      let image_height: u32 = image.height as u32;   // In pixels
-      let planes: u16 = 1 as u16;         // Number of color planes, in BMP this is always 1
-      let bits_per_pixel: u16 = 32 as u16;  // Number of bits per pixel
-
-      file.write_le_u32(header_size).unwrap();
-      file.write_le_u32(image_width).unwrap();
-      file.write_le_u32(image_height).unwrap();
-      file.write_le_u16(planes).unwrap();
-      file.write_le_u16(bits_per_pixel).unwrap();
-
-
-      let compression_type: u32 = 3 as u32;    // 0 is uncompressed, 1 is RLE algorithm, 2 is 4-bit RLE algorithm
-      let size_of_bitmap: u32 = (image.width * image.height * 4) as u32; // Size in bytes, 0 when uncompressed = 0
-      let horizontal_resolution: u32 = 2835 as u32;  // In pixels per meter
-      let vertical_resolution: u32 = 2835 as u32; // In pixels per meter
-      let colors_used: u32 = 0 as u32;        // Number of colors in palette, 0 if no palette
-      let colors_important: u32 = 0 as u32;   // 0 if all colors are important
-
-      file.write_le_u32(compression_type).unwrap();
-      file.write_le_u32(size_of_bitmap).unwrap();
-      file.write_le_u32(horizontal_resolution).unwrap();
-      file.write_le_u32(vertical_resolution).unwrap();
-      file.write_le_u32(colors_used).unwrap();
-      file.write_le_u32(colors_important).unwrap();        
-
-
-      let red_mask: u32 = 0xFF000000 as u32;
-      let green_mask: u32 = 0x00FF0000 as u32;
-      let blue_mask: u32 = 0x0000FF00 as u32;
-      let alpha_mask: u32 = 0x000000FF as u32;
-      let cs_type: u32 = 0x73524742 as u32;   // write sRGB in little endian -> BGRs
-      let endpoint_red_x: u32 = 0 as u32;
-      let endpoint_red_y: u32 = 0 as u32;
-      let endpoint_red_z: u32 = 0 as u32;
-      let endpoint_green_x: u32 = 0 as u32;
-      let endpoint_green_y: u32 = 0 as u32;
-      let endpoint_green_z: u32 = 0 as u32;
-      let endpoint_blue_x: u32 = 0 as u32;
-      let endpoint_blue_y: u32 = 0 as u32;
-      let endpoint_blue_z: u32 = 0 as u32;
-      let gamma_red: u32 = 0 as u32;
-      let gamma_green: u32 = 0 as u32;
-      let gamma_blue: u32 = 0 as u32;
-
-      file.write_le_u32(red_mask).unwrap();
-      file.write_le_u32(green_mask).unwrap();
-      file.write_le_u32(blue_mask).unwrap();
-      file.write_le_u32(alpha_mask).unwrap();
-      file.write_le_u32(cs_type).unwrap();
-      file.write_le_u32(endpoint_red_x).unwrap();
-      file.write_le_u32(endpoint_red_y).unwrap();
-      file.write_le_u32(endpoint_red_z).unwrap();
-      file.write_le_u32(endpoint_green_x).unwrap();
-      file.write_le_u32(endpoint_green_y).unwrap();
-      file.write_le_u32(endpoint_green_z).unwrap();
-      file.write_le_u32(endpoint_blue_x).unwrap();
-      file.write_le_u32(endpoint_blue_y).unwrap();
-      file.write_le_u32(endpoint_blue_z).unwrap();
-      file.write_le_u32(gamma_red).unwrap();
-      file.write_le_u32(gamma_green).unwrap();
-      file.write_le_u32(gamma_blue).unwrap();
-
-
-      let intent: u32 = 2 as u32; // Rendering intent values not specified
-      let profile_data: u32 = 0 as u32;
-      let profile_size: u32 = 0 as u32;
-      let reserved: u32 = 0 as u32;
-
-      file.write_le_u32(intent).unwrap();
-      file.write_le_u32(profile_data).unwrap();
-      file.write_le_u32(profile_size).unwrap();
-      file.write_le_u32(reserved).unwrap();
-
+      let stride = row_stride(image.width, 32);
+      let mut pixel_data: Vec<u8> = Vec::from_elem(stride * image.height, 0u8);
 
       for y in range(0, image.height) {
 
-        let bmp_y = image.height - 1 - y;
+        let bmp_y = if options.top_down { y } else { image.height - 1 - y };
 
         for x in range(0, image.width) {
 
           let i = x * 4 + image.width * bmp_y * 4;
+          let j = x * 4 + stride * y;
 
-          // Write ABGR
-          file.write_u8(*image.data.get(i+3)).unwrap();
-          file.write_u8(*image.data.get(i+2)).unwrap();
-          file.write_u8(*image.data.get(i+1)).unwrap();
-          file.write_u8(*image.data.get(i)).unwrap();
+          // Write ABGR, 32-bit scanlines never need padding
+          *pixel_data.get_mut(j)     = *image.data.get(i + 3);
+          *pixel_data.get_mut(j + 1) = *image.data.get(i + 2);
+          *pixel_data.get_mut(j + 2) = *image.data.get(i + 1);
+          *pixel_data.get_mut(j + 3) = *image.data.get(i);
 
         }
       }
 
-      true
+      let layout = Layout {
+        bits_per_pixel: 32,
+        compression_type: 3,
+        top_down: options.top_down,
+        masks: (0xFF000000, 0x00FF0000, 0x0000FF00, 0x000000FF),
+        palette: Vec::new(),
+        pixel_data: pixel_data,
+      };
+      write_layout(image, &layout, file)
+
+    },
+
+  }
+
+}
+
+// Writes palette indices as an indexed image with 1, 4 or 8 bits per pixel,
+// run-length encoded when the options ask for it and the depth is 8
+fn encode_indexed<W: Writer>(image: &Image, indices: &[u8], palette: &[(u8, u8, u8)], bits: uint, file: &mut W, options: &BmpOptions) -> Result<(), BmpError> {
+
+  let rle = options.rle && bits == 8;
+  let top_down = options.top_down && !rle;    // Compressed images can't be top down
+
+  let layout = Layout {
+    bits_per_pixel: bits as u16,
+    compression_type: if rle { 1 } else { 0 },   // 0 is uncompressed, 1 is RLE algorithm, 2 is 4-bit RLE algorithm
+    top_down: top_down,
+    masks: (0, 0, 0, 0),    // No masks for indexed images
+    palette: Vec::from_slice(palette),
+    pixel_data: if rle {
+      encode_rle8(image.width, image.height, indices)
+    }
+    else {
+      pack_indices(image.width, image.height, indices, bits, top_down)
+    },
+  };
+  write_layout(image, &layout, file)
+}
+
+// Everything that differs between color types when writing, the pixel data
+// already encoded with scanlines in file order
+struct Layout {
+  bits_per_pixel: u16,
+  compression_type: u32,
+  top_down: bool,
+  masks: (u32, u32, u32, u32),    // Red, green, blue and alpha
+  palette: Vec<(u8, u8, u8)>,
+  pixel_data: Vec<u8>,
+}
 
+// Writes the headers, color table, pixel data and ICC profile of an image. A
+// BMP 4.x header is used unless the image has alpha or colorimetry, which need
+// BMP 5.x for the alpha mask, rendering intent and profile.
+fn write_layout<W: Writer>(image: &Image, layout: &Layout, file: &mut W) -> Result<(), BmpError> {
+
+  // Linked profiles are a null terminated file name
+  let profile: Vec<u8> = match image.colorimetry {
+    Some(Colorimetry{color_space: EmbeddedProfile(ref bytes), ..}) => bytes.clone(),
+    Some(Colorimetry{color_space: LinkedProfile(ref name), ..}) => {
+      let mut bytes: Vec<u8> = name.as_slice().chars().map(|c| c as u8).collect();
+      bytes.push(0);
+      bytes
     },
+    _ => Vec::new()
+  };
+
+  let (red_mask, green_mask, blue_mask, alpha_mask) = layout.masks;
+  let header_size: u32 = if alpha_mask != 0 || image.colorimetry.is_some() { 124 } else { 108 };   // Bitmap 4.x => 108, Bitmap 5.x => 124
+  let palette_size: u32 = layout.palette.len() as u32 * 4;
+  let size_of_bitmap: u32 = layout.pixel_data.len() as u32;   // Size in bytes
+
+  let filesize: u32 = 14 + header_size + palette_size + size_of_bitmap + profile.len() as u32;
+  let reserved: u32 = 0 as u32;
+  let bitmap_offset: u32 = 14 + header_size + palette_size;   // Bitmap 4.x => 122, Bitmap 5.x => 138, plus palette
+
+  try!(io(file.write(SIGNATURE.as_bytes())));
+  try!(io(file.write_le_u32(filesize)));
+  try!(io(file.write_le_u32(reserved)));
+  try!(io(file.write_le_u32(bitmap_offset)));
+
+
+  let image_width: i32 = image.width as i32;    // In pixels
+  let image_height: i32 = if layout.top_down { -(image.height as i32) } else { image.height as i32 };   // In pixels, negative for top down
+  let planes: u16 = 1 as u16;         // Number of color planes, in BMP this is always 1
+
+  try!(io(file.write_le_u32(header_size)));
+  try!(io(file.write_le_i32(image_width)));
+  try!(io(file.write_le_i32(image_height)));
+  try!(io(file.write_le_u16(planes)));
+  try!(io(file.write_le_u16(layout.bits_per_pixel)));
+
+
+  // Defaults to 72 DPI
+  let (horizontal_resolution, vertical_resolution) = match image.resolution {
+    Some(ref resolution) => (resolution.horizontal.round() as u32, resolution.vertical.round() as u32),   // In pixels per meter
+    None => (2835u32, 2835u32)
+  };
+  let colors_used: u32 = layout.palette.len() as u32;   // Number of colors in palette, 0 if no palette
+  let colors_important: u32 = 0 as u32;   // 0 if all colors are important
+
+  try!(io(file.write_le_u32(layout.compression_type)));
+  try!(io(file.write_le_u32(size_of_bitmap)));
+  try!(io(file.write_le_u32(horizontal_resolution)));
+  try!(io(file.write_le_u32(vertical_resolution)));
+  try!(io(file.write_le_u32(colors_used)));
+  try!(io(file.write_le_u32(colors_important)));
+
+  try!(io(file.write_le_u32(red_mask)));
+  try!(io(file.write_le_u32(green_mask)));
+  try!(io(file.write_le_u32(blue_mask)));
+  try!(io(file.write_le_u32(alpha_mask)));
+
+
+  // Colorimetry, written as sRGB when the image doesn't have any
+  let (cs_type, colorimetry) = match image.colorimetry {
+    Some(ref colorimetry) => {
+      let cs_type = match colorimetry.color_space {
+        CalibratedRGB       => LCS_CALIBRATED_RGB,
+        SRGB                => LCS_SRGB,
+        WindowsColorSpace   => LCS_WINDOWS_COLOR_SPACE,
+        EmbeddedProfile(_)  => PROFILE_EMBEDDED,
+        LinkedProfile(_)    => PROFILE_LINKED
+      };
+      (cs_type, colorimetry.clone())
+    },
+    None => {
+      let colorimetry = Colorimetry {
+        color_space: SRGB,
+        red: (0., 0., 0.),
+        green: (0., 0., 0.),
+        blue: (0., 0., 0.),
+        gamma: (0., 0., 0.),
+        intent: 2,    // Rendering intent values not specified
+      };
+      (LCS_SRGB, colorimetry)
+    }
+  };
+
+  let (endpoint_red_x, endpoint_red_y, endpoint_red_z) = to_fixed_endpoint(colorimetry.red);
+  let (endpoint_green_x, endpoint_green_y, endpoint_green_z) = to_fixed_endpoint(colorimetry.green);
+  let (endpoint_blue_x, endpoint_blue_y, endpoint_blue_z) = to_fixed_endpoint(colorimetry.blue);
+  let (gamma_red, gamma_green, gamma_blue) = colorimetry.gamma;
+
+  try!(io(file.write_le_u32(cs_type)));
+  try!(io(file.write_le_i32(endpoint_red_x)));
+  try!(io(file.write_le_i32(endpoint_red_y)));
+  try!(io(file.write_le_i32(endpoint_red_z)));
+  try!(io(file.write_le_i32(endpoint_green_x)));
+  try!(io(file.write_le_i32(endpoint_green_y)));
+  try!(io(file.write_le_i32(endpoint_green_z)));
+  try!(io(file.write_le_i32(endpoint_blue_x)));
+  try!(io(file.write_le_i32(endpoint_blue_y)));
+  try!(io(file.write_le_i32(endpoint_blue_z)));
+  try!(io(file.write_le_u32((gamma_red * FXPT16DOT16).round() as u32)));
+  try!(io(file.write_le_u32((gamma_green * FXPT16DOT16).round() as u32)));
+  try!(io(file.write_le_u32((gamma_blue * FXPT16DOT16).round() as u32)));
+
+
+  // The profile goes after the pixel data, its offset is from the start of the info header
+  if header_size == 124 {
+    let intent: u32 = colorimetry.intent;
+    let profile_data: u32 = if profile.len() > 0 { header_size + palette_size + size_of_bitmap } else { 0 };
+    let profile_size: u32 = profile.len() as u32;
+    let reserved: u32 = 0 as u32;
+
+    try!(io(file.write_le_u32(intent)));
+    try!(io(file.write_le_u32(profile_data)));
+    try!(io(file.write_le_u32(profile_size)));
+    try!(io(file.write_le_u32(reserved)));
+  }
 
+
+  // Color table, stored as BGRX
+  for &(r, g, b) in layout.palette.iter() {
+    try!(io(file.write_u8(b)));
+    try!(io(file.write_u8(g)));
+    try!(io(file.write_u8(r)));
+    try!(io(file.write_u8(0)));
+  }
+
+  try!(io(file.write(layout.pixel_data.as_slice())));
+  try!(io(file.write(profile.as_slice())));
+
+  Ok(())
+}
+
+// Packs one index per pixel into 1, 4 or 8 bit scanlines, padded to 4 bytes
+fn pack_indices(width: uint, height: uint, indices: &[u8], bits: uint, top_down: bool) -> Vec<u8> {
+
+  let stride = row_stride(width, bits);
+  let mut packed: Vec<u8> = Vec::from_elem(stride * height, 0u8);
+
+  for y in range(0, height) {
+    let bmp_y = if top_down { y } else { height - 1 - y };
+    for x in range(0, width) {
+      let index = indices[x + width * bmp_y];
+      let bit = x * bits;
+      let shift = 8 - bits - bit % 8;
+      *packed.get_mut(y * stride + bit / 8) |= index << shift;
+    }
+  }
+
+  packed
+}
+
+// Reads 1, 4 or 8 bit palette indices, one per pixel, with scanlines in file order
+fn unpack_indices<R: Reader>(image: &mut R, width: uint, height: uint, bits: uint) -> Result<Vec<u8>, BmpError> {
+
+  let row_bytes = (width * bits + 7) / 8;
+  let stride = row_stride(width, bits);
+  let mask = ((1u << bits) - 1) as u8;
+  let mut indices: Vec<u8> = Vec::new();
+
+  for _ in range(0, height) {
+    let row = try!(read_bytes(image, row_bytes));
+    for x in range(0, width) {
+      let bit = x * bits;
+      let shift = 8 - bits - bit % 8;
+      indices.push((*row.get(bit / 8) >> shift) & mask);
+    }
+
+    // Padding, all scanlines must be multiple of 4
+    try!(read_padding(image, stride - row_bytes));
+  }
+
+  Ok(indices)
+}
+
+// Collects the distinct colors of an RGB8 image, returning the palette and one
+// index per pixel, or None if there are more than 256 colors
+fn build_palette(image: &Image) -> Option<(Vec<(u8, u8, u8)>, Vec<u8>)> {
+
+  let mut palette: Vec<(u8, u8, u8)> = Vec::new();
+  let mut lookup: HashMap<(u8, u8, u8), u8> = HashMap::new();
+  let mut indices: Vec<u8> = Vec::with_capacity(image.width * image.height);
+
+  for i in range(0, image.width * image.height) {
+    let color = (*image.data.get(i * 3), *image.data.get(i * 3 + 1), *image.data.get(i * 3 + 2));
+    let index = match lookup.find_copy(&color) {
+      Some(index) => index,
+      None => {
+        if palette.len() == 256 {
+          return None;
+        }
+        let index = palette.len() as u8;
+        palette.push(color);
+        lookup.insert(color, index);
+        index
+      }
+    };
+    indices.push(index);
   }
 
+  Some((palette, indices))
+}
+
+// Run-length encodes one palette index per pixel as BI_RLE8, scanlines bottom
+// up. Runs of two or more use encoded mode, stretches without runs of three
+// use absolute mode so they aren't doubled in size.
+fn encode_rle8(width: uint, height: uint, indices: &[u8]) -> Vec<u8> {
+
+  let mut encoded: Vec<u8> = Vec::new();
+
+  for y in range(0, height) {
+
+    let bmp_y = height - 1 - y;
+    let start = width * bmp_y;
+    let row = indices.slice(start, start + width);
+    let mut i = 0u;
+
+    while i < row.len() {
+
+      // Encoded mode
+      let mut run = 1u;
+      while i + run < row.len() && run < 255 && row[i + run] == row[i] {
+        run += 1;
+      }
+      if run >= 2 {
+        encoded.push(run as u8);
+        encoded.push(row[i]);
+        i += run;
+        continue;
+      }
+
+      // Absolute mode, up until the next run of three
+      let mut end = i;
+      while end < row.len() && end - i < 255 {
+        if end + 2 < row.len() && row[end] == row[end + 1] && row[end + 1] == row[end + 2] {
+          break;
+        }
+        end += 1;
+      }
+
+      let length = end - i;
+      if length >= 3 {
+        encoded.push(0);
+        encoded.push(length as u8);
+        encoded.push_all(row.slice(i, end));
+        if length % 2 == 1 {
+          encoded.push(0);
+        }
+      }
+      else {
+        // Absolute mode needs at least 3 pixels, shorter stretches are runs of 1
+        for &index in row.slice(i, end).iter() {
+          encoded.push(1);
+          encoded.push(index);
+        }
+      }
+      i = end;
+    }
+
+    // End of line, or end of bitmap after the last scanline
+    encoded.push(0);
+    if y == height - 1 {
+      encoded.push(1);
+    }
+    else {
+      encoded.push(0);
+    }
+  }
+
+  encoded
 }
 
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use image::*;
+  use std::io::{MemWriter, BufReader};
+
+  // Builds a BMPv3 file around the given color table and already encoded pixel data
+  fn bitmap_bytes(width: u32, height: i32, bits_per_pixel: u16, compression_type: u32, palette: &[(u8, u8, u8)], pixels: &[u8]) -> Vec<u8> {
+    bitfields_bytes(width, height, bits_per_pixel, compression_type, &[], palette, pixels)
+  }
+
+  // Same as bitmap_bytes, with BI_BITFIELDS masks stored after the header
+  fn bitfields_bytes(width: u32, height: i32, bits_per_pixel: u16, compression_type: u32, masks: &[u32], palette: &[(u8, u8, u8)], pixels: &[u8]) -> Vec<u8> {
+    let mut file = MemWriter::new();
+    let offset = 14 + 40 + masks.len() as u32 * 4 + palette.len() as u32 * 4;
+
+    file.write("BM".as_bytes()).unwrap();
+    file.write_le_u32(offset + pixels.len() as u32).unwrap();
+    file.write_le_u32(0).unwrap();
+    file.write_le_u32(offset).unwrap();
+
+    file.write_le_u32(40).unwrap();
+    file.write_le_u32(width).unwrap();
+    file.write_le_i32(height).unwrap();
+    file.write_le_u16(1).unwrap();
+    file.write_le_u16(bits_per_pixel).unwrap();
+    file.write_le_u32(compression_type).unwrap();
+    file.write_le_u32(pixels.len() as u32).unwrap();
+    file.write_le_u32(2835).unwrap();
+    file.write_le_u32(2835).unwrap();
+    file.write_le_u32(palette.len() as u32).unwrap();
+    file.write_le_u32(0).unwrap();
+
+    for &mask in masks.iter() {
+      file.write_le_u32(mask).unwrap();
+    }
+    for &(r, g, b) in palette.iter() {
+      file.write(&[b, g, r, 0]).unwrap();
+    }
+    file.write(pixels).unwrap();
+    file.unwrap()
+  }
 
   // Reading: verify meta data
 
@@ -763,17 +1351,657 @@ mod tests {
 
       
       match image {
-        Some(image) => {
+        Ok(image) => {
           let path_prefix: String = "../test/bmp/".to_string();
           let path_to_write: String = path_prefix.append(*filename);
-          assert!(write_bitmap(image, path_to_write.as_slice()));
+          assert!(write_bitmap(image, path_to_write.as_slice()).is_ok());
         },
-        None  => {
-          fail!("Looks like you didn't get a valid image.");
+        Err(e)  => {
+          fail!("Looks like you didn't get a valid image: {}", e);
         }
       }
 
     }
 
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn test_missing_file() {
+    match read_bitmap("../bmp/does_not_exist.bmp") {
+      Err(IoFailure(_)) => {},
+      Err(e)  => fail!("Expected an I/O failure, got {}", e),
+      Ok(_)   => fail!("Read an image from a file that doesn't exist")
+    }
+  }
+
+  #[test]
+  fn test_bad_signature() {
+    let bytes = [0x50u8, 0x4E, 0x47, 0x00, 0x00, 0x00];
+    match from_bytes(&bytes) {
+      Err(BadSignature) => {},
+      Err(e)  => fail!("Expected a bad signature, got {}", e),
+      Ok(_)   => fail!("Decoded an image without a BMP signature")
+    }
+  }
+
+  #[test]
+  fn test_truncated_header() {
+    let bytes = [0x42u8, 0x4D, 0x36, 0x00];
+    match from_bytes(&bytes) {
+      Err(TruncatedHeader) => {},
+      Err(e)  => fail!("Expected a truncated header, got {}", e),
+      Ok(_)   => fail!("Decoded an image from a truncated header")
+    }
+  }
+
+  #[test]
+  fn test_huge_dimensions() {
+    // Headers claiming billions of pixels with almost no data fail once the data runs out
+    let palette = [(0u8, 0u8, 0u8), (9u8, 9u8, 9u8)];
+    for &compression_type in [0u32, 1].iter() {
+      let bytes = bitmap_bytes(0x7FFFFFFF, 0x7FFFFFFF, 8, compression_type, &palette, &[0x01u8, 0x01]);
+      match from_bytes(bytes.as_slice()) {
+        Err(IoFailure(_)) => {},
+        Err(e)  => fail!("Expected the data to run out, got {}", e),
+        Ok(_)   => fail!("Decoded an image far larger than its data")
+      }
+    }
+  }
+
+  #[test]
+  fn test_round_trip_in_memory() {
+    let mut image = Image::new(4, 2, RGB8);
+    image.set_pixel(0, 0, vec!(255, 0, 0));
+    image.set_pixel(3, 1, vec!(0, 128, 255));
+
+    let bytes = to_bytes(&image).unwrap();
+    let decoded = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(decoded.width, 4);
+    assert_eq!(decoded.height, 2);
+    assert_eq!(decoded.data, image.data);
+  }
+
+  #[test]
+  fn test_read_info() {
+    let image = Image::new(6, 3, GRAYSCALE8);
+    let bytes = to_bytes(&image).unwrap();
+    let info = decode_bitmap_info(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(info.version, BmpV4);
+    assert_eq!(info.header_size, 108);
+    assert_eq!(info.width, 6);
+    assert_eq!(info.height, 3);
+    assert_eq!(info.bits_per_pixel, 8);
+    assert_eq!(info.compression_type, 0);
+    assert_eq!(info.palette_size, 256);
+
+    let image = Image::new(2, 5, RGBA8);
+    let bytes = to_bytes(&image).unwrap();
+    let info = decode_bitmap_info(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(info.version, BmpV5);
+    assert_eq!(info.bits_per_pixel, 32);
+    assert_eq!(info.compression_type, 3);
+    assert_eq!(info.alpha_mask, 0x000000FF);
+    assert_eq!(info.palette_size, 0);
+  }
+
+  #[test]
+  fn test_decode_rle8() {
+    let palette = [(0u8, 0u8, 0u8), (255u8, 0u8, 0u8)];
+    let pixels = [
+      0x03u8, 0x01, 0x01, 0x00, 0x00, 0x00,       // Bottom row: run of three 1s, one 0, end of line
+      0x00, 0x03, 0x00, 0x01, 0x01, 0x00,         // Top row: absolute 0 1 1, padded
+      0x00, 0x01,                                 // End of bitmap
+    ];
+    let bytes = bitmap_bytes(4, 2, 8, 1, &palette, &pixels);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+
+    assert_eq!(image.width, 4);
+    assert_eq!(image.height, 2);
+    assert_eq!(image.get_pixel(0, 0), vec!(0, 0, 0));
+    assert_eq!(image.get_pixel(1, 0), vec!(255, 0, 0));
+    assert_eq!(image.get_pixel(2, 0), vec!(255, 0, 0));
+    assert_eq!(image.get_pixel(3, 0), vec!(0, 0, 0));
+    assert_eq!(image.get_pixel(0, 1), vec!(255, 0, 0));
+    assert_eq!(image.get_pixel(2, 1), vec!(255, 0, 0));
+    assert_eq!(image.get_pixel(3, 1), vec!(0, 0, 0));
+  }
+
+  #[test]
+  fn test_decode_rle8_delta() {
+    let palette = [(0u8, 0u8, 0u8), (9u8, 9u8, 9u8)];
+    let pixels = [
+      0x00u8, 0x02, 0x02, 0x01,                   // Delta: 2 right, 1 up
+      0x01, 0x01,                                 // One pixel of index 1
+      0x00, 0x01,                                 // End of bitmap
+    ];
+    let bytes = bitmap_bytes(3, 2, 8, 1, &palette, &pixels);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+
+    assert_eq!(image.data, vec!(0, 0, 9, 0, 0, 0));
+  }
+
+  #[test]
+  fn test_decode_rle4() {
+    let mut palette: Vec<(u8, u8, u8)> = Vec::new();
+    for i in range(0u8, 16) {
+      palette.push((i * 17, i * 17, i * 17));
+    }
+    let pixels = [
+      0x04u8, 0x12,                               // 1 2 1 2
+      0x00, 0x00,                                 // End of line
+      0x00, 0x03, 0x34, 0x50,                     // Absolute 3 4 5
+      0x00, 0x01,                                 // End of bitmap
+    ];
+    let bytes = bitmap_bytes(4, 2, 4, 2, palette.as_slice(), &pixels);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+
+    match image.color_type {
+      GRAYSCALE8 => {},
+      _ => fail!("Gray palette should decode to GRAYSCALE8")
+    }
+    assert_eq!(image.data, vec!(51, 68, 85, 0, 17, 34, 17, 34));
+  }
+
+  #[test]
+  fn test_encode_rle8() {
+    let mut image = Image::new(16, 8, GRAYSCALE8);
+    for x in range(0u, 16) {
+      image.set_pixel(x, 2, vec!(x as u8 * 10));
+    }
+    image.set_pixel(7, 5, vec!(200));
+
+    let options = BmpOptions{rle: true, ..BmpOptions::new()};
+    let bytes = to_bytes_with(&image, &options).unwrap();
+    let uncompressed = to_bytes(&image).unwrap();
+    assert!(bytes.len() < uncompressed.len());
+
+    let info = decode_bitmap_info(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(info.compression_type, 1);
+
+    let decoded = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(decoded.data, image.data);
+  }
+
+  #[test]
+  fn test_decode_1bit() {
+    let palette = [(0u8, 0u8, 0u8), (255u8, 255u8, 0u8)];
+    let pixels = [
+      0b01000000u8, 0, 0, 0,                      // Bottom row: 0 1 0
+      0b10100000u8, 0, 0, 0,                      // Top row: 1 0 1
+    ];
+    let bytes = bitmap_bytes(3, 2, 1, 0, &palette, &pixels);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+
+    assert_eq!(image.get_pixel(0, 0), vec!(255, 255, 0));
+    assert_eq!(image.get_pixel(1, 0), vec!(0, 0, 0));
+    assert_eq!(image.get_pixel(2, 0), vec!(255, 255, 0));
+    assert_eq!(image.get_pixel(0, 1), vec!(0, 0, 0));
+    assert_eq!(image.get_pixel(1, 1), vec!(255, 255, 0));
+  }
+
+  #[test]
+  fn test_decode_4bit() {
+    let palette = [(10u8, 20u8, 30u8), (40u8, 50u8, 60u8), (70u8, 80u8, 90u8)];
+    let pixels = [0x12u8, 0x00, 0x00, 0x00];      // 1 2 0
+    let bytes = bitmap_bytes(3, 1, 4, 0, &palette, &pixels);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+
+    assert_eq!(image.data, vec!(40, 50, 60, 70, 80, 90, 10, 20, 30));
+  }
+
+  #[test]
+  fn test_grayscale_odd_width() {
+    let mut image = Image::new(5, 3, GRAYSCALE8);
+    for i in range(0u, 15) {
+      *image.data.get_mut(i) = i as u8 * 16;
+    }
+
+    let decoded = from_bytes(to_bytes(&image).unwrap().as_slice()).unwrap();
+    assert_eq!(decoded.data, image.data);
+  }
+
+  #[test]
+  fn test_write_indexed() {
+    let options = BmpOptions{palette: true, ..BmpOptions::new()};
+
+    // Two colors fit in 1 bit, a third needs 4
+    let mut image = Image::new(7, 3, RGB8);
+    image.set_pixel(1, 1, vec!(200, 10, 10));
+    let bytes = to_bytes_with(&image, &options).unwrap();
+    let info = decode_bitmap_info(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(info.bits_per_pixel, 1);
+    assert_eq!(from_bytes(bytes.as_slice()).unwrap().data, image.data);
+
+    image.set_pixel(6, 2, vec!(0, 0, 255));
+    let bytes = to_bytes_with(&image, &options).unwrap();
+    let info = decode_bitmap_info(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(info.bits_per_pixel, 4);
+    assert_eq!(info.colors_used, 3);
+    assert_eq!(from_bytes(bytes.as_slice()).unwrap().data, image.data);
+
+    // The default options index too, so a plain write is just as small
+    let bytes = to_bytes(&image).unwrap();
+    let info = decode_bitmap_info(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(info.bits_per_pixel, 4);
+    assert_eq!(from_bytes(bytes.as_slice()).unwrap().data, image.data);
+
+    // Unless indexing is turned off
+    let direct = BmpOptions{palette: false, ..BmpOptions::new()};
+    let bytes = to_bytes_with(&image, &direct).unwrap();
+    let info = decode_bitmap_info(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(info.bits_per_pixel, 24);
+
+    // Only grays still decode as RGB8, with an unused color in the palette
+    let mut image = Image::new(5, 2, RGB8);
+    image.set_pixel(3, 1, vec!(255, 255, 255));
+    let bytes = to_bytes(&image).unwrap();
+    let info = decode_bitmap_info(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(info.bits_per_pixel, 4);
+    assert_eq!(info.colors_used, 3);
+    let decoded = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(decoded.color_type as uint, RGB8 as uint);
+    assert_eq!(decoded.data, image.data);
+
+    let image = Image::new(4, 4, RGB8);
+    assert_eq!(check_round_trip(&image, &BmpOptions::new()), 1);
+
+    // More than 256 colors falls back to 24 bits
+    let mut image = Image::new(20, 20, RGB8);
+    for y in range(0u, 20) {
+      for x in range(0u, 20) {
+        image.set_pixel(x, y, vec!(x as u8, y as u8, 0));
+      }
+    }
+    let bytes = to_bytes_with(&image, &options).unwrap();
+    let info = decode_bitmap_info(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(info.bits_per_pixel, 24);
+  }
+
+  #[test]
+  fn test_decode_16bit() {
+    // 5-6-5 masks, full red then full green
+    let masks = [0xF800u32, 0x07E0, 0x001F];
+    let pixels = [0x00u8, 0xF8, 0xE0, 0x07];
+    let bytes = bitfields_bytes(2, 1, 16, 3, &masks, &[], &pixels);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.data, vec!(255, 0, 0, 0, 255, 0));
+
+    // BI_RGB is always 5-5-5, with small channels scaled up to 8 bits
+    let pixels = [0x10u8, 0x7C, 0x00, 0x00];
+    let bytes = bitmap_bytes(1, 1, 16, 0, &[], &pixels);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.data, vec!(255, 0, 132));
+  }
+
+  #[test]
+  fn test_decode_32bit_masks() {
+    // BGRA byte order, as most screen capture tools write it
+    let masks = [0x00FF0000u32, 0x0000FF00, 0x000000FF];
+    let pixels = [0x30u8, 0x20, 0x10, 0x00];
+    let bytes = bitfields_bytes(1, 1, 32, 3, &masks, &[], &pixels);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    match image.color_type {
+      RGB8 => {},
+      _ => fail!("No alpha mask should decode to RGB8")
+    }
+    assert_eq!(image.data, vec!(0x10, 0x20, 0x30));
+
+    // 10 bits per channel keeps the top 8
+    let masks = [0x3FF00000u32, 0x000FFC00, 0x000003FF];
+    let pixel: u32 = (0x3FF << 20) | (0x200 << 10) | 0x004;
+    let pixels = [pixel as u8, (pixel >> 8) as u8, (pixel >> 16) as u8, (pixel >> 24) as u8];
+    let bytes = bitfields_bytes(1, 1, 32, 3, &masks, &[], &pixels);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.data, vec!(255, 128, 1));
+  }
+
+  #[test]
+  fn test_rgba_round_trip() {
+    let mut image = Image::new(3, 2, RGBA8);
+    image.set_pixel(0, 0, vec!(1, 2, 3, 4));
+    image.set_pixel(2, 1, vec!(250, 251, 252, 253));
+
+    let decoded = from_bytes(to_bytes(&image).unwrap().as_slice()).unwrap();
+    match decoded.color_type {
+      RGBA8 => {},
+      _ => fail!("Alpha mask should decode to RGBA8")
+    }
+    assert_eq!(decoded.data, image.data);
+  }
+
+  #[test]
+  fn test_decode_top_down() {
+    let palette = [(0u8, 0u8, 0u8), (255u8, 255u8, 255u8)];
+    let pixels = [
+      0x01u8, 0x00, 0x00, 0x00,                   // Top row: 1 0
+      0x00u8, 0x01, 0x00, 0x00,                   // Bottom row: 0 1
+    ];
+    let bytes = bitmap_bytes(2, -2, 8, 0, &palette, &pixels);
+    let info = decode_bitmap_info(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(info.height, -2);
+
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.height, 2);
+    assert_eq!(image.data, vec!(255, 0, 0, 255));
+  }
+
+  #[test]
+  fn test_write_top_down() {
+    let options = BmpOptions{top_down: true, ..BmpOptions::new()};
+
+    let mut image = Image::new(3, 2, RGB8);
+    image.set_pixel(0, 0, vec!(9, 8, 7));
+    let bytes = to_bytes_with(&image, &options).unwrap();
+    let info = decode_bitmap_info(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(info.height, -2);
+    assert_eq!(from_bytes(bytes.as_slice()).unwrap().data, image.data);
+
+    let mut image = Image::new(3, 2, GRAYSCALE8);
+    image.set_pixel(2, 0, vec!(77));
+    let bytes = to_bytes_with(&image, &options).unwrap();
+    assert_eq!(from_bytes(bytes.as_slice()).unwrap().data, image.data);
+
+    let mut image = Image::new(3, 2, RGBA8);
+    image.set_pixel(1, 0, vec!(1, 2, 3, 4));
+    let bytes = to_bytes_with(&image, &options).unwrap();
+    assert_eq!(from_bytes(bytes.as_slice()).unwrap().data, image.data);
+  }
+
+  #[test]
+  fn test_decode_core_header() {
+    let mut file = MemWriter::new();
+    file.write("BM".as_bytes()).unwrap();
+    file.write_le_u32(14 + 12 + 6 + 8).unwrap();
+    file.write_le_u32(0).unwrap();
+    file.write_le_u32(14 + 12 + 6).unwrap();
+
+    file.write_le_u32(12).unwrap();
+    file.write_le_u16(3).unwrap();
+    file.write_le_u16(2).unwrap();
+    file.write_le_u16(1).unwrap();
+    file.write_le_u16(1).unwrap();
+
+    file.write(&[0x00u8, 0x00, 0xFF]).unwrap();   // Red, BGR without a reserved byte
+    file.write(&[0xFFu8, 0x00, 0x00]).unwrap();   // Blue
+    file.write(&[0b11000000u8, 0, 0, 0]).unwrap();  // Bottom row: 1 1 0
+    file.write(&[0b00100000u8, 0, 0, 0]).unwrap();  // Top row: 0 0 1
+    let bytes = file.unwrap();
+
+    let info = decode_bitmap_info(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(info.version, BmpV2);
+    assert_eq!(info.width, 3);
+    assert_eq!(info.height, 2);
+    assert_eq!(info.palette_size, 2);
+
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.data, vec!(255, 0, 0, 255, 0, 0, 0, 0, 255, 0, 0, 255, 0, 0, 255, 255, 0, 0));
+  }
+
+  #[test]
+  fn test_decode_v3_alpha_header() {
+    let mut file = MemWriter::new();
+    file.write("BM".as_bytes()).unwrap();
+    file.write_le_u32(14 + 56 + 4).unwrap();
+    file.write_le_u32(0).unwrap();
+    file.write_le_u32(14 + 56).unwrap();
+
+    file.write_le_u32(56).unwrap();
+    file.write_le_i32(1).unwrap();
+    file.write_le_i32(1).unwrap();
+    file.write_le_u16(1).unwrap();
+    file.write_le_u16(32).unwrap();
+    file.write_le_u32(3).unwrap();
+    file.write_le_u32(4).unwrap();
+    for _ in range(0u, 4) {
+      file.write_le_u32(0).unwrap();
+    }
+    file.write_le_u32(0x00FF0000).unwrap();
+    file.write_le_u32(0x0000FF00).unwrap();
+    file.write_le_u32(0x000000FF).unwrap();
+    file.write_le_u32(0xFF000000).unwrap();
+    file.write(&[0x30u8, 0x20, 0x10, 0x80]).unwrap();
+    let bytes = file.unwrap();
+
+    let info = decode_bitmap_info(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(info.version, BmpV3Alpha);
+    assert_eq!(info.alpha_mask, 0xFF000000);
+
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.data, vec!(0x10, 0x20, 0x30, 0x80));
+  }
+
+  #[test]
+  fn test_decode_v3_masks_header() {
+    let mut file = MemWriter::new();
+    file.write("BM".as_bytes()).unwrap();
+    file.write_le_u32(14 + 52 + 4).unwrap();
+    file.write_le_u32(0).unwrap();
+    file.write_le_u32(14 + 52).unwrap();
+
+    file.write_le_u32(52).unwrap();
+    file.write_le_i32(2).unwrap();
+    file.write_le_i32(1).unwrap();
+    file.write_le_u16(1).unwrap();
+    file.write_le_u16(16).unwrap();
+    file.write_le_u32(3).unwrap();
+    file.write_le_u32(4).unwrap();
+    for _ in range(0u, 4) {
+      file.write_le_u32(0).unwrap();
+    }
+    file.write_le_u32(0xF800).unwrap();
+    file.write_le_u32(0x07E0).unwrap();
+    file.write_le_u32(0x001F).unwrap();
+    file.write(&[0x1Fu8, 0x00, 0x00, 0xF8]).unwrap();
+    let bytes = file.unwrap();
+
+    let info = decode_bitmap_info(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(info.version, BmpV3Masks);
+
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.data, vec!(0, 0, 255, 255, 0, 0));
+  }
+
+  // Values exactly representable in fixed point, so they compare equal after a round trip
+  fn test_colorimetry(color_space: ColorSpace) -> Colorimetry {
+    Colorimetry {
+      color_space: color_space,
+      red: (0.640625, 0.328125, 0.03125),
+      green: (0.296875, 0.59375, 0.109375),
+      blue: (0.15625, 0.0625, 0.78125),
+      gamma: (2.19921875, 2.19921875, 1.796875),
+      intent: 4,
+    }
+  }
+
+  #[test]
+  fn test_embedded_profile_round_trip() {
+    let profile: Vec<u8> = range(0u, 300).map(|i| (i % 251) as u8).collect();
+
+    let mut image = Image::new(3, 2, RGB8);
+    image.set_pixel(1, 1, vec!(10, 20, 30));
+    image.colorimetry = Some(test_colorimetry(EmbeddedProfile(profile.clone())));
+
+    let bytes = to_bytes(&image).unwrap();
+    let info = decode_bitmap_info(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(info.version, BmpV5);
+    assert_eq!(info.profile_size, 300);
+    assert!(info.has_profile());
+
+    let decoded = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(decoded.data, image.data);
+    assert_eq!(decoded.colorimetry, image.colorimetry);
+  }
+
+  #[test]
+  fn test_linked_profile_round_trip() {
+    let mut image = Image::new(2, 2, GRAYSCALE8);
+    image.colorimetry = Some(test_colorimetry(LinkedProfile("C:\\profiles\\scanner.icc".to_string())));
+
+    let decoded = from_bytes(to_bytes(&image).unwrap().as_slice()).unwrap();
+    assert_eq!(decoded.colorimetry, image.colorimetry);
+  }
+
+  #[test]
+  fn test_calibrated_round_trip() {
+    let mut image = Image::new(2, 2, RGBA8);
+    image.colorimetry = Some(test_colorimetry(CalibratedRGB));
+
+    let bytes = to_bytes(&image).unwrap();
+    let info = decode_bitmap_info(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(info.cs_type, 0);
+    assert_eq!(info.gamma_red, 144128);   // 2.19921875 in 16.16 fixed point
+    assert!(!info.has_profile());
+
+    let decoded = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(decoded.colorimetry, image.colorimetry);
+  }
+
+  #[test]
+  fn test_profile_outside_file() {
+    let mut image = Image::new(2, 2, RGB8);
+    image.colorimetry = Some(test_colorimetry(EmbeddedProfile(vec!(1, 2, 3, 4))));
+
+    // Drop the last byte of the profile
+    let mut bytes = to_bytes(&image).unwrap();
+    bytes.pop();
+    match from_bytes(bytes.as_slice()) {
+      Err(BadProfile) => {},
+      Err(e)  => fail!("Expected a bad profile, got {}", e),
+      Ok(_)   => fail!("Decoded a profile past the end of the file")
+    }
+  }
+
+  #[test]
+  fn test_decode_32bit_bgrx() {
+    // Unused fourth byte, as GDI screenshots leave it
+    let pixels = [0x30u8, 0x20, 0x10, 0x00, 0x03, 0x02, 0x01, 0x00];
+    let bytes = bitmap_bytes(2, 1, 32, 0, &[], &pixels);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    match image.color_type {
+      RGB8 => {},
+      _ => fail!("Zeroed fourth byte should decode to RGB8")
+    }
+    assert_eq!(image.data, vec!(0x10, 0x20, 0x30, 0x01, 0x02, 0x03));
+
+    // Fully opaque is the same as no alpha
+    let pixels = [0x30u8, 0x20, 0x10, 0xFF, 0x03, 0x02, 0x01, 0xFF];
+    let bytes = bitmap_bytes(2, 1, 32, 0, &[], &pixels);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.data, vec!(0x10, 0x20, 0x30, 0x01, 0x02, 0x03));
+  }
+
+  #[test]
+  fn test_decode_32bit_bgra() {
+    let pixels = [0x30u8, 0x20, 0x10, 0x80, 0x03, 0x02, 0x01, 0xFF];
+    let bytes = bitmap_bytes(2, 1, 32, 0, &[], &pixels);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    match image.color_type {
+      RGBA8 => {},
+      _ => fail!("Varying fourth byte should decode to RGBA8")
+    }
+    assert_eq!(image.data, vec!(0x10, 0x20, 0x30, 0x80, 0x01, 0x02, 0x03, 0xFF));
+  }
+
+  // Padded scanline size worked out byte by byte, independent of row_stride
+  fn expected_stride(width: uint, bits_per_pixel: uint) -> uint {
+    let mut stride = (width * bits_per_pixel + 7) / 8;
+    while stride % 4 != 0 {
+      stride += 1;
+    }
+    stride
+  }
+
+  // Fills an image with a pattern that differs between neighboring pixels and rows
+  fn pattern_image(width: uint, height: uint, color_type: ColorType, colors: uint) -> Image {
+    let mut image = Image::new(width, height, color_type);
+    let channels = image.data.len() / (width * height);
+    for y in range(0, height) {
+      for x in range(0, width) {
+        let color = ((x * 7 + y * 3) % colors) as u8;
+        let pixel: Vec<u8> = range(0, channels).map(|c| color * 13 + c as u8 * 50).collect();
+        image.set_pixel(x, y, pixel);
+      }
+    }
+    image
+  }
+
+  // Checks that sizes in the headers match the bytes written, then decodes the
+  // image back. Returns the bit depth it was written with.
+  fn check_round_trip(image: &Image, options: &BmpOptions) -> u16 {
+    let bytes = to_bytes_with(image, options).unwrap();
+    let info = decode_bitmap_info(&mut BufReader::new(bytes.as_slice())).unwrap();
+
+    assert_eq!(info.file_size as uint, bytes.len());
+    if info.compression_type != 1 {
+      let pixel_bytes = expected_stride(image.width, info.bits_per_pixel as uint) * image.height;
+      assert_eq!(info.size_of_bitmap as uint, pixel_bytes);
+      assert_eq!(info.offset as uint + pixel_bytes, bytes.len());
+    }
+
+    let decoded = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(decoded.color_type as uint, image.color_type as uint);
+    assert_eq!(decoded.width, image.width);
+    assert_eq!(decoded.height, image.height);
+    assert_eq!(decoded.data, image.data);
+
+    info.bits_per_pixel
+  }
+
+  #[test]
+  fn test_row_stride() {
+    for width in range(1u, 17) {
+      for &bits in [1u, 4, 8, 16, 24, 32].iter() {
+        assert_eq!(row_stride(width, bits), expected_stride(width, bits));
+      }
+    }
+  }
+
+  #[test]
+  fn test_round_trip_all_widths() {
+    let bottom_up = BmpOptions::new();
+    let top_down = BmpOptions{top_down: true, ..BmpOptions::new()};
+
+    for width in range(1u, 17) {
+      for &options in [&bottom_up, &top_down].iter() {
+        assert_eq!(check_round_trip(&pattern_image(width, 3, GRAYSCALE8, 256), options), 8);
+        let direct = BmpOptions{palette: false, ..options.clone()};
+        assert_eq!(check_round_trip(&pattern_image(width, 3, RGB8, 256), &direct), 24);
+        assert_eq!(check_round_trip(&pattern_image(width, 3, RGBA8, 256), options), 32);
+
+        // Depth depends on how many colors fit in the image
+        let indexed = BmpOptions{palette: true, ..options.clone()};
+        assert_eq!(check_round_trip(&pattern_image(width, 3, RGB8, 2), &indexed), 1);
+        assert!(check_round_trip(&pattern_image(width, 3, RGB8, 11), &indexed) <= 4);
+        assert!(check_round_trip(&pattern_image(width, 3, RGB8, 40), &indexed) <= 8);
+      }
+
+      let rle = BmpOptions{rle: true, ..BmpOptions::new()};
+      assert_eq!(check_round_trip(&pattern_image(width, 3, GRAYSCALE8, 5), &rle), 8);
+    }
+  }
+
+  #[test]
+  fn test_resolution_round_trip() {
+    let mut image = Image::new(2, 2, RGB8);
+    image.resolution = Some(Resolution::from_dpi(300., 600.));
+
+    let bytes = to_bytes(&image).unwrap();
+    let info = decode_bitmap_info(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(info.horizontal_resolution, 11811);
+    assert_eq!(info.vertical_resolution, 23622);
+
+    let decoded = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(decoded.color_type as uint, RGB8 as uint);
+    assert_eq!(decoded.data, image.data);
+    let (horizontal, vertical) = decoded.resolution.unwrap().dpi();
+    assert_eq!(horizontal.round(), 300.);
+    assert_eq!(vertical.round(), 600.);
+
+    // No resolution at all is left as None
+    let mut bytes = bitmap_bytes(1, 1, 24, 0, &[], &[0u8, 0, 0, 0]);
+    for i in range(38u, 46) {
+      *bytes.get_mut(i) = 0;    // Both resolution fields of the header
+    }
+    assert!(from_bytes(bytes.as_slice()).unwrap().resolution.is_none());
+  }
+}
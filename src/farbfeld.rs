@@ -0,0 +1,217 @@
+// Farbfeld Image format
+
+use std::path::posix::{Path};
+use std::io::{File, IoResult, IoError, EndOfFile, BufReader, MemWriter};
+use std::cmp;
+use image::*;
+
+static SIGNATURE: &'static str = "farbfeld";
+
+
+/* NOTES:
+ * Farbfeld is "farbfeld", big endian 32 bit width and height, then rows top to bottom of
+ *   16 bit big endian red, green, blue and alpha samples
+ * Samples are sRGB with linear, unpremultiplied alpha, the same as the Image struct but at twice the depth
+ * Images decode as RGBA8 with samples rounded to 8 bits, and 8 bit samples are written as value * 257,
+ *   so an RGBA8 image comes back unchanged
+ * GRAYSCALE8 and RGB8 images are written opaque, with gray copied to each of red, green and blue
+ */
+
+
+// Everything that can go wrong while reading or writing a farbfeld image
+#[deriving(Show)]
+pub enum FarbfeldError {
+  BadSignature,                   // File doesn't start with "farbfeld"
+  Truncated,                      // File ended before all the pixels were read
+  InvalidDimensions(uint, uint),  // Width or height of zero
+  IoFailure(IoError),             // Any other error from the underlying file
+}
+
+// Wraps an I/O error so it can be returned with try!
+fn io<T>(result: IoResult<T>) -> Result<T, FarbfeldError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => Err(IoFailure(e))
+  }
+}
+
+// Same as io(), but running out of bytes means the file was cut short
+fn chunk<T>(result: IoResult<T>) -> Result<T, FarbfeldError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => {
+      if e.kind == EndOfFile {
+        Err(Truncated)
+      }
+      else {
+        Err(IoFailure(e))
+      }
+    }
+  }
+}
+
+
+#[allow(dead_code)]
+pub fn read_farbfeld(image_path_str: &str) -> Result<Image, FarbfeldError> {
+  let path = Path::new(image_path_str);
+  let mut file = try!(io(File::open(&path)));
+  decode_farbfeld(&mut file)
+}
+
+// Decodes a farbfeld image held in memory
+#[allow(dead_code)]
+pub fn from_bytes(bytes: &[u8]) -> Result<Image, FarbfeldError> {
+  let mut reader = BufReader::new(bytes);
+  decode_farbfeld(&mut reader)
+}
+
+// Decodes a farbfeld image from any reader as RGBA8
+#[allow(dead_code)]
+pub fn decode_farbfeld<R: Reader>(file: &mut R) -> Result<Image, FarbfeldError> {
+
+  let signature = try!(chunk(file.read_exact(8)));
+  if signature.as_slice() != SIGNATURE.as_bytes() {
+    return Err(BadSignature);
+  }
+  let width = try!(chunk(file.read_be_u32())) as uint;
+  let height = try!(chunk(file.read_be_u32())) as uint;
+  if width == 0 || height == 0 {
+    return Err(InvalidDimensions(width, height));
+  }
+
+  // The header's size isn't trusted for allocating, so pixels are read a buffer
+  // at a time and the data grows only as far as the file goes
+  let mut data: Vec<u8> = Vec::new();
+  let mut buffer = [0u8, ..8 * 1024];
+  for _ in range(0, height) {
+    let mut left = width * 8;
+    while left > 0 {
+      let length = cmp::min(left, buffer.len());
+      try!(chunk(file.read_at_least(length, buffer.mut_slice_to(length))));
+      for sample in buffer.slice_to(length).chunks(2) {
+        let value = ((sample[0] as uint) << 8) | sample[1] as uint;
+        data.push(((value * 255 + 32767) / 65535) as u8);
+      }
+      left -= length;
+    }
+  }
+
+  Ok(Image{width: width, height: height, color_type: RGBA8, data: data, colorimetry: None, resolution: None})
+}
+
+
+#[allow(dead_code)]
+pub fn write_farbfeld(image: Image, filename: &str) -> Result<(), FarbfeldError> {
+  let path = Path::new(filename);
+  let mut file = try!(io(File::create(&path)));
+  encode_farbfeld(&image, &mut file)
+}
+
+// Encodes a farbfeld image into a new in-memory buffer
+#[allow(dead_code)]
+pub fn to_bytes(image: &Image) -> Result<Vec<u8>, FarbfeldError> {
+  let mut writer = MemWriter::new();
+  try!(encode_farbfeld(image, &mut writer));
+  Ok(writer.unwrap())
+}
+
+// Encodes an image of any color type as farbfeld to any writer
+#[allow(dead_code)]
+pub fn encode_farbfeld<W: Writer>(image: &Image, file: &mut W) -> Result<(), FarbfeldError> {
+
+  if image.width == 0 || image.height == 0 {
+    return Err(InvalidDimensions(image.width, image.height));
+  }
+
+  try!(io(file.write(SIGNATURE.as_bytes())));
+  try!(io(file.write_be_u32(image.width as u32)));
+  try!(io(file.write_be_u32(image.height as u32)));
+
+  let channels = image.data.len() / (image.width * image.height);
+  let mut row: Vec<u8> = Vec::with_capacity(image.width * 8);
+  for samples in image.data.as_slice().chunks(image.width * channels) {
+    row.clear();
+    for pixel in samples.chunks(channels) {
+      let rgba = match image.color_type {
+        GRAYSCALE8  => [pixel[0], pixel[0], pixel[0], 255],
+        RGB8        => [pixel[0], pixel[1], pixel[2], 255],
+        RGBA8       => [pixel[0], pixel[1], pixel[2], pixel[3]]
+      };
+      // value * 257 is the value in both bytes
+      for &value in rgba.iter() {
+        row.push_all(&[value, value]);
+      }
+    }
+    try!(io(file.write(row.as_slice())));
+  }
+  Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::*;
+
+  #[test]
+  fn test_round_trip() {
+    let mut image = Image::new(3, 2, RGBA8);
+    for (i, sample) in image.data.mut_iter().enumerate() {
+      *sample = (i * 11) as u8;
+    }
+    let bytes = to_bytes(&image).unwrap();
+    assert_eq!(bytes.len(), 16 + 3 * 2 * 8);
+    assert_eq!(bytes.slice(0, 20), b"farbfeld\x00\x00\x00\x03\x00\x00\x00\x02\x00\x00\x0B\x0B");
+
+    let decoded = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(decoded.color_type as uint, RGBA8 as uint);
+    assert_eq!((decoded.width, decoded.height), (3, 2));
+    assert_eq!(decoded.data, image.data);
+  }
+
+  #[test]
+  fn test_color_types() {
+    let mut gray = Image::new(2, 1, GRAYSCALE8);
+    gray.data = vec!(0, 200);
+    let decoded = from_bytes(to_bytes(&gray).unwrap().as_slice()).unwrap();
+    assert_eq!(decoded.data, vec!(0, 0, 0, 255,  200, 200, 200, 255));
+
+    let mut rgb = Image::new(1, 1, RGB8);
+    rgb.data = vec!(10, 20, 30);
+    let decoded = from_bytes(to_bytes(&rgb).unwrap().as_slice()).unwrap();
+    assert_eq!(decoded.data, vec!(10, 20, 30, 255));
+  }
+
+  #[test]
+  fn test_rounding() {
+    // 16 bit samples round to the nearest 8 bit value
+    let mut bytes: Vec<u8> = Vec::from_slice(b"farbfeld\x00\x00\x00\x01\x00\x00\x00\x01");
+    bytes.push_all(&[0x00, 0x80,  0x01, 0x00,  0x7F, 0xFF,  0xFF, 0xFF]);
+    let decoded = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(decoded.data, vec!(0, 1, 127, 255));
+  }
+
+  #[test]
+  fn test_errors() {
+    match from_bytes(b"farbfelt\x00\x00\x00\x01\x00\x00\x00\x01") {
+      Err(BadSignature) => {},
+      Err(e)  => fail!("Expected a bad signature, got {}", e),
+      Ok(_)   => fail!("Decoded a file that isn't farbfeld")
+    }
+    match from_bytes(b"farbfeld\x00\x00\x00\x00\x00\x00\x00\x01") {
+      Err(InvalidDimensions(0, 1)) => {},
+      Err(e)  => fail!("Expected invalid dimensions, got {}", e),
+      Ok(_)   => fail!("Decoded an image without width")
+    }
+    match from_bytes(b"farbfeld\x00\x00\x00\x01\x00\x00\x00\x02\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF") {
+      Err(Truncated) => {},
+      Err(e)  => fail!("Expected a truncated file, got {}", e),
+      Ok(_)   => fail!("Decoded a file missing pixels")
+    }
+    match from_bytes(b"farbfeld\xFF\xFF\xFF\xFF\xFF\xFF\xFF\xFF\x00\x00\x00\x00") {
+      Err(Truncated) => {},
+      Err(e)  => fail!("Expected a truncated file, got {}", e),
+      Ok(_)   => fail!("Decoded a file far smaller than its dimensions")
+    }
+  }
+}
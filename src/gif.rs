@@ -0,0 +1,952 @@
+// GIF Image format
+
+use std::path::posix::{Path};
+use std::io::{File, IoResult, IoError, EndOfFile, BufReader, MemWriter};
+use std::iter::range_step;
+use std::collections::HashMap;
+use std::cmp;
+use image::*;
+
+// First row and row spacing of each pass of an interlaced image
+static INTERLACE_PASSES: [(uint, uint), ..4] = [(0, 8), (4, 8), (2, 4), (1, 2)];
+
+// Codes never grow past 12 bits, so a table holds at most 4096 entries
+static MAX_CODES: uint = 4096;
+
+
+/* NOTES:
+ * GIF is a header, the logical screen (canvas) size and an optional global palette, then blocks until a 0x3B trailer
+ * All integers are little endian
+ * Each image block covers a rectangle of the canvas with palette indices, LZW compressed and split into
+ *   sub-blocks of at most 255 bytes, each after its length
+ * A graphic control extension before an image gives its delay, its transparent index, and its disposal:
+ *   what happens to its rectangle before the next image is drawn
+ * LZW codes are packed least significant bit first and grow from the minimum code size + 1 up to 12 bits
+ * Interlaced images store every 8th row from 0, every 8th from 4, every 4th from 2, then every 2nd from 1
+ * Frames are drawn onto a canvas that starts out transparent, ignoring the background color like browsers do,
+ *   so every frame decodes as RGBA8
+ * Written frames cover the whole canvas and each carry their own palette, median cut when there are too many colors
+ */
+
+
+// Everything that can go wrong while reading or writing a GIF image
+#[deriving(Show)]
+pub enum GifError {
+  BadSignature,                   // File doesn't start with GIF87a or GIF89a
+  Truncated,                      // File ended in the middle of a block
+  InvalidDimensions(uint, uint),  // Canvas or image of zero width or height, or too large to write
+  BadBlock(u8),                   // Found a byte where a block introducer should be
+  BadCodeSize(u8),                // LZW minimum code size of 0 or above 11
+  BadCode,                        // LZW code that isn't in the table yet
+  NoFrames,                       // File without any images, or nothing to write
+  FrameSizeMismatch(uint),        // Frame to write isn't the same size as the first
+  NotRGB(uint),                   // Frame to write isn't RGB8 or RGBA8
+  IoFailure(IoError),             // Any other error from the underlying file
+}
+
+// Wraps an I/O error so it can be returned with try!
+fn io<T>(result: IoResult<T>) -> Result<T, GifError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => Err(IoFailure(e))
+  }
+}
+
+// Same as io(), but running out of bytes means the file was cut short
+fn chunk<T>(result: IoResult<T>) -> Result<T, GifError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => {
+      if e.kind == EndOfFile {
+        Err(Truncated)
+      }
+      else {
+        Err(IoFailure(e))
+      }
+    }
+  }
+}
+
+// One frame of an animation, the whole canvas as it's shown for delay hundredths of a second
+pub struct GifFrame {
+  pub image: Image,
+  pub delay: u16,
+}
+
+// Contents of a graphic control extension, which applies to the next image
+struct GraphicControl {
+  delay: u16,
+  transparent: Option<u8>,
+  disposal: u8,             // 0 or 1 = leave the image, 2 = clear its rectangle, 3 = restore what was under it
+}
+
+impl GraphicControl {
+
+  fn new() -> GraphicControl {
+    GraphicControl{delay: 0, transparent: None, disposal: 0}
+  }
+
+}
+
+
+#[allow(dead_code)]
+pub fn read_gif(image_path_str: &str) -> Result<Vec<GifFrame>, GifError> {
+  let path = Path::new(image_path_str);
+  let mut file = try!(io(File::open(&path)));
+  decode_gif(&mut file)
+}
+
+// Decodes a GIF image held in memory
+#[allow(dead_code)]
+pub fn from_bytes(bytes: &[u8]) -> Result<Vec<GifFrame>, GifError> {
+  let mut reader = BufReader::new(bytes);
+  decode_gif(&mut reader)
+}
+
+// Decodes every frame of a GIF image from any reader, composited onto the
+// canvas as RGBA8 images. A still image is a single frame.
+#[allow(dead_code)]
+pub fn decode_gif<R: Reader>(file: &mut R) -> Result<Vec<GifFrame>, GifError> {
+
+  let signature = try!(chunk(file.read_exact(6)));
+  if signature.as_slice() != "GIF87a".as_bytes() && signature.as_slice() != "GIF89a".as_bytes() {
+    return Err(BadSignature);
+  }
+
+  let width = try!(chunk(file.read_le_u16())) as uint;
+  let height = try!(chunk(file.read_le_u16())) as uint;
+  let flags = try!(chunk(file.read_u8()));
+  try!(chunk(file.read_u8()));    // Background color
+  try!(chunk(file.read_u8()));    // Pixel aspect ratio
+  if width == 0 || height == 0 {
+    return Err(InvalidDimensions(width, height));
+  }
+
+  let global_palette = if flags & 0x80 != 0 { try!(read_palette(file, flags)) } else { Vec::new() };
+
+  // Allocated once there's an image to draw, so the screen size alone can't ask for memory
+  let mut canvas: Vec<u8> = Vec::new();
+  let mut frames: Vec<GifFrame> = Vec::new();
+  let mut control = GraphicControl::new();
+
+  loop {
+    let introducer = match file.read_u8() {
+      Ok(byte) => byte,
+      // Plenty of files stop without a trailer after their last image
+      Err(ref e) if e.kind == EndOfFile && frames.len() > 0 => break,
+      Err(e) => return chunk(Err(e))
+    };
+
+    match introducer {
+      0x3B => break,
+
+      0x21 => {
+        let label = try!(chunk(file.read_u8()));
+        let data = try!(read_sub_blocks(file));
+        if label == 0xF9 && data.len() >= 4 {
+          control = GraphicControl {
+            delay: *data.get(1) as u16 | ((*data.get(2) as u16) << 8),
+            transparent: if *data.get(0) & 1 != 0 { Some(*data.get(3)) } else { None },
+            disposal: (*data.get(0) >> 2) & 7,
+          };
+        }
+      },
+
+      0x2C => {
+        let left = try!(chunk(file.read_le_u16())) as uint;
+        let top = try!(chunk(file.read_le_u16())) as uint;
+        let image_width = try!(chunk(file.read_le_u16())) as uint;
+        let image_height = try!(chunk(file.read_le_u16())) as uint;
+        let flags = try!(chunk(file.read_u8()));
+        if image_width == 0 || image_height == 0 {
+          return Err(InvalidDimensions(image_width, image_height));
+        }
+
+        let local_palette = if flags & 0x80 != 0 { Some(try!(read_palette(file, flags))) } else { None };
+        let palette = match local_palette {
+          Some(ref palette) => palette.as_slice(),
+          None => global_palette.as_slice()
+        };
+
+        let min_size = try!(chunk(file.read_u8()));
+        if min_size == 0 || min_size > 11 {
+          return Err(BadCodeSize(min_size));
+        }
+        let data = try!(read_sub_blocks(file));
+        let indices = try!(lzw_decode(data.as_slice(), min_size as uint, image_width * image_height));
+        if canvas.len() == 0 {
+          canvas = Vec::from_elem(width * height * 4, 0u8);
+        }
+
+        let rows: Vec<uint> = if flags & 0x40 != 0 {
+          let mut rows = Vec::with_capacity(image_height);
+          for &(start, step) in INTERLACE_PASSES.iter() {
+            for row in range_step(start, image_height, step) {
+              rows.push(row);
+            }
+          }
+          rows
+        }
+        else {
+          range(0, image_height).collect()
+        };
+
+        let previous = if control.disposal == 3 { Some(canvas.clone()) } else { None };
+
+        // Pixels off the canvas, transparent, or past the end of the palette are left alone
+        for (i, &index) in indices.iter().enumerate() {
+          let x = left + i % image_width;
+          let y = top + *rows.get(i / image_width);
+          if x >= width || y >= height || Some(index) == control.transparent || index as uint * 3 + 2 >= palette.len() {
+            continue;
+          }
+          let offset = (y * width + x) * 4;
+          let color = index as uint * 3;
+          *canvas.get_mut(offset) = palette[color];
+          *canvas.get_mut(offset + 1) = palette[color + 1];
+          *canvas.get_mut(offset + 2) = palette[color + 2];
+          *canvas.get_mut(offset + 3) = 255;
+        }
+
+        let image = Image{width: width, height: height, color_type: RGBA8, data: canvas.clone(), colorimetry: None, resolution: None};
+        frames.push(GifFrame{image: image, delay: control.delay});
+
+        match control.disposal {
+          2 => {
+            for y in range(top, cmp::min(top + image_height, height)) {
+              for x in range(left, cmp::min(left + image_width, width)) {
+                for c in range(0u, 4) {
+                  *canvas.get_mut((y * width + x) * 4 + c) = 0;
+                }
+              }
+            }
+          },
+          3 => canvas = previous.unwrap(),
+          _ => {}
+        }
+        control = GraphicControl::new();
+      },
+
+      _ => return Err(BadBlock(introducer))
+    }
+  }
+
+  if frames.len() == 0 {
+    return Err(NoFrames);
+  }
+  Ok(frames)
+}
+
+// Palette following a screen or image descriptor, as RGB triples
+fn read_palette<R: Reader>(file: &mut R, flags: u8) -> Result<Vec<u8>, GifError> {
+  chunk(file.read_exact(3 * (2 << (flags & 7) as uint)))
+}
+
+// Joins a run of sub-blocks, up to the zero length that ends them
+fn read_sub_blocks<R: Reader>(file: &mut R) -> Result<Vec<u8>, GifError> {
+  let mut data: Vec<u8> = Vec::new();
+  loop {
+    let size = try!(chunk(file.read_u8())) as uint;
+    if size == 0 {
+      return Ok(data);
+    }
+    data.push_all(try!(chunk(file.read_exact(size))).as_slice());
+  }
+}
+
+// Decompresses up to count palette indices. Data that ends early, with or
+// without an end code, gives fewer indices rather than an error. The output
+// grows with the codes rather than being sized from the frame.
+fn lzw_decode(data: &[u8], min_size: uint, count: uint) -> Result<Vec<u8>, GifError> {
+
+  let clear = 1u << min_size;
+  let end = clear + 1;
+
+  // Each entry is an earlier entry plus one more index
+  let mut prefixes: Vec<u16> = Vec::from_elem(MAX_CODES, 0u16);
+  let mut suffixes: Vec<u8> = Vec::from_elem(MAX_CODES, 0u8);
+  let mut lengths: Vec<u16> = Vec::from_elem(MAX_CODES, 0u16);
+  for code in range(0, clear) {
+    *suffixes.get_mut(code) = code as u8;
+    *lengths.get_mut(code) = 1;
+  }
+
+  let mut output: Vec<u8> = Vec::new();
+  let mut width = min_size + 1;
+  let mut next = clear + 2;
+  let mut previous: Option<uint> = None;
+  let mut bits = 0u32;
+  let mut available = 0u;
+  let mut position = 0u;
+
+  while output.len() < count {
+    while available < width && position < data.len() {
+      bits |= (data[position] as u32) << available;
+      available += 8;
+      position += 1;
+    }
+    if available < width {
+      break;
+    }
+    let code = (bits & ((1 << width) - 1)) as uint;
+    bits >>= width;
+    available -= width;
+
+    if code == clear {
+      width = min_size + 1;
+      next = clear + 2;
+      previous = None;
+      continue;
+    }
+    if code == end {
+      break;
+    }
+
+    let prefix = match previous {
+      Some(prefix) => prefix,
+      None => {
+        if code > clear {
+          return Err(BadCode);
+        }
+        output.push(code as u8);
+        previous = Some(code);
+        continue;
+      }
+    };
+
+    // A code one past the table is the previous entry followed by its own first index
+    let start = output.len();
+    if code < next {
+      write_entry(&mut output, code, prefixes.as_slice(), suffixes.as_slice(), lengths.as_slice());
+    }
+    else if code == next {
+      write_entry(&mut output, prefix, prefixes.as_slice(), suffixes.as_slice(), lengths.as_slice());
+      let first = *output.get(start);
+      output.push(first);
+    }
+    else {
+      return Err(BadCode);
+    }
+
+    if next < MAX_CODES {
+      *prefixes.get_mut(next) = prefix as u16;
+      *suffixes.get_mut(next) = *output.get(start);
+      *lengths.get_mut(next) = *lengths.get(prefix) + 1;
+      next += 1;
+      if next == 1 << width && width < 12 {
+        width += 1;
+      }
+    }
+    previous = Some(code);
+  }
+
+  output.truncate(count);
+  Ok(output)
+}
+
+// Appends the indices of a table entry, which are found last to first
+fn write_entry(output: &mut Vec<u8>, code: uint, prefixes: &[u16], suffixes: &[u8], lengths: &[u16]) {
+  let start = output.len();
+  let length = lengths[code] as uint;
+  output.grow(length, &0u8);
+  let mut code = code;
+  for i in range(0, length).rev() {
+    *output.get_mut(start + i) = suffixes[code];
+    code = prefixes[code] as uint;
+  }
+}
+
+
+// Settings for how an animation is written
+#[deriving(Show, Clone)]
+pub struct GifOptions {
+  pub loop_count: Option<u16>,    // Times an animation repeats after playing once, 0 forever, None for no looping extension
+}
+
+impl GifOptions {
+
+  // Loop forever, which is what most viewers assume
+  pub fn new() -> GifOptions {
+    GifOptions{loop_count: Some(0)}
+  }
+
+}
+
+#[allow(dead_code)]
+pub fn write_gif(frames: &[GifFrame], filename: &str) -> Result<(), GifError> {
+  write_gif_with(frames, filename, &GifOptions::new())
+}
+
+#[allow(dead_code)]
+pub fn write_gif_with(frames: &[GifFrame], filename: &str, options: &GifOptions) -> Result<(), GifError> {
+  let path = Path::new(filename);
+  let mut file = try!(io(File::create(&path)));
+  encode_gif_with(frames, &mut file, options)
+}
+
+// Encodes a GIF image into a new in-memory buffer
+#[allow(dead_code)]
+pub fn to_bytes(frames: &[GifFrame]) -> Result<Vec<u8>, GifError> {
+  to_bytes_with(frames, &GifOptions::new())
+}
+
+#[allow(dead_code)]
+pub fn to_bytes_with(frames: &[GifFrame], options: &GifOptions) -> Result<Vec<u8>, GifError> {
+  let mut writer = MemWriter::new();
+  try!(encode_gif_with(frames, &mut writer, options));
+  Ok(writer.unwrap())
+}
+
+// Encodes RGB8 or RGBA8 frames of the same size as a GIF to any writer. A
+// single frame makes a still image. Pixels under half alpha are transparent.
+#[allow(dead_code)]
+pub fn encode_gif<W: Writer>(frames: &[GifFrame], file: &mut W) -> Result<(), GifError> {
+  encode_gif_with(frames, file, &GifOptions::new())
+}
+
+#[allow(dead_code)]
+pub fn encode_gif_with<W: Writer>(frames: &[GifFrame], file: &mut W, options: &GifOptions) -> Result<(), GifError> {
+
+  if frames.len() == 0 {
+    return Err(NoFrames);
+  }
+  let width = frames[0].image.width;
+  let height = frames[0].image.height;
+  if width == 0 || height == 0 || width > 65535 || height > 65535 {
+    return Err(InvalidDimensions(width, height));
+  }
+  for (i, frame) in frames.iter().enumerate() {
+    if frame.image.width != width || frame.image.height != height {
+      return Err(FrameSizeMismatch(i));
+    }
+    match frame.image.color_type {
+      GRAYSCALE8 => return Err(NotRGB(i)),
+      _ => {}
+    }
+  }
+
+  try!(io(file.write("GIF89a".as_bytes())));
+  try!(io(file.write_le_u16(width as u16)));
+  try!(io(file.write_le_u16(height as u16)));
+  try!(io(file.write(&[0, 0, 0])));   // No global palette, background color and aspect ratio
+
+  if frames.len() > 1 {
+    match options.loop_count {
+      Some(count) => {
+        try!(io(file.write(&[0x21, 0xFF, 11])));
+        try!(io(file.write("NETSCAPE2.0".as_bytes())));
+        try!(io(file.write(&[3, 1])));
+        try!(io(file.write_le_u16(count)));
+        try!(io(file.write_u8(0)));
+      },
+      None => {}
+    }
+  }
+
+  for frame in frames.iter() {
+    let (palette, indices, transparent) = quantize(&frame.image);
+
+    // Frames with transparent pixels are cleared before the next one so the two don't mix
+    let disposal = if transparent.is_some() { 2u8 } else { 1u8 };
+    let flags = (disposal << 2) | if transparent.is_some() { 1 } else { 0 };
+    try!(io(file.write(&[0x21, 0xF9, 4, flags])));
+    try!(io(file.write_le_u16(frame.delay)));
+    try!(io(file.write(&[transparent.unwrap_or(0), 0])));
+
+    // Palettes hold a power of two colors, the transparent index included
+    let colors = palette.len() + if transparent.is_some() { 1 } else { 0 };
+    let mut bits = 1u;
+    while (1u << bits) < colors {
+      bits += 1;
+    }
+
+    try!(io(file.write_u8(0x2C)));
+    try!(io(file.write(&[0, 0, 0, 0])));
+    try!(io(file.write_le_u16(width as u16)));
+    try!(io(file.write_le_u16(height as u16)));
+    try!(io(file.write_u8(0x80 | (bits - 1) as u8)));
+    for i in range(0, 1u << bits) {
+      let (r, g, b) = if i < palette.len() { *palette.get(i) } else { (0, 0, 0) };
+      try!(io(file.write(&[r, g, b])));
+    }
+
+    let min_size = cmp::max(2, bits);
+    try!(io(file.write_u8(min_size as u8)));
+    let data = lzw_encode(indices.as_slice(), min_size);
+    for block in data.as_slice().chunks(255) {
+      try!(io(file.write_u8(block.len() as u8)));
+      try!(io(file.write(block)));
+    }
+    try!(io(file.write_u8(0)));
+  }
+
+  io(file.write_u8(0x3B))
+}
+
+// Palette and one index per pixel for a frame. Pixels under half alpha take
+// a transparent index after the colors. When there are too many colors the
+// palette is a median cut and every pixel takes the nearest color.
+fn quantize(image: &Image) -> (Vec<(u8, u8, u8)>, Vec<u8>, Option<u8>) {
+
+  let channels = image.color_type as uint / 8;
+  let pixels: Vec<Option<(u8, u8, u8)>> = image.data.as_slice().chunks(channels).map(|pixel| {
+    if channels == 4 && pixel[3] < 128 { None } else { Some((pixel[0], pixel[1], pixel[2])) }
+  }).collect();
+
+  let mut counts: HashMap<(u8, u8, u8), u32> = HashMap::new();
+  let mut transparent = false;
+  for pixel in pixels.iter() {
+    match *pixel {
+      Some(color) => {
+        let count = counts.find_copy(&color).unwrap_or(0);
+        counts.insert(color, count + 1);
+      },
+      None => transparent = true
+    }
+  }
+
+  // Sorted so the same frame always gets the same palette
+  let mut histogram: Vec<((u8, u8, u8), u32)> = counts.iter().map(|(&pixel, &count)| (pixel, count)).collect();
+  histogram.as_mut_slice().sort();
+
+  let limit = if transparent { 255 } else { 256 };
+  let palette: Vec<(u8, u8, u8)> = if histogram.len() <= limit {
+    histogram.iter().map(|&(pixel, _)| pixel).collect()
+  }
+  else {
+    median_cut(histogram.clone(), limit)
+  };
+
+  let mut lookup: HashMap<(u8, u8, u8), u8> = HashMap::new();
+  for &(pixel, _) in histogram.iter() {
+    lookup.insert(pixel, nearest(palette.as_slice(), pixel));
+  }
+
+  let transparent_index = if transparent { Some(palette.len() as u8) } else { None };
+  let indices: Vec<u8> = pixels.iter().map(|pixel| {
+    match *pixel {
+      Some(color) => lookup.find_copy(&color).unwrap(),
+      None => transparent_index.unwrap()
+    }
+  }).collect();
+
+  (palette, indices, transparent_index)
+}
+
+fn channel(color: (u8, u8, u8), channel: uint) -> u8 {
+  let (r, g, b) = color;
+  match channel {
+    0 => r,
+    1 => g,
+    _ => b
+  }
+}
+
+// Splits the colors into boxes, one per palette entry, by repeatedly cutting
+// the box with the widest range in any channel where half of its pixels fall
+// on each side. Each box becomes the average of its pixels.
+fn median_cut(histogram: Vec<((u8, u8, u8), u32)>, limit: uint) -> Vec<(u8, u8, u8)> {
+
+  let mut boxes: Vec<Vec<((u8, u8, u8), u32)>> = vec!(histogram);
+
+  while boxes.len() < limit {
+    let mut widest: Option<(uint, uint, u8)> = None;
+    for (i, colors) in boxes.iter().enumerate() {
+      if colors.len() < 2 {
+        continue;
+      }
+      for c in range(0u, 3) {
+        let low = colors.iter().map(|&(color, _)| channel(color, c)).min().unwrap();
+        let high = colors.iter().map(|&(color, _)| channel(color, c)).max().unwrap();
+        match widest {
+          Some((_, _, extent)) if extent >= high - low => {},
+          _ => widest = Some((i, c, high - low))
+        }
+      }
+    }
+    let (index, c) = match widest {
+      Some((index, c, _)) => (index, c),
+      None => break     // Every box is a single color
+    };
+
+    let mut colors = boxes.remove(index).unwrap();
+    colors.as_mut_slice().sort_by(|&(a, _), &(b, _)| channel(a, c).cmp(&channel(b, c)));
+
+    // Both halves keep at least one color
+    let total = colors.iter().fold(0u64, |sum, &(_, count)| sum + count as u64);
+    let mut seen = 0u64;
+    let mut split = colors.len() - 1;
+    for (i, &(_, count)) in colors.iter().enumerate() {
+      seen += count as u64;
+      if seen * 2 >= total {
+        split = i + 1;
+        break;
+      }
+    }
+    split = cmp::min(split, colors.len() - 1);
+
+    let upper = Vec::from_slice(colors.slice_from(split));
+    colors.truncate(split);
+    boxes.push(colors);
+    boxes.push(upper);
+  }
+
+  boxes.iter().map(|colors| {
+    let mut sums = [0u64, ..3];
+    let mut total = 0u64;
+    for &(color, count) in colors.iter() {
+      for c in range(0u, 3) {
+        sums[c] += channel(color, c) as u64 * count as u64;
+      }
+      total += count as u64;
+    }
+    ((sums[0] / total) as u8, (sums[1] / total) as u8, (sums[2] / total) as u8)
+  }).collect()
+}
+
+// Index of the palette color closest to color
+fn nearest(palette: &[(u8, u8, u8)], color: (u8, u8, u8)) -> u8 {
+  let mut best = 0u;
+  let mut best_distance = -1i;
+  for (i, &entry) in palette.iter().enumerate() {
+    let mut distance = 0i;
+    for c in range(0u, 3) {
+      let difference = channel(entry, c) as int - channel(color, c) as int;
+      distance += difference * difference;
+    }
+    if best_distance < 0 || distance < best_distance {
+      best = i;
+      best_distance = distance;
+    }
+  }
+  best as u8
+}
+
+// Compresses palette indices, starting with a clear code and starting over
+// with another whenever the table fills. Widths change at the same points
+// the decoder's do, which is one entry behind the encoder's table.
+fn lzw_encode(indices: &[u8], min_size: uint) -> Vec<u8> {
+
+  let clear = 1u << min_size;
+  let end = clear + 1;
+
+  let mut writer = CodeWriter{output: Vec::new(), bits: 0, count: 0};
+
+  // Entries are found by the code of their prefix and their last index
+  let mut table: HashMap<u32, uint> = HashMap::new();
+  let mut width = min_size + 1;
+  let mut next = clear + 2;
+  writer.write(clear, width);
+
+  let mut current: Option<uint> = None;
+  for &index in indices.iter() {
+    let prefix = match current {
+      Some(prefix) => prefix,
+      None => {
+        current = Some(index as uint);
+        continue;
+      }
+    };
+    let key = ((prefix as u32) << 8) | index as u32;
+    match table.find_copy(&key) {
+      Some(code) => {
+        current = Some(code);
+        continue;
+      },
+      None => {}
+    }
+
+    writer.write(prefix, width);
+    table.insert(key, next);
+    next += 1;
+    if next == (1 << width) + 1 && width < 12 {
+      width += 1;
+    }
+    if next == MAX_CODES {
+      writer.write(clear, width);
+      table.clear();
+      width = min_size + 1;
+      next = clear + 2;
+    }
+    current = Some(index as uint);
+  }
+
+  match current {
+    Some(code) => {
+      writer.write(code, width);
+      // Reading that code adds an entry, which can widen the end code
+      if next == 1 << width && width < 12 {
+        width += 1;
+      }
+    },
+    None => {}
+  }
+  writer.write(end, width);
+  if writer.count > 0 {
+    writer.output.push(writer.bits as u8);
+  }
+  writer.output
+}
+
+// Packs codes least significant bit first
+struct CodeWriter {
+  output: Vec<u8>,
+  bits: u32,
+  count: uint,
+}
+
+impl CodeWriter {
+
+  fn write(&mut self, code: uint, width: uint) {
+    self.bits |= (code as u32) << self.count;
+    self.count += width;
+    while self.count >= 8 {
+      self.output.push(self.bits as u8);
+      self.bits >>= 8;
+      self.count -= 8;
+    }
+  }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::*;
+
+  // The smallest GIF around: one transparent pixel
+  static TRANSPARENT_PIXEL: [u8, ..43] = [
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xFF, 0xFF, 0xFF, 0x21, 0xF9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2C, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3B,
+  ];
+
+  fn rgb_image(width: uint, height: uint, pixel: |uint, uint| -> (u8, u8, u8)) -> Image {
+    let mut image = Image::new(width, height, RGB8);
+    for y in range(0, height) {
+      for x in range(0, width) {
+        let (r, g, b) = pixel(x, y);
+        let offset = (y * width + x) * 3;
+        *image.data.get_mut(offset) = r;
+        *image.data.get_mut(offset + 1) = g;
+        *image.data.get_mut(offset + 2) = b;
+      }
+    }
+    image
+  }
+
+  fn to_rgba(image: &Image) -> Vec<u8> {
+    let mut data: Vec<u8> = Vec::new();
+    for pixel in image.data.as_slice().chunks(3) {
+      data.push_all(pixel);
+      data.push(255);
+    }
+    data
+  }
+
+  // Builds a GIF from blocks, with a 4 color global palette: black, red, green, blue
+  fn gif_bytes(width: u16, height: u16, blocks: &[Vec<u8>]) -> Vec<u8> {
+    let mut output: Vec<u8> = Vec::from_slice("GIF89a".as_bytes());
+    output.push_all(&[width as u8, (width >> 8) as u8, height as u8, (height >> 8) as u8, 0x81, 0, 0]);
+    output.push_all(&[0, 0, 0, 255, 0, 0, 0, 255, 0, 0, 0, 255]);
+    for block in blocks.iter() {
+      output.push_all(block.as_slice());
+    }
+    output.push(0x3B);
+    output
+  }
+
+  fn control_block(delay: u16, transparent: Option<u8>, disposal: u8) -> Vec<u8> {
+    let flags = (disposal << 2) | if transparent.is_some() { 1 } else { 0 };
+    vec!(0x21, 0xF9, 4, flags, delay as u8, (delay >> 8) as u8, transparent.unwrap_or(0), 0)
+  }
+
+  fn image_block(left: u16, top: u16, width: u16, height: u16, interlaced: bool, indices: &[u8]) -> Vec<u8> {
+    let mut block = vec!(0x2C, left as u8, (left >> 8) as u8, top as u8, (top >> 8) as u8,
+                         width as u8, (width >> 8) as u8, height as u8, (height >> 8) as u8, if interlaced { 0x40 } else { 0 }, 2);
+    let data = super::lzw_encode(indices, 2);
+    for sub_block in data.as_slice().chunks(255) {
+      block.push(sub_block.len() as u8);
+      block.push_all(sub_block);
+    }
+    block.push(0);
+    block
+  }
+
+  #[test]
+  fn test_lzw() {
+    let mut seed = 7u32;
+    let noise: Vec<u8> = range(0u, 20000).map(|_| {
+      seed = seed * 1103515245 + 12345;
+      (seed >> 16) as u8
+    }).collect();
+    let pattern: Vec<u8> = range(0u, 50000).map(|i| ((i / 7) % 4) as u8).collect();
+
+    for &(data, min_size) in [(noise.as_slice(), 8u), (pattern.as_slice(), 2), (pattern.slice(0, 1), 2), (pattern.slice(0, 9), 3), (pattern.slice(0, 0), 2)].iter() {
+      let encoded = super::lzw_encode(data, min_size);
+      assert_eq!(super::lzw_decode(encoded.as_slice(), min_size, data.len()).unwrap().as_slice(), data);
+    }
+
+    // Code 7 when only 6 codes exist
+    match super::lzw_decode(&[0xC4, 0x01], 2, 4) {
+      Err(BadCode) => {},
+      Err(e)  => fail!("Expected a bad code, got {}", e),
+      Ok(_)   => fail!("Decoded an undefined code")
+    }
+  }
+
+  #[test]
+  fn test_transparent_pixel() {
+    let frames = from_bytes(&TRANSPARENT_PIXEL).unwrap();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames.get(0).image.color_type as uint, RGBA8 as uint);
+    assert_eq!(frames.get(0).image.data, vec!(0, 0, 0, 0));
+  }
+
+  #[test]
+  fn test_interlaced() {
+    // One column, every row its own color in stored order
+    let stored = [1u8, 2, 3, 1, 2, 3, 1, 2, 3, 1];
+    let bytes = gif_bytes(1, 10, &[image_block(0, 0, 1, 10, true, &stored)]);
+    let frames = from_bytes(bytes.as_slice()).unwrap();
+
+    // Passes hold rows 0 and 8, then 4, then 2 and 6, then the odd rows
+    let order = [0u, 8, 4, 2, 6, 1, 3, 5, 7, 9];
+    for (i, &row) in order.iter().enumerate() {
+      let color = stored[i] as uint;
+      assert_eq!(frames.get(0).image.data.slice(row * 4, row * 4 + 4), [(color == 1) as u8 * 255, (color == 2) as u8 * 255, (color == 3) as u8 * 255, 255].as_slice());
+    }
+  }
+
+  #[test]
+  fn test_disposal() {
+    let red = [255u8, 0, 0, 255];
+    let green = [0u8, 255, 0, 255];
+    let blue = [0u8, 0, 255, 255];
+    let clear = [0u8, 0, 0, 0];
+
+    // Red background, then a green pixel that's cleared, then a blue pixel
+    // that's restored, then a pixel left transparent
+    let bytes = gif_bytes(2, 1, &[
+      control_block(10, None, 1), image_block(0, 0, 2, 1, false, &[1, 1]),
+      control_block(20, None, 2), image_block(1, 0, 1, 1, false, &[2]),
+      control_block(30, None, 3), image_block(0, 0, 1, 1, false, &[3]),
+      control_block(40, Some(0), 0), image_block(0, 0, 2, 1, false, &[0, 0]),
+    ]);
+    let frames = from_bytes(bytes.as_slice()).unwrap();
+    let delays: Vec<u16> = frames.iter().map(|frame| frame.delay).collect();
+    assert_eq!(delays, vec!(10, 20, 30, 40));
+
+    let expected = [
+      [red, red],
+      [red, green],
+      [blue, clear],
+      [red, clear],
+    ];
+    for (frame, pixels) in frames.iter().zip(expected.iter()) {
+      assert_eq!(frame.image.data.slice(0, 4), pixels[0].as_slice());
+      assert_eq!(frame.image.data.slice(4, 8), pixels[1].as_slice());
+    }
+  }
+
+  #[test]
+  fn test_round_trip() {
+    // Few enough colors to be kept exactly
+    let first = rgb_image(13, 7, |x, y| (x as u8 * 16, y as u8 * 32, 200));
+    let second = rgb_image(13, 7, |x, _| if x % 2 == 0 { (0, 0, 0) } else { (255, 255, 255) });
+    let expected = vec!(to_rgba(&first), to_rgba(&second));
+    let frames = [GifFrame{image: first, delay: 5}, GifFrame{image: second, delay: 50}];
+
+    let bytes = to_bytes(&frames).unwrap();
+    assert!(range(0, bytes.len() - 11).any(|i| bytes.slice(i, i + 11) == "NETSCAPE2.0".as_bytes()));
+
+    let decoded = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(decoded.len(), 2);
+    assert_eq!(decoded.get(0).delay, 5);
+    assert_eq!(decoded.get(1).delay, 50);
+    assert_eq!(decoded.get(0).image.data, *expected.get(0));
+    assert_eq!(decoded.get(1).image.data, *expected.get(1));
+
+    // A still image has no looping extension
+    let still = rgb_image(13, 7, |x, y| (x as u8, y as u8, 0));
+    let bytes = to_bytes(&[GifFrame{image: still, delay: 0}]).unwrap();
+    assert!(!range(0, bytes.len() - 11).any(|i| bytes.slice(i, i + 11) == "NETSCAPE2.0".as_bytes()));
+  }
+
+  #[test]
+  fn test_transparency() {
+    let mut image = Image::new(4, 4, RGBA8);
+    for i in range(0u, 16) {
+      let alpha = if i % 3 == 0 { 0 } else { 255 };
+      image.data.mut_slice(i * 4, i * 4 + 4).copy_from(&[i as u8 * 10, 100, 50, alpha]);
+    }
+    let expected = image.data.clone();
+    let decoded = from_bytes(to_bytes(&[GifFrame{image: image, delay: 0}]).unwrap().as_slice()).unwrap();
+    for i in range(0u, 16) {
+      let pixel = decoded.get(0).image.data.slice(i * 4, i * 4 + 4);
+      if i % 3 == 0 {
+        assert_eq!(pixel[3], 0);
+      }
+      else {
+        assert_eq!(pixel, expected.slice(i * 4, i * 4 + 4));
+      }
+    }
+  }
+
+  #[test]
+  fn test_quantize() {
+    // 4096 colors reduced to 256, each pixel close to its original
+    let image = rgb_image(64, 64, |x, y| (x as u8 * 4, y as u8 * 4, ((x + y) * 2) as u8));
+    let original = to_rgba(&image);
+    let bytes = to_bytes(&[GifFrame{image: image, delay: 0}]).unwrap();
+    let decoded = from_bytes(bytes.as_slice()).unwrap();
+
+    let data = &decoded.get(0).image.data;
+    let mut total = 0u;
+    for i in range(0, original.len()) {
+      let difference = *original.get(i) as int - *data.get(i) as int;
+      total += if difference < 0 { -difference } else { difference } as uint;
+    }
+    assert!((total as f64 / original.len() as f64) < 6.);
+  }
+
+  #[test]
+  fn test_errors() {
+    match from_bytes("GIF88a".as_bytes()) {
+      Err(BadSignature) => {},
+      Err(e)  => fail!("Expected a bad signature, got {}", e),
+      Ok(_)   => fail!("Decoded a file that isn't a GIF")
+    }
+    match from_bytes(TRANSPARENT_PIXEL.slice(0, 30)) {
+      Err(Truncated) => {},
+      Err(e)  => fail!("Expected a truncated file, got {}", e),
+      Ok(_)   => fail!("Decoded a truncated file")
+    }
+
+    // The largest screen and image a file can claim, without any image data
+    match from_bytes(b"GIF89a\xFF\xFF\xFF\xFF\x00\x00\x00\x3B") {
+      Err(NoFrames) => {},
+      Err(e)  => fail!("Expected no frames, got {}", e),
+      Ok(_)   => fail!("Decoded a file without images")
+    }
+    match from_bytes(b"GIF89a\xFF\xFF\xFF\xFF\x00\x00\x00\x2C\x00\x00\x00\x00\xFF\xFF\xFF\xFF\x00\x02") {
+      Err(Truncated) => {},
+      Err(e)  => fail!("Expected a truncated file, got {}", e),
+      Ok(_)   => fail!("Decoded an image without its data")
+    }
+
+    let small = GifFrame{image: Image::new(2, 2, RGB8), delay: 0};
+    let large = GifFrame{image: Image::new(3, 2, RGB8), delay: 0};
+    match to_bytes(&[small, large]) {
+      Err(FrameSizeMismatch(1)) => {},
+      Err(e)  => fail!("Expected a frame size mismatch, got {}", e),
+      Ok(_)   => fail!("Encoded frames of different sizes")
+    }
+    match to_bytes(&[GifFrame{image: Image::new(2, 2, GRAYSCALE8), delay: 0}]) {
+      Err(NotRGB(0)) => {},
+      Err(e)  => fail!("Expected a color type error, got {}", e),
+      Ok(_)   => fail!("Encoded a grayscale frame")
+    }
+  }
+}
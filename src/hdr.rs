@@ -0,0 +1,501 @@
+// Radiance HDR (RGBE) Image format
+
+use std::path::posix::{Path};
+use std::io::{File, IoResult, IoError, EndOfFile, BufReader, MemWriter};
+use std::num::CheckedMul;
+use std::cmp;
+use image::*;
+
+static SIGNATURES: [&'static str, ..2] = ["#?RADIANCE", "#?RGBE"];
+static FORMAT: &'static str = "32-bit_rle_rgbe";
+
+// Scanlines in this range can use the per-component run length encoding
+static MIN_RLE_WIDTH: uint = 8;
+static MAX_RLE_WIDTH: uint = 32767;
+
+// Shorter runs are cheaper as part of a literal packet
+static MIN_RUN: uint = 4;
+
+
+/* NOTES:
+ * HDR files are text header lines up to an empty line, a resolution line, then scanlines of pixels
+ * The header starts with #?RADIANCE (or #?RGBE), and its FORMAT line says how pixels are stored. Only RGB is
+ *   read, not XYZ. EXPOSURE lines give multipliers already applied to the pixels, which are divided out
+ * The resolution line is usually "-Y height +X width": rows top to bottom, pixels left to right. Scanlines can
+ *   also go bottom to top, right to left, or be columns when X comes first
+ * Each pixel is RGBE: a mantissa byte per channel sharing an exponent byte, value = (mantissa + 0.5) * 2^(E - 136)
+ * Scanlines from 8 to 32767 pixels wide are usually run length encoded: 2, 2 and the width, then each of the
+ *   four components on its own as packets: a count above 128 repeats the next byte count - 128 times,
+ *   otherwise count bytes follow as they are
+ * Older files can instead repeat the previous pixel with 1, 1, 1 and a count, shifted 8 bits more for each
+ *   repeat in a row
+ * Files are written top to bottom, run length encoded when the width allows
+ */
+
+
+// Everything that can go wrong while reading or writing an HDR image
+#[deriving(Show)]
+pub enum HdrError {
+  BadSignature,                   // File doesn't start with #?RADIANCE or #?RGBE
+  Truncated,                      // File ended in the header or before every scanline
+  UnsupportedFormat(String),      // FORMAT other than 32-bit_rle_rgbe
+  BadResolution(String),          // Resolution line that doesn't give both axes
+  InvalidDimensions(uint, uint),  // Width or height of zero, or too many pixels to hold
+  BadScanline,                    // Run longer than its scanline, or a repeat without a pixel before it
+  IoFailure(IoError),             // Any other error from the underlying file
+}
+
+// Wraps an I/O error so it can be returned with try!
+fn io<T>(result: IoResult<T>) -> Result<T, HdrError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => Err(IoFailure(e))
+  }
+}
+
+// Same as io(), but running out of bytes means the file was cut short
+fn chunk<T>(result: IoResult<T>) -> Result<T, HdrError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => {
+      if e.kind == EndOfFile {
+        Err(Truncated)
+      }
+      else {
+        Err(IoFailure(e))
+      }
+    }
+  }
+}
+
+// Reads a header line without its newline
+fn read_line<R: Reader>(file: &mut R) -> Result<String, HdrError> {
+  let mut line = String::new();
+  loop {
+    let byte = try!(chunk(file.read_u8()));
+    if byte == b'\n' {
+      return Ok(line);
+    }
+    line.push_char(byte as char);
+  }
+}
+
+
+#[allow(dead_code)]
+pub fn read_hdr(image_path_str: &str) -> Result<HdrImage, HdrError> {
+  let path = Path::new(image_path_str);
+  let mut file = try!(io(File::open(&path)));
+  decode_hdr(&mut file)
+}
+
+// Decodes an HDR image held in memory
+#[allow(dead_code)]
+pub fn from_bytes(bytes: &[u8]) -> Result<HdrImage, HdrError> {
+  let mut reader = BufReader::new(bytes);
+  decode_hdr(&mut reader)
+}
+
+// Decodes an HDR image from any reader into linear floating point RGB
+#[allow(dead_code)]
+pub fn decode_hdr<R: Reader>(file: &mut R) -> Result<HdrImage, HdrError> {
+
+  let signature = try!(read_line(file));
+  if !SIGNATURES.iter().any(|&expected| signature.as_slice().trim_right() == expected) {
+    return Err(BadSignature);
+  }
+
+  let mut exposure = 1f32;
+  loop {
+    let line = try!(read_line(file));
+    let line = line.as_slice().trim();
+    if line.len() == 0 {
+      break;
+    }
+    if line.starts_with("FORMAT=") {
+      let format = line.slice_from(7).trim();
+      if format != FORMAT {
+        return Err(UnsupportedFormat(String::from_str(format)));
+      }
+    }
+    else if line.starts_with("EXPOSURE=") {
+      match from_str::<f32>(line.slice_from(9).trim()) {
+        Some(value) if value > 0.0 => exposure *= value,
+        _ => {}
+      }
+    }
+  }
+
+  // Major axis, then the axis along each scanline
+  let resolution = try!(read_line(file));
+  let words: Vec<&str> = resolution.as_slice().words().collect();
+  if words.len() != 4 || words.get(0).len() != 2 || words.get(2).len() != 2 {
+    return Err(BadResolution(resolution.clone()));
+  }
+  let major = words.get(0).as_bytes();
+  let minor = words.get(2).as_bytes();
+  let (major_count, minor_count) = match (from_str::<uint>(*words.get(1)), from_str::<uint>(*words.get(3))) {
+    (Some(major_count), Some(minor_count)) => (major_count, minor_count),
+    _ => return Err(BadResolution(resolution.clone()))
+  };
+  let signs_valid = (major[0] == b'+' || major[0] == b'-') && (minor[0] == b'+' || minor[0] == b'-');
+  let axes_valid = (major[1] == b'Y' && minor[1] == b'X') || (major[1] == b'X' && minor[1] == b'Y');
+  if !signs_valid || !axes_valid {
+    return Err(BadResolution(resolution.clone()));
+  }
+
+  let (width, height) = if major[1] == b'Y' { (minor_count, major_count) } else { (major_count, minor_count) };
+  if width == 0 || height == 0 {
+    return Err(InvalidDimensions(width, height));
+  }
+
+  if width.checked_mul(&height).and_then(|pixels| pixels.checked_mul(&3)).is_none() {
+    return Err(InvalidDimensions(width, height));
+  }
+
+  // Pixels in the order they're stored, growing as scanlines decode rather
+  // than from the resolution line
+  let mut pixels: Vec<f32> = Vec::new();
+  for _ in range(0, major_count) {
+    let scanline = try!(read_scanline(file, minor_count));
+    for rgbe in scanline.as_slice().chunks(4) {
+      let (r, g, b) = rgbe_to_float(rgbe);
+      pixels.push_all(&[r / exposure, g / exposure, b / exposure]);
+    }
+  }
+
+  // Rows top to bottom with pixels left to right are already in place
+  if major[0] == b'-' && major[1] == b'Y' && minor[0] == b'+' {
+    return Ok(HdrImage{width: width, height: height, data: pixels});
+  }
+
+  let mut image = HdrImage::new(width, height);
+  for i in range(0, major_count) {
+    for j in range(0, minor_count) {
+      // Y goes up the image and X to the right, each way round when its sign is -
+      let (x, y) = if major[1] == b'Y' {
+        (axis_position(minor[0], width, j), height - 1 - axis_position(major[0], height, i))
+      }
+      else {
+        (axis_position(major[0], width, i), height - 1 - axis_position(minor[0], height, j))
+      };
+      let source = (i * minor_count + j) * 3;
+      let offset = (y * width + x) * 3;
+      image.data.mut_slice(offset, offset + 3).copy_from(pixels.slice(source, source + 3));
+    }
+  }
+  Ok(image)
+}
+
+// Coordinate along an axis of the index-th scanline or pixel, counting up from 0 for +
+fn axis_position(sign: u8, size: uint, index: uint) -> uint {
+  if sign == b'+' { index } else { size - 1 - index }
+}
+
+fn rgbe_to_float(rgbe: &[u8]) -> (f32, f32, f32) {
+  if rgbe[3] == 0 {
+    return (0.0, 0.0, 0.0);
+  }
+  let scale = 2f32.powi(rgbe[3] as i32 - 136);
+  ((rgbe[0] as f32 + 0.5) * scale, (rgbe[1] as f32 + 0.5) * scale, (rgbe[2] as f32 + 0.5) * scale)
+}
+
+// Reads the RGBE pixels of a scanline, in whichever of the three encodings it uses
+fn read_scanline<R: Reader>(file: &mut R, length: uint) -> Result<Vec<u8>, HdrError> {
+
+  let first = try!(chunk(file.read_exact(4)));
+  let run_length = *first.get(0) == 2 && *first.get(1) == 2 && *first.get(2) & 0x80 == 0;
+
+  if length >= MIN_RLE_WIDTH && length <= MAX_RLE_WIDTH && run_length {
+    if ((*first.get(2) as uint) << 8) | *first.get(3) as uint != length {
+      return Err(BadScanline);
+    }
+    let mut scanline: Vec<u8> = Vec::from_elem(length * 4, 0u8);
+    for component in range(0u, 4) {
+      let mut x = 0u;
+      while x < length {
+        let count = try!(chunk(file.read_u8())) as uint;
+        if count > 128 {
+          let run = count - 128;
+          if x + run > length {
+            return Err(BadScanline);
+          }
+          let value = try!(chunk(file.read_u8()));
+          for i in range(x, x + run) {
+            *scanline.get_mut(i * 4 + component) = value;
+          }
+          x += run;
+        }
+        else {
+          if count == 0 || x + count > length {
+            return Err(BadScanline);
+          }
+          let values = try!(chunk(file.read_exact(count)));
+          for (i, &value) in values.iter().enumerate() {
+            *scanline.get_mut((x + i) * 4 + component) = value;
+          }
+          x += count;
+        }
+      }
+    }
+    return Ok(scanline);
+  }
+
+  // Flat pixels, some of which may repeat the one before
+  let mut scanline: Vec<u8> = Vec::new();
+  let mut pixel = first;
+  let mut shift = 0u;
+  loop {
+    if *pixel.get(0) == 1 && *pixel.get(1) == 1 && *pixel.get(2) == 1 {
+      let count = (*pixel.get(3) as uint) << shift;
+      if scanline.len() == 0 || shift > 24 || scanline.len() / 4 + count > length {
+        return Err(BadScanline);
+      }
+      let previous = Vec::from_slice(scanline.slice_from(scanline.len() - 4));
+      for _ in range(0, count) {
+        scanline.push_all(previous.as_slice());
+      }
+      shift += 8;
+    }
+    else {
+      scanline.push_all(pixel.as_slice());
+      shift = 0;
+    }
+    if scanline.len() == length * 4 {
+      return Ok(scanline);
+    }
+    pixel = try!(chunk(file.read_exact(4)));
+  }
+}
+
+
+#[allow(dead_code)]
+pub fn write_hdr(image: HdrImage, filename: &str) -> Result<(), HdrError> {
+  let path = Path::new(filename);
+  let mut file = try!(io(File::create(&path)));
+  encode_hdr(&image, &mut file)
+}
+
+// Encodes an HDR image into a new in-memory buffer
+#[allow(dead_code)]
+pub fn to_bytes(image: &HdrImage) -> Result<Vec<u8>, HdrError> {
+  let mut writer = MemWriter::new();
+  try!(encode_hdr(image, &mut writer));
+  Ok(writer.unwrap())
+}
+
+// Encodes an HDR image to any writer, with negative values stored as 0
+#[allow(dead_code)]
+pub fn encode_hdr<W: Writer>(image: &HdrImage, file: &mut W) -> Result<(), HdrError> {
+
+  if image.width == 0 || image.height == 0 {
+    return Err(InvalidDimensions(image.width, image.height));
+  }
+
+  try!(io(write!(file, "{}\nFORMAT={}\n\n-Y {} +X {}\n", SIGNATURES[0], FORMAT, image.height, image.width)));
+
+  let run_length = image.width >= MIN_RLE_WIDTH && image.width <= MAX_RLE_WIDTH;
+  let mut scanline: Vec<u8> = Vec::with_capacity(image.width * 4);
+  for row in image.data.as_slice().chunks(image.width * 3) {
+    scanline.clear();
+    for pixel in row.chunks(3) {
+      scanline.push_all(&float_to_rgbe(pixel[0], pixel[1], pixel[2]));
+    }
+
+    if run_length {
+      let mut packets: Vec<u8> = vec!(2, 2, (image.width >> 8) as u8, image.width as u8);
+      for component in range(0u, 4) {
+        let values: Vec<u8> = scanline.as_slice().chunks(4).map(|pixel| pixel[component]).collect();
+        rle_component(values.as_slice(), &mut packets);
+      }
+      try!(io(file.write(packets.as_slice())));
+    }
+    else {
+      try!(io(file.write(scanline.as_slice())));
+    }
+  }
+  Ok(())
+}
+
+// RGBE with the exponent of the largest channel, all 0 when it's too small to store
+fn float_to_rgbe(r: f32, g: f32, b: f32) -> [u8, ..4] {
+  let largest = if r > g { r } else { g };
+  let largest = (if b > largest { b } else { largest }) as f64;
+  if !(largest > 1e-32) {
+    return [0, 0, 0, 0];
+  }
+
+  // largest = mantissa * 2^exponent with the mantissa from 0.5 up to 1
+  let mut exponent = largest.log2().floor() as int + 1;
+  let mut mantissa = largest / 2f64.powi(exponent as i32);
+  if mantissa >= 1.0 {
+    mantissa /= 2.0;
+    exponent += 1;
+  }
+  else if mantissa < 0.5 {
+    mantissa *= 2.0;
+    exponent -= 1;
+  }
+  if exponent < -128 {
+    return [0, 0, 0, 0];
+  }
+  if exponent > 127 {
+    return [255, 255, 255, 255];
+  }
+
+  let scale = mantissa * 256.0 / largest;
+  let byte = |value: f32| -> u8 {
+    let scaled = value as f64 * scale;
+    if scaled > 0.0 { cmp::min(255, scaled as uint) as u8 } else { 0 }
+  };
+  [byte(r), byte(g), byte(b), (exponent + 128) as u8]
+}
+
+// Run length encodes one component of a scanline: runs of at least MIN_RUN
+// bytes as repeats, everything between them as literal packets
+fn rle_component(values: &[u8], packets: &mut Vec<u8>) {
+  let mut position = 0u;
+  while position < values.len() {
+    // Start and length of the next run worth encoding
+    let mut start = position;
+    let mut run = 0u;
+    while start < values.len() {
+      run = 1;
+      while start + run < values.len() && run < 127 && values[start + run] == values[start] {
+        run += 1;
+      }
+      if run >= MIN_RUN {
+        break;
+      }
+      start += run;
+    }
+    if start >= values.len() {
+      start = values.len();
+      run = 0;
+    }
+
+    while position < start {
+      let count = cmp::min(128, start - position);
+      packets.push(count as u8);
+      packets.push_all(values.slice(position, position + count));
+      position += count;
+    }
+    if run >= MIN_RUN {
+      packets.push_all(&[(128 + run) as u8, values[start]]);
+      position = start + run;
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::*;
+
+  fn test_image(width: uint, height: uint) -> HdrImage {
+    let mut image = HdrImage::new(width, height);
+    for (i, value) in image.data.mut_iter().enumerate() {
+      let pixel = i / 3;
+      // Flat areas for runs, and values across many exponents
+      *value = if pixel % 10 < 5 { 0.25 } else { (((i * 37) % 101) as f32 - 20.0) * 0.37 };
+    }
+    image
+  }
+
+  fn assert_close(decoded: &HdrImage, image: &HdrImage) {
+    assert_eq!((decoded.width, decoded.height), (image.width, image.height));
+    for (pixel, expected) in decoded.data.as_slice().chunks(3).zip(image.data.as_slice().chunks(3)) {
+      // Channels share an exponent, so error is relative to the brightest
+      let largest = expected.iter().fold(0f32, |largest, &value| if value > largest { value } else { largest });
+      for (&value, &expected) in pixel.iter().zip(expected.iter()) {
+        let expected = if expected > 0.0 { expected } else { 0.0 };
+        assert!((value - expected).abs() <= largest / 128.0);
+      }
+    }
+  }
+
+  #[test]
+  fn test_round_trip() {
+    // Run length encoded, then too narrow for it
+    for &width in [37u, 5].iter() {
+      let image = test_image(width, 6);
+      let bytes = to_bytes(&image).unwrap();
+      assert!(bytes.as_slice().starts_with("#?RADIANCE\n".as_bytes()));
+      assert_close(&from_bytes(bytes.as_slice()).unwrap(), &image);
+    }
+
+    let image = test_image(300, 2);
+    let bytes = to_bytes(&image).unwrap();
+    assert!(bytes.len() < 300 * 2 * 4);
+    assert_close(&from_bytes(bytes.as_slice()).unwrap(), &image);
+  }
+
+  #[test]
+  fn test_rgbe() {
+    assert_eq!(super::float_to_rgbe(1.0, 0.5, 0.25).as_slice(), [128, 64, 32, 129].as_slice());
+    assert_eq!(super::float_to_rgbe(0.0, -1.0, 0.0).as_slice(), [0, 0, 0, 0].as_slice());
+    assert_eq!(super::rgbe_to_float(&[128, 64, 32, 129]), (1.00390625, 0.50390625, 0.25390625));
+  }
+
+  #[test]
+  fn test_old_encoding() {
+    // Bottom to top, an exposure of 2, and a pixel repeated with 1, 1, 1
+    let mut bytes: Vec<u8> = Vec::from_slice("#?RGBE\n# made by hand\nFORMAT=32-bit_rle_rgbe\nEXPOSURE=2\n\n+Y 2 +X 3\n".as_bytes());
+    bytes.push_all(&[128, 128, 128, 129,  1, 1, 1, 2,  0, 0, 0, 0,  127, 0, 0, 130,  0, 0, 0, 0]);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!((image.width, image.height), (3, 2));
+    let bottom = Vec::from_slice(image.data.slice_from(9));
+    assert_eq!(bottom, Vec::from_elem(9, 0.501953125f32));
+    assert_eq!(image.data.slice_to(9), [0.0f32, 0.0, 0.0, 0.99609375, 0.00390625, 0.00390625, 0.0, 0.0, 0.0].as_slice());
+  }
+
+  #[test]
+  fn test_rle_packets() {
+    let mut packets = Vec::new();
+    super::rle_component(&[1, 2, 3, 3, 3, 3, 3, 4, 4], &mut packets);
+    assert_eq!(packets, vec!(2, 1, 2, 128 + 5, 3, 2, 4, 4));
+
+    let mut packets = Vec::new();
+    super::rle_component(Vec::from_elem(130, 7u8).as_slice(), &mut packets);
+    assert_eq!(packets, vec!(128 + 127, 7, 3, 7, 7, 7));
+  }
+
+  #[test]
+  fn test_errors() {
+    match from_bytes(b"#?RADIANCE\nFORMAT=32-bit_rle_xyze\n\n-Y 1 +X 1\n\x80\x80\x80\x80") {
+      Err(UnsupportedFormat(format)) => assert_eq!(format.as_slice(), "32-bit_rle_xyze"),
+      Err(e)  => fail!("Expected an unsupported format, got {}", e),
+      Ok(_)   => fail!("Decoded XYZ pixels")
+    }
+    match from_bytes("P6\n1 1\n255\n".as_bytes()) {
+      Err(BadSignature) => {},
+      Err(e)  => fail!("Expected a bad signature, got {}", e),
+      Ok(_)   => fail!("Decoded a file that isn't HDR")
+    }
+    match from_bytes(b"#?RADIANCE\n\n-Y 1 -Y 1\n\x80\x80\x80\x80") {
+      Err(BadResolution(_)) => {},
+      Err(e)  => fail!("Expected a bad resolution, got {}", e),
+      Ok(_)   => fail!("Decoded a resolution without an X axis")
+    }
+    match from_bytes(b"#?RADIANCE\n\n-Y 2 +X 8\n\x02\x02\x00\x08\x88\x01") {
+      Err(Truncated) => {},
+      Err(e)  => fail!("Expected a truncated file, got {}", e),
+      Ok(_)   => fail!("Decoded a file missing scanlines")
+    }
+    match from_bytes(b"#?RADIANCE\n\n-Y 1 +X 8\n\x02\x02\x00\x08\x89\x01") {
+      Err(BadScanline) => {},
+      Err(e)  => fail!("Expected a bad scanline, got {}", e),
+      Ok(_)   => fail!("Decoded a run past the end of its scanline")
+    }
+    match from_bytes(b"#?RADIANCE\n\n-Y 100000 +X 100000\n\x80\x80\x80\x80") {
+      Err(Truncated) => {},
+      Err(e)  => fail!("Expected a truncated file, got {}", e),
+      Ok(_)   => fail!("Decoded a file far smaller than its resolution")
+    }
+    match from_bytes(b"#?RADIANCE\n\n-Y 4611686018427387904 +X 2\n\x80\x80\x80\x80") {
+      Err(InvalidDimensions(2, _)) => {},
+      Err(e)  => fail!("Expected invalid dimensions, got {}", e),
+      Ok(_)   => fail!("Decoded a resolution too large to hold")
+    }
+  }
+}
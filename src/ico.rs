@@ -0,0 +1,446 @@
+// ICO and CUR Image formats
+
+use std::path::posix::{Path};
+use std::io::{File, IoResult, IoError, BufReader, MemWriter};
+use image::*;
+use bmp;
+use bmp::{BmpError};
+use png;
+use png::{PngError};
+
+static PNG_SIGNATURE: [u8, ..8] = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
+
+
+/* NOTES:
+ * An icon is a directory of images, each stored as a DIB (BMP without the file header) or a PNG
+ * DIB heights are doubled: the color (XOR) bitmap is followed by a 1-bit AND mask of the same size
+ * AND mask bits of 1 are transparent. 32-bit entries carry real alpha and the mask is redundant
+ * Cursors are icons with a hotspot stored where an icon entry keeps its planes and bit count
+ * Dimensions are stored in a byte each, 0 means 256
+ * PNG entries, usually the 256 pixel size, decode through the PNG decoder and are converted to RGBA8 like DIBs
+ */
+
+
+// Everything that can go wrong while reading or writing an icon or cursor
+#[deriving(Show)]
+pub enum IcoError {
+  BadDirectory,                   // Header isn't an icon or cursor directory
+  BadEntry(uint),                 // Directory entry points outside the file
+  BadBitmap(uint, BmpError),      // Entry's DIB couldn't be decoded
+  BadPng(uint, PngError),         // Entry's PNG couldn't be decoded
+  UnsupportedSize(uint, uint),    // Images must be between 1 and 256 pixels on a side
+  NotRGBA8(uint),                 // Only RGBA8 images can be written
+  IoFailure(IoError),             // Any other error from the underlying file
+}
+
+fn io<T>(result: IoResult<T>) -> Result<T, IcoError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => Err(IoFailure(e))
+  }
+}
+
+// Whether a directory holds icons or cursors
+#[deriving(Show, PartialEq, Clone)]
+pub enum IcoKind {
+  Icon = 1,
+  Cursor = 2,
+}
+
+// One image of an icon or cursor. The hotspot is where the pointer clicks,
+// and is (0, 0) for icons.
+pub struct IconEntry {
+  pub image: Image,
+  pub hotspot: (u16, u16),
+}
+
+
+// Reads every size stored in an icon or cursor file
+#[allow(dead_code)]
+pub fn read_icon(image_path_str: &str) -> Result<Vec<Image>, IcoError> {
+  let path = Path::new(image_path_str);
+  let mut file = try!(io(File::open(&path)));
+  decode_icon(&mut file)
+}
+
+// Decodes every size of an icon or cursor, in directory order, as RGBA8 images
+#[allow(dead_code)]
+pub fn decode_icon<R: Reader>(file: &mut R) -> Result<Vec<Image>, IcoError> {
+  let (_, entries) = try!(decode_icon_entries(file));
+  Ok(entries.move_iter().map(|entry| entry.image).collect())
+}
+
+// Decodes an icon or cursor along with its kind and the hotspot of every entry
+#[allow(dead_code)]
+pub fn decode_icon_entries<R: Reader>(file: &mut R) -> Result<(IcoKind, Vec<IconEntry>), IcoError> {
+
+  // Entries are found by offset, so the whole file is needed
+  let bytes = try!(io(file.read_to_end()));
+  let mut directory = BufReader::new(bytes.as_slice());
+
+  let reserved = try!(io(directory.read_le_u16()));
+  let kind = match try!(io(directory.read_le_u16())) {
+    1 => Icon,
+    2 => Cursor,
+    _ => return Err(BadDirectory)
+  };
+  let count = try!(io(directory.read_le_u16())) as uint;
+  if reserved != 0 {
+    return Err(BadDirectory);
+  }
+
+  let mut entries: Vec<IconEntry> = Vec::with_capacity(count);
+
+  for i in range(0, count) {
+
+    try!(io(directory.read_u8()));   // Width, the DIB header is trusted over this
+    try!(io(directory.read_u8()));   // Height
+    try!(io(directory.read_u8()));   // Number of palette colors
+    try!(io(directory.read_u8()));   // Reserved
+    let planes_or_x = try!(io(directory.read_le_u16()));
+    let bit_count_or_y = try!(io(directory.read_le_u16()));
+    let size = try!(io(directory.read_le_u32())) as uint;
+    let offset = try!(io(directory.read_le_u32())) as uint;
+
+    if offset + size > bytes.len() {
+      return Err(BadEntry(i));
+    }
+    let data = bytes.slice(offset, offset + size);
+
+    let image = if data.len() >= 8 && data.slice(0, 8) == PNG_SIGNATURE.as_slice() {
+      match png::from_bytes(data) {
+        Ok(mut image) => {
+          match image.color_type {
+            RGBA8 => {},
+            _     => { image.convert_to_rgba8(); }
+          }
+          image
+        },
+        Err(e)    => return Err(BadPng(i, e))
+      }
+    }
+    else {
+      match decode_entry(data) {
+        Ok(image) => image,
+        Err(e)    => return Err(BadBitmap(i, e))
+      }
+    };
+
+    let hotspot = match kind {
+      Cursor => (planes_or_x, bit_count_or_y),
+      Icon   => (0, 0)
+    };
+    entries.push(IconEntry{image: image, hotspot: hotspot});
+  }
+
+  Ok((kind, entries))
+}
+
+// Decodes one DIB entry and applies its AND mask as alpha, unless the entry
+// already had an alpha channel of its own
+fn decode_entry(data: &[u8]) -> Result<Image, BmpError> {
+
+  let mut reader = BufReader::new(data);
+  let mut info = try!(bmp::decode_dib_info(&mut reader));
+
+  // The stored height covers both the color bitmap and the mask
+  info.height = info.height / 2;
+  let mut image = try!(bmp::decode_pixels(&mut reader, &info));
+
+  match image.color_type {
+    RGBA8 => {
+      return Ok(image);
+    },
+    _ => {
+      image.convert_to_rgba8();
+    }
+  }
+
+  // Old icons sometimes leave the mask out entirely, which means fully opaque
+  let stride = bmp::row_stride(image.width, 1);
+  let mask = match reader.read_exact(stride * image.height) {
+    Ok(mask) => mask,
+    Err(_)   => return Ok(image)
+  };
+
+  // Mask scanlines are bottom up, like the color bitmap
+  for y in range(0, image.height) {
+    let mask_row = (image.height - 1 - y) * stride;
+    for x in range(0, image.width) {
+      let bit = (*mask.get(mask_row + x / 8) >> (7 - x % 8)) & 1;
+      if bit == 1 {
+        *image.data.get_mut((x + image.width * y) * 4 + 3) = 0;
+      }
+    }
+  }
+
+  Ok(image)
+}
+
+
+// Writes RGBA8 images as the sizes of one icon
+#[allow(dead_code)]
+pub fn write_icon(images: &[Image], filename: &str) -> Result<(), IcoError> {
+  let path = Path::new(filename);
+  let mut file = try!(io(File::create(&path)));
+  encode_icon(images, &mut file)
+}
+
+// Writes RGBA8 images as the sizes of one cursor
+#[allow(dead_code)]
+pub fn write_cursor(cursors: &[IconEntry], filename: &str) -> Result<(), IcoError> {
+  let path = Path::new(filename);
+  let mut file = try!(io(File::create(&path)));
+  encode_cursor(cursors, &mut file)
+}
+
+#[allow(dead_code)]
+pub fn encode_icon<W: Writer>(images: &[Image], file: &mut W) -> Result<(), IcoError> {
+  let entries: Vec<(&Image, (u16, u16))> = images.iter().map(|image| (image, (0u16, 0u16))).collect();
+  encode_entries(Icon, entries.as_slice(), file)
+}
+
+#[allow(dead_code)]
+pub fn encode_cursor<W: Writer>(cursors: &[IconEntry], file: &mut W) -> Result<(), IcoError> {
+  let entries: Vec<(&Image, (u16, u16))> = cursors.iter().map(|cursor| (&cursor.image, cursor.hotspot)).collect();
+  encode_entries(Cursor, entries.as_slice(), file)
+}
+
+// Writes the directory followed by every entry as a 32-bit DIB with an AND mask
+fn encode_entries<W: Writer>(kind: IcoKind, entries: &[(&Image, (u16, u16))], file: &mut W) -> Result<(), IcoError> {
+
+  let mut encoded: Vec<Vec<u8>> = Vec::with_capacity(entries.len());
+  for (i, &(image, _)) in entries.iter().enumerate() {
+    match image.color_type {
+      RGBA8 => {},
+      _ => return Err(NotRGBA8(i))
+    }
+    if image.width == 0 || image.height == 0 || image.width > 256 || image.height > 256 {
+      return Err(UnsupportedSize(image.width, image.height));
+    }
+    encoded.push(encode_entry(image));
+  }
+
+  let reserved: u16 = 0;
+  try!(io(file.write_le_u16(reserved)));
+  try!(io(file.write_le_u16(kind as u16)));
+  try!(io(file.write_le_u16(entries.len() as u16)));
+
+  let mut offset = 6 + 16 * entries.len();
+  for (&(image, (x, y)), data) in entries.iter().zip(encoded.iter()) {
+
+    // 256 doesn't fit in a byte and is stored as 0
+    try!(io(file.write_u8(image.width as u8)));
+    try!(io(file.write_u8(image.height as u8)));
+    try!(io(file.write_u8(0)));    // No palette
+    try!(io(file.write_u8(0)));    // Reserved
+
+    match kind {
+      Icon => {
+        try!(io(file.write_le_u16(1)));    // Planes
+        try!(io(file.write_le_u16(32)));   // Bits per pixel
+      },
+      Cursor => {
+        try!(io(file.write_le_u16(x)));
+        try!(io(file.write_le_u16(y)));
+      }
+    }
+
+    try!(io(file.write_le_u32(data.len() as u32)));
+    try!(io(file.write_le_u32(offset as u32)));
+    offset += data.len();
+  }
+
+  for data in encoded.iter() {
+    try!(io(file.write(data.as_slice())));
+  }
+
+  Ok(())
+}
+
+// Encodes one RGBA8 image as a BITMAPINFOHEADER DIB with BGRA pixels and an
+// AND mask that hides fully transparent pixels from viewers that ignore alpha
+fn encode_entry(image: &Image) -> Vec<u8> {
+
+  let mask_stride = bmp::row_stride(image.width, 1);
+  let pixel_bytes = image.width * image.height * 4;
+  let mask_bytes = mask_stride * image.height;
+
+  let mut file = MemWriter::new();
+
+  let header_size: u32 = 40;
+  let planes: u16 = 1;
+  let bits_per_pixel: u16 = 32;
+  let compression_type: u32 = 0;    // BI_RGB, the fourth byte is alpha in icons
+
+  file.write_le_u32(header_size).unwrap();
+  file.write_le_i32(image.width as i32).unwrap();
+  file.write_le_i32(image.height as i32 * 2).unwrap();    // Color bitmap and mask
+  file.write_le_u16(planes).unwrap();
+  file.write_le_u16(bits_per_pixel).unwrap();
+  file.write_le_u32(compression_type).unwrap();
+  file.write_le_u32((pixel_bytes + mask_bytes) as u32).unwrap();
+  file.write_le_u32(0).unwrap();    // Horizontal resolution
+  file.write_le_u32(0).unwrap();    // Vertical resolution
+  file.write_le_u32(0).unwrap();    // Colors used
+  file.write_le_u32(0).unwrap();    // Colors important
+
+  let mut mask: Vec<u8> = Vec::from_elem(mask_bytes, 0u8);
+
+  for y in range(0, image.height) {
+
+    let bmp_y = image.height - 1 - y;
+
+    for x in range(0, image.width) {
+
+      let i = (x + image.width * bmp_y) * 4;
+
+      // Write BGRA
+      file.write_u8(*image.data.get(i + 2)).unwrap();
+      file.write_u8(*image.data.get(i + 1)).unwrap();
+      file.write_u8(*image.data.get(i)).unwrap();
+      file.write_u8(*image.data.get(i + 3)).unwrap();
+
+      if *image.data.get(i + 3) == 0 {
+        *mask.get_mut(y * mask_stride + x / 8) |= 0x80 >> (x % 8);
+      }
+    }
+  }
+
+  file.write(mask.as_slice()).unwrap();
+  file.unwrap()
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::*;
+  use std::io::{MemWriter, BufReader};
+  use png;
+
+  fn test_image(size: uint) -> Image {
+    let mut image = Image::new(size, size, RGBA8);
+    for y in range(0, size) {
+      for x in range(0, size) {
+        let alpha = if x == y { 0 } else { (x * 255 / size) as u8 };
+        image.set_pixel(x, y, vec!(x as u8, y as u8, 100, alpha));
+      }
+    }
+    image
+  }
+
+  #[test]
+  fn test_icon_round_trip() {
+    let images = vec!(test_image(16), test_image(32), test_image(48));
+
+    let mut file = MemWriter::new();
+    encode_icon(images.as_slice(), &mut file).unwrap();
+    let bytes = file.unwrap();
+
+    let decoded = decode_icon(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(decoded.len(), 3);
+    for (original, image) in images.iter().zip(decoded.iter()) {
+      assert_eq!(image.width, original.width);
+      assert_eq!(image.height, original.height);
+      assert_eq!(image.data, original.data);
+    }
+  }
+
+  #[test]
+  fn test_cursor_hotspot() {
+    let cursors = vec!(IconEntry{image: test_image(32), hotspot: (5, 9)});
+
+    let mut file = MemWriter::new();
+    encode_cursor(cursors.as_slice(), &mut file).unwrap();
+    let bytes = file.unwrap();
+
+    let (kind, entries) = decode_icon_entries(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(kind, Cursor);
+    assert_eq!(entries.get(0).hotspot, (5, 9));
+  }
+
+  #[test]
+  fn test_and_mask() {
+    // A 2x2 24-bit icon: the AND mask hides the top left pixel
+    let mut file = MemWriter::new();
+    file.write_le_u16(0).unwrap();
+    file.write_le_u16(1).unwrap();
+    file.write_le_u16(1).unwrap();
+
+    let dib_size = 40 + 8 * 2 + 4 * 2;
+    file.write(&[2u8, 2, 0, 0]).unwrap();
+    file.write_le_u16(1).unwrap();
+    file.write_le_u16(24).unwrap();
+    file.write_le_u32(dib_size).unwrap();
+    file.write_le_u32(6 + 16).unwrap();
+
+    file.write_le_u32(40).unwrap();
+    file.write_le_i32(2).unwrap();
+    file.write_le_i32(4).unwrap();
+    file.write_le_u16(1).unwrap();
+    file.write_le_u16(24).unwrap();
+    for _ in range(0u, 6) {
+      file.write_le_u32(0).unwrap();
+    }
+    file.write(&[0u8, 0, 255, 0, 255, 0, 0, 0]).unwrap();     // Bottom row: red, green
+    file.write(&[255u8, 0, 0, 255, 255, 255, 0, 0]).unwrap(); // Top row: blue, white
+    file.write(&[0u8, 0, 0, 0]).unwrap();                     // Bottom mask row
+    file.write(&[0x80u8, 0, 0, 0]).unwrap();                  // Top mask row
+    let bytes = file.unwrap();
+
+    let images = decode_icon(&mut BufReader::new(bytes.as_slice())).unwrap();
+    let image = images.get(0);
+    assert_eq!(image.get_pixel(0, 0), vec!(0, 0, 255, 0));
+    assert_eq!(image.get_pixel(1, 0), vec!(255, 255, 255, 255));
+    assert_eq!(image.get_pixel(0, 1), vec!(255, 0, 0, 255));
+    assert_eq!(image.get_pixel(1, 1), vec!(0, 255, 0, 255));
+  }
+
+  #[test]
+  fn test_png_entry() {
+    // A PNG entry, as modern icons store their largest size, next to a DIB entry
+    let mut rgb = Image::new(3, 2, RGB8);
+    rgb.data = vec!(255, 0, 0,  0, 255, 0,  0, 0, 255,  10, 20, 30,  40, 50, 60,  70, 80, 90);
+    let png_data = png::to_bytes(&rgb).unwrap();
+    let dib = test_image(16);
+    let dib_data = super::encode_entry(&dib);
+
+    let mut file = MemWriter::new();
+    file.write_le_u16(0).unwrap();
+    file.write_le_u16(1).unwrap();
+    file.write_le_u16(2).unwrap();
+    let mut offset = 6 + 16 * 2;
+    for &(size, length) in [(3u8, png_data.len()), (16u8, dib_data.len())].iter() {
+      file.write(&[size, size, 0, 0]).unwrap();
+      file.write_le_u16(1).unwrap();
+      file.write_le_u16(32).unwrap();
+      file.write_le_u32(length as u32).unwrap();
+      file.write_le_u32(offset as u32).unwrap();
+      offset += length;
+    }
+    file.write(png_data.as_slice()).unwrap();
+    file.write(dib_data.as_slice()).unwrap();
+    let bytes = file.unwrap();
+
+    let images = decode_icon(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(images.len(), 2);
+    let png_image = images.get(0);
+    assert_eq!(png_image.color_type as uint, RGBA8 as uint);
+    assert_eq!((png_image.width, png_image.height), (3, 2));
+    assert_eq!(png_image.get_pixel(0, 0), vec!(255, 0, 0, 255));
+    assert_eq!(png_image.get_pixel(2, 1), vec!(70, 80, 90, 255));
+    assert_eq!(images.get(1).data, dib.data);
+  }
+
+  #[test]
+  fn test_rejects_large_images() {
+    let images = vec!(Image::new(300, 16, RGBA8));
+    let mut file = MemWriter::new();
+    match encode_icon(images.as_slice(), &mut file) {
+      Err(UnsupportedSize(300, 16)) => {},
+      Err(e)  => fail!("Expected an unsupported size, got {}", e),
+      Ok(_)   => fail!("Wrote an icon larger than 256 pixels")
+    }
+  }
+}
@@ -29,11 +29,56 @@ pub enum ColorType {
   RGBA8 = 32,
 }
 
+// How the color values of an image should be interpreted
+#[deriving(Show, Clone, PartialEq)]
+pub enum ColorSpace {
+  CalibratedRGB,              // Given by the endpoints and gamma of the Colorimetry
+  SRGB,
+  WindowsColorSpace,          // Whatever the system's default color space is
+  EmbeddedProfile(Vec<u8>),   // ICC profile stored with the image
+  LinkedProfile(String),      // Path to an ICC profile stored elsewhere
+}
+
+// Color management data carried with an image so it survives a round trip
+#[deriving(Show, Clone, PartialEq)]
+pub struct Colorimetry {
+  pub color_space: ColorSpace,
+  pub red: (f64, f64, f64),     // CIEXYZ endpoint of the red primary
+  pub green: (f64, f64, f64),   // CIEXYZ endpoint of the green primary
+  pub blue: (f64, f64, f64),    // CIEXYZ endpoint of the blue primary
+  pub gamma: (f64, f64, f64),   // Red, green and blue gamma
+  pub intent: u32,              // Rendering intent, 1 = business, 2 = graphics, 4 = images, 8 = absolute colorimetric
+}
+
+// Physical size of a pixel, kept so printing and scanning pipelines preserve DPI
+#[deriving(Show, Clone, PartialEq)]
+pub struct Resolution {
+  pub horizontal: f64,    // In pixels per meter
+  pub vertical: f64,      // In pixels per meter
+}
+
+impl Resolution {
+
+  #[allow(dead_code)]
+  pub fn from_dpi(horizontal: f64, vertical: f64) -> Resolution {
+    Resolution{horizontal: horizontal / 0.0254, vertical: vertical / 0.0254}
+  }
+
+  // Horizontal and vertical resolution in pixels per inch
+  #[allow(dead_code)]
+  pub fn dpi(&self) -> (f64, f64) {
+    (self.horizontal * 0.0254, self.vertical * 0.0254)
+  }
+
+}
+
 pub struct Image {
   pub width: uint,
   pub height: uint,
   pub color_type: ColorType,
   pub data: Vec<u8>,
+  pub colorimetry: Option<Colorimetry>,   // None when the source didn't describe its colors
+  pub resolution: Option<Resolution>,     // None when the source didn't store a resolution
 }
 
 impl Image {
@@ -45,19 +90,19 @@ impl Image {
       GRAYSCALE8   => {
         let size: uint = width * height;
         let buffer: Vec<u8> = Vec::from_elem(size, 0u8);
-        Image{width: width, height: height, color_type: GRAYSCALE8, data: buffer}
+        Image{width: width, height: height, color_type: GRAYSCALE8, data: buffer, colorimetry: None, resolution: None}
       },
 
       RGB8         => {
         let size: uint = 3 * width * height;
         let buffer: Vec<u8> = Vec::from_elem(size, 0u8);
-        Image{width: width, height: height, color_type: RGB8, data: buffer}
+        Image{width: width, height: height, color_type: RGB8, data: buffer, colorimetry: None, resolution: None}
       },
 
       RGBA8        => {
         let size: uint = 4 * width * height;
         let buffer: Vec<u8> = Vec::from_elem(size, 0u8);
-        Image{width: width, height: height, color_type: RGBA8, data: buffer}
+        Image{width: width, height: height, color_type: RGBA8, data: buffer, colorimetry: None, resolution: None}
       }
 
     }
@@ -351,6 +396,88 @@ impl Image {
 
 }
 
+// Floating point RGB image for high dynamic range sources like environment maps.
+// Values are linear light, where 1.0 is as bright as 255 in an RGB8 image
+pub struct HdrImage {
+  pub width: uint,
+  pub height: uint,
+  pub data: Vec<f32>,   // Red, green and blue of each pixel, rows top to bottom
+}
+
+// Ways of bringing HDR values down to 0.0 to 1.0, applied to each channel before sRGB encoding
+#[deriving(Show, PartialEq, Clone)]
+pub enum ToneMapper {
+  Clamp,            // Everything at or above 1.0 is white
+  Reinhard,         // x / (1 + x), keeps shadows and compresses highlights without ever reaching white
+  Exposure(f32),    // 1 - e^(-x * exposure), like exposing film for longer as the value goes up
+  Filmic,           // Fit of the ACES filmic curve, with more contrast than Reinhard
+}
+
+impl HdrImage {
+
+  #[allow(dead_code)]
+  pub fn new(width: uint, height: uint) -> HdrImage {
+    HdrImage{width: width, height: height, data: Vec::from_elem(width * height * 3, 0f32)}
+  }
+
+  // Linear copy of an 8-bit image, decoding its sRGB levels and dropping alpha
+  #[allow(dead_code)]
+  pub fn from_image(image: &Image) -> HdrImage {
+    let channels = match image.color_type {
+      GRAYSCALE8  => 1u,
+      RGB8        => 3,
+      RGBA8       => 4
+    };
+    let mut data: Vec<f32> = Vec::with_capacity(image.width * image.height * 3);
+    for pixel in image.data.as_slice().chunks(channels) {
+      if channels == 1 {
+        let level = srgb_to_linear(pixel[0]);
+        data.push_all(&[level, level, level]);
+      }
+      else {
+        data.push_all(&[srgb_to_linear(pixel[0]), srgb_to_linear(pixel[1]), srgb_to_linear(pixel[2])]);
+      }
+    }
+    HdrImage{width: image.width, height: image.height, data: data}
+  }
+
+  // RGB8 image through a tone mapper and the sRGB transfer curve
+  #[allow(dead_code)]
+  pub fn tone_map(&self, mapper: ToneMapper) -> Image {
+    let mut data: Vec<u8> = Vec::with_capacity(self.data.len());
+    for &value in self.data.iter() {
+      // Negative values and NaN have no light in them
+      let value = if value > 0.0 { value } else { 0.0 };
+      let mapped = match mapper {
+        Clamp               => value,
+        Reinhard            => value / (1.0 + value),
+        Exposure(exposure)  => 1.0 - (-value * exposure).exp(),
+        Filmic              => (value * (2.51 * value + 0.03)) / (value * (2.43 * value + 0.59) + 0.14)
+      };
+      data.push(linear_to_srgb(mapped));
+    }
+    Image{width: self.width, height: self.height, color_type: RGB8, data: data, colorimetry: None, resolution: None}
+  }
+
+}
+
+fn srgb_to_linear(level: u8) -> f32 {
+  let c = level as f32 / 255.0;
+  if c <= 0.04045 {
+    c / 12.92
+  }
+  else {
+    ((c + 0.055) / 1.055).powf(2.4)
+  }
+}
+
+// sRGB level of linear light, clamped to 0.0 to 1.0
+fn linear_to_srgb(value: f32) -> u8 {
+  let c = if value >= 1.0 { 1.0 } else if value > 0.0 { value } else { 0.0 };
+  let encoded = if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 };
+  (encoded * 255.0 + 0.5) as u8
+}
+
 pub trait Transform {
   fn flip_vertical(&mut self);
   fn flip_horizontal(&mut self);
@@ -1023,6 +1150,39 @@ mod tests {
     assert_eq!(image.height, 1000);
   }
 
+  #[test]
+  fn test_resolution_dpi() {
+    let resolution = Resolution::from_dpi(300., 150.);
+    let (horizontal, vertical) = resolution.dpi();
+    assert!((horizontal - 300.).abs() < 1e-9);
+    assert!((vertical - 150.).abs() < 1e-9);
+    assert!((resolution.horizontal - 11811.02).abs() < 0.01);
+  }
+
+  #[test]
+  fn test_tone_map() {
+    let mut hdr = HdrImage::new(2, 1);
+    hdr.data = vec!(0.0, 0.5, 1.0, 4.0, -1.0, 100.0);
+
+    assert_eq!(hdr.tone_map(Clamp).data, vec!(0, 188, 255, 255, 0, 255));
+    assert_eq!(hdr.tone_map(Reinhard).data, vec!(0, 156, 188, 231, 0, 254));
+    assert_eq!(hdr.tone_map(Exposure(2.0)).data, vec!(0, 208, 239, 255, 0, 255));
+
+    let filmic = hdr.tone_map(Filmic);
+    assert_eq!(filmic.color_type as uint, RGB8 as uint);
+    assert_eq!((*filmic.data.get(1), *filmic.data.get(2), *filmic.data.get(5)), (206, 232, 255));
+    assert!(*filmic.data.get(3) > 232);
+
+    // sRGB levels survive going to linear light and back
+    let mut image = Image::new(16, 16, RGB8);
+    for (i, sample) in image.data.mut_iter().enumerate() {
+      *sample = (i % 256) as u8;
+    }
+    let hdr = HdrImage::from_image(&image);
+    assert!((*hdr.data.get(255) - 1.0).abs() < 1e-6);
+    assert_eq!(hdr.tone_map(Clamp).data, image.data);
+  }
+
   #[test]
   fn test_get_pixel() {
     let image = Image::new(20, 20, GRAYSCALE8);
@@ -1256,16 +1416,16 @@ mod processing_tests {
       let image = read_bitmap(path_to_file.as_slice());
       
       match image {
-        Some(mut image) => {
+        Ok(mut image) => {
           let path_prefix: String = "../test/image/".to_string();
           let path_to_write: String = path_prefix.append("vflip_").append(*filename);
 
           image.flip_vertical();
 
-          assert!(write_bitmap(image, path_to_write.as_slice()));
+          assert!(write_bitmap(image, path_to_write.as_slice()).is_ok());
         },
-        None  => {
-          fail!("Looks like you didn't get a valid image.");
+        Err(e)  => {
+          fail!("Looks like you didn't get a valid image: {}", e);
         }
       }
 
@@ -1289,16 +1449,16 @@ mod processing_tests {
       let image = read_bitmap(path_to_file.as_slice());
       
       match image {
-        Some(mut image) => {
+        Ok(mut image) => {
           let path_prefix: String = "../test/image/".to_string();
           let path_to_write: String = path_prefix.append("hflip_").append(*filename);
 
           image.flip_horizontal();
 
-          assert!(write_bitmap(image, path_to_write.as_slice()));
+          assert!(write_bitmap(image, path_to_write.as_slice()).is_ok());
         },
-        None  => {
-          fail!("Looks like you didn't get a valid image.");
+        Err(e)  => {
+          fail!("Looks like you didn't get a valid image: {}", e);
         }
       }
 
@@ -1322,16 +1482,16 @@ mod processing_tests {
       let image = read_bitmap(path_to_file.as_slice());
       
       match image {
-        Some(mut image) => {
+        Ok(mut image) => {
           let path_prefix: String = "../test/image/".to_string();
           let path_to_write: String = path_prefix.append("neg_").append(*filename);
 
           image.negative();
 
-          assert!(write_bitmap(image, path_to_write.as_slice()));
+          assert!(write_bitmap(image, path_to_write.as_slice()).is_ok());
         },
-        None  => {
-          fail!("Looks like you didn't get a valid image.");
+        Err(e)  => {
+          fail!("Looks like you didn't get a valid image: {}", e);
         }
       }
 
@@ -1355,16 +1515,16 @@ mod processing_tests {
       let image = read_bitmap(path_to_file.as_slice());
       
       match image {
-        Some(mut image) => {
+        Ok(mut image) => {
           let path_prefix: String = "../test/image/".to_string();
           let path_to_write: String = path_prefix.append("bright_bias_").append(*filename);
 
           image.brighten(100i);
 
-          assert!(write_bitmap(image, path_to_write.as_slice()));
+          assert!(write_bitmap(image, path_to_write.as_slice()).is_ok());
         },
-        None  => {
-          fail!("Looks like you didn't get a valid image.");
+        Err(e)  => {
+          fail!("Looks like you didn't get a valid image: {}", e);
         }
       }
 
@@ -1388,16 +1548,16 @@ mod processing_tests {
       let image = read_bitmap(path_to_file.as_slice());
       
       match image {
-        Some(mut image) => {
+        Ok(mut image) => {
           let path_prefix: String = "../test/image/".to_string();
           let path_to_write: String = path_prefix.append("contrast_gain_").append(*filename);
 
           image.contrast(1.8f32);
 
-          assert!(write_bitmap(image, path_to_write.as_slice()));
+          assert!(write_bitmap(image, path_to_write.as_slice()).is_ok());
         },
-        None  => {
-          fail!("Looks like you didn't get a valid image.");
+        Err(e)  => {
+          fail!("Looks like you didn't get a valid image: {}", e);
         }
       }
 
@@ -1421,16 +1581,16 @@ mod processing_tests {
       let image = read_bitmap(path_to_file.as_slice());
       
       match image {
-        Some(mut image) => {
+        Ok(mut image) => {
           let path_prefix: String = "../test/image/".to_string();
           let path_to_write: String = path_prefix.append("saturate_gain_").append(*filename);
 
           image.saturate(1.8f32);
 
-          assert!(write_bitmap(image, path_to_write.as_slice()));
+          assert!(write_bitmap(image, path_to_write.as_slice()).is_ok());
         },
-        None  => {
-          fail!("Looks like you didn't get a valid image.");
+        Err(e)  => {
+          fail!("Looks like you didn't get a valid image: {}", e);
         }
       }
 
@@ -1454,16 +1614,16 @@ mod processing_tests {
       let image = read_bitmap(path_to_file.as_slice());
       
       match image {
-        Some(mut image) => {
+        Ok(mut image) => {
           let path_prefix: String = "../test/image/".to_string();
           let path_to_write: String = path_prefix.append("blur_").append(*filename);
 
           image.blur();
 
-          assert!(write_bitmap(image, path_to_write.as_slice()));
+          assert!(write_bitmap(image, path_to_write.as_slice()).is_ok());
         },
-        None  => {
-          fail!("Looks like you didn't get a valid image.");
+        Err(e)  => {
+          fail!("Looks like you didn't get a valid image: {}", e);
         }
       }
 
@@ -0,0 +1,2265 @@
+// JPEG Image format
+
+use std::path::posix::{Path};
+use std::f32::consts::PI;
+use std::cmp;
+use std::io::{File, IoResult, IoError, BufReader, MemWriter};
+use image::*;
+use zlib;
+
+// Natural (row major) position of each coefficient, in the zigzag order they're stored in
+static ZIGZAG: [uint, ..64] = [
+   0,  1,  8, 16,  9,  2,  3, 10,
+  17, 24, 32, 25, 18, 11,  4,  5,
+  12, 19, 26, 33, 40, 48, 41, 34,
+  27, 20, 13,  6,  7, 14, 21, 28,
+  35, 42, 49, 56, 57, 50, 43, 36,
+  29, 22, 15, 23, 30, 37, 44, 51,
+  58, 59, 52, 45, 38, 31, 39, 46,
+  53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+// Quantization tables from Annex K of the standard, in natural order, used as they are at quality 50
+static LUMINANCE_QUANTIZATION: [u16, ..64] = [
+  16,  11,  10,  16,  24,  40,  51,  61,
+  12,  12,  14,  19,  26,  58,  60,  55,
+  14,  13,  16,  24,  40,  57,  69,  56,
+  14,  17,  22,  29,  51,  87,  80,  62,
+  18,  22,  37,  56,  68, 109, 103,  77,
+  24,  35,  55,  64,  81, 104, 113,  92,
+  49,  64,  78,  87, 103, 121, 120, 101,
+  72,  92,  95,  98, 112, 100, 103,  99,
+];
+
+static CHROMINANCE_QUANTIZATION: [u16, ..64] = [
+  17,  18,  24,  47,  99,  99,  99,  99,
+  18,  21,  26,  66,  99,  99,  99,  99,
+  24,  26,  56,  99,  99,  99,  99,  99,
+  47,  66,  99,  99,  99,  99,  99,  99,
+  99,  99,  99,  99,  99,  99,  99,  99,
+  99,  99,  99,  99,  99,  99,  99,  99,
+  99,  99,  99,  99,  99,  99,  99,  99,
+  99,  99,  99,  99,  99,  99,  99,  99,
+];
+
+// Huffman tables from Annex K, as the number of codes of each length from 1 to 16 and then the symbols
+static DC_LUMINANCE_COUNTS: [u8, ..16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+static DC_CHROMINANCE_COUNTS: [u8, ..16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+static DC_VALUES: [u8, ..12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+static AC_LUMINANCE_COUNTS: [u8, ..16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7D];
+static AC_LUMINANCE_VALUES: [u8, ..162] = [
+  0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+  0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52, 0xD1, 0xF0,
+  0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25, 0x26, 0x27, 0x28,
+  0x29, 0x2A, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+  0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+  0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+  0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7,
+  0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5,
+  0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2,
+  0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+  0xF9, 0xFA,
+];
+
+static AC_CHROMINANCE_COUNTS: [u8, ..16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+static AC_CHROMINANCE_VALUES: [u8, ..162] = [
+  0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+  0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33, 0x52, 0xF0,
+  0x15, 0x62, 0x72, 0xD1, 0x0A, 0x16, 0x24, 0x34, 0xE1, 0x25, 0xF1, 0x17, 0x18, 0x19, 0x1A, 0x26,
+  0x27, 0x28, 0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+  0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+  0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+  0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5,
+  0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3,
+  0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA,
+  0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+  0xF9, 0xFA,
+];
+
+
+/* NOTES:
+ * JPEG is a sequence of markers (0xFF and a code), most followed by a segment starting with its big endian length
+ * Pixels are stored as 8x8 blocks of DCT coefficients, Huffman coded in zigzag order
+ * DC coefficients are stored as the difference from the previous block of the same component
+ * Components can be subsampled: each has horizontal and vertical sampling factors from 1 to 4,
+ *   and a minimum coded unit (MCU) holds h x v blocks of every component
+ *   4:4:4 is every factor 1, 4:2:2 is Y at 2x1, and 4:2:0 is Y at 2x2
+ * A scan with a single component isn't interleaved: its MCU is always one block
+ * 0xFF inside entropy coded data is followed by a stuffed 0x00
+ * Restart markers (RST0-RST7) between MCUs reset the DC predictions and byte align the data
+ * Three components are YCbCr unless an Adobe marker says otherwise, or the component ids spell "RGB"
+ * Progressive images spread the coefficients over many scans: each holds either the DC coefficients or one
+ *   band of AC coefficients (spectral selection), and either their top bits or one more bit (successive approximation)
+ * Coefficients are kept for the whole image until the last scan, so a preview can be rendered between scans
+ * Coefficients are stored as scans reach their blocks, and a scan stops where its data runs out, so a small file
+ *   claiming a huge frame fails as truncated instead of allocating for the frame. Later scans cut short are kept
+ * Written images are JFIF: Y, Cb and Cr with only the luma subsampled, one table for luma and one for chroma
+ * Quality scales the example quantization tables from the standard, and optimized Huffman tables are built
+ *   from the symbol counts of a first pass over each scan
+ */
+
+
+// Everything that can go wrong while reading or writing a JPEG image
+#[deriving(Show)]
+pub enum JpegError {
+  BadSignature,                   // File doesn't start with an SOI marker
+  Truncated,                      // File ended in the middle of a segment, or before the end of the first scan
+  BadMarker(u8),                  // Found a byte where a marker should be
+  UnsupportedProcess(u8),         // Frame marker for arithmetic, lossless or hierarchical coding
+  UnsupportedPrecision(u8),       // Sample precision other than 8 bits
+  UnsupportedComponents(uint),    // Component count other than 1 (gray) or 3 (YCbCr or RGB)
+  BadSampling(u8, u8),            // Sampling factor of 0 or above 4
+  InvalidDimensions(uint, uint),  // Width or height of 0, heights given later by a DNL marker aren't supported
+  BadTable,                       // DHT or DQT segment with a bad id or that overruns its length
+  MissingTable,                   // Component uses a table that was never defined
+  BadScan,                        // Scan before the frame header, or for a component the frame doesn't have
+  BadHuffmanCode,                 // Bits don't match any code, or a run goes past the end of a block
+  UnsupportedAlpha,               // Only RGB8 and GRAYSCALE8 images can be written, JPEG has no alpha channel
+  IoFailure(IoError),             // Any other error from the underlying file
+}
+
+// Wraps an I/O error so it can be returned with try!
+fn io<T>(result: IoResult<T>) -> Result<T, JpegError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => Err(IoFailure(e))
+  }
+}
+
+fn read_u16(data: &[u8], position: uint) -> u16 {
+  ((data[position] as u16) << 8) | data[position + 1] as u16
+}
+
+
+// Reads entropy coded data most significant bit first, skipping stuffed
+// zeros. Once a marker is reached it reads zeros, like libjpeg, and
+// exhausted() tells when only those zeros are left.
+struct BitReader<'a> {
+  data: &'a [u8],
+  position: uint,
+  bits: u32,          // Loaded bits, aligned to the top
+  count: uint,
+  padding: uint,      // How many of the loaded bits are zeros past the data
+  at_marker: bool,    // Stopped on the 0xFF of a marker
+}
+
+impl<'a> BitReader<'a> {
+
+  fn new(data: &'a [u8], position: uint) -> BitReader<'a> {
+    BitReader{data: data, position: position, bits: 0, count: 0, padding: 0, at_marker: false}
+  }
+
+  fn fill(&mut self) {
+    while self.count <= 24 {
+      let mut byte = 0u32;
+      let mut padded = true;
+      if !self.at_marker && self.position < self.data.len() {
+        let value = self.data[self.position];
+        if value != 0xFF {
+          byte = value as u32;
+          padded = false;
+          self.position += 1;
+        }
+        else if self.position + 1 < self.data.len() && self.data[self.position + 1] == 0x00 {
+          byte = 0xFF;
+          padded = false;
+          self.position += 2;
+        }
+        else {
+          self.at_marker = true;
+        }
+      }
+      if padded {
+        self.padding += 8;
+      }
+      self.bits |= byte << (24 - self.count);
+      self.count += 8;
+    }
+  }
+
+  fn read_bits(&mut self, count: uint) -> u32 {
+    if count == 0 {
+      return 0;
+    }
+    if self.count < count {
+      self.fill();
+    }
+    let value = self.bits >> (32 - count);
+    self.bits <<= count;
+    self.count -= count;
+    self.padding = cmp::min(self.padding, self.count);
+    value
+  }
+
+  // Whether every bit of the data has been read, so only zeros are left
+  fn exhausted(&self) -> bool {
+    self.count == self.padding && (self.at_marker || self.position >= self.data.len())
+  }
+
+  // Reads a size bit value and extends it to a signed coefficient: values
+  // below half the range are negative
+  fn receive_extend(&mut self, size: uint) -> i32 {
+    if size == 0 {
+      return 0;
+    }
+    let value = self.read_bits(size) as i32;
+    if value < (1i32 << (size - 1)) {
+      value - (1i32 << size) + 1
+    }
+    else {
+      value
+    }
+  }
+
+  // Drops the partial byte and skips the next restart marker
+  fn restart(&mut self) {
+    self.bits = 0;
+    self.count = 0;
+    self.padding = 0;
+    self.position = self.next_marker();
+    if self.position + 1 < self.data.len() && self.data[self.position + 1] >= 0xD0 && self.data[self.position + 1] <= 0xD7 {
+      self.position += 2;
+    }
+    self.at_marker = false;
+  }
+
+  // Position of the next marker that isn't a stuffed zero
+  fn next_marker(&self) -> uint {
+    let mut position = self.position;
+    while position + 1 < self.data.len() {
+      if self.data[position] == 0xFF && self.data[position + 1] != 0x00 && self.data[position + 1] != 0xFF {
+        return position;
+      }
+      position += 1;
+    }
+    self.data.len()
+  }
+
+  // Position of the first marker after the scan, skipping any restart
+  // markers left after the last MCU
+  fn end_of_scan(&mut self) -> uint {
+    loop {
+      let position = self.next_marker();
+      if position + 1 < self.data.len() && self.data[position + 1] >= 0xD0 && self.data[position + 1] <= 0xD7 {
+        self.position = position + 2;
+      }
+      else {
+        return position;
+      }
+    }
+  }
+
+}
+
+
+// Huffman table, decoded with the largest code of each length as in the
+// JPEG specification (F.2.2.3)
+struct HuffmanTable {
+  max_code: Vec<i32>,   // Largest code of each length, -1 when there are none
+  offsets: Vec<i32>,    // Index of a length's first value, minus its first code
+  values: Vec<u8>,
+}
+
+impl HuffmanTable {
+
+  // counts gives the number of codes of each length from 1 to 16
+  fn new(counts: &[u8], values: &[u8]) -> HuffmanTable {
+    let mut max_code: Vec<i32> = Vec::from_elem(17, -1i32);
+    let mut offsets: Vec<i32> = Vec::from_elem(17, 0i32);
+    let mut code = 0i32;
+    let mut index = 0i32;
+    for length in range(1u, 17) {
+      let count = counts[length - 1] as i32;
+      if count > 0 {
+        *offsets.get_mut(length) = index - code;
+        code += count;
+        index += count;
+        *max_code.get_mut(length) = code - 1;
+      }
+      code <<= 1;
+    }
+    HuffmanTable{max_code: max_code, offsets: offsets, values: Vec::from_slice(values)}
+  }
+
+  fn decode(&self, reader: &mut BitReader) -> Result<u8, JpegError> {
+    let mut code = reader.read_bits(1) as i32;
+    for length in range(1u, 17) {
+      if code <= *self.max_code.get(length) {
+        let index = (code + *self.offsets.get(length)) as uint;
+        if index >= self.values.len() {
+          return Err(BadHuffmanCode);
+        }
+        return Ok(*self.values.get(index));
+      }
+      code = (code << 1) | reader.read_bits(1) as i32;
+    }
+    Err(BadHuffmanCode)
+  }
+
+}
+
+
+struct Component {
+  id: u8,
+  h: uint,                  // Horizontal sampling factor
+  v: uint,                  // Vertical sampling factor
+  quantization_table: uint,
+  width: uint,              // In samples, before padding to whole blocks
+  height: uint,
+  blocks_per_line: uint,    // Padded to whole MCUs
+  blocks_per_column: uint,
+  coefficients: Vec<i32>,   // 64 per block in natural order, not yet dequantized, only as far as scans have reached
+}
+
+struct Frame {
+  progressive: bool,
+  width: uint,
+  height: uint,
+  max_h: uint,
+  max_v: uint,
+  mcus_x: uint,             // MCUs per line of an interleaved scan
+  mcus_y: uint,
+  components: Vec<Component>,
+}
+
+// A component taking part in a scan, and the tables it's coded with
+struct ScanComponent {
+  index: uint,      // Into the frame's components
+  dc_table: uint,
+  ac_table: uint,
+}
+
+// Everything read from the markers so far
+struct Decoder {
+  frame: Option<Frame>,
+  quantization_tables: Vec<Option<Vec<u16>>>,   // In natural order
+  dc_tables: Vec<Option<HuffmanTable>>,
+  ac_tables: Vec<Option<HuffmanTable>>,
+  restart_interval: uint,                       // MCUs between restart markers, 0 for none
+  resolution: Option<Resolution>,
+  jfif: bool,
+  adobe_transform: Option<u8>,                  // 0 = RGB, 1 = YCbCr, from an Adobe marker
+  scans: uint,                                  // Number of scans decoded
+  position: uint,                               // Where to look for the next marker
+  finished: bool,                               // Reached EOI, or the end of the data after a scan
+}
+
+
+#[allow(dead_code)]
+pub fn read_jpeg(image_path_str: &str) -> Result<Image, JpegError> {
+  let path = Path::new(image_path_str);
+  let mut file = try!(io(File::open(&path)));
+  decode_jpeg(&mut file)
+}
+
+// Decodes a JPEG image held in memory
+#[allow(dead_code)]
+pub fn from_bytes(bytes: &[u8]) -> Result<Image, JpegError> {
+  let mut reader = BufReader::new(bytes);
+  decode_jpeg(&mut reader)
+}
+
+// Decodes a JPEG image from any reader, as GRAYSCALE8 for one component and RGB8 for three
+#[allow(dead_code)]
+pub fn decode_jpeg<R: Reader>(image: &mut R) -> Result<Image, JpegError> {
+  let data = try!(io(image.read_to_end()));
+  let mut decoder = Decoder::new();
+  while try!(decoder.next_scan(data.as_slice())) {}
+  decoder.output()
+}
+
+// Decodes only as far as the first scan. For a progressive image that's a
+// blurry or grayscale version of the whole picture, usually from a small
+// part of the file. Baseline images are complete after their first scan
+// unless their components are stored in separate scans.
+#[allow(dead_code)]
+pub fn preview_jpeg<R: Reader>(image: &mut R) -> Result<Image, JpegError> {
+  let data = try!(io(image.read_to_end()));
+  let mut decoder = Decoder::new();
+  try!(decoder.next_scan(data.as_slice()));
+  decoder.output()
+}
+
+// Decodes a JPEG image, rendering the image as it stands after every scan
+// and handing it to preview along with the number of scans so far. Decoding
+// stops early if preview returns false. The last image rendered is returned.
+#[allow(dead_code)]
+pub fn decode_jpeg_scans<R: Reader>(image: &mut R, preview: |uint, &Image| -> bool) -> Result<Image, JpegError> {
+  let data = try!(io(image.read_to_end()));
+  let mut decoder = Decoder::new();
+  while try!(decoder.next_scan(data.as_slice())) {
+    let rendered = try!(decoder.output());
+    if !preview(decoder.scans, &rendered) {
+      return Ok(rendered);
+    }
+  }
+  decoder.output()
+}
+
+impl Decoder {
+
+  fn new() -> Decoder {
+    Decoder {
+      frame: None,
+      quantization_tables: Vec::from_fn(4, |_| None),
+      dc_tables: Vec::from_fn(4, |_| None),
+      ac_tables: Vec::from_fn(4, |_| None),
+      restart_interval: 0,
+      resolution: None,
+      jfif: false,
+      adobe_transform: None,
+      scans: 0,
+      position: 0,
+      finished: false,
+    }
+  }
+
+  // Reads markers up to and including the next scan, decoding its
+  // coefficients. Returns false once there are no more scans.
+  fn next_scan(&mut self, data: &[u8]) -> Result<bool, JpegError> {
+
+    if self.finished {
+      return Ok(false);
+    }
+
+    if self.position == 0 {
+      if data.len() < 2 || data[0] != 0xFF || data[1] != 0xD8 {
+        return Err(BadSignature);
+      }
+      self.position = 2;
+    }
+
+    let mut position = self.position;
+    loop {
+
+      // A file cut short after its first scan still has an image to show
+      if position >= data.len() {
+        if self.scans > 0 {
+          self.finished = true;
+          return Ok(false);
+        }
+        return Err(Truncated);
+      }
+
+      // Markers can be padded with any number of 0xFF bytes
+      if data[position] != 0xFF {
+        return Err(BadMarker(data[position]));
+      }
+      while position < data.len() && data[position] == 0xFF {
+        position += 1;
+      }
+      if position >= data.len() {
+        continue;
+      }
+      let marker = data[position];
+      position += 1;
+
+      // End of image, and markers without a segment
+      if marker == 0xD9 {
+        self.finished = true;
+        return Ok(false);
+      }
+      if marker == 0x01 || (marker >= 0xD0 && marker <= 0xD7) {
+        continue;
+      }
+
+      if position + 2 > data.len() {
+        return Err(Truncated);
+      }
+      let length = read_u16(data, position) as uint;
+      if length < 2 || position + length > data.len() {
+        return Err(Truncated);
+      }
+      let segment = data.slice(position + 2, position + length);
+      position += length;
+
+      match marker {
+        0xC0 | 0xC1 => {
+          // Baseline, and extended sequential which only differs in allowing more tables
+          try!(self.read_frame(segment, false));
+        },
+        0xC2 => {
+          try!(self.read_frame(segment, true));
+        },
+        0xC4 => {
+          try!(self.read_huffman_tables(segment));
+        },
+        0xDB => {
+          try!(self.read_quantization_tables(segment));
+        },
+        0xDD => {
+          if segment.len() < 2 {
+            return Err(Truncated);
+          }
+          self.restart_interval = read_u16(segment, 0) as uint;
+        },
+        0xDA => {
+          self.position = try!(self.decode_scan(segment, data, position));
+          self.scans += 1;
+          return Ok(true);
+        },
+        0xE0 => {
+          self.read_jfif(segment);
+        },
+        0xEE => {
+          self.read_adobe(segment);
+        },
+        marker if marker >= 0xC3 && marker <= 0xCF => {
+          // Lossless, hierarchical and arithmetic coded frames
+          return Err(UnsupportedProcess(marker));
+        },
+        _ => {
+          // Other application segments, comments and DNL
+        }
+      }
+    }
+  }
+
+  fn read_frame(&mut self, segment: &[u8], progressive: bool) -> Result<(), JpegError> {
+
+    if segment.len() < 6 {
+      return Err(Truncated);
+    }
+    if segment[0] != 8 {
+      return Err(UnsupportedPrecision(segment[0]));
+    }
+
+    let height = read_u16(segment, 1) as uint;
+    let width = read_u16(segment, 3) as uint;
+    let count = segment[5] as uint;
+    if width == 0 || height == 0 {
+      return Err(InvalidDimensions(width, height));
+    }
+    if count != 1 && count != 3 {
+      return Err(UnsupportedComponents(count));
+    }
+    if segment.len() < 6 + 3 * count {
+      return Err(Truncated);
+    }
+
+    let mut factors: Vec<(u8, uint, uint, uint)> = Vec::with_capacity(count);
+    for i in range(0, count) {
+      let id = segment[6 + 3 * i];
+      let h = segment[7 + 3 * i] >> 4;
+      let v = segment[7 + 3 * i] & 0x0F;
+      let table = segment[8 + 3 * i] as uint;
+      if h == 0 || h > 4 || v == 0 || v > 4 {
+        return Err(BadSampling(h, v));
+      }
+      if table > 3 {
+        return Err(BadTable);
+      }
+      factors.push((id, h as uint, v as uint, table));
+    }
+
+    let max_h = factors.iter().map(|&(_, h, _, _)| h).max().unwrap();
+    let max_v = factors.iter().map(|&(_, _, v, _)| v).max().unwrap();
+    let mcus_x = (width + 8 * max_h - 1) / (8 * max_h);
+    let mcus_y = (height + 8 * max_v - 1) / (8 * max_v);
+
+    let components: Vec<Component> = factors.iter().map(|&(id, h, v, table)| {
+      let blocks_per_line = mcus_x * h;
+      let blocks_per_column = mcus_y * v;
+      Component {
+        id: id,
+        h: h,
+        v: v,
+        quantization_table: table,
+        width: (width * h + max_h - 1) / max_h,
+        height: (height * v + max_v - 1) / max_v,
+        blocks_per_line: blocks_per_line,
+        blocks_per_column: blocks_per_column,
+        coefficients: Vec::new(),
+      }
+    }).collect();
+
+    self.frame = Some(Frame {
+      progressive: progressive,
+      width: width,
+      height: height,
+      max_h: max_h,
+      max_v: max_v,
+      mcus_x: mcus_x,
+      mcus_y: mcus_y,
+      components: components,
+    });
+    Ok(())
+  }
+
+  fn read_quantization_tables(&mut self, segment: &[u8]) -> Result<(), JpegError> {
+    let mut position = 0;
+    while position < segment.len() {
+      let precision = segment[position] >> 4;
+      let id = (segment[position] & 0x0F) as uint;
+      let size = if precision == 0 { 64 } else { 128 };
+      if id > 3 || precision > 1 || position + 1 + size > segment.len() {
+        return Err(BadTable);
+      }
+
+      let mut table: Vec<u16> = Vec::from_elem(64, 0u16);
+      for i in range(0u, 64) {
+        let value = if precision == 0 { segment[position + 1 + i] as u16 } else { read_u16(segment, position + 1 + 2 * i) };
+        *table.get_mut(ZIGZAG[i]) = value;
+      }
+      *self.quantization_tables.get_mut(id) = Some(table);
+      position += 1 + size;
+    }
+    Ok(())
+  }
+
+  fn read_huffman_tables(&mut self, segment: &[u8]) -> Result<(), JpegError> {
+    let mut position = 0;
+    while position < segment.len() {
+      let class = segment[position] >> 4;
+      let id = (segment[position] & 0x0F) as uint;
+      if class > 1 || id > 3 || position + 17 > segment.len() {
+        return Err(BadTable);
+      }
+
+      let counts = segment.slice(position + 1, position + 17);
+      let total = counts.iter().fold(0u, |sum, &count| sum + count as uint);
+      if position + 17 + total > segment.len() {
+        return Err(BadTable);
+      }
+      let table = HuffmanTable::new(counts, segment.slice(position + 17, position + 17 + total));
+
+      if class == 0 {
+        *self.dc_tables.get_mut(id) = Some(table);
+      }
+      else {
+        *self.ac_tables.get_mut(id) = Some(table);
+      }
+      position += 17 + total;
+    }
+    Ok(())
+  }
+
+  fn read_jfif(&mut self, segment: &[u8]) {
+    if segment.len() < 12 || segment.slice(0, 5) != "JFIF\0".as_bytes() {
+      return;
+    }
+    self.jfif = true;
+
+    let horizontal = read_u16(segment, 8) as f64;
+    let vertical = read_u16(segment, 10) as f64;
+    if horizontal == 0. || vertical == 0. {
+      return;
+    }
+    self.resolution = match segment[7] {
+      1 => Some(Resolution::from_dpi(horizontal, vertical)),
+      2 => Some(Resolution{horizontal: horizontal * 100., vertical: vertical * 100.}),
+      _ => None    // Only an aspect ratio
+    };
+  }
+
+  fn read_adobe(&mut self, segment: &[u8]) {
+    if segment.len() >= 12 && segment.slice(0, 5) == "Adobe".as_bytes() {
+      self.adobe_transform = Some(segment[11]);
+    }
+  }
+
+  // Reads a scan header and decodes its entropy coded data, returning the
+  // position of the marker that follows it
+  fn decode_scan(&mut self, segment: &[u8], data: &[u8], start: uint) -> Result<uint, JpegError> {
+
+    let frame = match self.frame {
+      Some(ref mut frame) => frame,
+      None => return Err(BadScan)
+    };
+
+    if segment.len() < 1 {
+      return Err(Truncated);
+    }
+    let count = segment[0] as uint;
+    if count == 0 || count > frame.components.len() || segment.len() < 4 + 2 * count {
+      return Err(BadScan);
+    }
+
+    // Progressive scans carry a band of coefficients, and which bits of them
+    let spectral_start = segment[1 + 2 * count] as uint;
+    let spectral_end = segment[2 + 2 * count] as uint;
+    let approximation_high = (segment[3 + 2 * count] >> 4) as uint;
+    let approximation_low = (segment[3 + 2 * count] & 0x0F) as uint;
+
+    if frame.progressive {
+      let dc_band = spectral_start == 0 && spectral_end == 0;
+      let ac_band = spectral_start > 0 && spectral_start <= spectral_end && spectral_end <= 63 && count == 1;
+      if !(dc_band || ac_band) || approximation_low > 13 {
+        return Err(BadScan);
+      }
+    }
+
+    // DC refinement needs no tables, and AC bands only need an AC table
+    let needs_dc = spectral_start == 0 && !(frame.progressive && approximation_high > 0);
+    let needs_ac = !frame.progressive || spectral_start > 0;
+
+    let mut scan: Vec<ScanComponent> = Vec::with_capacity(count);
+    for i in range(0, count) {
+      let id = segment[1 + 2 * i];
+      let dc_table = (segment[2 + 2 * i] >> 4) as uint;
+      let ac_table = (segment[2 + 2 * i] & 0x0F) as uint;
+      let index = match frame.components.iter().position(|component| component.id == id) {
+        Some(index) => index,
+        None => return Err(BadScan)
+      };
+      if dc_table > 3 || ac_table > 3 {
+        return Err(BadTable);
+      }
+      if (needs_dc && self.dc_tables.get(dc_table).is_none()) || (needs_ac && self.ac_tables.get(ac_table).is_none()) {
+        return Err(MissingTable);
+      }
+      scan.push(ScanComponent{index: index, dc_table: dc_table, ac_table: ac_table});
+    }
+
+    let mut reader = BitReader::new(data, start);
+    let mut predictors: Vec<i32> = Vec::from_elem(count, 0i32);
+    let mut eob_run = 0u;
+    let mut dropped = [0i32, ..64];
+    let ac_band = frame.progressive && spectral_start > 0;
+
+    // A single component is coded a block at a time, over just the blocks that hold the image
+    let (mcus_x, mcus_y) = if count == 1 {
+      let component = frame.components.get(scan.get(0).index);
+      ((component.width + 7) / 8, (component.height + 7) / 8)
+    }
+    else {
+      (frame.mcus_x, frame.mcus_y)
+    };
+
+    for mcu in range(0, mcus_x * mcus_y) {
+
+      if self.restart_interval > 0 && mcu > 0 && mcu % self.restart_interval == 0 {
+        reader.restart();
+        for predictor in predictors.mut_iter() {
+          *predictor = 0;
+        }
+        eob_run = 0;
+      }
+
+      // The rest of the scan would be decoded from zeros. Without an earlier
+      // scan there's no image to show, otherwise it's kept as far as it goes.
+      if reader.exhausted() && eob_run == 0 {
+        if self.scans == 0 {
+          return Err(Truncated);
+        }
+        break;
+      }
+
+      let mcu_x = mcu % mcus_x;
+      let mcu_y = mcu / mcus_x;
+
+      for (i, scan_component) in scan.iter().enumerate() {
+
+        let dc = self.dc_tables.get(scan_component.dc_table).as_ref();
+        let ac = self.ac_tables.get(scan_component.ac_table).as_ref();
+        let component = frame.components.get_mut(scan_component.index);
+        let (blocks_x, blocks_y) = if count == 1 { (1, 1) } else { (component.h, component.v) };
+
+        for v in range(0, blocks_y) {
+          for h in range(0, blocks_x) {
+            let row = mcu_y * blocks_y + v;
+            let column = mcu_x * blocks_x + h;
+            let offset = (row * component.blocks_per_line + column) * 64;
+
+            // Coefficients grow as blocks are decoded rather than from the frame's
+            // size. AC bands only add to blocks an earlier scan stored, since a
+            // run of empty blocks costs a few bits, so other blocks are dropped.
+            if component.coefficients.len() < offset + 64 && !ac_band {
+              let length = component.coefficients.len();
+              component.coefficients.grow(offset + 64 - length, &0i32);
+            }
+            let block = if component.coefficients.len() >= offset + 64 {
+              component.coefficients.mut_slice(offset, offset + 64)
+            }
+            else {
+              dropped = [0i32, ..64];
+              dropped.as_mut_slice()
+            };
+
+            if !frame.progressive {
+              try!(decode_block(&mut reader, dc.unwrap(), ac.unwrap(), block, predictors.get_mut(i)));
+            }
+            else if spectral_start == 0 && approximation_high == 0 {
+              try!(decode_dc_first(&mut reader, dc.unwrap(), block, predictors.get_mut(i), approximation_low));
+            }
+            else if spectral_start == 0 {
+              decode_dc_refine(&mut reader, block, approximation_low);
+            }
+            else if approximation_high == 0 {
+              try!(decode_ac_first(&mut reader, ac.unwrap(), block, spectral_start, spectral_end, approximation_low, &mut eob_run));
+            }
+            else {
+              try!(decode_ac_refine(&mut reader, ac.unwrap(), block, spectral_start, spectral_end, approximation_low, &mut eob_run));
+            }
+          }
+        }
+      }
+    }
+
+    Ok(reader.end_of_scan())
+  }
+
+  // Dequantizes and transforms every component, then upsamples and converts to RGB
+  fn output(&self) -> Result<Image, JpegError> {
+
+    let frame = match self.frame {
+      Some(ref frame) => frame,
+      None => return Err(Truncated)
+    };
+
+    let cosines = idct_table();
+    let mut planes: Vec<Vec<u8>> = Vec::with_capacity(frame.components.len());
+    for component in frame.components.iter() {
+      let table = match *self.quantization_tables.get(component.quantization_table) {
+        Some(ref table) => table,
+        None => return Err(MissingTable)
+      };
+      planes.push(component_plane(component, table.as_slice(), cosines.as_slice()));
+    }
+
+    let gray = frame.components.len() == 1;
+    let transform = self.color_transform(frame);
+    let mut image = Image::new(frame.width, frame.height, if gray { GRAYSCALE8 } else { RGB8 });
+
+    for y in range(0, frame.height) {
+      for x in range(0, frame.width) {
+
+        // Subsampled components are stretched by repeating samples
+        let mut samples = [0u8, ..3];
+        for (i, component) in frame.components.iter().enumerate() {
+          let sample_x = x * component.h / frame.max_h;
+          let sample_y = y * component.v / frame.max_v;
+          samples[i] = *planes.get(i).get(sample_y * component.blocks_per_line * 8 + sample_x);
+        }
+
+        if gray {
+          *image.data.get_mut(x + frame.width * y) = samples[0];
+        }
+        else {
+          let (red, green, blue) = if transform {
+            ycbcr_to_rgb(samples[0], samples[1], samples[2])
+          }
+          else {
+            (samples[0], samples[1], samples[2])
+          };
+          let offset = (x + frame.width * y) * 3;
+          *image.data.get_mut(offset) = red;
+          *image.data.get_mut(offset + 1) = green;
+          *image.data.get_mut(offset + 2) = blue;
+        }
+      }
+    }
+
+    image.resolution = self.resolution.clone();
+    Ok(image)
+  }
+
+  // Whether three components are YCbCr and need converting to RGB
+  fn color_transform(&self, frame: &Frame) -> bool {
+    match self.adobe_transform {
+      Some(transform) => return transform != 0,
+      None => {}
+    }
+    if self.jfif {
+      return true;
+    }
+    let ids: Vec<u8> = frame.components.iter().map(|component| component.id).collect();
+    ids.as_slice() != "RGB".as_bytes()
+  }
+
+}
+
+// Decodes one block of a baseline scan into its coefficients
+fn decode_block(reader: &mut BitReader, dc: &HuffmanTable, ac: &HuffmanTable, block: &mut [i32], predictor: &mut i32) -> Result<(), JpegError> {
+
+  let size = try!(dc.decode(reader)) as uint;
+  if size > 16 {
+    return Err(BadHuffmanCode);
+  }
+  *predictor += reader.receive_extend(size);
+  block[0] = *predictor;
+
+  // Each AC symbol is a run of zeros in the high nibble and the size of the next coefficient in the low
+  let mut k = 1u;
+  while k < 64 {
+    let symbol = try!(ac.decode(reader));
+    let run = (symbol >> 4) as uint;
+    let size = (symbol & 0x0F) as uint;
+
+    if size == 0 {
+      if run == 15 {
+        k += 16;    // Sixteen zeros
+        continue;
+      }
+      break;        // End of block
+    }
+
+    k += run;
+    if k > 63 {
+      return Err(BadHuffmanCode);
+    }
+    block[ZIGZAG[k]] = reader.receive_extend(size);
+    k += 1;
+  }
+
+  Ok(())
+}
+
+// First scan of a progressive DC band: the same DC difference as baseline,
+// but only the bits above approximation_low are stored
+fn decode_dc_first(reader: &mut BitReader, dc: &HuffmanTable, block: &mut [i32], predictor: &mut i32, approximation_low: uint) -> Result<(), JpegError> {
+  let size = try!(dc.decode(reader)) as uint;
+  if size > 16 {
+    return Err(BadHuffmanCode);
+  }
+  *predictor += reader.receive_extend(size);
+  block[0] = *predictor << approximation_low;
+  Ok(())
+}
+
+// Later DC scans add one more bit to every DC coefficient, uncoded
+fn decode_dc_refine(reader: &mut BitReader, block: &mut [i32], approximation_low: uint) {
+  if reader.read_bits(1) == 1 {
+    block[0] |= 1i32 << approximation_low;
+  }
+}
+
+// First scan of an AC band. Runs of blocks with nothing left in the band are
+// coded once as an end of band run (EOBRUN) rather than per block.
+fn decode_ac_first(reader: &mut BitReader, ac: &HuffmanTable, block: &mut [i32], start: uint, end: uint, approximation_low: uint, eob_run: &mut uint) -> Result<(), JpegError> {
+
+  if *eob_run > 0 {
+    *eob_run -= 1;
+    return Ok(());
+  }
+
+  let mut k = start;
+  while k <= end {
+    let symbol = try!(ac.decode(reader));
+    let run = (symbol >> 4) as uint;
+    let size = (symbol & 0x0F) as uint;
+
+    if size == 0 {
+      if run == 15 {
+        k += 16;
+        continue;
+      }
+      // This block is the first of the run
+      *eob_run = (1u << run) + reader.read_bits(run) as uint - 1;
+      break;
+    }
+
+    k += run;
+    if k > 63 {
+      return Err(BadHuffmanCode);
+    }
+    block[ZIGZAG[k]] = reader.receive_extend(size) << approximation_low;
+    k += 1;
+  }
+
+  Ok(())
+}
+
+// Later scans of an AC band. Each newly nonzero coefficient is coded as a
+// run of zeros and a sign, and every coefficient already nonzero that the
+// run passes over gets one correction bit. This follows libjpeg's
+// decode_mcu_AC_refine.
+fn decode_ac_refine(reader: &mut BitReader, ac: &HuffmanTable, block: &mut [i32], start: uint, end: uint, approximation_low: uint, eob_run: &mut uint) -> Result<(), JpegError> {
+
+  let positive = 1i32 << approximation_low;
+  let negative = (-1i32) << approximation_low;
+  let mut k = start;
+
+  if *eob_run == 0 {
+    while k <= end {
+      let symbol = try!(ac.decode(reader));
+      let mut run = (symbol >> 4) as int;
+      let size = symbol & 0x0F;
+
+      let mut value = 0i32;
+      if size != 0 {
+        if size != 1 {
+          return Err(BadHuffmanCode);
+        }
+        value = if reader.read_bits(1) == 1 { positive } else { negative };
+      }
+      else if run != 15 {
+        *eob_run = (1u << run as uint) + reader.read_bits(run as uint) as uint;
+        break;
+      }
+
+      // Skip run zeros, refining the nonzero coefficients on the way
+      while k <= end {
+        let position = ZIGZAG[k];
+        if block[position] != 0 {
+          refine_coefficient(reader, &mut block[position], positive, negative);
+        }
+        else {
+          run -= 1;
+          if run < 0 {
+            break;
+          }
+        }
+        k += 1;
+      }
+
+      if value != 0 && k <= end {
+        block[ZIGZAG[k]] = value;
+      }
+      k += 1;
+    }
+  }
+
+  // The rest of a block in an end of band run still refines its nonzero coefficients
+  if *eob_run > 0 {
+    while k <= end {
+      let position = ZIGZAG[k];
+      if block[position] != 0 {
+        refine_coefficient(reader, &mut block[position], positive, negative);
+      }
+      k += 1;
+    }
+    *eob_run -= 1;
+  }
+
+  Ok(())
+}
+
+// Adds a correction bit to a coefficient, away from zero
+fn refine_coefficient(reader: &mut BitReader, coefficient: &mut i32, positive: i32, negative: i32) {
+  if reader.read_bits(1) == 1 && (*coefficient & positive) == 0 {
+    *coefficient += if *coefficient >= 0 { positive } else { negative };
+  }
+}
+
+// Basis functions of the inverse DCT: entry x * 8 + u is C(u) cos((2x + 1) u pi / 16) / 2
+fn idct_table() -> Vec<f32> {
+  let mut table: Vec<f32> = Vec::with_capacity(64);
+  for x in range(0u, 8) {
+    for u in range(0u, 8) {
+      let scale = if u == 0 { 1. / 2f32.sqrt() } else { 1. };
+      table.push(scale * (((2 * x + 1) * u) as f32 * PI / 16.).cos() / 2.);
+    }
+  }
+  table
+}
+
+// Separable 8x8 inverse DCT, along rows and then columns
+fn idct(coefficients: &[f32], cosines: &[f32], output: &mut [f32]) {
+  let mut rows = [0f32, ..64];
+  for y in range(0u, 8) {
+    for x in range(0u, 8) {
+      let mut sum = 0f32;
+      for u in range(0u, 8) {
+        sum += cosines[x * 8 + u] * coefficients[y * 8 + u];
+      }
+      rows[y * 8 + x] = sum;
+    }
+  }
+  for x in range(0u, 8) {
+    for y in range(0u, 8) {
+      let mut sum = 0f32;
+      for v in range(0u, 8) {
+        sum += cosines[y * 8 + v] * rows[v * 8 + x];
+      }
+      output[y * 8 + x] = sum;
+    }
+  }
+}
+
+// Samples of a component at its own resolution, padded to whole MCUs
+fn component_plane(component: &Component, quantization: &[u16], cosines: &[f32]) -> Vec<u8> {
+
+  let width = component.blocks_per_line * 8;
+  let mut plane: Vec<u8> = Vec::from_elem(width * component.blocks_per_column * 8, 0u8);
+  let mut coefficients = [0f32, ..64];
+  let mut output = [0f32, ..64];
+
+  for row in range(0, component.blocks_per_column) {
+    for column in range(0, component.blocks_per_line) {
+
+      // Blocks no scan reached are all zero
+      let offset = (row * component.blocks_per_line + column) * 64;
+      let stored = offset < component.coefficients.len();
+      for i in range(0u, 64) {
+        coefficients[i] = if stored { (*component.coefficients.get(offset + i) * quantization[i] as i32) as f32 } else { 0. };
+      }
+      idct(coefficients.as_slice(), cosines, output.as_mut_slice());
+
+      // Samples are stored shifted down by 128
+      for y in range(0u, 8) {
+        for x in range(0u, 8) {
+          *plane.get_mut((row * 8 + y) * width + column * 8 + x) = clamp(output[y * 8 + x] + 128.);
+        }
+      }
+    }
+  }
+
+  plane
+}
+
+fn clamp(value: f32) -> u8 {
+  if value <= 0. {
+    0
+  }
+  else if value >= 255. {
+    255
+  }
+  else {
+    value.round() as u8
+  }
+}
+
+// JFIF conversion, where Cb and Cr are centered on 128
+fn ycbcr_to_rgb(y: u8, cb: u8, cr: u8) -> (u8, u8, u8) {
+  let y = y as f32;
+  let cb = cb as f32 - 128.;
+  let cr = cr as f32 - 128.;
+  (clamp(y + 1.402 * cr), clamp(y - 0.344136 * cb - 0.714136 * cr), clamp(y + 1.772 * cb))
+}
+
+
+// How much the chroma of a color image is subsampled when it's written
+#[deriving(Show, PartialEq, Clone)]
+pub enum ChromaSubsampling {
+  Chroma444,    // Full resolution
+  Chroma422,    // Half the horizontal resolution
+  Chroma420,    // Half the horizontal and vertical resolution
+}
+
+// Settings for how an image is compressed when it's written
+#[deriving(Show, Clone)]
+pub struct JpegOptions {
+  pub quality: u8,                      // 1 to 100, scaling the standard quantization tables the way libjpeg does
+  pub subsampling: ChromaSubsampling,   // Ignored for grayscale images
+  pub optimize: bool,                   // Build Huffman tables for the image instead of using the standard ones
+  pub progressive: bool,                // Spread the coefficients over several scans, always with optimized tables
+}
+
+impl JpegOptions {
+
+  // The same defaults as libjpeg
+  pub fn new() -> JpegOptions {
+    JpegOptions{quality: 75, subsampling: Chroma420, optimize: false, progressive: false}
+  }
+
+}
+
+// One scan of the image being written: its components, the band of
+// coefficients it holds, and which of their bits
+struct ScanScript {
+  components: Vec<uint>,
+  start: uint,
+  end: uint,
+  high: uint,     // Bit position coded by the previous scan of this band, 0 for the first
+  low: uint,      // Bits below this one are left for later scans
+}
+
+#[allow(dead_code)]
+pub fn write_jpeg(image: Image, filename: &str) -> Result<(), JpegError> {
+  write_jpeg_with(image, filename, &JpegOptions::new())
+}
+
+#[allow(dead_code)]
+pub fn write_jpeg_with(image: Image, filename: &str, options: &JpegOptions) -> Result<(), JpegError> {
+  let path = Path::new(filename);
+  let mut file = try!(io(File::create(&path)));
+  encode_jpeg_with(&image, &mut file, options)
+}
+
+// Encodes a JPEG image into a new in-memory buffer
+#[allow(dead_code)]
+pub fn to_bytes(image: &Image) -> Result<Vec<u8>, JpegError> {
+  to_bytes_with(image, &JpegOptions::new())
+}
+
+#[allow(dead_code)]
+pub fn to_bytes_with(image: &Image, options: &JpegOptions) -> Result<Vec<u8>, JpegError> {
+  let mut writer = MemWriter::new();
+  try!(encode_jpeg_with(image, &mut writer, options));
+  Ok(writer.unwrap())
+}
+
+// Encodes an RGB8 or GRAYSCALE8 image as a JFIF file to any writer
+#[allow(dead_code)]
+pub fn encode_jpeg<W: Writer>(image: &Image, file: &mut W) -> Result<(), JpegError> {
+  encode_jpeg_with(image, file, &JpegOptions::new())
+}
+
+#[allow(dead_code)]
+pub fn encode_jpeg_with<W: Writer>(image: &Image, file: &mut W, options: &JpegOptions) -> Result<(), JpegError> {
+
+  if image.width == 0 || image.height == 0 || image.width > 65535 || image.height > 65535 {
+    return Err(InvalidDimensions(image.width, image.height));
+  }
+
+  // Sampling factors of the luma component, chroma is always 1x1
+  let (h, v) = match (image.color_type, options.subsampling) {
+    (RGBA8, _)          => return Err(UnsupportedAlpha),
+    (GRAYSCALE8, _)     => (1, 1),
+    (RGB8, Chroma444)   => (1, 1),
+    (RGB8, Chroma422)   => (2, 1),
+    (RGB8, Chroma420)   => (2, 2)
+  };
+
+  // Luma uses table 0 for quantization and Huffman coding, chroma uses table 1
+  let quantization = vec!(scaled_table(LUMINANCE_QUANTIZATION.as_slice(), options.quality),
+                          scaled_table(CHROMINANCE_QUANTIZATION.as_slice(), options.quality));
+  let frame = encoder_frame(image, h, v, quantization.as_slice(), options.progressive);
+  let table_count = if frame.components.len() == 1 { 1 } else { 2 };
+
+  try!(io(file.write(&[0xFF, 0xD8])));
+  try!(write_segment(file, 0xE0, jfif_segment(image).as_slice()));
+
+  let mut tables: Vec<u8> = Vec::new();
+  for (id, table) in quantization.iter().take(table_count).enumerate() {
+    tables.push(id as u8);    // 8-bit values
+    for i in range(0u, 64) {
+      tables.push(*table.get(ZIGZAG[i]) as u8);
+    }
+  }
+  try!(write_segment(file, 0xDB, tables.as_slice()));
+
+  let mut header: Vec<u8> = vec!(8, (image.height >> 8) as u8, image.height as u8, (image.width >> 8) as u8, image.width as u8, frame.components.len() as u8);
+  for component in frame.components.iter() {
+    header.push_all(&[component.id, ((component.h << 4) | component.v) as u8, component.quantization_table as u8]);
+  }
+  try!(write_segment(file, if options.progressive { 0xC2 } else { 0xC0 }, header.as_slice()));
+
+  let mut coder = EntropyCoder::new();
+
+  if options.progressive {
+    // End of band symbols aren't in the standard tables, so every scan gets its own
+    for scan in progressive_script(frame.components.len()).iter() {
+      try!(write_scan(file, &mut coder, &frame, scan, true));
+    }
+  }
+  else {
+    if !options.optimize {
+      let standard = vec!((0u, 0u, DC_LUMINANCE_COUNTS.as_slice(), DC_VALUES.as_slice()),
+                          (1, 0, AC_LUMINANCE_COUNTS.as_slice(), AC_LUMINANCE_VALUES.as_slice()),
+                          (0, 1, DC_CHROMINANCE_COUNTS.as_slice(), DC_VALUES.as_slice()),
+                          (1, 1, AC_CHROMINANCE_COUNTS.as_slice(), AC_CHROMINANCE_VALUES.as_slice()));
+      let mut data: Vec<u8> = Vec::new();
+      for &(class, table, counts, values) in standard.iter().take(table_count * 2) {
+        data.push(((class << 4) | table) as u8);
+        data.push_all(counts);
+        data.push_all(values);
+        coder.set_codes(class, table, counts, values);
+      }
+      try!(write_segment(file, 0xC4, data.as_slice()));
+    }
+    let scan = ScanScript{components: range(0, frame.components.len()).collect(), start: 0, end: 63, high: 0, low: 0};
+    try!(write_scan(file, &mut coder, &frame, &scan, options.optimize));
+  }
+
+  io(file.write(&[0xFF, 0xD9]))
+}
+
+fn write_segment<W: Writer>(file: &mut W, marker: u8, data: &[u8]) -> Result<(), JpegError> {
+  try!(io(file.write(&[0xFF, marker])));
+  try!(io(file.write_be_u16((data.len() + 2) as u16)));
+  io(file.write(data))
+}
+
+// JFIF marker, with the resolution in dots per inch when the image has one
+fn jfif_segment(image: &Image) -> Vec<u8> {
+  let (units, horizontal, vertical) = match image.resolution {
+    Some(ref resolution) => {
+      let (horizontal, vertical) = resolution.dpi();
+      (1u8, density(horizontal), density(vertical))
+    },
+    None => (0u8, 1u16, 1u16)    // Square pixels
+  };
+  let mut data: Vec<u8> = Vec::from_slice("JFIF\0".as_bytes());
+  data.push_all(&[1, 1, units, (horizontal >> 8) as u8, horizontal as u8, (vertical >> 8) as u8, vertical as u8, 0, 0]);
+  data
+}
+
+fn density(dpi: f64) -> u16 {
+  if dpi < 1. { 1 } else if dpi > 65535. { 65535 } else { dpi.round() as u16 }
+}
+
+// Scales a quantization table for a quality from 1 to 100 like libjpeg:
+// 50 keeps the table, 100 makes every entry 1 and lower qualities grow it
+fn scaled_table(table: &[u16], quality: u8) -> Vec<u16> {
+  let quality = cmp::max(1, cmp::min(100, quality)) as u32;
+  let scale = if quality < 50 { 5000 / quality } else { 200 - quality * 2 };
+  table.iter().map(|&value| cmp::max(1, cmp::min(255, (value as u32 * scale + 50) / 100)) as u16).collect()
+}
+
+// Scans written for a progressive image, the same as libjpeg's: the DC
+// coefficients without their last bit, then low frequency luma and all of
+// the chroma, then the rest of the luma, then the last bits of everything
+fn progressive_script(components: uint) -> Vec<ScanScript> {
+  let scan = |components: &[uint], start: uint, end: uint, high: uint, low: uint| {
+    ScanScript{components: Vec::from_slice(components), start: start, end: end, high: high, low: low}
+  };
+  if components == 1 {
+    vec!(scan(&[0], 0, 0, 0, 1),
+         scan(&[0], 1, 5, 0, 2),
+         scan(&[0], 6, 63, 0, 2),
+         scan(&[0], 1, 63, 2, 1),
+         scan(&[0], 0, 0, 1, 0),
+         scan(&[0], 1, 63, 1, 0))
+  }
+  else {
+    vec!(scan(&[0, 1, 2], 0, 0, 0, 1),
+         scan(&[0], 1, 5, 0, 2),
+         scan(&[2], 1, 63, 0, 1),
+         scan(&[1], 1, 63, 0, 1),
+         scan(&[0], 6, 63, 0, 2),
+         scan(&[0], 1, 63, 2, 1),
+         scan(&[0, 1, 2], 0, 0, 1, 0),
+         scan(&[2], 1, 63, 1, 0),
+         scan(&[1], 1, 63, 1, 0),
+         scan(&[0], 1, 63, 1, 0))
+  }
+}
+
+// Converts an image to YCbCr and transforms it into quantized coefficients,
+// padded to whole MCUs by repeating the last row and column
+fn encoder_frame(image: &Image, h: uint, v: uint, quantization: &[Vec<u16>], progressive: bool) -> Frame {
+
+  let channels = match image.color_type {
+    GRAYSCALE8  => 1u,
+    _           => 3u
+  };
+  let mcus_x = (image.width + 8 * h - 1) / (8 * h);
+  let mcus_y = (image.height + 8 * v - 1) / (8 * v);
+  let padded_width = mcus_x * h * 8;
+  let padded_height = mcus_y * v * 8;
+
+  let mut planes: Vec<Vec<f32>> = Vec::from_fn(channels, |_| Vec::with_capacity(padded_width * padded_height));
+  for y in range(0, padded_height) {
+    let row = cmp::min(y, image.height - 1) * image.width;
+    for x in range(0, padded_width) {
+      let offset = (row + cmp::min(x, image.width - 1)) * channels;
+      if channels == 1 {
+        planes.get_mut(0).push(*image.data.get(offset) as f32);
+      }
+      else {
+        let (luma, cb, cr) = rgb_to_ycbcr(*image.data.get(offset), *image.data.get(offset + 1), *image.data.get(offset + 2));
+        planes.get_mut(0).push(luma);
+        planes.get_mut(1).push(cb);
+        planes.get_mut(2).push(cr);
+      }
+    }
+  }
+
+  let cosines = idct_table();
+  let mut samples = [0f32, ..64];
+  let mut output = [0f32, ..64];
+  let mut components: Vec<Component> = Vec::with_capacity(channels);
+
+  for (i, plane) in planes.iter().enumerate() {
+
+    // Chroma samples are the average of the h x v luma sized samples they cover
+    let (component_h, component_v) = if i == 0 { (h, v) } else { (1, 1) };
+    let scale_x = h / component_h;
+    let scale_y = v / component_v;
+    let table = if i == 0 { 0 } else { 1 };
+    let blocks_per_line = mcus_x * component_h;
+    let blocks_per_column = mcus_y * component_v;
+    let mut coefficients: Vec<i32> = Vec::with_capacity(blocks_per_line * blocks_per_column * 64);
+
+    for row in range(0, blocks_per_column) {
+      for column in range(0, blocks_per_line) {
+        for y in range(0u, 8) {
+          for x in range(0u, 8) {
+            let mut sum = 0f32;
+            for dy in range(0, scale_y) {
+              for dx in range(0, scale_x) {
+                sum += *plane.get(((row * 8 + y) * scale_y + dy) * padded_width + (column * 8 + x) * scale_x + dx);
+              }
+            }
+            // Samples are stored shifted down by 128
+            samples[y * 8 + x] = sum / (scale_x * scale_y) as f32 - 128.;
+          }
+        }
+        fdct(samples.as_slice(), cosines.as_slice(), output.as_mut_slice());
+        for k in range(0u, 64) {
+          coefficients.push(quantize(output[k], *quantization[table].get(k)));
+        }
+      }
+    }
+
+    components.push(Component {
+      id: i as u8 + 1,
+      h: component_h,
+      v: component_v,
+      quantization_table: table,
+      width: (image.width * component_h + h - 1) / h,
+      height: (image.height * component_v + v - 1) / v,
+      blocks_per_line: blocks_per_line,
+      blocks_per_column: blocks_per_column,
+      coefficients: coefficients,
+    });
+  }
+
+  Frame {
+    progressive: progressive,
+    width: image.width,
+    height: image.height,
+    max_h: h,
+    max_v: v,
+    mcus_x: mcus_x,
+    mcus_y: mcus_y,
+    components: components,
+  }
+}
+
+// Separable 8x8 forward DCT with the same basis functions as idct(), transposed
+fn fdct(samples: &[f32], cosines: &[f32], output: &mut [f32]) {
+  let mut rows = [0f32, ..64];
+  for y in range(0u, 8) {
+    for u in range(0u, 8) {
+      let mut sum = 0f32;
+      for x in range(0u, 8) {
+        sum += cosines[x * 8 + u] * samples[y * 8 + x];
+      }
+      rows[y * 8 + u] = sum;
+    }
+  }
+  for u in range(0u, 8) {
+    for v in range(0u, 8) {
+      let mut sum = 0f32;
+      for y in range(0u, 8) {
+        sum += cosines[y * 8 + v] * rows[y * 8 + u];
+      }
+      output[v * 8 + u] = sum;
+    }
+  }
+}
+
+// Rounds a coefficient to a multiple of its quantizer, kept within what baseline Huffman tables can code
+fn quantize(coefficient: f32, quantizer: u16) -> i32 {
+  let value = (coefficient / quantizer as f32).round() as i32;
+  cmp::max(-1023, cmp::min(1023, value))
+}
+
+// JFIF conversion, the inverse of ycbcr_to_rgb
+fn rgb_to_ycbcr(r: u8, g: u8, b: u8) -> (f32, f32, f32) {
+  let r = r as f32;
+  let g = g as f32;
+  let b = b as f32;
+  (0.299 * r + 0.587 * g + 0.114 * b,
+   -0.168736 * r - 0.331264 * g + 0.5 * b + 128.,
+   0.5 * r - 0.418688 * g - 0.081312 * b + 128.)
+}
+
+// Writes a scan's header and entropy coded data. With optimize the scan is
+// coded twice, first only counting symbols, and is preceded by the Huffman
+// tables built from those counts.
+fn write_scan<W: Writer>(file: &mut W, coder: &mut EntropyCoder, frame: &Frame, scan: &ScanScript, optimize: bool) -> Result<(), JpegError> {
+
+  if optimize {
+    coder.start(true);
+    encode_scan(coder, frame, scan);
+
+    // DC refinement needs no tables, and AC bands only need an AC table
+    let mut used: Vec<(uint, uint)> = Vec::new();
+    for &index in scan.components.iter() {
+      let table = frame.components.get(index).quantization_table;
+      if scan.start == 0 && scan.high == 0 && !used.contains(&(0, table)) {
+        used.push((0, table));
+      }
+      if scan.end > 0 && !used.contains(&(1, table)) {
+        used.push((1, table));
+      }
+    }
+
+    if used.len() > 0 {
+      let mut data: Vec<u8> = Vec::new();
+      for &(class, table) in used.iter() {
+        let (counts, values) = optimized_table(coder.frequencies.get(class * 2 + table).as_slice());
+        data.push(((class << 4) | table) as u8);
+        data.push_all(counts.as_slice());
+        data.push_all(values.as_slice());
+        coder.set_codes(class, table, counts.as_slice(), values.as_slice());
+      }
+      try!(write_segment(file, 0xC4, data.as_slice()));
+    }
+  }
+
+  coder.start(false);
+  encode_scan(coder, frame, scan);
+
+  let mut header: Vec<u8> = vec!(scan.components.len() as u8);
+  for &index in scan.components.iter() {
+    let component = frame.components.get(index);
+    header.push_all(&[component.id, ((component.quantization_table << 4) | component.quantization_table) as u8]);
+  }
+  header.push_all(&[scan.start as u8, scan.end as u8, ((scan.high << 4) | scan.low) as u8]);
+  try!(write_segment(file, 0xDA, header.as_slice()));
+  io(file.write(coder.output.as_slice()))
+}
+
+// Codes every block of a scan, in the same order decode_scan reads them
+fn encode_scan(coder: &mut EntropyCoder, frame: &Frame, scan: &ScanScript) {
+
+  let count = scan.components.len();
+  let mut predictors: Vec<i32> = Vec::from_elem(count, 0i32);
+
+  let (mcus_x, mcus_y) = if count == 1 {
+    let component = frame.components.get(*scan.components.get(0));
+    ((component.width + 7) / 8, (component.height + 7) / 8)
+  }
+  else {
+    (frame.mcus_x, frame.mcus_y)
+  };
+
+  for mcu in range(0, mcus_x * mcus_y) {
+    let mcu_x = mcu % mcus_x;
+    let mcu_y = mcu / mcus_x;
+
+    for (i, &index) in scan.components.iter().enumerate() {
+      let component = frame.components.get(index);
+      let table = component.quantization_table;
+      let (blocks_x, blocks_y) = if count == 1 { (1, 1) } else { (component.h, component.v) };
+
+      for v in range(0, blocks_y) {
+        for h in range(0, blocks_x) {
+          let row = mcu_y * blocks_y + v;
+          let column = mcu_x * blocks_x + h;
+          let offset = (row * component.blocks_per_line + column) * 64;
+          let block = component.coefficients.slice(offset, offset + 64);
+
+          if !frame.progressive {
+            encode_block(coder, table, block, predictors.get_mut(i));
+          }
+          else if scan.start == 0 && scan.high == 0 {
+            encode_dc_first(coder, table, block, predictors.get_mut(i), scan.low);
+          }
+          else if scan.start == 0 {
+            encode_dc_refine(coder, block, scan.low);
+          }
+          else if scan.high == 0 {
+            encode_ac_first(coder, table, block, scan.start, scan.end, scan.low);
+          }
+          else {
+            encode_ac_refine(coder, table, block, scan.start, scan.end, scan.low);
+          }
+        }
+      }
+    }
+  }
+
+  if frame.progressive && scan.start > 0 {
+    let table = frame.components.get(*scan.components.get(0)).quantization_table;
+    coder.end_band(table);
+  }
+  coder.flush();
+}
+
+// Number of bits needed for a magnitude, the size category of a coefficient
+fn bit_size(magnitude: i32) -> uint {
+  let mut size = 0u;
+  while (magnitude >> size) > 0 {
+    size += 1;
+  }
+  size
+}
+
+// Writes a size category and then the value in that many bits, negative values one less than their two's complement
+fn encode_value(coder: &mut EntropyCoder, class: uint, table: uint, run: uint, value: i32) {
+  let magnitude = if value < 0 { -value } else { value };
+  let size = bit_size(magnitude);
+  coder.symbol(class, table, ((run << 4) | size) as u8);
+  let bits = if value < 0 { value + (1 << size) - 1 } else { value };
+  coder.write(bits as u32, size);
+}
+
+fn encode_block(coder: &mut EntropyCoder, table: uint, block: &[i32], predictor: &mut i32) {
+  encode_value(coder, 0, table, 0, block[0] - *predictor);
+  *predictor = block[0];
+
+  let mut run = 0u;
+  for k in range(1u, 64) {
+    let value = block[ZIGZAG[k]];
+    if value == 0 {
+      run += 1;
+      continue;
+    }
+    while run > 15 {
+      coder.symbol(1, table, 0xF0);
+      run -= 16;
+    }
+    encode_value(coder, 1, table, run, value);
+    run = 0;
+  }
+  if run > 0 {
+    coder.symbol(1, table, 0x00);
+  }
+}
+
+fn encode_dc_first(coder: &mut EntropyCoder, table: uint, block: &[i32], predictor: &mut i32, approximation_low: uint) {
+  let value = block[0] >> approximation_low;
+  encode_value(coder, 0, table, 0, value - *predictor);
+  *predictor = value;
+}
+
+fn encode_dc_refine(coder: &mut EntropyCoder, block: &[i32], approximation_low: uint) {
+  coder.write(((block[0] >> approximation_low) & 1) as u32, 1);
+}
+
+// Coefficients are divided by 2^approximation_low, rounding towards zero like the decoder expects
+fn encode_ac_first(coder: &mut EntropyCoder, table: uint, block: &[i32], start: uint, end: uint, approximation_low: uint) {
+  let mut run = 0u;
+  for k in range(start, end + 1) {
+    let value = block[ZIGZAG[k]];
+    let magnitude = (if value < 0 { -value } else { value }) >> approximation_low;
+    if magnitude == 0 {
+      run += 1;
+      continue;
+    }
+    coder.end_band(table);
+    while run > 15 {
+      coder.symbol(1, table, 0xF0);
+      run -= 16;
+    }
+    encode_value(coder, 1, table, run, if value < 0 { -magnitude } else { magnitude });
+    run = 0;
+  }
+  if run > 0 {
+    coder.eob_run += 1;
+    if coder.eob_run == 0x7FFF {
+      coder.end_band(table);
+    }
+  }
+}
+
+// Follows libjpeg: coefficients that become nonzero in this scan are coded
+// like a first scan, with a 1 bit sign. The new bit of every coefficient
+// that was already nonzero is held back and written after the next symbol,
+// or after the end of band run the block ends up in.
+fn encode_ac_refine(coder: &mut EntropyCoder, table: uint, block: &[i32], start: uint, end: uint, approximation_low: uint) {
+
+  let mut magnitudes = [0i32, ..64];
+  let mut last_new = 0u;    // Position of the last coefficient that becomes nonzero, 0 for none
+  for k in range(start, end + 1) {
+    let value = block[ZIGZAG[k]];
+    magnitudes[k] = (if value < 0 { -value } else { value }) >> approximation_low;
+    if magnitudes[k] == 1 {
+      last_new = k;
+    }
+  }
+
+  let mut run = 0u;
+  let mut corrections: Vec<u8> = Vec::new();
+  for k in range(start, end + 1) {
+    let magnitude = magnitudes[k];
+    if magnitude == 0 {
+      run += 1;
+      continue;
+    }
+
+    // Runs of zeros after the last new coefficient are left to the end of band
+    while run > 15 && k <= last_new {
+      coder.end_band(table);
+      coder.symbol(1, table, 0xF0);
+      run -= 16;
+      for &bit in corrections.iter() {
+        coder.write(bit as u32, 1);
+      }
+      corrections.clear();
+    }
+
+    if magnitude > 1 {
+      corrections.push((magnitude & 1) as u8);
+      continue;
+    }
+
+    coder.end_band(table);
+    coder.symbol(1, table, ((run << 4) | 1) as u8);
+    coder.write(if block[ZIGZAG[k]] < 0 { 0 } else { 1 }, 1);
+    for &bit in corrections.iter() {
+      coder.write(bit as u32, 1);
+    }
+    corrections.clear();
+    run = 0;
+  }
+
+  if run > 0 || corrections.len() > 0 {
+    coder.eob_run += 1;
+    coder.corrections.push_all(corrections.as_slice());
+    if coder.eob_run == 0x7FFF {
+      coder.end_band(table);
+    }
+  }
+}
+
+// Huffman codes scan data, or only counts the symbols it would write so that
+// tables can be built for them. Tables are indexed by class (0 = DC, 1 = AC)
+// times 2 plus the table id.
+struct EntropyCoder {
+  counting: bool,
+  frequencies: Vec<Vec<u32>>,     // Uses of each symbol since counting started
+  codes: Vec<Vec<(u16, u8)>>,     // Code and length of each symbol
+  output: Vec<u8>,
+  bits: u32,                      // Bits not yet making up a whole byte, in the low end
+  count: uint,
+  eob_run: uint,                  // Blocks in the end of band run not yet written
+  corrections: Vec<u8>,           // Correction bits of the blocks in that run
+}
+
+impl EntropyCoder {
+
+  fn new() -> EntropyCoder {
+    EntropyCoder {
+      counting: false,
+      frequencies: Vec::from_fn(4, |_| Vec::from_elem(256, 0u32)),
+      codes: Vec::from_fn(4, |_| Vec::from_elem(256, (0u16, 0u8))),
+      output: Vec::new(),
+      bits: 0,
+      count: 0,
+      eob_run: 0,
+      corrections: Vec::new(),
+    }
+  }
+
+  // Starts a scan, either counting its symbols or writing them with the current codes
+  fn start(&mut self, counting: bool) {
+    self.counting = counting;
+    if counting {
+      for frequencies in self.frequencies.mut_iter() {
+        for frequency in frequencies.mut_iter() {
+          *frequency = 0;
+        }
+      }
+    }
+    self.output = Vec::new();
+    self.bits = 0;
+    self.count = 0;
+    self.eob_run = 0;
+    self.corrections = Vec::new();
+  }
+
+  // Canonical codes for a table given as counts of each code length and its symbols
+  fn set_codes(&mut self, class: uint, table: uint, counts: &[u8], values: &[u8]) {
+    let codes = self.codes.get_mut(class * 2 + table);
+    let mut code = 0u;
+    let mut k = 0u;
+    for length in range(1u, 17) {
+      for _ in range(0, counts[length - 1]) {
+        *codes.get_mut(values[k] as uint) = (code as u16, length as u8);
+        code += 1;
+        k += 1;
+      }
+      code <<= 1;
+    }
+  }
+
+  fn symbol(&mut self, class: uint, table: uint, symbol: u8) {
+    if self.counting {
+      *self.frequencies.get_mut(class * 2 + table).get_mut(symbol as uint) += 1;
+    }
+    else {
+      let (code, length) = *self.codes.get(class * 2 + table).get(symbol as uint);
+      self.write(code as u32, length as uint);
+    }
+  }
+
+  // Writes the low count bits of value, most significant first, stuffing a zero after every 0xFF
+  fn write(&mut self, value: u32, count: uint) {
+    if self.counting || count == 0 {
+      return;
+    }
+    self.bits = (self.bits << count) | (value & ((1 << count) - 1));
+    self.count += count;
+    while self.count >= 8 {
+      let byte = (self.bits >> (self.count - 8)) as u8;
+      self.output.push(byte);
+      if byte == 0xFF {
+        self.output.push(0);
+      }
+      self.count -= 8;
+    }
+    self.bits &= (1 << self.count) - 1;
+  }
+
+  // Pads the last byte with ones
+  fn flush(&mut self) {
+    if self.count > 0 {
+      let padding = 8 - self.count;
+      self.write((1 << padding) - 1, padding);
+    }
+  }
+
+  // Writes the pending end of band run, then the correction bits held back for it
+  fn end_band(&mut self, table: uint) {
+    if self.eob_run == 0 {
+      return;
+    }
+    let run = self.eob_run;
+    let size = bit_size(run as i32) - 1;
+    self.symbol(1, table, (size << 4) as u8);
+    self.write(run as u32, size);
+    self.eob_run = 0;
+
+    let corrections = self.corrections.clone();
+    self.corrections.clear();
+    for &bit in corrections.iter() {
+      self.write(bit as u32, 1);
+    }
+  }
+
+}
+
+// Huffman table for symbol frequencies, as the counts of each code length
+// and the symbols in code order. A dummy symbol is given the longest code so
+// that no real symbol gets the code of all ones, which JPEG reserves.
+fn optimized_table(frequencies: &[u32]) -> (Vec<u8>, Vec<u8>) {
+
+  let mut weights: Vec<u32> = Vec::from_slice(frequencies);
+  weights.push(1);
+  let mut lengths = zlib::huffman_lengths(weights.as_slice(), 16);
+
+  // The dummy is among the least frequent symbols, but not always one of the deepest
+  let longest = *lengths.iter().max().unwrap();
+  if *lengths.get(256) != longest {
+    let deepest = lengths.iter().position(|&length| length == longest).unwrap();
+    *lengths.get_mut(deepest) = *lengths.get(256);
+    *lengths.get_mut(256) = longest;
+  }
+
+  let mut counts: Vec<u8> = Vec::from_elem(16, 0u8);
+  let mut values: Vec<u8> = Vec::new();
+  for length in range(1u8, 17) {
+    for symbol in range(0u, 256) {
+      if *lengths.get(symbol) == length {
+        *counts.get_mut(length as uint - 1) += 1;
+        values.push(symbol as u8);
+      }
+    }
+  }
+  (counts, values)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::*;
+  use std::io::{BufReader};
+
+  // Writes entropy coded bits most significant first, stuffing a zero after every 0xFF
+  struct BitWriter {
+    output: Vec<u8>,
+    bits: u32,
+    count: uint,
+  }
+
+  impl BitWriter {
+    fn write(&mut self, value: u32, count: uint) {
+      for i in range(0, count).rev() {
+        self.bits = (self.bits << 1) | ((value >> i) & 1);
+        self.count += 1;
+        if self.count == 8 {
+          self.output.push(self.bits as u8);
+          if self.bits == 0xFF {
+            self.output.push(0);
+          }
+          self.bits = 0;
+          self.count = 0;
+        }
+      }
+    }
+
+    // Pads the last byte with ones
+    fn flush(&mut self) {
+      while self.count != 0 {
+        self.write(1, 1);
+      }
+    }
+  }
+
+  // Writes a value's size as a 4 bit code, then the value in that many bits
+  fn write_value(writer: &mut BitWriter, value: i32) {
+    let magnitude = if value < 0 { -value } else { value };
+    let mut size = 0u;
+    while (magnitude >> size) > 0 {
+      size += 1;
+    }
+    writer.write(size as u32, 4);
+    let bits = if value < 0 { value + (1 << size) - 1 } else { value };
+    writer.write(bits as u32, size);
+  }
+
+  fn segment(output: &mut Vec<u8>, marker: u8, data: &[u8]) {
+    output.push_all(&[0xFF, marker, ((data.len() + 2) >> 8) as u8, (data.len() + 2) as u8]);
+    output.push_all(data);
+  }
+
+  // Builds a baseline JPEG of flat blocks. Every table is trivial: quantization
+  // is all ones, DC sizes have 4 bit codes equal to the size, and the only AC
+  // code is a 1 bit end of block. values holds each block's level in scan
+  // order, and layout gives the component of each block in an MCU.
+  fn jpeg_bytes(width: u16, height: u16, sampling: &[u8], layout: &[uint], values: &[u8], restart_interval: u16) -> Vec<u8> {
+    let mut output: Vec<u8> = vec!(0xFF, 0xD8);
+
+    segment(&mut output, 0xE0, &[0x4A, 0x46, 0x49, 0x46, 0, 1, 1, 1, 0, 72, 0, 72, 0, 0]);
+    let mut quantization = vec!(0u8);
+    quantization.push_all(Vec::from_elem(64, 1u8).as_slice());
+    segment(&mut output, 0xDB, quantization.as_slice());
+
+    let mut frame = vec!(8u8, (height >> 8) as u8, height as u8, (width >> 8) as u8, width as u8, sampling.len() as u8);
+    for (i, &factors) in sampling.iter().enumerate() {
+      frame.push_all(&[i as u8 + 1, factors, 0]);
+    }
+    segment(&mut output, 0xC0, frame.as_slice());
+
+    segment(&mut output, 0xC4, &[0x00, 0, 0, 0, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+    segment(&mut output, 0xC4, &[0x10, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x00]);
+    if restart_interval > 0 {
+      segment(&mut output, 0xDD, &[(restart_interval >> 8) as u8, restart_interval as u8]);
+    }
+
+    let mut scan = vec!(sampling.len() as u8);
+    for i in range(0, sampling.len()) {
+      scan.push_all(&[i as u8 + 1, 0x00]);
+    }
+    scan.push_all(&[0, 63, 0]);
+    segment(&mut output, 0xDA, scan.as_slice());
+
+    let mut writer = BitWriter{output: Vec::new(), bits: 0, count: 0};
+    let mut predictors = Vec::from_elem(sampling.len(), 0i32);
+    for (i, &value) in values.iter().enumerate() {
+      let mcu = i / layout.len();
+      if i % layout.len() == 0 && mcu > 0 && restart_interval > 0 && mcu % restart_interval as uint == 0 {
+        writer.flush();
+        writer.output.push_all(&[0xFF, 0xD0 + ((mcu / restart_interval as uint - 1) % 8) as u8]);
+        predictors = Vec::from_elem(sampling.len(), 0i32);
+      }
+
+      // A flat block's DC coefficient is 8 times its level shifted value
+      let component = layout[i % layout.len()];
+      let coefficient = (value as i32 - 128) * 8;
+      let difference = coefficient - *predictors.get(component);
+      *predictors.get_mut(component) = coefficient;
+
+      write_value(&mut writer, difference);
+      writer.write(0, 1);
+    }
+    writer.flush();
+    output.push_all(writer.output.as_slice());
+
+    output.push_all(&[0xFF, 0xD9]);
+    output
+  }
+
+  #[test]
+  fn test_grayscale() {
+    let bytes = jpeg_bytes(16, 8, &[0x11], &[0], &[50, 200], 0);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.color_type as uint, GRAYSCALE8 as uint);
+    assert_eq!((image.width, image.height), (16, 8));
+    assert_eq!(image.get_pixel(7, 7), vec!(50));
+    assert_eq!(image.get_pixel(8, 0), vec!(200));
+    assert_eq!(image.resolution, Some(Resolution::from_dpi(72., 72.)));
+  }
+
+  #[test]
+  fn test_partial_blocks() {
+    let bytes = jpeg_bytes(10, 3, &[0x11], &[0], &[10, 20], 0);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.data.len(), 30);
+    assert_eq!(image.get_pixel(7, 2), vec!(10));
+    assert_eq!(image.get_pixel(9, 2), vec!(20));
+  }
+
+  #[test]
+  fn test_subsampling_420() {
+    // One MCU: four luma blocks, then Cb and Cr covering all of them
+    let bytes = jpeg_bytes(16, 16, &[0x22, 0x11, 0x11], &[0, 0, 0, 0, 1, 2], &[10, 20, 30, 40, 128, 128], 0);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.color_type as uint, RGB8 as uint);
+    assert_eq!(image.get_pixel(0, 0), vec!(10, 10, 10));
+    assert_eq!(image.get_pixel(15, 0), vec!(20, 20, 20));
+    assert_eq!(image.get_pixel(0, 15), vec!(30, 30, 30));
+    assert_eq!(image.get_pixel(15, 15), vec!(40, 40, 40));
+
+    let bytes = jpeg_bytes(16, 16, &[0x22, 0x11, 0x11], &[0, 0, 0, 0, 1, 2], &[128, 128, 128, 128, 128, 255], 0);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.get_pixel(9, 3), vec!(255, 37, 128));
+  }
+
+  #[test]
+  fn test_subsampling_422() {
+    let bytes = jpeg_bytes(16, 8, &[0x21, 0x11, 0x11], &[0, 0, 1, 2], &[100, 200, 128, 128], 0);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.get_pixel(3, 5), vec!(100, 100, 100));
+    assert_eq!(image.get_pixel(12, 5), vec!(200, 200, 200));
+  }
+
+  #[test]
+  fn test_restart_markers() {
+    // 4:4:4 with a restart after every MCU, so each MCU starts its predictions over
+    let values = [60u8, 128, 128, 120, 128, 128, 240, 128, 128];
+    let bytes = jpeg_bytes(24, 8, &[0x11, 0x11, 0x11], &[0, 1, 2], &values, 1);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.get_pixel(0, 0), vec!(60, 60, 60));
+    assert_eq!(image.get_pixel(8, 0), vec!(120, 120, 120));
+    assert_eq!(image.get_pixel(16, 0), vec!(240, 240, 240));
+  }
+
+  // One gray 8x8 block with a DC coefficient and the first AC coefficient,
+  // either baseline or as four progressive scans split at bit 1. DC and AC
+  // share one table where the symbol is its own 4 bit code, so 0x00 is an
+  // end of block and 0x0s is a coefficient of size s.
+  fn coefficient_jpeg(dc: i32, ac: i32, progressive: bool) -> Vec<u8> {
+    let mut output: Vec<u8> = vec!(0xFF, 0xD8);
+
+    let mut quantization = vec!(0u8);
+    quantization.push_all(Vec::from_elem(64, 1u8).as_slice());
+    segment(&mut output, 0xDB, quantization.as_slice());
+    segment(&mut output, if progressive { 0xC2 } else { 0xC0 }, &[8, 0, 8, 0, 8, 1, 1, 0x11, 0]);
+    let table = [0u8, 0, 0, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+    for &class in [0x00u8, 0x10].iter() {
+      let mut data = vec!(class);
+      data.push_all(&table);
+      segment(&mut output, 0xC4, data.as_slice());
+    }
+
+    let ac_magnitude = if ac < 0 { -ac } else { ac };
+    let ac_first = if ac < 0 { -(ac_magnitude >> 1) } else { ac_magnitude >> 1 };
+
+    // Spectral start and end, and the approximation bits, of each scan
+    let scans = if progressive { vec!((0u8, 0u8, 0x01u8), (1, 63, 0x01), (0, 0, 0x10), (1, 63, 0x10)) } else { vec!((0, 63, 0)) };
+    for (i, &(start, end, approximation)) in scans.iter().enumerate() {
+      segment(&mut output, 0xDA, &[1, 1, 0x00, start, end, approximation]);
+
+      let mut writer = BitWriter{output: Vec::new(), bits: 0, count: 0};
+      match (progressive, i) {
+        (false, _) => {
+          write_value(&mut writer, dc);
+          write_value(&mut writer, ac);
+          writer.write(0, 4);
+        },
+        (true, 0) => write_value(&mut writer, dc >> 1),
+        (true, 1) => {
+          write_value(&mut writer, ac_first);
+          writer.write(0, 4);
+        },
+        (true, 2) => writer.write((dc & 1) as u32, 1),
+        (true, _) => {
+          // End of band, then the correction bit for the coefficient already found
+          writer.write(0, 4);
+          writer.write((ac_magnitude & 1) as u32, 1);
+        }
+      }
+      writer.flush();
+      output.push_all(writer.output.as_slice());
+    }
+
+    output.push_all(&[0xFF, 0xD9]);
+    output
+  }
+
+  #[test]
+  fn test_progressive() {
+    let baseline = from_bytes(coefficient_jpeg(81, -21, false).as_slice()).unwrap();
+    let progressive = from_bytes(coefficient_jpeg(81, -21, true).as_slice()).unwrap();
+    assert_eq!(progressive.data, baseline.data);
+
+    let baseline = from_bytes(coefficient_jpeg(-46, 37, false).as_slice()).unwrap();
+    let progressive = from_bytes(coefficient_jpeg(-46, 37, true).as_slice()).unwrap();
+    assert_eq!(progressive.data, baseline.data);
+  }
+
+  #[test]
+  fn test_progressive_preview() {
+    let bytes = coefficient_jpeg(81, -21, true);
+
+    // The first scan only has the DC coefficient without its last bit: 80 / 8 above 128
+    let preview = preview_jpeg(&mut BufReader::new(bytes.as_slice())).unwrap();
+    assert_eq!(preview.data, Vec::from_elem(64, 138u8));
+
+    let mut scans: Vec<uint> = Vec::new();
+    let image = decode_jpeg_scans(&mut BufReader::new(bytes.as_slice()), |scan, _| {
+      scans.push(scan);
+      true
+    }).unwrap();
+    assert_eq!(scans, vec!(1, 2, 3, 4));
+    assert_eq!(image.data, from_bytes(bytes.as_slice()).unwrap().data);
+
+    let image = decode_jpeg_scans(&mut BufReader::new(bytes.as_slice()), |_, _| false).unwrap();
+    assert_eq!(image.data, preview.data);
+  }
+
+  #[test]
+  fn test_errors() {
+    match from_bytes(&[0x89u8, 0x50, 0x4E, 0x47]) {
+      Err(BadSignature) => {},
+      Err(e)  => fail!("Expected a bad signature, got {}", e),
+      Ok(_)   => fail!("Decoded a file that isn't a JPEG")
+    }
+
+    // Arithmetic coding, marked by changing the frame marker
+    let mut bytes = jpeg_bytes(8, 8, &[0x11], &[0], &[128], 0);
+    let frame = range(0, bytes.len() - 1).find(|&i| *bytes.get(i) == 0xFF && *bytes.get(i + 1) == 0xC0).unwrap();
+    *bytes.get_mut(frame + 1) = 0xC9;
+    match from_bytes(bytes.as_slice()) {
+      Err(UnsupportedProcess(0xC9)) => {},
+      Err(e)  => fail!("Expected an unsupported process, got {}", e),
+      Ok(_)   => fail!("Decoded an arithmetic coded image")
+    }
+
+    *bytes.get_mut(frame + 1) = 0xC0;
+    *bytes.get_mut(frame + 4) = 12;
+    match from_bytes(bytes.as_slice()) {
+      Err(UnsupportedPrecision(12)) => {},
+      Err(e)  => fail!("Expected an unsupported precision, got {}", e),
+      Ok(_)   => fail!("Decoded a 12-bit image")
+    }
+
+    match from_bytes(bytes.slice(0, frame)) {
+      Err(Truncated) => {},
+      Err(e)  => fail!("Expected a truncated file, got {}", e),
+      Ok(_)   => fail!("Decoded a file without a frame")
+    }
+
+    // A 65535x65535 frame with one block of data
+    match from_bytes(jpeg_bytes(0xFFFF, 0xFFFF, &[0x11], &[0], &[128], 0).as_slice()) {
+      Err(Truncated) => {},
+      Err(e)  => fail!("Expected a truncated file, got {}", e),
+      Ok(_)   => fail!("Decoded a frame far larger than its data")
+    }
+    let mut bytes = coefficient_jpeg(81, -21, true);
+    let frame = range(0, bytes.len() - 1).find(|&i| *bytes.get(i) == 0xFF && *bytes.get(i + 1) == 0xC2).unwrap();
+    for i in range(frame + 5, frame + 9) {
+      *bytes.get_mut(i) = 0xFF;
+    }
+    match from_bytes(bytes.as_slice()) {
+      Err(Truncated) => {},
+      Err(e)  => fail!("Expected a truncated file, got {}", e),
+      Ok(_)   => fail!("Decoded a progressive frame far larger than its data")
+    }
+  }
+
+  #[test]
+  fn test_truncated_scan() {
+    // The last scan loses its data and the end of image marker, leaving the first three
+    let bytes = coefficient_jpeg(81, -21, true);
+    let three_scans = decode_jpeg_scans(&mut BufReader::new(bytes.as_slice()), |scan, _| scan < 3).unwrap();
+    let image = from_bytes(bytes.slice_to(bytes.len() - 3)).unwrap();
+    assert_eq!(image.data, three_scans.data);
+  }
+
+  // Smooth gradients on the left and noise on the right, so the encoder
+  // sees both long runs of zeros and large coefficients
+  fn test_image(width: uint, height: uint, color_type: ColorType) -> Image {
+    let mut image = Image::new(width, height, color_type);
+    let channels = color_type as uint / 8;
+    let mut seed = 12345u32;
+    for y in range(0, height) {
+      for x in range(0, width) {
+        for c in range(0, channels) {
+          seed = seed * 1103515245 + 12345;
+          let value = if x < width / 2 { (x * 255 / width + y * 4 + c * 40) % 256 } else { (seed >> 16) as uint % 256 };
+          *image.data.get_mut((y * width + x) * channels + c) = value as u8;
+        }
+      }
+    }
+    image
+  }
+
+  fn mean_error(a: &Image, b: &Image) -> f64 {
+    let total = a.data.iter().zip(b.data.iter()).fold(0u, |sum, (&x, &y)| sum + (if x > y { x - y } else { y - x }) as uint);
+    total as f64 / a.data.len() as f64
+  }
+
+  #[test]
+  fn test_encode_grayscale() {
+    let mut image = test_image(19, 13, GRAYSCALE8);
+    image.resolution = Some(Resolution::from_dpi(300., 150.));
+    let bytes = to_bytes_with(&image, &JpegOptions{quality: 95, ..JpegOptions::new()}).unwrap();
+    let decoded = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!((decoded.width, decoded.height), (19, 13));
+    assert_eq!(decoded.color_type as uint, GRAYSCALE8 as uint);
+    assert!(mean_error(&image, &decoded) < 6.);
+    assert_eq!(decoded.resolution, Some(Resolution::from_dpi(300., 150.)));
+  }
+
+  #[test]
+  fn test_encode_subsampling() {
+    let image = test_image(37, 21, RGB8);
+    for &(subsampling, factors) in [(Chroma444, 0x11u8), (Chroma422, 0x21), (Chroma420, 0x22)].iter() {
+      let options = JpegOptions{quality: 90, subsampling: subsampling, ..JpegOptions::new()};
+      let bytes = to_bytes_with(&image, &options).unwrap();
+
+      // Luma sampling factors in the frame header
+      let frame = range(0, bytes.len() - 1).find(|&i| *bytes.get(i) == 0xFF && *bytes.get(i + 1) == 0xC0).unwrap();
+      assert_eq!(*bytes.get(frame + 11), factors);
+
+      let decoded = from_bytes(bytes.as_slice()).unwrap();
+      assert_eq!((decoded.width, decoded.height), (37, 21));
+      assert_eq!(decoded.color_type as uint, RGB8 as uint);
+      assert!(mean_error(&image, &decoded) < 12.);
+    }
+  }
+
+  #[test]
+  fn test_encode_quality() {
+    let image = test_image(32, 32, RGB8);
+    let low = to_bytes_with(&image, &JpegOptions{quality: 10, ..JpegOptions::new()}).unwrap();
+    let high = to_bytes_with(&image, &JpegOptions{quality: 95, ..JpegOptions::new()}).unwrap();
+    assert!(low.len() < high.len());
+    let low_error = mean_error(&image, &from_bytes(low.as_slice()).unwrap());
+    let high_error = mean_error(&image, &from_bytes(high.as_slice()).unwrap());
+    assert!(high_error < low_error);
+
+    // Out of range qualities are clamped
+    let options = JpegOptions{quality: 0, ..JpegOptions::new()};
+    assert_eq!(to_bytes_with(&image, &options).unwrap(), to_bytes_with(&image, &JpegOptions{quality: 1, ..options}).unwrap());
+  }
+
+  #[test]
+  fn test_encode_optimized() {
+    for &color_type in [GRAYSCALE8, RGB8].iter() {
+      let image = test_image(40, 24, color_type);
+      let standard = to_bytes_with(&image, &JpegOptions{quality: 100, ..JpegOptions::new()}).unwrap();
+      let optimized = to_bytes_with(&image, &JpegOptions{quality: 100, optimize: true, ..JpegOptions::new()}).unwrap();
+      assert!(optimized.len() < standard.len());
+
+      // Huffman coding is lossless, so only the size changes
+      assert_eq!(from_bytes(optimized.as_slice()).unwrap().data, from_bytes(standard.as_slice()).unwrap().data);
+    }
+  }
+
+  #[test]
+  fn test_encode_progressive() {
+    for &(color_type, scans) in [(GRAYSCALE8, 6u), (RGB8, 10)].iter() {
+      for &quality in [100u8, 75, 20].iter() {
+        let image = test_image(45, 35, color_type);
+        let options = JpegOptions{quality: quality, ..JpegOptions::new()};
+        let baseline = to_bytes_with(&image, &options).unwrap();
+        let progressive = to_bytes_with(&image, &JpegOptions{progressive: true, ..options}).unwrap();
+        assert!(range(0, progressive.len() - 1).any(|i| *progressive.get(i) == 0xFF && *progressive.get(i + 1) == 0xC2));
+
+        let mut count = 0u;
+        let decoded = decode_jpeg_scans(&mut BufReader::new(progressive.as_slice()), |scan, _| {
+          count = scan;
+          true
+        }).unwrap();
+        assert_eq!(count, scans);
+        assert_eq!(decoded.data, from_bytes(baseline.as_slice()).unwrap().data);
+      }
+    }
+  }
+
+  #[test]
+  fn test_encode_errors() {
+    match to_bytes(&Image::new(8, 8, RGBA8)) {
+      Err(UnsupportedAlpha) => {},
+      Err(e)  => fail!("Expected unsupported alpha, got {}", e),
+      Ok(_)   => fail!("Encoded an RGBA8 image")
+    }
+    match to_bytes(&Image::new(0, 8, RGB8)) {
+      Err(InvalidDimensions(0, 8)) => {},
+      Err(e)  => fail!("Expected invalid dimensions, got {}", e),
+      Ok(_)   => fail!("Encoded an empty image")
+    }
+  }
+}
@@ -1,12 +1,27 @@
 
 #![feature(globs)]
 
+#[cfg(test)]
+extern crate test;
+
 //use std::os;
 //use std::str;
 //use image::*;
 
 mod image;
 mod bmp;
+mod ico;
+mod zlib;
+mod png;
+mod jpeg;
+mod gif;
+mod pnm;
+mod tga;
+mod qoi;
+mod tiff;
+mod hdr;
+mod farbfeld;
+mod raw;
 
 
 #[allow(dead_code)]
@@ -0,0 +1,903 @@
+// PNG Image format
+
+use std::path::posix::{Path};
+use std::io::{File, IoResult, IoError, EndOfFile, BufReader, MemWriter};
+use std::num::{CheckedAdd, CheckedMul};
+use std::cmp;
+use image::*;
+use zlib;
+use zlib::{ZlibError};
+
+static SIGNATURE: [u8, ..8] = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
+
+// Origin and spacing (x, y, dx, dy) of the seven Adam7 passes
+static ADAM7: [(uint, uint, uint, uint), ..7] = [(0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4), (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2)];
+
+// Colorimetry intents for the sRGB chunk's perceptual, relative colorimetric, saturation and absolute colorimetric
+static SRGB_INTENTS: [u32, ..4] = [4, 2, 1, 8];
+
+// Largest IDAT chunk written, longer image data is split across several
+static IDAT_SIZE: uint = 65536;
+
+
+/* NOTES:
+ * PNG is an 8 byte signature followed by chunks: length, 4 character type, data, CRC of the type and data
+ * All integers are big endian
+ * Pixel data is the zlib stream of every IDAT chunk joined together
+ * Each scanline starts with a filter byte, and filters work on bytes, not samples
+ * Bit depths under 8 are packed most significant bit first, with scanlines padded to a byte
+ * Interlaced images store seven Adam7 passes one after another, each a small image of its own
+ * Images only hold 8 bits per channel, so 16-bit samples keep their high byte
+ * Gray with alpha, and any image with a tRNS chunk, decode as RGBA8
+ * Chunk lengths and IHDR sizes aren't trusted for allocating: chunks are read a piece at a time, and the
+ *   inflated data has to hold every scanline before the image is allocated
+ */
+
+
+// Everything that can go wrong while reading or writing a PNG image
+#[deriving(Show)]
+pub enum PngError {
+  BadSignature,                   // First eight bytes aren't the PNG signature
+  Truncated,                      // File ended in the middle of a chunk or before IEND
+  BadChecksum(String),            // CRC of the named chunk doesn't match its contents
+  MissingHeader,                  // First chunk isn't IHDR
+  InvalidDimensions(u32, u32),    // Width or height of zero, or too large
+  UnsupportedFormat(u8, u8),      // Bit depth and color type that can't be used together
+  UnsupportedMethod(u8, u8),      // Compression or filter method other than 0
+  UnsupportedInterlace(u8),       // Interlace method other than none or Adam7
+  UnknownCriticalChunk(String),   // Chunk this decoder doesn't know but must understand to decode
+  MissingPalette,                 // Indexed image without a PLTE chunk
+  BadPaletteIndex(u16),           // Pixel refers to a color past the end of the palette
+  BadFilter(u8),                  // Scanline filter type above 4
+  MissingImageData,               // No IDAT chunks, or less pixel data than the image needs
+  BadCompression(ZlibError),      // Pixel data or profile couldn't be inflated
+  IoFailure(IoError),             // Any other error from the underlying file
+}
+
+// Wraps an I/O error so it can be returned with try!
+fn io<T>(result: IoResult<T>) -> Result<T, PngError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => Err(IoFailure(e))
+  }
+}
+
+// Same as io(), but running out of bytes means the file was cut short
+fn chunk<T>(result: IoResult<T>) -> Result<T, PngError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => {
+      if e.kind == EndOfFile {
+        Err(Truncated)
+      }
+      else {
+        Err(IoFailure(e))
+      }
+    }
+  }
+}
+
+// Reads count bytes a piece at a time, growing the buffer as the data arrives
+fn read_bytes<R: Reader>(file: &mut R, count: uint) -> Result<Vec<u8>, PngError> {
+  let mut bytes: Vec<u8> = Vec::new();
+  let mut buffer = [0u8, ..4096];
+  while bytes.len() < count {
+    let length = cmp::min(count - bytes.len(), buffer.len());
+    try!(chunk(file.read_at_least(length, buffer.mut_slice_to(length))));
+    bytes.push_all(buffer.slice_to(length));
+  }
+  Ok(bytes)
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>, PngError> {
+  match zlib::inflate(data) {
+    Ok(inflated) => Ok(inflated),
+    Err(e)       => Err(BadCompression(e))
+  }
+}
+
+
+// Contents of the IHDR chunk
+struct Header {
+  width: uint,
+  height: uint,
+  bit_depth: uint,
+  color_type: u8,   // 0 = gray, 2 = RGB, 3 = indexed, 4 = gray and alpha, 6 = RGBA
+  interlaced: bool,
+}
+
+impl Header {
+
+  fn channels(&self) -> uint {
+    match self.color_type {
+      2 => 3,
+      4 => 2,
+      6 => 4,
+      _ => 1
+    }
+  }
+
+  // Origin and spacing of each pass, the whole image at once unless it's interlaced
+  fn passes(&self) -> Vec<(uint, uint, uint, uint)> {
+    if self.interlaced {
+      ADAM7.iter().map(|&pass| pass).collect()
+    }
+    else {
+      vec!((0, 0, 1, 1))
+    }
+  }
+
+  // Bytes of filtered scanlines in every pass, or None when that doesn't fit in a uint
+  fn data_length(&self) -> Option<uint> {
+    let bits_per_pixel = self.channels() * self.bit_depth;
+    let mut length = 0u;
+    for &(x0, y0, dx, dy) in self.passes().iter() {
+      if x0 >= self.width || y0 >= self.height {
+        continue;
+      }
+      let pass_width = (self.width - x0 + dx - 1) / dx;
+      let pass_height = (self.height - y0 + dy - 1) / dy;
+      let row_bits = match pass_width.checked_mul(&bits_per_pixel) {
+        Some(bits) => bits,
+        None => return None
+      };
+      let row_bytes = row_bits / 8 + if row_bits % 8 == 0 { 0 } else { 1 };
+      length = match (1 + row_bytes).checked_mul(&pass_height).and_then(|bytes| length.checked_add(&bytes)) {
+        Some(length) => length,
+        None => return None
+      };
+    }
+    Some(length)
+  }
+
+}
+
+fn read_header(data: &[u8]) -> Result<Header, PngError> {
+
+  let mut reader = BufReader::new(data);
+  let width = try!(chunk(reader.read_be_u32()));
+  let height = try!(chunk(reader.read_be_u32()));
+  let bit_depth = try!(chunk(reader.read_u8()));
+  let color_type = try!(chunk(reader.read_u8()));
+  let compression = try!(chunk(reader.read_u8()));
+  let filter = try!(chunk(reader.read_u8()));
+  let interlace = try!(chunk(reader.read_u8()));
+
+  if width == 0 || height == 0 || width > 0x7FFFFFFF || height > 0x7FFFFFFF {
+    return Err(InvalidDimensions(width, height));
+  }
+
+  let allowed = match color_type {
+    0     => bit_depth == 1 || bit_depth == 2 || bit_depth == 4 || bit_depth == 8 || bit_depth == 16,
+    3     => bit_depth == 1 || bit_depth == 2 || bit_depth == 4 || bit_depth == 8,
+    2 | 4 | 6 => bit_depth == 8 || bit_depth == 16,
+    _     => false
+  };
+  if !allowed {
+    return Err(UnsupportedFormat(bit_depth, color_type));
+  }
+  if compression != 0 || filter != 0 {
+    return Err(UnsupportedMethod(compression, filter));
+  }
+  if interlace > 1 {
+    return Err(UnsupportedInterlace(interlace));
+  }
+
+  Ok(Header{width: width as uint, height: height as uint, bit_depth: bit_depth as uint, color_type: color_type, interlaced: interlace == 1})
+}
+
+
+#[allow(dead_code)]
+pub fn read_png(image_path_str: &str) -> Result<Image, PngError> {
+  let path = Path::new(image_path_str);
+  let mut file = try!(io(File::open(&path)));
+  decode_png(&mut file)
+}
+
+// Decodes a PNG image held in memory
+#[allow(dead_code)]
+pub fn from_bytes(bytes: &[u8]) -> Result<Image, PngError> {
+  let mut reader = BufReader::new(bytes);
+  decode_png(&mut reader)
+}
+
+// Decodes a PNG image from any reader, starting at the signature
+#[allow(dead_code)]
+pub fn decode_png<R: Reader>(image: &mut R) -> Result<Image, PngError> {
+
+  let signature = try!(chunk(image.read_exact(8)));
+  if signature.as_slice() != SIGNATURE.as_slice() {
+    return Err(BadSignature);
+  }
+
+  let mut header: Option<Header> = None;
+  let mut palette: Vec<u8> = Vec::new();
+  let mut transparency: Option<Vec<u8>> = None;
+  let mut compressed: Vec<u8> = Vec::new();
+  let mut resolution: Option<Resolution> = None;
+  let mut colorimetry: Option<Colorimetry> = None;
+
+  loop {
+    let length = try!(chunk(image.read_be_u32())) as uint;
+    let kind = try!(chunk(image.read_exact(4)));
+    let data = try!(read_bytes(image, length));
+    let crc = try!(chunk(image.read_be_u32()));
+
+    let name: String = kind.iter().map(|&c| c as char).collect();
+    if zlib::update_crc32(zlib::crc32(kind.as_slice()), data.as_slice()) != crc {
+      return Err(BadChecksum(name));
+    }
+    if header.is_none() && name.as_slice() != "IHDR" {
+      return Err(MissingHeader);
+    }
+
+    match name.as_slice() {
+      "IHDR" => {
+        header = Some(try!(read_header(data.as_slice())));
+      },
+      "PLTE" => {
+        palette = data;
+      },
+      "tRNS" => {
+        transparency = Some(data);
+      },
+      "IDAT" => {
+        compressed.push_all(data.as_slice());
+      },
+      "pHYs" => {
+        resolution = read_resolution(data.as_slice());
+      },
+      "sRGB" => {
+        // An ICC profile takes precedence over sRGB
+        if colorimetry.is_none() && data.len() == 1 && *data.get(0) < 4 {
+          colorimetry = Some(srgb_colorimetry(SRGB_INTENTS[*data.get(0) as uint]));
+        }
+      },
+      "iCCP" => {
+        colorimetry = Some(try!(read_profile(data.as_slice())));
+      },
+      "IEND" => {
+        break;
+      },
+      _ => {
+        // Ancillary chunks have a lowercase first letter and can be skipped
+        if *kind.get(0) & 0x20 == 0 {
+          return Err(UnknownCriticalChunk(name));
+        }
+      }
+    }
+  }
+
+  if compressed.len() == 0 {
+    return Err(MissingImageData);
+  }
+  let raw = try!(inflate(compressed.as_slice()));
+
+  let mut decoded = try!(decode_pixels(&header.unwrap(), raw.as_slice(), palette.as_slice(), transparency));
+  decoded.resolution = resolution;
+  decoded.colorimetry = colorimetry;
+  Ok(decoded)
+}
+
+// Physical pixel size, only kept when the unit is meters rather than just an aspect ratio
+fn read_resolution(data: &[u8]) -> Option<Resolution> {
+  let mut reader = BufReader::new(data);
+  match (reader.read_be_u32(), reader.read_be_u32(), reader.read_u8()) {
+    (Ok(horizontal), Ok(vertical), Ok(1)) => Some(Resolution{horizontal: horizontal as f64, vertical: vertical as f64}),
+    _ => None
+  }
+}
+
+fn srgb_colorimetry(intent: u32) -> Colorimetry {
+  Colorimetry {
+    color_space: SRGB,
+    red: (0., 0., 0.),
+    green: (0., 0., 0.),
+    blue: (0., 0., 0.),
+    gamma: (0., 0., 0.),
+    intent: intent,
+  }
+}
+
+// iCCP holds a profile name, a null, the compression method and the deflated profile
+fn read_profile(data: &[u8]) -> Result<Colorimetry, PngError> {
+  let name_length = data.iter().take_while(|&&c| c != 0).count();
+  if name_length + 2 > data.len() {
+    return Err(Truncated);
+  }
+  if data[name_length + 1] != 0 {
+    return Err(UnsupportedMethod(data[name_length + 1], 0));
+  }
+  let profile = try!(inflate(data.slice_from(name_length + 2)));
+
+  let mut colorimetry = srgb_colorimetry(4);
+  colorimetry.color_space = EmbeddedProfile(profile);
+  Ok(colorimetry)
+}
+
+// Unfilters the scanlines of every pass and converts their samples to 8-bit channels
+fn decode_pixels(header: &Header, raw: &[u8], palette: &[u8], transparency: Option<Vec<u8>>) -> Result<Image, PngError> {
+
+  let width = header.width;
+  let height = header.height;
+  let depth = header.bit_depth;
+  let channels = header.channels();
+  let bits_per_pixel = channels * depth;
+
+  // Filters look back one whole pixel, or one byte for depths under 8
+  let filter_width = if bits_per_pixel < 8 { 1 } else { bits_per_pixel / 8 };
+
+  if header.color_type == 3 && palette.len() == 0 {
+    return Err(MissingPalette);
+  }
+
+  let color_type = match (header.color_type, transparency.is_some()) {
+    (0, false)  => GRAYSCALE8,
+    (2, false)  => RGB8,
+    (3, false)  => RGB8,
+    _           => RGBA8
+  };
+  let bytes_per_pixel = color_type as uint / 8;
+
+  // Gray and RGB images mark one color as transparent, indexed images give each palette entry an alpha
+  let key: Option<(u16, u16, u16)> = match (header.color_type, &transparency) {
+    (0, &Some(ref t)) if t.len() >= 2 => {
+      let gray = read_u16(t.as_slice(), 0);
+      Some((gray, gray, gray))
+    },
+    (2, &Some(ref t)) if t.len() >= 6 => Some((read_u16(t.as_slice(), 0), read_u16(t.as_slice(), 2), read_u16(t.as_slice(), 4))),
+    _ => None
+  };
+  let palette_alpha: Vec<u8> = match (header.color_type, transparency) {
+    (3, Some(t)) => t,
+    _ => Vec::new()
+  };
+
+  // Every scanline has to be there before the header's size is allocated
+  match header.data_length() {
+    Some(length) if length <= raw.len() => {},
+    Some(_) => return Err(MissingImageData),
+    None    => return Err(InvalidDimensions(width as u32, height as u32))
+  }
+
+  let mut image = Image::new(width, height, color_type);
+  let mut position = 0;
+
+  for &(x0, y0, dx, dy) in header.passes().iter() {
+
+    // Small images leave some passes empty
+    if x0 >= width || y0 >= height {
+      continue;
+    }
+    let pass_width = (width - x0 + dx - 1) / dx;
+    let pass_height = (height - y0 + dy - 1) / dy;
+    let row_bytes = (pass_width * bits_per_pixel + 7) / 8;
+
+    let mut previous: Vec<u8> = Vec::from_elem(row_bytes, 0u8);
+
+    for row in range(0, pass_height) {
+
+      if position + 1 + row_bytes > raw.len() {
+        return Err(MissingImageData);
+      }
+      let filter = raw[position];
+      let mut current = Vec::from_slice(raw.slice(position + 1, position + 1 + row_bytes));
+      position += 1 + row_bytes;
+      try!(unfilter(filter, filter_width, current.as_mut_slice(), previous.as_slice()));
+
+      let y = y0 + row * dy;
+      for column in range(0, pass_width) {
+
+        let x = x0 + column * dx;
+        let samples = current.as_slice();
+
+        let (raw_color, color) = match header.color_type {
+          0 => {
+            let gray = read_sample(samples, column, depth);
+            let value = scale_sample(gray, depth);
+            ((gray, gray, gray), (value, value, value, 255))
+          },
+          2 => {
+            let red = read_sample(samples, column * 3, depth);
+            let green = read_sample(samples, column * 3 + 1, depth);
+            let blue = read_sample(samples, column * 3 + 2, depth);
+            ((red, green, blue), (scale_sample(red, depth), scale_sample(green, depth), scale_sample(blue, depth), 255))
+          },
+          3 => {
+            let index = read_sample(samples, column, depth);
+            let entry = index as uint * 3;
+            if entry + 3 > palette.len() {
+              return Err(BadPaletteIndex(index));
+            }
+            let alpha = if (index as uint) < palette_alpha.len() { *palette_alpha.get(index as uint) } else { 255 };
+            ((index, index, index), (palette[entry], palette[entry + 1], palette[entry + 2], alpha))
+          },
+          4 => {
+            let gray = scale_sample(read_sample(samples, column * 2, depth), depth);
+            let alpha = scale_sample(read_sample(samples, column * 2 + 1, depth), depth);
+            ((0, 0, 0), (gray, gray, gray, alpha))
+          },
+          _ => {
+            let red = scale_sample(read_sample(samples, column * 4, depth), depth);
+            let green = scale_sample(read_sample(samples, column * 4 + 1, depth), depth);
+            let blue = scale_sample(read_sample(samples, column * 4 + 2, depth), depth);
+            let alpha = scale_sample(read_sample(samples, column * 4 + 3, depth), depth);
+            ((0, 0, 0), (red, green, blue, alpha))
+          }
+        };
+
+        let (red, green, blue, mut alpha) = color;
+        if key == Some(raw_color) {
+          alpha = 0;
+        }
+
+        let offset = (x + width * y) * bytes_per_pixel;
+        match color_type {
+          GRAYSCALE8 => {
+            *image.data.get_mut(offset) = red;
+          },
+          RGB8 => {
+            *image.data.get_mut(offset) = red;
+            *image.data.get_mut(offset + 1) = green;
+            *image.data.get_mut(offset + 2) = blue;
+          },
+          RGBA8 => {
+            *image.data.get_mut(offset) = red;
+            *image.data.get_mut(offset + 1) = green;
+            *image.data.get_mut(offset + 2) = blue;
+            *image.data.get_mut(offset + 3) = alpha;
+          }
+        }
+      }
+
+      previous = current;
+    }
+  }
+
+  Ok(image)
+}
+
+fn read_u16(bytes: &[u8], offset: uint) -> u16 {
+  ((bytes[offset] as u16) << 8) | bytes[offset + 1] as u16
+}
+
+// Sample number index of a scanline, at any bit depth
+fn read_sample(row: &[u8], index: uint, depth: uint) -> u16 {
+  match depth {
+    16 => read_u16(row, index * 2),
+    8  => row[index] as u16,
+    _  => {
+      let bit = index * depth;
+      let mask = (1u8 << depth) - 1;
+      ((row[bit / 8] >> (8 - depth - bit % 8)) & mask) as u16
+    }
+  }
+}
+
+// Stretches a sample to 8 bits, so a 1-bit white is 255 and not 1
+fn scale_sample(value: u16, depth: uint) -> u8 {
+  match depth {
+    16 => (value >> 8) as u8,
+    8  => value as u8,
+    _  => (value as uint * 255 / ((1u << depth) - 1)) as u8
+  }
+}
+
+// The byte a filter predicts from the bytes to the left, above, and above left
+fn predict(filter: u8, left: u8, up: u8, upper_left: u8) -> u8 {
+  match filter {
+    1 => left,
+    2 => up,
+    3 => ((left as uint + up as uint) / 2) as u8,
+    4 => paeth(left, up, upper_left),
+    _ => 0
+  }
+}
+
+// Whichever neighbour is closest to left + up - upper_left, preferring left then up on ties
+fn paeth(left: u8, up: u8, upper_left: u8) -> u8 {
+  let estimate = left as int + up as int - upper_left as int;
+  let distance = |value: u8| -> int {
+    let difference = estimate - value as int;
+    if difference < 0 { -difference } else { difference }
+  };
+  let (to_left, to_up, to_upper_left) = (distance(left), distance(up), distance(upper_left));
+  if to_left <= to_up && to_left <= to_upper_left {
+    left
+  }
+  else if to_up <= to_upper_left {
+    up
+  }
+  else {
+    upper_left
+  }
+}
+
+fn unfilter(filter: u8, filter_width: uint, current: &mut [u8], previous: &[u8]) -> Result<(), PngError> {
+  if filter > 4 {
+    return Err(BadFilter(filter));
+  }
+  if filter == 0 {
+    return Ok(());
+  }
+  for i in range(0, current.len()) {
+    let left = if i >= filter_width { current[i - filter_width] } else { 0 };
+    let upper_left = if i >= filter_width { previous[i - filter_width] } else { 0 };
+    current[i] = current[i] + predict(filter, left, previous[i], upper_left);
+  }
+  Ok(())
+}
+
+
+// Scanline filter used when writing
+#[deriving(Show, PartialEq, Clone)]
+pub enum PngFilter {
+  NoFilter = 0,
+  SubFilter = 1,        // Difference from the pixel to the left
+  UpFilter = 2,         // Difference from the pixel above
+  AverageFilter = 3,    // Difference from the average of left and above
+  PaethFilter = 4,      // Difference from whichever of left, above and above left is the best guess
+  AdaptiveFilter,       // Whichever of the others gives the smallest differences, chosen per scanline
+}
+
+// Settings for how an image is compressed when it's written
+#[deriving(Show, Clone)]
+pub struct PngOptions {
+  pub level: u8,          // zlib level, 0 stores the pixel data uncompressed and 9 compresses hardest
+  pub filter: PngFilter,
+}
+
+impl PngOptions {
+
+  // The usual zlib default, with a filter picked for each scanline
+  pub fn new() -> PngOptions {
+    PngOptions{level: 6, filter: AdaptiveFilter}
+  }
+
+}
+
+#[allow(dead_code)]
+pub fn write_png(image: Image, filename: &str) -> Result<(), PngError> {
+  write_png_with(image, filename, &PngOptions::new())
+}
+
+#[allow(dead_code)]
+pub fn write_png_with(image: Image, filename: &str, options: &PngOptions) -> Result<(), PngError> {
+  let path = Path::new(filename);
+  let mut file = try!(io(File::create(&path)));
+  encode_png_with(&image, &mut file, options)
+}
+
+// Encodes a PNG image into a new in-memory buffer
+#[allow(dead_code)]
+pub fn to_bytes(image: &Image) -> Result<Vec<u8>, PngError> {
+  to_bytes_with(image, &PngOptions::new())
+}
+
+#[allow(dead_code)]
+pub fn to_bytes_with(image: &Image, options: &PngOptions) -> Result<Vec<u8>, PngError> {
+  let mut writer = MemWriter::new();
+  try!(encode_png_with(image, &mut writer, options));
+  Ok(writer.unwrap())
+}
+
+// Encodes a PNG image to any writer
+#[allow(dead_code)]
+pub fn encode_png<W: Writer>(image: &Image, file: &mut W) -> Result<(), PngError> {
+  encode_png_with(image, file, &PngOptions::new())
+}
+
+#[allow(dead_code)]
+pub fn encode_png_with<W: Writer>(image: &Image, file: &mut W, options: &PngOptions) -> Result<(), PngError> {
+
+  if image.width == 0 || image.height == 0 || image.width > 0x7FFFFFFF || image.height > 0x7FFFFFFF {
+    return Err(InvalidDimensions(image.width as u32, image.height as u32));
+  }
+
+  let (color_type, channels) = match image.color_type {
+    GRAYSCALE8  => (0u8, 1u),
+    RGB8        => (2u8, 3u),
+    RGBA8       => (6u8, 4u)
+  };
+
+  try!(io(file.write(SIGNATURE.as_slice())));
+
+  let mut header = MemWriter::new();
+  try!(io(header.write_be_u32(image.width as u32)));
+  try!(io(header.write_be_u32(image.height as u32)));
+  try!(io(header.write_u8(8)));             // Bit depth
+  try!(io(header.write_u8(color_type)));
+  try!(io(header.write_u8(0)));             // Compression method, always deflate
+  try!(io(header.write_u8(0)));             // Filter method, always adaptive filtering
+  try!(io(header.write_u8(0)));             // Not interlaced
+  try!(write_chunk(file, "IHDR", header.unwrap().as_slice()));
+
+  // Only color spaces PNG can describe are kept
+  match image.colorimetry {
+    Some(ref colorimetry) => {
+      match colorimetry.color_space {
+        SRGB => {
+          let intent = SRGB_INTENTS.iter().position(|&intent| intent == colorimetry.intent).unwrap_or(0);
+          try!(write_chunk(file, "sRGB", &[intent as u8]));
+        },
+        EmbeddedProfile(ref profile) => {
+          let mut data: Vec<u8> = Vec::from_slice("ICC profile".as_bytes());
+          data.push(0);   // End of the name
+          data.push(0);   // Deflate
+          data.push_all(zlib::deflate(profile.as_slice(), options.level).as_slice());
+          try!(write_chunk(file, "iCCP", data.as_slice()));
+        },
+        _ => {}
+      }
+    },
+    None => {}
+  }
+
+  match image.resolution {
+    Some(ref resolution) => {
+      let mut data = MemWriter::new();
+      try!(io(data.write_be_u32(resolution.horizontal.round() as u32)));
+      try!(io(data.write_be_u32(resolution.vertical.round() as u32)));
+      try!(io(data.write_u8(1)));   // Meters
+      try!(write_chunk(file, "pHYs", data.unwrap().as_slice()));
+    },
+    None => {}
+  }
+
+  // Filter every scanline against the one above it, the first against zeros
+  let stride = image.width * channels;
+  let zeros: Vec<u8> = Vec::from_elem(stride, 0u8);
+  let mut filtered: Vec<u8> = Vec::with_capacity((stride + 1) * image.height);
+
+  for y in range(0, image.height) {
+    let current = image.data.slice(y * stride, (y + 1) * stride);
+    let previous = if y == 0 { zeros.as_slice() } else { image.data.slice((y - 1) * stride, y * stride) };
+
+    let filter = match options.filter {
+      AdaptiveFilter  => choose_filter(current, previous, channels),
+      filter          => filter as u8
+    };
+    filtered.push(filter);
+    filter_row(filter, channels, current, previous, &mut filtered);
+  }
+
+  let compressed = zlib::deflate(filtered.as_slice(), options.level);
+  for data in compressed.as_slice().chunks(IDAT_SIZE) {
+    try!(write_chunk(file, "IDAT", data));
+  }
+
+  write_chunk(file, "IEND", &[])
+}
+
+fn write_chunk<W: Writer>(file: &mut W, name: &str, data: &[u8]) -> Result<(), PngError> {
+  try!(io(file.write_be_u32(data.len() as u32)));
+  try!(io(file.write(name.as_bytes())));
+  try!(io(file.write(data)));
+  try!(io(file.write_be_u32(zlib::update_crc32(zlib::crc32(name.as_bytes()), data))));
+  Ok(())
+}
+
+// Appends a scanline with every byte replaced by its difference from the prediction
+fn filter_row(filter: u8, filter_width: uint, current: &[u8], previous: &[u8], output: &mut Vec<u8>) {
+  for i in range(0, current.len()) {
+    let left = if i >= filter_width { current[i - filter_width] } else { 0 };
+    let upper_left = if i >= filter_width { previous[i - filter_width] } else { 0 };
+    output.push(current[i] - predict(filter, left, previous[i], upper_left));
+  }
+}
+
+// Picks the filter whose output has the smallest sum of differences, read as
+// signed bytes, which tends to be the one that compresses best
+fn choose_filter(current: &[u8], previous: &[u8], filter_width: uint) -> u8 {
+  let mut best_filter = 0;
+  let mut best_sum = -1i;
+  let mut output: Vec<u8> = Vec::with_capacity(current.len());
+
+  for filter in range(0u8, 5) {
+    output.clear();
+    filter_row(filter, filter_width, current, previous, &mut output);
+    let sum = output.iter().fold(0i, |sum, &byte| sum + if byte < 128 { byte as int } else { 256 - byte as int });
+    if best_sum < 0 || sum < best_sum {
+      best_filter = filter;
+      best_sum = sum;
+    }
+  }
+  best_filter
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::*;
+  use zlib;
+  use std::io::{MemWriter};
+  use std::iter::range_step;
+
+  // Builds a PNG around raw scanlines, which already include their filter bytes
+  fn png_bytes(width: u32, height: u32, bit_depth: u8, color_type: u8, interlace: u8, chunks: &[(&str, Vec<u8>)], raw: &[u8]) -> Vec<u8> {
+    let mut file = MemWriter::new();
+    file.write(&[0x89u8, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A]).unwrap();
+
+    let mut header = MemWriter::new();
+    header.write_be_u32(width).unwrap();
+    header.write_be_u32(height).unwrap();
+    header.write(&[bit_depth, color_type, 0, 0, interlace]).unwrap();
+    super::write_chunk(&mut file, "IHDR", header.unwrap().as_slice()).unwrap();
+
+    for &(name, ref data) in chunks.iter() {
+      super::write_chunk(&mut file, name, data.as_slice()).unwrap();
+    }
+    super::write_chunk(&mut file, "IDAT", zlib::deflate(raw, 6).as_slice()).unwrap();
+    super::write_chunk(&mut file, "IEND", &[]).unwrap();
+    file.unwrap()
+  }
+
+  fn pattern_image(width: uint, height: uint, color_type: ColorType) -> Image {
+    let mut image = Image::new(width, height, color_type);
+    for (i, byte) in image.data.mut_iter().enumerate() {
+      *byte = ((i * 7) ^ (i / 5)) as u8;
+    }
+    image
+  }
+
+  #[test]
+  fn test_round_trip_every_filter() {
+    let filters = [NoFilter, SubFilter, UpFilter, AverageFilter, PaethFilter, AdaptiveFilter];
+    for &color_type in [GRAYSCALE8, RGB8, RGBA8].iter() {
+      let image = pattern_image(13, 7, color_type);
+      for filter in filters.iter() {
+        for &level in [0u8, 9].iter() {
+          let options = PngOptions{level: level, filter: filter.clone()};
+          let bytes = to_bytes_with(&image, &options).unwrap();
+          let decoded = from_bytes(bytes.as_slice()).unwrap();
+          assert_eq!(decoded.width, 13);
+          assert_eq!(decoded.height, 7);
+          assert_eq!(decoded.color_type as uint, color_type as uint);
+          assert_eq!(decoded.data, image.data);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn test_low_bit_depths() {
+    // 1-bit and 4-bit gray, scaled up to the full 8-bit range
+    let decoded = from_bytes(png_bytes(10, 1, 1, 0, 0, &[], &[0u8, 0b10110000, 0b01000000]).as_slice()).unwrap();
+    assert_eq!(decoded.data, vec!(255, 0, 255, 255, 0, 0, 0, 0, 0, 255));
+
+    let decoded = from_bytes(png_bytes(3, 1, 4, 0, 0, &[], &[0u8, 0x0F, 0x50]).as_slice()).unwrap();
+    assert_eq!(decoded.data, vec!(0, 255, 85));
+
+    let decoded = from_bytes(png_bytes(4, 1, 2, 0, 0, &[], &[0u8, 0b00011011]).as_slice()).unwrap();
+    assert_eq!(decoded.data, vec!(0, 85, 170, 255));
+  }
+
+  #[test]
+  fn test_sixteen_bit() {
+    let raw = [0u8, 0x12, 0x34, 0xAB, 0xCD, 0xFF, 0xFF, 0x00, 0x01, 0x80, 0x00, 0x7F, 0xFF];
+    let decoded = from_bytes(png_bytes(2, 1, 16, 2, 0, &[], &raw).as_slice()).unwrap();
+    assert_eq!(decoded.color_type as uint, RGB8 as uint);
+    assert_eq!(decoded.data, vec!(0x12, 0xAB, 0xFF, 0x00, 0x80, 0x7F));
+
+    let raw = [0u8, 0xFF, 0x00, 0x80, 0x00];
+    let decoded = from_bytes(png_bytes(1, 1, 16, 4, 0, &[], &raw).as_slice()).unwrap();
+    assert_eq!(decoded.data, vec!(0xFF, 0xFF, 0xFF, 0x80));
+  }
+
+  #[test]
+  fn test_palette_with_transparency() {
+    let palette = vec!(255u8, 0, 0, 0, 255, 0, 0, 0, 255);
+    let alpha = vec!(0u8, 128);
+    let chunks = [("PLTE", palette), ("tRNS", alpha)];
+    let decoded = from_bytes(png_bytes(3, 1, 2, 3, 0, &chunks, &[0u8, 0b00011000]).as_slice()).unwrap();
+    assert_eq!(decoded.color_type as uint, RGBA8 as uint);
+    assert_eq!(decoded.data, vec!(255, 0, 0, 0, 0, 255, 0, 128, 0, 0, 255, 255));
+
+    match from_bytes(png_bytes(1, 1, 8, 3, 0, &chunks, &[0u8, 5]).as_slice()) {
+      Err(BadPaletteIndex(5)) => {},
+      Err(e)  => fail!("Expected a bad palette index, got {}", e),
+      Ok(_)   => fail!("Decoded a pixel past the end of the palette")
+    }
+  }
+
+  #[test]
+  fn test_color_key_transparency() {
+    let chunks = [("tRNS", vec!(0u8, 7))];
+    let decoded = from_bytes(png_bytes(2, 1, 8, 0, 0, &chunks, &[0u8, 7, 8]).as_slice()).unwrap();
+    assert_eq!(decoded.data, vec!(7, 7, 7, 0, 8, 8, 8, 255));
+  }
+
+  #[test]
+  fn test_adam7_interlacing() {
+    let image = pattern_image(11, 9, RGB8);
+
+    // Every pass as its own unfiltered image
+    let mut raw: Vec<u8> = Vec::new();
+    for &(x0, y0, dx, dy) in super::ADAM7.iter() {
+      if x0 >= 11 || y0 >= 9 {
+        continue;
+      }
+      for y in range_step(y0, 9, dy) {
+        raw.push(0);
+        for x in range_step(x0, 11, dx) {
+          raw.push_all(image.get_pixel(x, y).as_slice());
+        }
+      }
+    }
+
+    let decoded = from_bytes(png_bytes(11, 9, 8, 2, 1, &[], raw.as_slice()).as_slice()).unwrap();
+    assert_eq!(decoded.data, image.data);
+  }
+
+  #[test]
+  fn test_metadata_round_trip() {
+    let mut image = pattern_image(4, 4, RGB8);
+    image.resolution = Some(Resolution{horizontal: 2835., vertical: 5670.});
+    image.colorimetry = Some(Colorimetry {
+      color_space: EmbeddedProfile(vec!(1, 2, 3, 4, 5)),
+      red: (0., 0., 0.),
+      green: (0., 0., 0.),
+      blue: (0., 0., 0.),
+      gamma: (0., 0., 0.),
+      intent: 4,
+    });
+
+    let decoded = from_bytes(to_bytes(&image).unwrap().as_slice()).unwrap();
+    assert_eq!(decoded.resolution, image.resolution);
+    assert_eq!(decoded.colorimetry, image.colorimetry);
+
+    image.colorimetry = Some(Colorimetry{color_space: SRGB, intent: 2, ..image.colorimetry.clone().unwrap()});
+    let decoded = from_bytes(to_bytes(&image).unwrap().as_slice()).unwrap();
+    assert_eq!(decoded.colorimetry, image.colorimetry);
+  }
+
+  #[test]
+  fn test_errors() {
+    let mut bytes = to_bytes(&pattern_image(2, 2, RGB8)).unwrap();
+    *bytes.get_mut(20) ^= 1;
+    match from_bytes(bytes.as_slice()) {
+      Err(BadChecksum(name)) => assert_eq!(name.as_slice(), "IHDR"),
+      Err(e)  => fail!("Expected a bad checksum, got {}", e),
+      Ok(_)   => fail!("Decoded a corrupt header")
+    }
+
+    let bytes = to_bytes(&pattern_image(2, 2, RGB8)).unwrap();
+    match from_bytes(bytes.slice(0, bytes.len() - 20)) {
+      Err(Truncated) => {},
+      Err(e)  => fail!("Expected a truncated file, got {}", e),
+      Ok(_)   => fail!("Decoded a truncated file")
+    }
+
+    match from_bytes("GIF89a".as_bytes()) {
+      Err(Truncated) | Err(BadSignature) => {},
+      Err(e)  => fail!("Expected a bad signature, got {}", e),
+      Ok(_)   => fail!("Decoded a file that isn't a PNG")
+    }
+
+    match from_bytes(png_bytes(1, 1, 16, 3, 0, &[], &[0u8, 0, 0]).as_slice()) {
+      Err(UnsupportedFormat(16, 3)) => {},
+      Err(e)  => fail!("Expected an unsupported format, got {}", e),
+      Ok(_)   => fail!("Decoded a 16-bit indexed image")
+    }
+
+    // Huge dimensions with one scanline of data, and scanlines too long to count
+    match from_bytes(png_bytes(0x7FFFFFFF, 0x7FFFFFFF, 8, 0, 0, &[], &[0u8, 0, 0]).as_slice()) {
+      Err(MissingImageData) => {},
+      Err(e)  => fail!("Expected missing image data, got {}", e),
+      Ok(_)   => fail!("Decoded an image far larger than its data")
+    }
+    match from_bytes(png_bytes(0x7FFFFFFF, 0x7FFFFFFF, 16, 6, 1, &[], &[0u8, 0, 0]).as_slice()) {
+      Err(InvalidDimensions(0x7FFFFFFF, 0x7FFFFFFF)) => {},
+      Err(e)  => fail!("Expected invalid dimensions, got {}", e),
+      Ok(_)   => fail!("Decoded an image too large to hold")
+    }
+
+    // A chunk claiming almost 4 GB
+    match from_bytes(b"\x89PNG\r\n\x1A\n\xFF\xFF\xFF\xF0IHDR\x00\x00\x00\x01") {
+      Err(Truncated) => {},
+      Err(e)  => fail!("Expected a truncated file, got {}", e),
+      Ok(_)   => fail!("Decoded a chunk far longer than the file")
+    }
+  }
+}
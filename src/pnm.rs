@@ -0,0 +1,650 @@
+// Netpbm Image formats: PBM, PGM, PPM and PAM
+
+use std::path::posix::{Path};
+use std::io::{File, IoResult, IoError, BufReader, MemWriter};
+use image::*;
+
+// Longest line plain (ASCII) files should have
+static PLAIN_LINE_LENGTH: uint = 70;
+
+
+/* NOTES:
+ * Every format starts with P and a digit: P1 to P3 are plain (ASCII) PBM, PGM and PPM, P4 to P6 the same with raw
+ *   samples, and P7 is PAM
+ * PBM, PGM and PPM headers are whitespace separated numbers (width, height, then maxval except for PBM), and
+ *   a # starts a comment running to the end of the line
+ * Raw samples start after exactly one whitespace byte following the header
+ * Samples go from 0 to the maxval, which is at most 65535. Above 255 raw samples take two bytes, big endian
+ * Images only hold 8 bits per channel, so samples are scaled from the maxval to 255
+ * PBM bits are 1 for black, raw rows are packed most significant bit first and padded to a byte.
+ *   Plain PBM digits don't need whitespace between them
+ * PAM headers are lines of a keyword and a value ending with ENDHDR, the depth is the number of channels:
+ *   1 is gray, 2 gray and alpha, 3 RGB and 4 RGBA. Gray and alpha decodes as RGBA8
+ * Only the first image of a file holding several is read
+ */
+
+
+// Everything that can go wrong while reading or writing a Netpbm image
+#[deriving(Show)]
+pub enum PnmError {
+  BadSignature,                   // File doesn't start with P1 to P7
+  Truncated,                      // File ended in the header or before every sample
+  BadNumber(String),              // Header field or plain sample that isn't a number
+  BadHeader(String),              // PAM header keyword that isn't known, or a required one that's missing
+  InvalidDimensions(uint, uint),  // Width or height of zero
+  BadMaxval(u32),                 // Maxval of zero or above 65535
+  UnsupportedDepth(uint),         // PAM depth other than 1 to 4
+  SampleOutOfRange(u32),          // Sample above the maxval
+  IoFailure(IoError),             // Any other error from the underlying file
+}
+
+// Wraps an I/O error so it can be returned with try!
+fn io<T>(result: IoResult<T>) -> Result<T, PnmError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => Err(IoFailure(e))
+  }
+}
+
+fn is_space(byte: u8) -> bool {
+  byte == b' ' || byte == b'\t' || byte == b'\n' || byte == b'\r' || byte == 0x0B || byte == 0x0C
+}
+
+fn parse_number(token: &[u8]) -> Result<u32, PnmError> {
+  let mut value = 0u64;
+  for &digit in token.iter() {
+    if digit < b'0' || digit > b'9' || value > 0xFFFFFFFF {
+      return Err(BadNumber(token.iter().map(|&c| c as char).collect()));
+    }
+    value = value * 10 + (digit - b'0') as u64;
+  }
+  if token.len() == 0 || value > 0xFFFFFFFF {
+    return Err(BadNumber(token.iter().map(|&c| c as char).collect()));
+  }
+  Ok(value as u32)
+}
+
+// Reads the whitespace separated parts of a header or plain raster
+struct Parser<'a> {
+  data: &'a [u8],
+  position: uint,
+}
+
+impl<'a> Parser<'a> {
+
+  // Skips whitespace and comments
+  fn skip_space(&mut self) {
+    while self.position < self.data.len() {
+      let byte = self.data[self.position];
+      if byte == b'#' {
+        while self.position < self.data.len() && self.data[self.position] != b'\n' {
+          self.position += 1;
+        }
+      }
+      else if !is_space(byte) {
+        return;
+      }
+      else {
+        self.position += 1;
+      }
+    }
+  }
+
+  fn token(&mut self) -> Result<&'a [u8], PnmError> {
+    self.skip_space();
+    let start = self.position;
+    while self.position < self.data.len() && !is_space(self.data[self.position]) && self.data[self.position] != b'#' {
+      self.position += 1;
+    }
+    if start == self.position {
+      return Err(Truncated);
+    }
+    Ok(self.data.slice(start, self.position))
+  }
+
+  fn number(&mut self) -> Result<u32, PnmError> {
+    let token = try!(self.token());
+    parse_number(token)
+  }
+
+  // Next line without its newline, None at the end of the data
+  fn line(&mut self) -> Option<&'a [u8]> {
+    if self.position >= self.data.len() {
+      return None;
+    }
+    let start = self.position;
+    while self.position < self.data.len() && self.data[self.position] != b'\n' {
+      self.position += 1;
+    }
+    let line = self.data.slice(start, self.position);
+    self.position += 1;
+    Some(line)
+  }
+
+}
+
+
+#[allow(dead_code)]
+pub fn read_pnm(image_path_str: &str) -> Result<Image, PnmError> {
+  let path = Path::new(image_path_str);
+  let mut file = try!(io(File::open(&path)));
+  decode_pnm(&mut file)
+}
+
+// Decodes a Netpbm image held in memory
+#[allow(dead_code)]
+pub fn from_bytes(bytes: &[u8]) -> Result<Image, PnmError> {
+  let mut reader = BufReader::new(bytes);
+  decode_pnm(&mut reader)
+}
+
+// Decodes any Netpbm image from any reader: PBM and PGM as GRAYSCALE8, PPM as
+// RGB8, and PAM as whichever fits its depth
+#[allow(dead_code)]
+pub fn decode_pnm<R: Reader>(file: &mut R) -> Result<Image, PnmError> {
+
+  let data = try!(io(file.read_to_end()));
+  if data.len() < 2 || *data.get(0) != b'P' || *data.get(1) < b'1' || *data.get(1) > b'7' {
+    return Err(BadSignature);
+  }
+  let kind = *data.get(1);
+  let mut parser = Parser{data: data.as_slice(), position: 2};
+
+  let (width, height, depth, maxval) = if kind == b'7' {
+    try!(read_pam_header(&mut parser))
+  }
+  else {
+    let width = try!(parser.number()) as uint;
+    let height = try!(parser.number()) as uint;
+    let maxval = if kind == b'1' || kind == b'4' { 1 } else { try!(parser.number()) };
+    (width, height, if kind == b'3' || kind == b'6' { 3 } else { 1 }, maxval)
+  };
+
+  if width == 0 || height == 0 {
+    return Err(InvalidDimensions(width, height));
+  }
+  if maxval == 0 || maxval > 65535 {
+    return Err(BadMaxval(maxval));
+  }
+  if depth == 0 || depth > 4 {
+    return Err(UnsupportedDepth(depth));
+  }
+
+  // The single whitespace byte before raw samples, PAM headers end at a newline
+  if kind == b'4' || kind == b'5' || kind == b'6' {
+    parser.position += 1;
+  }
+
+  // Sizes from the header are checked against the data before anything is
+  // allocated. Every sample takes at least a byte, except in raw PBM
+  let remaining = if parser.position < parser.data.len() { parser.data.len() - parser.position } else { 0 };
+  let bytes = if maxval > 255 && kind != b'2' && kind != b'3' { 2 } else { 1 };
+  let row_bytes = (width + 7) / 8;
+  let enough = if kind == b'4' { fits(remaining, &[row_bytes, height]) } else { fits(remaining, &[width, height, depth, bytes]) };
+  if !enough {
+    return Err(Truncated);
+  }
+
+  let count = width * height * depth;
+  let mut samples: Vec<u8> = Vec::with_capacity(count);
+
+  match kind {
+    b'1' => {
+      for _ in range(0, count) {
+        parser.skip_space();
+        if parser.position >= parser.data.len() {
+          return Err(Truncated);
+        }
+        match parser.data[parser.position] {
+          b'0' => samples.push(255),
+          b'1' => samples.push(0),
+          other => return Err(BadNumber(String::from_char(1, other as char)))
+        }
+        parser.position += 1;
+      }
+    },
+
+    b'4' => {
+      for y in range(0, height) {
+        let row = parser.data.slice_from(parser.position + y * row_bytes);
+        for x in range(0, width) {
+          let bit = (row[x / 8] >> (7 - x % 8)) & 1;
+          samples.push(if bit == 1 { 0 } else { 255 });
+        }
+      }
+    },
+
+    b'2' | b'3' => {
+      for _ in range(0, count) {
+        let sample = try!(parser.number());
+        samples.push(try!(scale_sample(sample, maxval)));
+      }
+    },
+
+    _ => {
+      let raster = parser.data.slice_from(parser.position);
+      for i in range(0, count) {
+        let sample = if bytes == 2 {
+          ((raster[i * 2] as u32) << 8) | raster[i * 2 + 1] as u32
+        }
+        else {
+          raster[i] as u32
+        };
+        samples.push(try!(scale_sample(sample, maxval)));
+      }
+    }
+  }
+
+  let (color_type, data) = match depth {
+    1 => (GRAYSCALE8, samples),
+    3 => (RGB8, samples),
+    4 => (RGBA8, samples),
+    _ => {
+      let mut data: Vec<u8> = Vec::with_capacity(width * height * 4);
+      for pixel in samples.as_slice().chunks(2) {
+        data.push_all(&[pixel[0], pixel[0], pixel[0], pixel[1]]);
+      }
+      (RGBA8, data)
+    }
+  };
+
+  Ok(Image{width: width, height: height, color_type: color_type, data: data, colorimetry: None, resolution: None})
+}
+
+// Whether the remaining bytes can hold the product of the sizes, which is
+// divided out rather than multiplied since the sizes can overflow
+fn fits(remaining: uint, sizes: &[uint]) -> bool {
+  let mut left = remaining;
+  for &size in sizes.iter() {
+    left = left / size;
+  }
+  left >= 1
+}
+
+// Width, height, depth and maxval from the lines of a PAM header
+fn read_pam_header(parser: &mut Parser) -> Result<(uint, uint, uint, u32), PnmError> {
+
+  let mut fields: [Option<u32>, ..4] = [None, None, None, None];
+  let names = ["WIDTH", "HEIGHT", "DEPTH", "MAXVAL"];
+
+  loop {
+    let line = match parser.line() {
+      Some(line) => line,
+      None => return Err(Truncated)
+    };
+    let words: Vec<&[u8]> = line.split(|&c| is_space(c)).filter(|word| word.len() > 0).collect();
+    if words.len() == 0 || (*words.get(0))[0] == b'#' {
+      continue;
+    }
+
+    let keyword = *words.get(0);
+    if keyword == "ENDHDR".as_bytes() {
+      break;
+    }
+    // Tuple types only name what the depth already says
+    if keyword == "TUPLTYPE".as_bytes() {
+      continue;
+    }
+    match names.iter().position(|name| name.as_bytes() == keyword) {
+      Some(field) => {
+        if words.len() < 2 {
+          return Err(Truncated);
+        }
+        fields[field] = Some(try!(parse_number(*words.get(1))));
+      },
+      None => return Err(BadHeader(keyword.iter().map(|&c| c as char).collect()))
+    }
+  }
+
+  let mut values = [0u32, ..4];
+  for i in range(0u, 4) {
+    values[i] = match fields[i] {
+      Some(value) => value,
+      None => return Err(BadHeader(String::from_str(names[i])))
+    };
+  }
+  Ok((values[0] as uint, values[1] as uint, values[2] as uint, values[3]))
+}
+
+// Scales a sample from 0 to maxval to 0 to 255, rounding to the nearest
+fn scale_sample(sample: u32, maxval: u32) -> Result<u8, PnmError> {
+  if sample > maxval {
+    return Err(SampleOutOfRange(sample));
+  }
+  Ok(((sample * 255 + maxval / 2) / maxval) as u8)
+}
+
+
+// Netpbm format to write
+#[deriving(Show, PartialEq, Clone)]
+pub enum PnmFormat {
+  Bitmap,           // PBM, black where the gray level is under 128
+  Graymap,          // PGM
+  Pixmap,           // PPM
+  ArbitraryMap,     // PAM, with the image's own channels, always raw
+  MatchingFormat,   // PGM for GRAYSCALE8, PPM for RGB8 and PAM for RGBA8, so nothing is lost
+}
+
+// Settings for how an image is written
+#[deriving(Show, Clone)]
+pub struct PnmOptions {
+  pub format: PnmFormat,
+  pub ascii: bool,        // Plain format (P1 to P3) instead of raw samples, ignored for PAM
+  pub maxval: u16,        // Samples are scaled from 255 to this, above 255 they take two bytes. Ignored for PBM
+}
+
+impl PnmOptions {
+
+  // Raw 8-bit samples in whichever format keeps every channel
+  pub fn new() -> PnmOptions {
+    PnmOptions{format: MatchingFormat, ascii: false, maxval: 255}
+  }
+
+}
+
+#[allow(dead_code)]
+pub fn write_pnm(image: Image, filename: &str) -> Result<(), PnmError> {
+  write_pnm_with(image, filename, &PnmOptions::new())
+}
+
+#[allow(dead_code)]
+pub fn write_pnm_with(image: Image, filename: &str, options: &PnmOptions) -> Result<(), PnmError> {
+  let path = Path::new(filename);
+  let mut file = try!(io(File::create(&path)));
+  encode_pnm_with(&image, &mut file, options)
+}
+
+// Encodes a Netpbm image into a new in-memory buffer
+#[allow(dead_code)]
+pub fn to_bytes(image: &Image) -> Result<Vec<u8>, PnmError> {
+  to_bytes_with(image, &PnmOptions::new())
+}
+
+#[allow(dead_code)]
+pub fn to_bytes_with(image: &Image, options: &PnmOptions) -> Result<Vec<u8>, PnmError> {
+  let mut writer = MemWriter::new();
+  try!(encode_pnm_with(image, &mut writer, options));
+  Ok(writer.unwrap())
+}
+
+// Encodes a Netpbm image to any writer
+#[allow(dead_code)]
+pub fn encode_pnm<W: Writer>(image: &Image, file: &mut W) -> Result<(), PnmError> {
+  encode_pnm_with(image, file, &PnmOptions::new())
+}
+
+// Encodes a Netpbm image, converting it to the format's colors when they
+// differ: RGB to gray by luminance, gray to RGB by copying, and alpha dropped
+#[allow(dead_code)]
+pub fn encode_pnm_with<W: Writer>(image: &Image, file: &mut W, options: &PnmOptions) -> Result<(), PnmError> {
+
+  if image.width == 0 || image.height == 0 {
+    return Err(InvalidDimensions(image.width, image.height));
+  }
+  if options.maxval == 0 {
+    return Err(BadMaxval(0));
+  }
+
+  let format = match (options.format, image.color_type) {
+    (MatchingFormat, GRAYSCALE8)  => Graymap,
+    (MatchingFormat, RGB8)        => Pixmap,
+    (MatchingFormat, RGBA8)       => ArbitraryMap,
+    (format, _)                   => format
+  };
+
+  match format {
+    Bitmap => {
+      let gray = converted(image, GRAYSCALE8);
+      try!(io(write!(file, "P{}\n{} {}\n", if options.ascii { 1u } else { 4u }, image.width, image.height)));
+
+      if options.ascii {
+        let mut line = String::new();
+        for row in gray.data.as_slice().chunks(image.width) {
+          for &level in row.iter() {
+            if line.len() + 2 > PLAIN_LINE_LENGTH {
+              line.push_char('\n');
+              try!(io(file.write(line.as_bytes())));
+              line = String::new();
+            }
+            line.push_str(if level < 128 { "1 " } else { "0 " });
+          }
+          line.push_char('\n');
+          try!(io(file.write(line.as_bytes())));
+          line = String::new();
+        }
+      }
+      else {
+        let row_bytes = (image.width + 7) / 8;
+        let mut packed: Vec<u8> = Vec::from_elem(row_bytes * image.height, 0u8);
+        for y in range(0, image.height) {
+          for x in range(0, image.width) {
+            if *gray.data.get(y * image.width + x) < 128 {
+              *packed.get_mut(y * row_bytes + x / 8) |= 0x80 >> (x % 8);
+            }
+          }
+        }
+        try!(io(file.write(packed.as_slice())));
+      }
+      Ok(())
+    },
+
+    Graymap | Pixmap => {
+      let (color_type, magic) = if format == Graymap { (GRAYSCALE8, 2u) } else { (RGB8, 3u) };
+      let converted = converted(image, color_type);
+      let magic = if options.ascii { magic } else { magic + 3 };
+      try!(io(write!(file, "P{}\n{} {}\n{}\n", magic, image.width, image.height, options.maxval)));
+      write_samples(file, converted.data.as_slice(), options.maxval, options.ascii)
+    },
+
+    _ => {
+      let (depth, tuple_type) = match image.color_type {
+        GRAYSCALE8  => (1u, "GRAYSCALE"),
+        RGB8        => (3u, "RGB"),
+        RGBA8       => (4u, "RGB_ALPHA")
+      };
+      try!(io(write!(file, "P7\nWIDTH {}\nHEIGHT {}\nDEPTH {}\nMAXVAL {}\nTUPLTYPE {}\nENDHDR\n",
+                     image.width, image.height, depth, options.maxval, tuple_type)));
+      write_samples(file, image.data.as_slice(), options.maxval, false)
+    }
+  }
+}
+
+// Copy of an image in another color type, through Image's own conversions
+fn converted(image: &Image, color_type: ColorType) -> Image {
+  let mut copy = Image{width: image.width, height: image.height, color_type: image.color_type, data: image.data.clone(), colorimetry: None, resolution: None};
+  if image.color_type as uint != color_type as uint {
+    match color_type {
+      GRAYSCALE8  => copy.convert_to_grayscale8(),
+      RGB8        => copy.convert_to_rgb8(),
+      RGBA8       => copy.convert_to_rgba8()
+    };
+  }
+  copy
+}
+
+// Writes 8-bit samples scaled to the maxval, as plain numbers or raw bytes
+fn write_samples<W: Writer>(file: &mut W, data: &[u8], maxval: u16, ascii: bool) -> Result<(), PnmError> {
+
+  let scale = |sample: u8| (sample as u32 * maxval as u32 + 127) / 255;
+
+  if ascii {
+    let mut line = String::new();
+    for &sample in data.iter() {
+      let number = format!("{}", scale(sample));
+      if line.len() > 0 && line.len() + 1 + number.len() > PLAIN_LINE_LENGTH {
+        line.push_char('\n');
+        try!(io(file.write(line.as_bytes())));
+        line = String::new();
+      }
+      if line.len() > 0 {
+        line.push_char(' ');
+      }
+      line.push_str(number.as_slice());
+    }
+    line.push_char('\n');
+    io(file.write(line.as_bytes()))
+  }
+  else {
+    let mut raw: Vec<u8> = Vec::with_capacity(data.len() * 2);
+    for &sample in data.iter() {
+      let value = scale(sample);
+      if maxval > 255 {
+        raw.push((value >> 8) as u8);
+      }
+      raw.push(value as u8);
+    }
+    io(file.write(raw.as_slice()))
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::*;
+
+  fn test_image(color_type: ColorType) -> Image {
+    let mut image = Image::new(5, 3, color_type);
+    for (i, sample) in image.data.mut_iter().enumerate() {
+      *sample = (i * 37 % 256) as u8;
+    }
+    image
+  }
+
+  #[test]
+  fn test_bitmap() {
+    // Plain digits can run together, and comments go anywhere in the header
+    let image = from_bytes("P1\n# A comment\n3 # width\n2\n010\n1 0 1\n".as_bytes()).unwrap();
+    assert_eq!(image.color_type as uint, GRAYSCALE8 as uint);
+    assert_eq!(image.data, vec!(255, 0, 255, 0, 255, 0));
+
+    // Raw rows are padded to a byte
+    let mut bytes: Vec<u8> = Vec::from_slice("P4\n10 2\n".as_bytes());
+    bytes.push_all(&[0b10000000, 0b01000000, 0b11111111, 0b11000000]);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.data, vec!(0, 255, 255, 255, 255, 255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0));
+  }
+
+  #[test]
+  fn test_maxval() {
+    let image = from_bytes("P2 3 1 15 0 15 7".as_bytes()).unwrap();
+    assert_eq!(image.data, vec!(0, 255, 119));
+
+    // 16-bit raw samples are big endian
+    let mut bytes: Vec<u8> = Vec::from_slice("P5 2 1 65535\n".as_bytes());
+    bytes.push_all(&[0xFF, 0xFF, 0x80, 0x00]);
+    assert_eq!(from_bytes(bytes.as_slice()).unwrap().data, vec!(255, 128));
+
+    let mut bytes: Vec<u8> = Vec::from_slice("P6 1 1 1000\n".as_bytes());
+    bytes.push_all(&[0x03, 0xE8, 0x00, 0x00, 0x01, 0xF4]);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.color_type as uint, RGB8 as uint);
+    assert_eq!(image.data, vec!(255, 0, 128));
+  }
+
+  #[test]
+  fn test_arbitrary_map() {
+    let mut bytes: Vec<u8> = Vec::from_slice("P7\nWIDTH 2\nHEIGHT 1\n# comment\nDEPTH 2\nMAXVAL 255\nTUPLTYPE GRAYSCALE_ALPHA\nENDHDR\n".as_bytes());
+    bytes.push_all(&[10, 20, 30, 40]);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.color_type as uint, RGBA8 as uint);
+    assert_eq!(image.data, vec!(10, 10, 10, 20, 30, 30, 30, 40));
+
+    match from_bytes("P7\nWIDTH 2\nHEIGHT 1\nMAXVAL 255\nENDHDR\n".as_bytes()) {
+      Err(BadHeader(name)) => assert_eq!(name.as_slice(), "DEPTH"),
+      Err(e)  => fail!("Expected a missing depth, got {}", e),
+      Ok(_)   => fail!("Decoded a PAM without a depth")
+    }
+    match from_bytes("P7\nWIDTH 1\nHEIGHT 1\nDEPTH 5\nMAXVAL 255\nENDHDR\n\x00\x00\x00\x00\x00".as_bytes()) {
+      Err(UnsupportedDepth(5)) => {},
+      Err(e)  => fail!("Expected an unsupported depth, got {}", e),
+      Ok(_)   => fail!("Decoded a PAM with 5 channels")
+    }
+  }
+
+  #[test]
+  fn test_round_trip() {
+    for &color_type in [GRAYSCALE8, RGB8, RGBA8].iter() {
+      let image = test_image(color_type);
+      for &ascii in [false, true].iter() {
+        for &maxval in [255u16, 1000, 65535].iter() {
+          let options = PnmOptions{ascii: ascii, maxval: maxval, ..PnmOptions::new()};
+          let decoded = from_bytes(to_bytes_with(&image, &options).unwrap().as_slice()).unwrap();
+          assert_eq!(decoded.color_type as uint, color_type as uint);
+          assert_eq!(decoded.data, image.data);
+        }
+      }
+    }
+
+    // RGBA8 needs PAM to keep its alpha
+    let bytes = to_bytes(&test_image(RGBA8)).unwrap();
+    assert_eq!(bytes.slice(0, 3), "P7\n".as_bytes());
+  }
+
+  #[test]
+  fn test_conversions() {
+    let image = test_image(RGB8);
+
+    let options = PnmOptions{format: Graymap, ..PnmOptions::new()};
+    let decoded = from_bytes(to_bytes_with(&image, &options).unwrap().as_slice()).unwrap();
+    assert_eq!(decoded.color_type as uint, GRAYSCALE8 as uint);
+    assert_eq!(decoded.data.len(), 15);
+
+    let gray = test_image(GRAYSCALE8);
+    for &ascii in [false, true].iter() {
+      let options = PnmOptions{format: Bitmap, ascii: ascii, ..PnmOptions::new()};
+      let decoded = from_bytes(to_bytes_with(&gray, &options).unwrap().as_slice()).unwrap();
+      let expected: Vec<u8> = gray.data.iter().map(|&level| if level < 128 { 0 } else { 255 }).collect();
+      assert_eq!(decoded.data, expected);
+    }
+
+    let options = PnmOptions{format: Pixmap, ..PnmOptions::new()};
+    let decoded = from_bytes(to_bytes_with(&gray, &options).unwrap().as_slice()).unwrap();
+    let expected: Vec<u8> = gray.data.iter().flat_map(|&level| vec!(level, level, level).move_iter()).collect();
+    assert_eq!(decoded.data, expected);
+  }
+
+  #[test]
+  fn test_errors() {
+    match from_bytes("GIF89a".as_bytes()) {
+      Err(BadSignature) => {},
+      Err(e)  => fail!("Expected a bad signature, got {}", e),
+      Ok(_)   => fail!("Decoded a file that isn't Netpbm")
+    }
+    match from_bytes("P2 1 1 0 0".as_bytes()) {
+      Err(BadMaxval(0)) => {},
+      Err(e)  => fail!("Expected a bad maxval, got {}", e),
+      Ok(_)   => fail!("Decoded a maxval of 0")
+    }
+    match from_bytes("P2 1 1 3 4".as_bytes()) {
+      Err(SampleOutOfRange(4)) => {},
+      Err(e)  => fail!("Expected a sample out of range, got {}", e),
+      Ok(_)   => fail!("Decoded a sample above the maxval")
+    }
+    match from_bytes("P2 2 x 255".as_bytes()) {
+      Err(BadNumber(token)) => assert_eq!(token.as_slice(), "x"),
+      Err(e)  => fail!("Expected a bad number, got {}", e),
+      Ok(_)   => fail!("Decoded a height that isn't a number")
+    }
+    match from_bytes("P6 2 2 255\n\x00\x00\x00".as_bytes()) {
+      Err(Truncated) => {},
+      Err(e)  => fail!("Expected a truncated file, got {}", e),
+      Ok(_)   => fail!("Decoded a file missing samples")
+    }
+    match from_bytes("P5 4294967295 4294967295 255\n".as_bytes()) {
+      Err(Truncated) => {},
+      Err(e)  => fail!("Expected a truncated file, got {}", e),
+      Ok(_)   => fail!("Decoded a file far smaller than its dimensions")
+    }
+    match from_bytes("P3 4294967295 4294967295 255 0 0 0".as_bytes()) {
+      Err(Truncated) => {},
+      Err(e)  => fail!("Expected a truncated file, got {}", e),
+      Ok(_)   => fail!("Decoded a file far smaller than its dimensions")
+    }
+    match to_bytes_with(&test_image(RGB8), &PnmOptions{maxval: 0, ..PnmOptions::new()}) {
+      Err(BadMaxval(0)) => {},
+      Err(e)  => fail!("Expected a bad maxval, got {}", e),
+      Ok(_)   => fail!("Encoded a maxval of 0")
+    }
+  }
+}
@@ -0,0 +1,408 @@
+// QOI (Quite OK Image) format
+
+use std::path::posix::{Path};
+use std::io::{File, IoResult, IoError, EndOfFile, BufReader, MemWriter};
+use image::*;
+
+static SIGNATURE: &'static str = "qoif";
+
+// Chunk tags. RGB and RGBA are whole bytes, the others are the top 2 bits
+static OP_INDEX: u8 = 0x00;
+static OP_DIFF: u8 = 0x40;
+static OP_LUMA: u8 = 0x80;
+static OP_RUN: u8 = 0xC0;
+static OP_RGB: u8 = 0xFE;
+static OP_RGBA: u8 = 0xFF;
+
+// Runs stop at 62 so their chunks never look like OP_RGB or OP_RGBA
+static MAX_RUN: uint = 62;
+
+// Decoders are expected to refuse anything larger, so the encoder does too
+static MAX_PIXELS: uint = 400000000;
+
+static END_MARKER: &'static [u8] = &[0, 0, 0, 0, 0, 0, 0, 1];
+
+
+/* NOTES:
+ * QOI is a 14 byte header ("qoif", big endian width and height, 3 or 4 channels and a color space byte),
+ *   a stream of chunks, and an end marker of seven 0 bytes and a 1
+ * Each chunk gives the next pixel from the previous one, which starts out as (0, 0, 0, 255):
+ *   a slot in an array of 64 recently seen pixels, a small difference, a difference from the green channel,
+ *   a run of the previous pixel, or the whole pixel
+ * Pixels go into the array at (r * 3 + g * 5 + b * 7 + a * 11) % 64
+ * Differences wrap around, so 255 to 0 is +1
+ * The color space byte is only informative: 0 is sRGB with linear alpha, 1 is all linear. It's written as 0
+ * Images with 3 channels can still hold OP_RGBA chunks, their alpha is dropped on decoding
+ */
+
+
+// Everything that can go wrong while reading or writing a QOI image
+#[deriving(Show)]
+pub enum QoiError {
+  BadSignature,                   // File doesn't start with "qoif"
+  Truncated,                      // File ended before all the pixels were read
+  InvalidDimensions(uint, uint),  // Width or height of zero, or more pixels than QOI allows
+  BadChannels(u8),                // Channel count other than 3 or 4
+  NotRGB,                         // Image to write isn't RGB8 or RGBA8
+  IoFailure(IoError),             // Any other error from the underlying file
+}
+
+// Wraps an I/O error so it can be returned with try!
+fn io<T>(result: IoResult<T>) -> Result<T, QoiError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => Err(IoFailure(e))
+  }
+}
+
+// Same as io(), but running out of bytes means the file was cut short
+fn chunk<T>(result: IoResult<T>) -> Result<T, QoiError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => {
+      if e.kind == EndOfFile {
+        Err(Truncated)
+      }
+      else {
+        Err(IoFailure(e))
+      }
+    }
+  }
+}
+
+// Slot of a pixel in the array of recently seen pixels
+fn hash(pixel: (u8, u8, u8, u8)) -> uint {
+  let (r, g, b, a) = pixel;
+  (r as uint * 3 + g as uint * 5 + b as uint * 7 + a as uint * 11) % 64
+}
+
+
+#[allow(dead_code)]
+pub fn read_qoi(image_path_str: &str) -> Result<Image, QoiError> {
+  let path = Path::new(image_path_str);
+  let mut file = try!(io(File::open(&path)));
+  decode_qoi(&mut file)
+}
+
+// Decodes a QOI image held in memory
+#[allow(dead_code)]
+pub fn from_bytes(bytes: &[u8]) -> Result<Image, QoiError> {
+  let mut reader = BufReader::new(bytes);
+  decode_qoi(&mut reader)
+}
+
+// Decodes a QOI image from any reader, as RGB8 or RGBA8 depending on its
+// channel count
+#[allow(dead_code)]
+pub fn decode_qoi<R: Reader>(file: &mut R) -> Result<Image, QoiError> {
+
+  let signature = try!(chunk(file.read_exact(4)));
+  if signature.as_slice() != SIGNATURE.as_bytes() {
+    return Err(BadSignature);
+  }
+  let width = try!(chunk(file.read_be_u32())) as uint;
+  let height = try!(chunk(file.read_be_u32())) as uint;
+  let channels = try!(chunk(file.read_u8()));
+  try!(chunk(file.read_u8()));  // Color space
+
+  if width == 0 || height == 0 || width * height > MAX_PIXELS {
+    return Err(InvalidDimensions(width, height));
+  }
+  if channels != 3 && channels != 4 {
+    return Err(BadChannels(channels));
+  }
+  let channels = channels as uint;
+
+  let chunks = try!(io(file.read_to_end()));
+  let chunks = chunks.as_slice();
+  let mut position = 0u;
+
+  // No chunk gives more than a run's 62 pixels per byte, so a header claiming
+  // more than that is cut short, and isn't trusted for allocating
+  if width * height > chunks.len() * MAX_RUN {
+    return Err(Truncated);
+  }
+
+  let mut seen = [(0u8, 0u8, 0u8, 0u8), ..64];
+  let mut pixel = (0u8, 0u8, 0u8, 255u8);
+  let mut run = 0u;
+
+  let mut data: Vec<u8> = Vec::with_capacity(width * height * channels);
+  for _ in range(0, width * height) {
+    if run > 0 {
+      run -= 1;
+    }
+    else {
+      if position >= chunks.len() {
+        return Err(Truncated);
+      }
+      let op = chunks[position];
+      position += 1;
+
+      let (r, g, b, a) = pixel;
+      if op == OP_RGB || op == OP_RGBA {
+        let length = if op == OP_RGB { 3 } else { 4 };
+        if position + length > chunks.len() {
+          return Err(Truncated);
+        }
+        let bytes = chunks.slice(position, position + length);
+        pixel = (bytes[0], bytes[1], bytes[2], if op == OP_RGBA { bytes[3] } else { a });
+        position += length;
+      }
+      else if op & 0xC0 == OP_INDEX {
+        pixel = seen[op as uint];
+      }
+      else if op & 0xC0 == OP_DIFF {
+        pixel = (r + ((op >> 4) & 3) - 2, g + ((op >> 2) & 3) - 2, b + (op & 3) - 2, a);
+      }
+      else if op & 0xC0 == OP_LUMA {
+        if position >= chunks.len() {
+          return Err(Truncated);
+        }
+        let differences = chunks[position];
+        position += 1;
+        let dg = (op & 0x3F) - 32;
+        pixel = (r + dg + (differences >> 4) - 8, g + dg, b + dg + (differences & 0x0F) - 8, a);
+      }
+      else {
+        // This pixel is the first of the run
+        run = (op & 0x3F) as uint;
+      }
+      seen[hash(pixel)] = pixel;
+    }
+
+    let (r, g, b, a) = pixel;
+    if channels == 4 {
+      data.push_all(&[r, g, b, a]);
+    }
+    else {
+      data.push_all(&[r, g, b]);
+    }
+  }
+
+  let color_type = if channels == 4 { RGBA8 } else { RGB8 };
+  Ok(Image{width: width, height: height, color_type: color_type, data: data, colorimetry: None, resolution: None})
+}
+
+
+#[allow(dead_code)]
+pub fn write_qoi(image: Image, filename: &str) -> Result<(), QoiError> {
+  let path = Path::new(filename);
+  let mut file = try!(io(File::create(&path)));
+  encode_qoi(&image, &mut file)
+}
+
+// Encodes a QOI image into a new in-memory buffer
+#[allow(dead_code)]
+pub fn to_bytes(image: &Image) -> Result<Vec<u8>, QoiError> {
+  let mut writer = MemWriter::new();
+  try!(encode_qoi(image, &mut writer));
+  Ok(writer.unwrap())
+}
+
+// Encodes an RGB8 or RGBA8 image as QOI to any writer
+#[allow(dead_code)]
+pub fn encode_qoi<W: Writer>(image: &Image, file: &mut W) -> Result<(), QoiError> {
+
+  let channels = match image.color_type {
+    GRAYSCALE8  => return Err(NotRGB),
+    RGB8        => 3u,
+    RGBA8       => 4u
+  };
+  if image.width == 0 || image.height == 0 || image.width * image.height > MAX_PIXELS {
+    return Err(InvalidDimensions(image.width, image.height));
+  }
+
+  try!(io(file.write(SIGNATURE.as_bytes())));
+  try!(io(file.write_be_u32(image.width as u32)));
+  try!(io(file.write_be_u32(image.height as u32)));
+  try!(io(file.write_u8(channels as u8)));
+  try!(io(file.write_u8(0)));
+
+  // Chunks are at most 5 bytes, but most are 1
+  let mut chunks: Vec<u8> = Vec::with_capacity(image.data.len() / 2);
+  let mut seen = [(0u8, 0u8, 0u8, 0u8), ..64];
+  let mut previous = (0u8, 0u8, 0u8, 255u8);
+  let mut run = 0u;
+
+  for samples in image.data.as_slice().chunks(channels) {
+    let pixel = (samples[0], samples[1], samples[2], if channels == 4 { samples[3] } else { 255 });
+
+    if pixel == previous {
+      run += 1;
+      if run == MAX_RUN {
+        chunks.push(OP_RUN | (run - 1) as u8);
+        run = 0;
+      }
+      continue;
+    }
+    if run > 0 {
+      chunks.push(OP_RUN | (run - 1) as u8);
+      run = 0;
+    }
+
+    let slot = hash(pixel);
+    if seen[slot] == pixel {
+      chunks.push(OP_INDEX | slot as u8);
+    }
+    else {
+      seen[slot] = pixel;
+      let (r, g, b, a) = pixel;
+      let (pr, pg, pb, pa) = previous;
+
+      if a != pa {
+        chunks.push_all(&[OP_RGBA, r, g, b, a]);
+      }
+      else {
+        let dr = (r - pr) as i8;
+        let dg = (g - pg) as i8;
+        let db = (b - pb) as i8;
+        let dr_dg = dr as int - dg as int;
+        let db_dg = db as int - dg as int;
+
+        if dr >= -2 && dr <= 1 && dg >= -2 && dg <= 1 && db >= -2 && db <= 1 {
+          chunks.push(OP_DIFF | (((dr + 2) as u8) << 4) | (((dg + 2) as u8) << 2) | (db + 2) as u8);
+        }
+        else if dg >= -32 && dg <= 31 && dr_dg >= -8 && dr_dg <= 7 && db_dg >= -8 && db_dg <= 7 {
+          chunks.push(OP_LUMA | (dg + 32) as u8);
+          chunks.push((((dr_dg + 8) as u8) << 4) | (db_dg + 8) as u8);
+        }
+        else {
+          chunks.push_all(&[OP_RGB, r, g, b]);
+        }
+      }
+    }
+    previous = pixel;
+  }
+  if run > 0 {
+    chunks.push(OP_RUN | (run - 1) as u8);
+  }
+
+  try!(io(file.write(chunks.as_slice())));
+  io(file.write(END_MARKER))
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::*;
+  use bmp;
+  use test::Bencher;
+
+  // Smooth gradients with some noise and flat areas, like a photo with a background
+  fn test_image(width: uint, height: uint, color_type: ColorType) -> Image {
+    let mut image = Image::new(width, height, color_type);
+    let channels = image.data.len() / (width * height);
+    for y in range(0, height) {
+      for x in range(0, width) {
+        for c in range(0, channels) {
+          let value = if x < width / 4 { 200 } else { (x * 3 + y * 2 + c * 40 + (x * y * 7) % 5) % 256 };
+          *image.data.get_mut((y * width + x) * channels + c) = value as u8;
+        }
+      }
+    }
+    image
+  }
+
+  #[test]
+  fn test_chunks() {
+    // RGB, a diff, a luma, a run of 3, an index back to the first pixel, and RGBA
+    let mut image = Image::new(8, 1, RGBA8);
+    image.data = vec!(100, 100, 100, 255,  101, 99, 100, 255,  111, 109, 105, 255,
+                      111, 109, 105, 255,  111, 109, 105, 255,  111, 109, 105, 255,
+                      100, 100, 100, 255,  100, 100, 100, 128);
+    let bytes = to_bytes(&image).unwrap();
+    assert_eq!(bytes.slice(0, 14), [b'q', b'o', b'i', b'f', 0, 0, 0, 8, 0, 0, 0, 1, 4, 0].as_slice());
+
+    let first = super::hash((100, 100, 100, 255)) as u8;
+    let expected = &[0xFE, 100, 100, 100,  0x40 | 3 << 4 | 1 << 2 | 2,  0x80 | 42, 8 << 4 | 3,
+                     0xC0 | 2,  first,  0xFF, 100, 100, 100, 128,  0, 0, 0, 0, 0, 0, 0, 1];
+    assert_eq!(bytes.slice_from(14), expected.as_slice());
+
+    let decoded = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(decoded.data, image.data);
+  }
+
+  #[test]
+  fn test_round_trip() {
+    for &color_type in [RGB8, RGBA8].iter() {
+      let image = test_image(67, 45, color_type);
+      let bytes = to_bytes(&image).unwrap();
+      assert!(bytes.len() < image.data.len());
+      let decoded = from_bytes(bytes.as_slice()).unwrap();
+      assert_eq!(decoded.color_type as uint, color_type as uint);
+      assert_eq!((decoded.width, decoded.height), (67, 45));
+      assert_eq!(decoded.data, image.data);
+    }
+
+    // Runs longer than 62 and differences that wrap around
+    let mut image = Image::new(200, 1, RGB8);
+    for (i, sample) in image.data.mut_iter().enumerate() {
+      *sample = if i < 450 { 0 } else { 255 };
+    }
+    let decoded = from_bytes(to_bytes(&image).unwrap().as_slice()).unwrap();
+    assert_eq!(decoded.data, image.data);
+  }
+
+  #[test]
+  fn test_errors() {
+    match from_bytes("qoig\x00\x00\x00\x01\x00\x00\x00\x01\x03\x00".as_bytes()) {
+      Err(BadSignature) => {},
+      Err(e)  => fail!("Expected a bad signature, got {}", e),
+      Ok(_)   => fail!("Decoded a file that isn't QOI")
+    }
+    match from_bytes("qoif\x00\x00\x00\x01\x00\x00\x00\x01\x02\x00".as_bytes()) {
+      Err(BadChannels(2)) => {},
+      Err(e)  => fail!("Expected bad channels, got {}", e),
+      Ok(_)   => fail!("Decoded 2 channels")
+    }
+    match from_bytes(b"qoif\x00\x00\x00\x02\x00\x00\x00\x02\x03\x00\xC0") {
+      Err(Truncated) => {},
+      Err(e)  => fail!("Expected a truncated file, got {}", e),
+      Ok(_)   => fail!("Decoded a file missing pixels")
+    }
+    // 20000x20000 is as large as QOI allows, but one run and the end marker hold at most 9 * 62 pixels
+    match from_bytes(b"qoif\x00\x00\x4E\x20\x00\x00\x4E\x20\x04\x00\xFD\x00\x00\x00\x00\x00\x00\x00\x01") {
+      Err(Truncated) => {},
+      Err(e)  => fail!("Expected a truncated file, got {}", e),
+      Ok(_)   => fail!("Decoded a file far smaller than its dimensions")
+    }
+    match to_bytes(&Image::new(2, 2, GRAYSCALE8)) {
+      Err(NotRGB) => {},
+      Err(e)  => fail!("Expected a color type error, got {}", e),
+      Ok(_)   => fail!("Encoded a grayscale image")
+    }
+  }
+
+  // Throughput against BMP, which only copies pixels, on a 512x512 image
+  #[bench]
+  fn bench_encode_qoi(b: &mut Bencher) {
+    let image = test_image(512, 512, RGB8);
+    b.bytes = image.data.len() as u64;
+    b.iter(|| to_bytes(&image).unwrap());
+  }
+
+  #[bench]
+  fn bench_encode_bmp(b: &mut Bencher) {
+    let image = test_image(512, 512, RGB8);
+    b.bytes = image.data.len() as u64;
+    b.iter(|| bmp::to_bytes(&image).unwrap());
+  }
+
+  #[bench]
+  fn bench_decode_qoi(b: &mut Bencher) {
+    let image = test_image(512, 512, RGB8);
+    let bytes = to_bytes(&image).unwrap();
+    b.bytes = image.data.len() as u64;
+    b.iter(|| from_bytes(bytes.as_slice()).unwrap());
+  }
+
+  #[bench]
+  fn bench_decode_bmp(b: &mut Bencher) {
+    let image = test_image(512, 512, RGB8);
+    let bytes = bmp::to_bytes(&image).unwrap();
+    b.bytes = image.data.len() as u64;
+    b.iter(|| bmp::from_bytes(bytes.as_slice()).unwrap());
+  }
+}
@@ -0,0 +1,277 @@
+// Raw headerless pixel dumps
+
+use std::path::posix::{Path};
+use std::io::{File, IoResult, IoError, EndOfFile, BufReader, MemWriter};
+use image::*;
+
+
+/* NOTES:
+ * A raw dump is only pixel data, so its layout is given by the caller: width, height, color type, the
+ *   stride from the start of one row to the next, and the byte order of each pixel
+ * Rows go top to bottom, and bytes in a row after the last pixel are padding. The last row's padding can
+ *   be missing when reading, which some tools do. Padding is written as 0
+ * Pixels are read as words of 1, 3 or 4 bytes. In big endian order they're the same as the Image struct,
+ *   R, G, B, A, and in little endian order reversed, A, B, G, R, like a 32 bit RGBA pixel in
+ *   little endian memory. Grayscale pixels are the same either way
+ * With the default layout, the stride is the width of a row and pixels are big endian, so the dump is
+ *   exactly Image.data
+ */
+
+
+// Everything that can go wrong while reading or writing a raw dump
+#[deriving(Show)]
+pub enum RawError {
+  Truncated,                      // Data ended before all the rows were read
+  InvalidDimensions(uint, uint),  // Width or height of zero
+  BadStride(uint),                // Stride shorter than a row of pixels
+  LayoutMismatch,                 // Image to write has a different size or color type than the layout
+  IoFailure(IoError),             // Any other error from the underlying file
+}
+
+// Order of the bytes of each pixel
+#[deriving(Show, PartialEq, Clone)]
+pub enum ByteOrder {
+  BigEndian,      // Same as Image.data
+  LittleEndian,   // Reversed
+}
+
+// How pixels are laid out in a raw dump
+pub struct RawLayout {
+  pub width: uint,
+  pub height: uint,
+  pub color_type: ColorType,
+  pub stride: uint,             // Bytes from the start of one row to the next
+  pub byte_order: ByteOrder,
+}
+
+impl RawLayout {
+
+  // Rows without padding and big endian pixels, which is the layout of Image.data
+  #[allow(dead_code)]
+  pub fn new(width: uint, height: uint, color_type: ColorType) -> RawLayout {
+    RawLayout{width: width, height: height, color_type: color_type, stride: width * bytes_per_pixel(color_type),
+              byte_order: BigEndian}
+  }
+
+  // Layout of an image's own data
+  #[allow(dead_code)]
+  pub fn of(image: &Image) -> RawLayout {
+    RawLayout::new(image.width, image.height, image.color_type)
+  }
+
+}
+
+fn bytes_per_pixel(color_type: ColorType) -> uint {
+  match color_type {
+    GRAYSCALE8  => 1,
+    RGB8        => 3,
+    RGBA8       => 4
+  }
+}
+
+// Wraps an I/O error so it can be returned with try!
+fn io<T>(result: IoResult<T>) -> Result<T, RawError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => Err(IoFailure(e))
+  }
+}
+
+// Same as io(), but running out of bytes means the dump was cut short
+fn chunk<T>(result: IoResult<T>) -> Result<T, RawError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => {
+      if e.kind == EndOfFile {
+        Err(Truncated)
+      }
+      else {
+        Err(IoFailure(e))
+      }
+    }
+  }
+}
+
+// Checks a layout, giving the bytes in a row of pixels
+fn row_length(layout: &RawLayout) -> Result<uint, RawError> {
+  if layout.width == 0 || layout.height == 0 {
+    return Err(InvalidDimensions(layout.width, layout.height));
+  }
+  let length = layout.width * bytes_per_pixel(layout.color_type);
+  if layout.stride < length {
+    return Err(BadStride(layout.stride));
+  }
+  Ok(length)
+}
+
+// Swaps between big and little endian pixels in place
+fn reverse_pixels(row: &mut [u8], channels: uint) {
+  for pixel in row.mut_chunks(channels) {
+    pixel.reverse();
+  }
+}
+
+
+#[allow(dead_code)]
+pub fn read_raw(image_path_str: &str, layout: &RawLayout) -> Result<Image, RawError> {
+  let path = Path::new(image_path_str);
+  let mut file = try!(io(File::open(&path)));
+  decode_raw(&mut file, layout)
+}
+
+// Decodes a raw dump held in memory
+#[allow(dead_code)]
+pub fn from_bytes(bytes: &[u8], layout: &RawLayout) -> Result<Image, RawError> {
+  let mut reader = BufReader::new(bytes);
+  decode_raw(&mut reader, layout)
+}
+
+// Decodes a raw dump from any reader into an image of the layout's color type
+#[allow(dead_code)]
+pub fn decode_raw<R: Reader>(file: &mut R, layout: &RawLayout) -> Result<Image, RawError> {
+
+  let length = try!(row_length(layout));
+  let channels = bytes_per_pixel(layout.color_type);
+
+  let mut data: Vec<u8> = Vec::with_capacity(length * layout.height);
+  for y in range(0, layout.height) {
+    let mut row = try!(chunk(file.read_exact(length)));
+    if layout.byte_order == LittleEndian {
+      reverse_pixels(row.as_mut_slice(), channels);
+    }
+    data.push_all(row.as_slice());
+    if y + 1 < layout.height && layout.stride > length {
+      try!(chunk(file.read_exact(layout.stride - length)));
+    }
+  }
+
+  Ok(Image{width: layout.width, height: layout.height, color_type: layout.color_type, data: data,
+           colorimetry: None, resolution: None})
+}
+
+
+#[allow(dead_code)]
+pub fn write_raw(image: Image, filename: &str) -> Result<(), RawError> {
+  let layout = RawLayout::of(&image);
+  write_raw_with(image, filename, &layout)
+}
+
+#[allow(dead_code)]
+pub fn write_raw_with(image: Image, filename: &str, layout: &RawLayout) -> Result<(), RawError> {
+  let path = Path::new(filename);
+  let mut file = try!(io(File::create(&path)));
+  encode_raw_with(&image, &mut file, layout)
+}
+
+// Encodes a raw dump into a new in-memory buffer, a copy of Image.data by default
+#[allow(dead_code)]
+pub fn to_bytes(image: &Image) -> Result<Vec<u8>, RawError> {
+  to_bytes_with(image, &RawLayout::of(image))
+}
+
+#[allow(dead_code)]
+pub fn to_bytes_with(image: &Image, layout: &RawLayout) -> Result<Vec<u8>, RawError> {
+  let mut writer = MemWriter::new();
+  try!(encode_raw_with(image, &mut writer, layout));
+  Ok(writer.unwrap())
+}
+
+#[allow(dead_code)]
+pub fn encode_raw<W: Writer>(image: &Image, file: &mut W) -> Result<(), RawError> {
+  encode_raw_with(image, file, &RawLayout::of(image))
+}
+
+// Writes an image's pixels to any writer in a layout describing the image,
+// so the same layout reads them back
+#[allow(dead_code)]
+pub fn encode_raw_with<W: Writer>(image: &Image, file: &mut W, layout: &RawLayout) -> Result<(), RawError> {
+
+  let length = try!(row_length(layout));
+  if layout.width != image.width || layout.height != image.height
+    || layout.color_type as uint != image.color_type as uint {
+    return Err(LayoutMismatch);
+  }
+  let channels = bytes_per_pixel(layout.color_type);
+
+  let mut row: Vec<u8> = Vec::from_elem(layout.stride, 0u8);
+  for pixels in image.data.as_slice().chunks(length) {
+    row.mut_slice_to(length).copy_from(pixels);
+    if layout.byte_order == LittleEndian {
+      reverse_pixels(row.mut_slice_to(length), channels);
+    }
+    try!(io(file.write(row.as_slice())));
+  }
+  Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::*;
+
+  fn test_image(width: uint, height: uint, color_type: ColorType) -> Image {
+    let mut image = Image::new(width, height, color_type);
+    for (i, sample) in image.data.mut_iter().enumerate() {
+      *sample = (i * 7) as u8;
+    }
+    image
+  }
+
+  #[test]
+  fn test_default_layout() {
+    // Exactly the image's data
+    for &color_type in [GRAYSCALE8, RGB8, RGBA8].iter() {
+      let image = test_image(5, 3, color_type);
+      let bytes = to_bytes(&image).unwrap();
+      assert_eq!(bytes, image.data);
+      let decoded = from_bytes(bytes.as_slice(), &RawLayout::of(&image)).unwrap();
+      assert_eq!(decoded.color_type as uint, color_type as uint);
+      assert_eq!((decoded.width, decoded.height), (5, 3));
+      assert_eq!(decoded.data, image.data);
+    }
+  }
+
+  #[test]
+  fn test_stride_and_byte_order() {
+    let mut image = Image::new(2, 2, RGBA8);
+    image.data = vec!(1, 2, 3, 4,  5, 6, 7, 8,  9, 10, 11, 12,  13, 14, 15, 16);
+    let layout = RawLayout{stride: 10, byte_order: LittleEndian, ..RawLayout::of(&image)};
+    let bytes = to_bytes_with(&image, &layout).unwrap();
+    assert_eq!(bytes, vec!(4, 3, 2, 1,  8, 7, 6, 5,  0, 0,  12, 11, 10, 9,  16, 15, 14, 13,  0, 0));
+    assert_eq!(from_bytes(bytes.as_slice(), &layout).unwrap().data, image.data);
+
+    // Without the last row's padding
+    assert_eq!(from_bytes(bytes.slice_to(18), &layout).unwrap().data, image.data);
+
+    // Big endian RGB with padding
+    let layout = RawLayout{width: 1, height: 2, color_type: RGB8, stride: 4, byte_order: BigEndian};
+    let decoded = from_bytes(&[1, 2, 3, 99, 4, 5, 6], &layout).unwrap();
+    assert_eq!(decoded.data, vec!(1, 2, 3, 4, 5, 6));
+  }
+
+  #[test]
+  fn test_errors() {
+    let layout = RawLayout::new(2, 2, RGB8);
+    match from_bytes(&[0, 0, 0, 0, 0, 0, 0, 0, 0], &layout) {
+      Err(Truncated) => {},
+      Err(e)  => fail!("Expected a truncated dump, got {}", e),
+      Ok(_)   => fail!("Decoded a dump missing pixels")
+    }
+    match from_bytes(&[0, 0, 0, 0, 0, 0], &RawLayout{stride: 5, ..RawLayout::new(2, 1, RGB8)}) {
+      Err(BadStride(5)) => {},
+      Err(e)  => fail!("Expected a bad stride, got {}", e),
+      Ok(_)   => fail!("Decoded rows overlapping each other")
+    }
+    match from_bytes(&[0u8], &RawLayout::new(0, 1, GRAYSCALE8)) {
+      Err(InvalidDimensions(0, 1)) => {},
+      Err(e)  => fail!("Expected invalid dimensions, got {}", e),
+      Ok(_)   => fail!("Decoded an image without width")
+    }
+    match to_bytes_with(&test_image(2, 2, RGB8), &RawLayout::new(2, 2, RGBA8)) {
+      Err(LayoutMismatch) => {},
+      Err(e)  => fail!("Expected a layout mismatch, got {}", e),
+      Ok(_)   => fail!("Encoded an image in a layout that doesn't describe it")
+    }
+  }
+}
@@ -0,0 +1,508 @@
+// TGA (Truevision Targa) Image format
+
+use std::path::posix::{Path};
+use std::cmp;
+use std::io::{File, IoResult, IoError, EndOfFile, BufReader, MemWriter};
+use image::*;
+
+// Image types, with 8 added for the RLE version of each
+static COLOR_MAPPED: u8 = 1;
+static TRUE_COLOR: u8 = 2;
+static GRAYSCALE: u8 = 3;
+static RLE: u8 = 8;
+
+// Image descriptor bits
+static ALPHA_BITS: u8 = 0x0F;
+static RIGHT_TO_LEFT: u8 = 0x10;
+static TOP_TO_BOTTOM: u8 = 0x20;
+
+// TGA 2.0 footer signature, after the extension and developer area offsets
+static FOOTER_SIGNATURE: &'static str = "TRUEVISION-XFILE.\0";
+
+
+/* NOTES:
+ * TGA has no signature at the start, only an 18 byte header, then an image ID, a color map and the pixels
+ * All integers are little endian
+ * Pixels are stored BGR or BGRA like BMP. 15 and 16-bit pixels are 5 bits per channel, and with 16 the top bit
+ *   is alpha. 16-bit grayscale is a gray byte then an alpha byte
+ * The descriptor's low 4 bits are the number of alpha bits, 0 meaning any alpha channel stored is ignored
+ * Rows are stored BOTTOM UP unless the descriptor's top-to-bottom bit is set, and pixels can be right to left
+ * RLE packets are a count byte then pixels: with the top bit set, one pixel repeated (count & 0x7F) + 1 times,
+ *   without it (count + 1) pixels as they are. Older files let packets run across rows, so reading allows it
+ * Color map indices start at the map's first entry index, not at 0
+ * Files are written top to bottom with RLE packets that stop at the end of each row, plus a TGA 2.0 footer
+ */
+
+
+// Everything that can go wrong while reading or writing a TGA image
+#[deriving(Show)]
+pub enum TgaError {
+  Truncated,                      // File ended before all the pixels were read
+  UnsupportedType(u8),            // Image type other than color mapped, true color or grayscale
+  UnsupportedDepth(u8),           // Pixel or color map entry size that doesn't fit the image type
+  MissingColorMap,                // Color mapped image without a color map
+  BadIndex(uint),                 // Color map index outside of the map
+  InvalidDimensions(uint, uint),  // Width or height of zero, or too large to write
+  IoFailure(IoError),             // Any other error from the underlying file
+}
+
+// Wraps an I/O error so it can be returned with try!
+fn io<T>(result: IoResult<T>) -> Result<T, TgaError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => Err(IoFailure(e))
+  }
+}
+
+// Same as io(), but running out of bytes means the file was cut short
+fn chunk<T>(result: IoResult<T>) -> Result<T, TgaError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => {
+      if e.kind == EndOfFile {
+        Err(Truncated)
+      }
+      else {
+        Err(IoFailure(e))
+      }
+    }
+  }
+}
+
+
+#[allow(dead_code)]
+pub fn read_tga(image_path_str: &str) -> Result<Image, TgaError> {
+  let path = Path::new(image_path_str);
+  let mut file = try!(io(File::open(&path)));
+  decode_tga(&mut file)
+}
+
+// Decodes a TGA image held in memory
+#[allow(dead_code)]
+pub fn from_bytes(bytes: &[u8]) -> Result<Image, TgaError> {
+  let mut reader = BufReader::new(bytes);
+  decode_tga(&mut reader)
+}
+
+// Decodes a TGA image from any reader: grayscale as GRAYSCALE8, and anything
+// with alpha bits as RGBA8, otherwise RGB8
+#[allow(dead_code)]
+pub fn decode_tga<R: Reader>(file: &mut R) -> Result<Image, TgaError> {
+
+  let id_length = try!(chunk(file.read_u8()));
+  let color_map_type = try!(chunk(file.read_u8()));
+  let image_type = try!(chunk(file.read_u8()));
+  let map_first = try!(chunk(file.read_le_u16())) as uint;
+  let map_length = try!(chunk(file.read_le_u16())) as uint;
+  let map_entry_size = try!(chunk(file.read_u8()));
+  try!(chunk(file.read_le_u16()));  // X origin
+  try!(chunk(file.read_le_u16()));  // Y origin
+  let width = try!(chunk(file.read_le_u16())) as uint;
+  let height = try!(chunk(file.read_le_u16())) as uint;
+  let depth = try!(chunk(file.read_u8()));
+  let descriptor = try!(chunk(file.read_u8()));
+
+  let kind = image_type & !RLE;
+  if image_type > (GRAYSCALE | RLE) || kind < COLOR_MAPPED || kind > GRAYSCALE {
+    return Err(UnsupportedType(image_type));
+  }
+  if width == 0 || height == 0 {
+    return Err(InvalidDimensions(width, height));
+  }
+  let alpha = descriptor & ALPHA_BITS > 0;
+
+  let depth_supported = if kind == TRUE_COLOR {
+    depth == 15 || depth == 16 || depth == 24 || depth == 32
+  }
+  else {
+    depth == 8 || depth == 16
+  };
+  if !depth_supported {
+    return Err(UnsupportedDepth(depth));
+  }
+
+  try!(chunk(file.read_exact(id_length as uint)));
+
+  // Color map entries as RGBA, read even when the image doesn't use them to get past them
+  let mut color_map: Vec<u8> = Vec::new();
+  if color_map_type == 1 {
+    if map_entry_size != 15 && map_entry_size != 16 && map_entry_size != 24 && map_entry_size != 32 {
+      return Err(UnsupportedDepth(map_entry_size));
+    }
+    let entry_bytes = (map_entry_size as uint + 7) / 8;
+    let entries = try!(chunk(file.read_exact(map_length * entry_bytes)));
+    for entry in entries.as_slice().chunks(entry_bytes) {
+      color_map.push_all(&true_color(entry, map_entry_size, alpha));
+    }
+  }
+  else if kind == COLOR_MAPPED {
+    return Err(MissingColorMap);
+  }
+
+  let has_alpha = alpha && if kind == COLOR_MAPPED {
+    map_entry_size == 16 || map_entry_size == 32
+  }
+  else {
+    depth == 16 || depth == 32
+  };
+  let color_type = if has_alpha { RGBA8 } else if kind == GRAYSCALE { GRAYSCALE8 } else { RGB8 };
+  let channels = match color_type { GRAYSCALE8 => 1, RGB8 => 3, RGBA8 => 4 };
+
+  // Pixels are read before the image is allocated, so the header's size is
+  // only trusted as far as the data goes
+  let pixel_bytes = (depth as uint + 7) / 8;
+  let pixels = if image_type & RLE != 0 {
+    try!(read_rle(file, width * height, pixel_bytes))
+  }
+  else {
+    try!(read_bytes(file, width * height * pixel_bytes))
+  };
+
+  let mut data: Vec<u8> = Vec::from_elem(width * height * channels, 0u8);
+  for (i, pixel) in pixels.as_slice().chunks(pixel_bytes).enumerate() {
+    let color = if kind == COLOR_MAPPED {
+      let index = if depth == 16 { (pixel[0] as uint) | ((pixel[1] as uint) << 8) } else { pixel[0] as uint };
+      if index < map_first || index - map_first >= map_length {
+        return Err(BadIndex(index));
+      }
+      let entry = (index - map_first) * 4;
+      [*color_map.get(entry), *color_map.get(entry + 1), *color_map.get(entry + 2), *color_map.get(entry + 3)]
+    }
+    else if kind == TRUE_COLOR {
+      true_color(pixel, depth, alpha)
+    }
+    else {
+      [pixel[0], pixel[0], pixel[0], if depth == 16 && alpha { pixel[1] } else { 255 }]
+    };
+
+    let row = i / width;
+    let column = i % width;
+    let y = if descriptor & TOP_TO_BOTTOM != 0 { row } else { height - 1 - row };
+    let x = if descriptor & RIGHT_TO_LEFT != 0 { width - 1 - column } else { column };
+    let offset = (y * width + x) * channels;
+    data.mut_slice(offset, offset + channels).copy_from(color.slice_to(channels));
+  }
+
+  Ok(Image{width: width, height: height, color_type: color_type, data: data, colorimetry: None, resolution: None})
+}
+
+// RGBA of a true color pixel or color map entry stored BGR(A) or as 5 bits per channel
+fn true_color(bytes: &[u8], depth: u8, alpha: bool) -> [u8, ..4] {
+  match depth {
+    15 | 16 => {
+      let value = (bytes[0] as u16) | ((bytes[1] as u16) << 8);
+      let expand = |bits: u16| -> u8 { let bits = (bits & 0x1F) as u8; (bits << 3) | (bits >> 2) };
+      let a = if depth == 16 && alpha && value & 0x8000 == 0 { 0 } else { 255 };
+      [expand(value >> 10), expand(value >> 5), expand(value), a]
+    },
+    24 => [bytes[2], bytes[1], bytes[0], 255],
+    _  => [bytes[2], bytes[1], bytes[0], if alpha { bytes[3] } else { 255 }]
+  }
+}
+
+// Reads count bytes a piece at a time, growing the buffer as the data arrives
+fn read_bytes<R: Reader>(file: &mut R, count: uint) -> Result<Vec<u8>, TgaError> {
+  let mut bytes: Vec<u8> = Vec::new();
+  let mut buffer = [0u8, ..4096];
+  while bytes.len() < count {
+    let length = cmp::min(count - bytes.len(), buffer.len());
+    try!(chunk(file.read_at_least(length, buffer.mut_slice_to(length))));
+    bytes.push_all(buffer.slice_to(length));
+  }
+  Ok(bytes)
+}
+
+// Expands RLE packets into count pixels of pixel_bytes each
+fn read_rle<R: Reader>(file: &mut R, count: uint, pixel_bytes: uint) -> Result<Vec<u8>, TgaError> {
+  let mut pixels: Vec<u8> = Vec::new();
+  let mut remaining = count;
+  while remaining > 0 {
+    let packet = try!(chunk(file.read_u8()));
+    let length = cmp::min((packet & 0x7F) as uint + 1, remaining);
+    if packet & 0x80 != 0 {
+      let pixel = try!(chunk(file.read_exact(pixel_bytes)));
+      for _ in range(0, length) {
+        pixels.push_all(pixel.as_slice());
+      }
+    }
+    else {
+      pixels.push_all(try!(chunk(file.read_exact(length * pixel_bytes))).as_slice());
+    }
+    remaining -= length;
+  }
+  Ok(pixels)
+}
+
+
+// Settings for how an image is written
+#[deriving(Show, Clone)]
+pub struct TgaOptions {
+  pub rle: bool,    // Compress each row with RLE packets
+}
+
+impl TgaOptions {
+
+  // Uncompressed, which every reader handles
+  pub fn new() -> TgaOptions {
+    TgaOptions{rle: false}
+  }
+
+}
+
+#[allow(dead_code)]
+pub fn write_tga(image: Image, filename: &str) -> Result<(), TgaError> {
+  write_tga_with(image, filename, &TgaOptions::new())
+}
+
+#[allow(dead_code)]
+pub fn write_tga_with(image: Image, filename: &str, options: &TgaOptions) -> Result<(), TgaError> {
+  let path = Path::new(filename);
+  let mut file = try!(io(File::create(&path)));
+  encode_tga_with(&image, &mut file, options)
+}
+
+// Encodes a TGA image into a new in-memory buffer
+#[allow(dead_code)]
+pub fn to_bytes(image: &Image) -> Result<Vec<u8>, TgaError> {
+  to_bytes_with(image, &TgaOptions::new())
+}
+
+#[allow(dead_code)]
+pub fn to_bytes_with(image: &Image, options: &TgaOptions) -> Result<Vec<u8>, TgaError> {
+  let mut writer = MemWriter::new();
+  try!(encode_tga_with(image, &mut writer, options));
+  Ok(writer.unwrap())
+}
+
+// Encodes a TGA image to any writer
+#[allow(dead_code)]
+pub fn encode_tga<W: Writer>(image: &Image, file: &mut W) -> Result<(), TgaError> {
+  encode_tga_with(image, file, &TgaOptions::new())
+}
+
+// Encodes RGB8 as 24-bit and RGBA8 as 32-bit true color, and GRAYSCALE8 as
+// 8-bit grayscale
+#[allow(dead_code)]
+pub fn encode_tga_with<W: Writer>(image: &Image, file: &mut W, options: &TgaOptions) -> Result<(), TgaError> {
+
+  if image.width == 0 || image.height == 0 || image.width > 0xFFFF || image.height > 0xFFFF {
+    return Err(InvalidDimensions(image.width, image.height));
+  }
+
+  let (kind, depth, alpha_bits, channels) = match image.color_type {
+    GRAYSCALE8  => (GRAYSCALE, 8u8, 0u8, 1u),
+    RGB8        => (TRUE_COLOR, 24, 0, 3),
+    RGBA8       => (TRUE_COLOR, 32, 8, 4)
+  };
+
+  try!(io(file.write_u8(0)));   // No image ID
+  try!(io(file.write_u8(0)));   // No color map
+  try!(io(file.write_u8(if options.rle { kind | RLE } else { kind })));
+  try!(io(file.write(&[0, 0, 0, 0, 0])));   // Color map specification
+  try!(io(file.write_le_u16(0)));
+  try!(io(file.write_le_u16(0)));
+  try!(io(file.write_le_u16(image.width as u16)));
+  try!(io(file.write_le_u16(image.height as u16)));
+  try!(io(file.write_u8(depth)));
+  try!(io(file.write_u8(alpha_bits | TOP_TO_BOTTOM)));
+
+  let mut row: Vec<u8> = Vec::with_capacity(image.width * channels);
+  for line in image.data.as_slice().chunks(image.width * channels) {
+    row.clear();
+    for pixel in line.chunks(channels) {
+      match channels {
+        1 => row.push(pixel[0]),
+        3 => row.push_all(&[pixel[2], pixel[1], pixel[0]]),
+        _ => row.push_all(&[pixel[2], pixel[1], pixel[0], pixel[3]])
+      }
+    }
+    if options.rle {
+      let mut packets: Vec<u8> = Vec::new();
+      rle_row(row.as_slice(), channels, &mut packets);
+      try!(io(file.write(packets.as_slice())));
+    }
+    else {
+      try!(io(file.write(row.as_slice())));
+    }
+  }
+
+  try!(io(file.write_le_u32(0)));   // No extension area
+  try!(io(file.write_le_u32(0)));   // No developer area
+  io(file.write(FOOTER_SIGNATURE.as_bytes()))
+}
+
+// Compresses a row into run packets for 2 or more equal pixels and raw
+// packets for everything else
+fn rle_row(row: &[u8], pixel_bytes: uint, packets: &mut Vec<u8>) {
+  let count = row.len() / pixel_bytes;
+  let pixel = |i: uint| row.slice(i * pixel_bytes, (i + 1) * pixel_bytes);
+
+  let mut i = 0;
+  while i < count {
+    let mut run = 1;
+    while i + run < count && run < 128 && pixel(i + run) == pixel(i) {
+      run += 1;
+    }
+
+    if run > 1 {
+      packets.push(0x80 | (run - 1) as u8);
+      packets.push_all(pixel(i));
+      i += run;
+    }
+    else {
+      // Raw pixels up to the start of the next run
+      let start = i;
+      i += 1;
+      while i < count && i - start < 128 && !(i + 1 < count && pixel(i) == pixel(i + 1)) {
+        i += 1;
+      }
+      packets.push((i - start - 1) as u8);
+      packets.push_all(row.slice(start * pixel_bytes, i * pixel_bytes));
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::*;
+
+  // Header for a file without an image ID
+  fn header(image_type: u8, map: (u16, u16, u8), width: u16, height: u16, depth: u8, descriptor: u8) -> Vec<u8> {
+    let (first, length, entry_size) = map;
+    vec!(0, if length > 0 { 1 } else { 0 }, image_type,
+         first as u8, (first >> 8) as u8, length as u8, (length >> 8) as u8, entry_size,
+         0, 0, 0, 0, width as u8, (width >> 8) as u8, height as u8, (height >> 8) as u8, depth, descriptor)
+  }
+
+  fn test_image(color_type: ColorType) -> Image {
+    let mut image = Image::new(7, 3, color_type);
+    let channels = image.data.len() / 21;
+    for (i, sample) in image.data.mut_iter().enumerate() {
+      // Runs of 5 pixels between different ones
+      let pixel = i / channels;
+      *sample = if pixel % 8 < 5 { 40 } else { ((pixel * 37 + i % channels * 11) % 256) as u8 };
+    }
+    image
+  }
+
+  #[test]
+  fn test_true_color() {
+    // Bottom up rows of BGR
+    let mut bytes = header(2, (0, 0, 0), 2, 2, 24, 0);
+    bytes.push_all(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.color_type as uint, RGB8 as uint);
+    assert_eq!(image.data, vec!(9, 8, 7, 12, 11, 10, 3, 2, 1, 6, 5, 4));
+
+    // Alpha is only used with alpha bits in the descriptor
+    let mut bytes = header(2, (0, 0, 0), 1, 1, 32, 0x20);
+    bytes.push_all(&[1, 2, 3, 4]);
+    assert_eq!(from_bytes(bytes.as_slice()).unwrap().color_type as uint, RGB8 as uint);
+    let mut bytes = header(2, (0, 0, 0), 1, 1, 32, 0x28);
+    bytes.push_all(&[1, 2, 3, 4]);
+    assert_eq!(from_bytes(bytes.as_slice()).unwrap().data, vec!(3, 2, 1, 4));
+
+    // 5 bits per channel with an alpha bit
+    let mut bytes = header(2, (0, 0, 0), 2, 1, 16, 0x21);
+    bytes.push_all(&[0x1F, 0xFC, 0xE0, 0x03]);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.color_type as uint, RGBA8 as uint);
+    assert_eq!(image.data, vec!(255, 0, 255, 255, 0, 255, 0, 0));
+  }
+
+  #[test]
+  fn test_grayscale() {
+    // Gray and alpha, right to left
+    let mut bytes = header(3, (0, 0, 0), 2, 1, 16, 0x38);
+    bytes.push_all(&[10, 20, 30, 40]);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.color_type as uint, RGBA8 as uint);
+    assert_eq!(image.data, vec!(30, 30, 30, 40, 10, 10, 10, 20));
+  }
+
+  #[test]
+  fn test_rle_color_mapped() {
+    // Map starting at index 5, and a run crossing from the first row into the second
+    let mut bytes = header(9, (5, 2, 24), 3, 2, 8, 0x20);
+    bytes.push_all(&[0, 0, 255, 255, 0, 0]);
+    bytes.push_all(&[0x83, 5, 0x01, 6, 5]);
+    let image = from_bytes(bytes.as_slice()).unwrap();
+    assert_eq!(image.color_type as uint, RGB8 as uint);
+    assert_eq!(image.data, vec!(255, 0, 0, 255, 0, 0, 255, 0, 0, 255, 0, 0, 0, 0, 255, 255, 0, 0));
+
+    let mut bytes = header(1, (5, 2, 24), 1, 1, 8, 0x20);
+    bytes.push_all(&[0, 0, 255, 255, 0, 0, 4]);
+    match from_bytes(bytes.as_slice()) {
+      Err(BadIndex(4)) => {},
+      Err(e)  => fail!("Expected a bad index, got {}", e),
+      Ok(_)   => fail!("Decoded an index before the color map")
+    }
+  }
+
+  #[test]
+  fn test_round_trip() {
+    for &color_type in [GRAYSCALE8, RGB8, RGBA8].iter() {
+      let image = test_image(color_type);
+      let raw = to_bytes(&image).unwrap();
+      let compressed = to_bytes_with(&image, &TgaOptions{rle: true}).unwrap();
+      assert!(compressed.len() < raw.len());
+
+      for bytes in [raw, compressed].iter() {
+        let decoded = from_bytes(bytes.as_slice()).unwrap();
+        assert_eq!(decoded.color_type as uint, color_type as uint);
+        assert_eq!(decoded.data, image.data);
+      }
+    }
+  }
+
+  #[test]
+  fn test_rle_packets() {
+    let mut packets = Vec::new();
+    super::rle_row(&[1, 1, 1, 2, 3, 4, 4], 1, &mut packets);
+    assert_eq!(packets, vec!(0x82, 1, 0x01, 2, 3, 0x81, 4));
+
+    // Packets hold at most 128 pixels
+    let mut packets = Vec::new();
+    super::rle_row(Vec::from_elem(130, 9u8).as_slice(), 1, &mut packets);
+    assert_eq!(packets, vec!(0xFF, 9, 0x81, 9));
+  }
+
+  #[test]
+  fn test_errors() {
+    match from_bytes(header(0, (0, 0, 0), 1, 1, 8, 0).as_slice()) {
+      Err(UnsupportedType(0)) => {},
+      Err(e)  => fail!("Expected an unsupported type, got {}", e),
+      Ok(_)   => fail!("Decoded a file without an image")
+    }
+    match from_bytes(header(3, (0, 0, 0), 1, 1, 24, 0).as_slice()) {
+      Err(UnsupportedDepth(24)) => {},
+      Err(e)  => fail!("Expected an unsupported depth, got {}", e),
+      Ok(_)   => fail!("Decoded 24-bit grayscale")
+    }
+    match from_bytes(header(1, (0, 0, 0), 1, 1, 8, 0).as_slice()) {
+      Err(MissingColorMap) => {},
+      Err(e)  => fail!("Expected a missing color map, got {}", e),
+      Ok(_)   => fail!("Decoded a color mapped image without a map")
+    }
+    let mut bytes = header(2, (0, 0, 0), 2, 2, 24, 0);
+    bytes.push_all(&[1, 2, 3]);
+    match from_bytes(bytes.as_slice()) {
+      Err(Truncated) => {},
+      Err(e)  => fail!("Expected a truncated file, got {}", e),
+      Ok(_)   => fail!("Decoded a file missing pixels")
+    }
+
+    // The largest size a header can claim, with one pixel of data
+    for &image_type in [2u8, 10].iter() {
+      let mut bytes = header(image_type, (0, 0, 0), 65535, 65535, 32, 0);
+      bytes.push_all(&[0x80, 1, 2, 3, 4]);
+      match from_bytes(bytes.as_slice()) {
+        Err(Truncated) => {},
+        Err(e)  => fail!("Expected a truncated file, got {}", e),
+        Ok(_)   => fail!("Decoded a file far smaller than its dimensions")
+      }
+    }
+  }
+}
@@ -0,0 +1,1267 @@
+// TIFF Image format
+
+use std::path::posix::{Path};
+use std::io::{File, IoResult, IoError, BufReader};
+use std::collections::HashMap;
+use std::num::CheckedMul;
+use std::cmp;
+use zlib;
+use zlib::{ZlibError};
+use image::*;
+
+// Tags read or written
+static NEW_SUBFILE_TYPE: u16 = 254;
+static IMAGE_WIDTH: u16 = 256;
+static IMAGE_LENGTH: u16 = 257;
+static BITS_PER_SAMPLE: u16 = 258;
+static COMPRESSION: u16 = 259;
+static PHOTOMETRIC: u16 = 262;
+static STRIP_OFFSETS: u16 = 273;
+static SAMPLES_PER_PIXEL: u16 = 277;
+static ROWS_PER_STRIP: u16 = 278;
+static STRIP_BYTE_COUNTS: u16 = 279;
+static X_RESOLUTION: u16 = 282;
+static Y_RESOLUTION: u16 = 283;
+static PLANAR_CONFIGURATION: u16 = 284;
+static RESOLUTION_UNIT: u16 = 296;
+static PREDICTOR: u16 = 317;
+static COLOR_MAP: u16 = 320;
+static TILE_WIDTH: u16 = 322;
+static TILE_LENGTH: u16 = 323;
+static TILE_OFFSETS: u16 = 324;
+static TILE_BYTE_COUNTS: u16 = 325;
+static EXTRA_SAMPLES: u16 = 338;
+static SAMPLE_FORMAT: u16 = 339;
+
+// Field types
+static BYTE: u16 = 1;
+static SHORT: u16 = 3;
+static LONG: u16 = 4;
+static RATIONAL: u16 = 5;
+
+// Photometric interpretations
+static WHITE_IS_ZERO: u16 = 0;
+static BLACK_IS_ZERO: u16 = 1;
+static PHOTOMETRIC_RGB: u16 = 2;
+static PALETTE: u16 = 3;
+
+// Extra sample meanings
+static ASSOCIATED_ALPHA: u32 = 1;
+static UNASSOCIATED_ALPHA: u32 = 2;
+
+// LZW codes never grow past 12 bits, and the first entry comes after the clear and end codes
+static CLEAR_CODE: uint = 256;
+static END_CODE: uint = 257;
+static FIRST_CODE: uint = 258;
+static MAX_CODES: uint = 4096;
+
+// Written strips hold about this many bytes before compression
+static STRIP_SIZE: uint = 8192;
+
+
+/* NOTES:
+ * TIFF starts with II (little endian) or MM (big endian), 42, and the offset of the first IFD
+ * An IFD (image file directory) is a count, 12 byte entries of tag, type, count and value, then the offset of
+ *   the next IFD or 0. Values that fit in 4 bytes are stored in the entry, others at an offset
+ * Each IFD is a page. Reduced resolution ones (NewSubfileType bit 0), like thumbnails, are skipped
+ * Pixels are split into strips of whole rows or into tiles, each compressed on its own. Tiles on the right and
+ *   bottom edges are stored whole and cut to the image
+ * 16-bit samples are in the file's byte order, and are scaled to 8 bits
+ * Rows are padded to a byte, so bilevel and 4-bit rows start on a byte boundary
+ * Only chunky (interleaved) samples are read, planar images need one plane per sample
+ * LZW codes are packed most significant bit first, and grow one code sooner than GIF's: at 511, 1023 and 2047.
+ *   The old LSB-first LZW from before TIFF 6.0 isn't read
+ * The horizontal predictor stores each sample as the difference from the same sample of the pixel to its left,
+ *   restarting at every row of a strip or tile
+ * The first extra sample is alpha when ExtraSamples says so. Associated alpha is premultiplied, and is divided out
+ * Files are written little endian in strips of about 8KB, with unassociated alpha for RGBA8
+ */
+
+
+// Everything that can go wrong while reading or writing a TIFF image
+#[deriving(Show)]
+pub enum TiffError {
+  BadSignature,                     // File doesn't start with II or MM and 42
+  Truncated,                        // Offset or count pointing past the end of the file, or a strip too short
+  IfdLoop,                          // Next IFD offset pointing back to an IFD already read
+  NoPages,                          // File without any full resolution IFD, or nothing to write
+  MissingTag(u16),                  // Tag the image needs that its IFD doesn't have
+  BadField(u16),                    // Tag with a type or number of values that doesn't fit it
+  InvalidDimensions(uint, uint),    // Width or height of zero, or too large to read or write
+  UnsupportedPhotometric(u16),      // Color interpretation other than gray, RGB or palette
+  UnsupportedBitDepth(uint),        // Bits per sample that don't fit the color interpretation, or differ by sample
+  UnsupportedSampleFormat(u32),     // Samples that aren't unsigned integers
+  UnsupportedPlanar(u32),           // Samples stored in separate planes
+  UnsupportedCompression(u32),      // Compression other than none, PackBits, LZW or Deflate
+  UnsupportedPredictor(u32),        // Predictor other than none, or horizontal with 8 or 16-bit samples
+  BadCode,                          // LZW code that isn't in the table yet
+  BadCompression(ZlibError),        // Deflate strip or tile couldn't be inflated
+  IoFailure(IoError),               // Any other error from the underlying file
+}
+
+// Wraps an I/O error so it can be returned with try!
+fn io<T>(result: IoResult<T>) -> Result<T, TiffError> {
+  match result {
+    Ok(value) => Ok(value),
+    Err(e)    => Err(IoFailure(e))
+  }
+}
+
+
+// The whole file, read at any offset in its byte order
+struct Buffer<'a> {
+  data: &'a [u8],
+  big_endian: bool,
+}
+
+impl<'a> Buffer<'a> {
+
+  fn bytes(&self, offset: uint, length: uint) -> Result<&'a [u8], TiffError> {
+    if offset > self.data.len() || length > self.data.len() - offset {
+      return Err(Truncated);
+    }
+    Ok(self.data.slice(offset, offset + length))
+  }
+
+  fn u16(&self, offset: uint) -> Result<u16, TiffError> {
+    let bytes = try!(self.bytes(offset, 2));
+    Ok(read_u16(bytes, self.big_endian))
+  }
+
+  fn u32(&self, offset: uint) -> Result<u32, TiffError> {
+    let first = try!(self.u16(offset)) as u32;
+    let second = try!(self.u16(offset + 2)) as u32;
+    Ok(if self.big_endian { (first << 16) | second } else { (second << 16) | first })
+  }
+
+}
+
+fn read_u16(bytes: &[u8], big_endian: bool) -> u16 {
+  if big_endian {
+    ((bytes[0] as u16) << 8) | bytes[1] as u16
+  }
+  else {
+    ((bytes[1] as u16) << 8) | bytes[0] as u16
+  }
+}
+
+// An IFD entry, with the offset of its values whether they're in the entry or not
+struct Field {
+  kind: u16,
+  count: uint,
+  offset: uint,
+}
+
+// Bytes taken by one value of a field type, 0 for types that aren't known
+fn type_size(kind: u16) -> uint {
+  match kind {
+    1 | 2 | 6 | 7   => 1,   // Byte, ASCII, signed byte, undefined
+    3 | 8           => 2,   // Short, signed short
+    4 | 9 | 11      => 4,   // Long, signed long, float
+    5 | 10 | 12     => 8,   // Rational, signed rational, double
+    _               => 0
+  }
+}
+
+// Fields of the IFD at an offset, and the offset of the next IFD
+fn read_ifd(buffer: &Buffer, offset: uint) -> Result<(HashMap<u16, Field>, uint), TiffError> {
+  let count = try!(buffer.u16(offset)) as uint;
+  let mut fields = HashMap::new();
+  for i in range(0, count) {
+    let entry = offset + 2 + i * 12;
+    let tag = try!(buffer.u16(entry));
+    let kind = try!(buffer.u16(entry + 2));
+    let count = try!(buffer.u32(entry + 4)) as uint;
+    let size = type_size(kind) * count;
+    if size == 0 {
+      continue;
+    }
+    let values = if size <= 4 { entry + 8 } else { try!(buffer.u32(entry + 8)) as uint };
+    fields.insert(tag, Field{kind: kind, count: count, offset: values});
+  }
+  let next = try!(buffer.u32(offset + 2 + count * 12)) as uint;
+  Ok((fields, next))
+}
+
+// Integer values of a field, None when the IFD doesn't have it
+fn values(buffer: &Buffer, fields: &HashMap<u16, Field>, tag: u16) -> Result<Option<Vec<u32>>, TiffError> {
+  let field = match fields.find(&tag) {
+    Some(field) => field,
+    None => return Ok(None)
+  };
+  if field.kind != BYTE && field.kind != SHORT && field.kind != LONG {
+    return Err(BadField(tag));
+  }
+  let size = type_size(field.kind);
+  let bytes = try!(buffer.bytes(field.offset, field.count * size));
+
+  let mut values: Vec<u32> = Vec::with_capacity(field.count);
+  for i in range(0, field.count) {
+    values.push(match size {
+      1 => bytes[i] as u32,
+      2 => try!(buffer.u16(field.offset + i * 2)) as u32,
+      _ => try!(buffer.u32(field.offset + i * 4))
+    });
+  }
+  Ok(Some(values))
+}
+
+// First value of a field, or the default when the IFD doesn't have it
+fn value(buffer: &Buffer, fields: &HashMap<u16, Field>, tag: u16, default: Option<u32>) -> Result<u32, TiffError> {
+  match try!(values(buffer, fields, tag)) {
+    Some(values) => {
+      if values.len() == 0 {
+        return Err(BadField(tag));
+      }
+      Ok(*values.get(0))
+    },
+    None => {
+      match default {
+        Some(value) => Ok(value),
+        None => Err(MissingTag(tag))
+      }
+    }
+  }
+}
+
+// A rational field as a float, None when the IFD doesn't have it or it divides by 0
+fn rational(buffer: &Buffer, fields: &HashMap<u16, Field>, tag: u16) -> Result<Option<f64>, TiffError> {
+  let field = match fields.find(&tag) {
+    Some(field) => field,
+    None => return Ok(None)
+  };
+  if field.kind != RATIONAL || field.count == 0 {
+    return Err(BadField(tag));
+  }
+  let numerator = try!(buffer.u32(field.offset));
+  let denominator = try!(buffer.u32(field.offset + 4));
+  if denominator == 0 {
+    return Ok(None);
+  }
+  Ok(Some(numerator as f64 / denominator as f64))
+}
+
+
+#[allow(dead_code)]
+pub fn read_tiff(image_path_str: &str) -> Result<Vec<Image>, TiffError> {
+  let path = Path::new(image_path_str);
+  let mut file = try!(io(File::open(&path)));
+  decode_tiff(&mut file)
+}
+
+// Decodes a TIFF image held in memory
+#[allow(dead_code)]
+pub fn from_bytes(bytes: &[u8]) -> Result<Vec<Image>, TiffError> {
+  let mut reader = BufReader::new(bytes);
+  decode_tiff(&mut reader)
+}
+
+// Decodes every page of a TIFF image from any reader: gray as GRAYSCALE8, RGB
+// and palette as RGB8, and either with an alpha extra sample as RGBA8
+#[allow(dead_code)]
+pub fn decode_tiff<R: Reader>(file: &mut R) -> Result<Vec<Image>, TiffError> {
+
+  let data = try!(io(file.read_to_end()));
+  if data.len() < 8 {
+    return Err(BadSignature);
+  }
+  let big_endian = match (*data.get(0), *data.get(1)) {
+    (b'I', b'I') => false,
+    (b'M', b'M') => true,
+    _ => return Err(BadSignature)
+  };
+  let buffer = Buffer{data: data.as_slice(), big_endian: big_endian};
+  if try!(buffer.u16(2)) != 42 {
+    return Err(BadSignature);
+  }
+
+  let mut pages: Vec<Image> = Vec::new();
+  let mut visited: Vec<uint> = Vec::new();
+  let mut offset = try!(buffer.u32(4)) as uint;
+  while offset != 0 {
+    if visited.contains(&offset) {
+      return Err(IfdLoop);
+    }
+    visited.push(offset);
+
+    let (fields, next) = try!(read_ifd(&buffer, offset));
+    if try!(value(&buffer, &fields, NEW_SUBFILE_TYPE, Some(0))) & 1 == 0 {
+      pages.push(try!(decode_page(&buffer, &fields)));
+    }
+    offset = next;
+  }
+
+  if pages.len() == 0 {
+    return Err(NoPages);
+  }
+  Ok(pages)
+}
+
+// Bytes in a row of pixels, padded to a byte
+fn row_bytes(pixels: uint, samples: uint, depth: uint) -> uint {
+  (pixels * samples * depth + 7) / 8
+}
+
+fn decode_page(buffer: &Buffer, fields: &HashMap<u16, Field>) -> Result<Image, TiffError> {
+
+  let width = try!(value(buffer, fields, IMAGE_WIDTH, None)) as uint;
+  let height = try!(value(buffer, fields, IMAGE_LENGTH, None)) as uint;
+  if width == 0 || height == 0 {
+    return Err(InvalidDimensions(width, height));
+  }
+
+  let samples = try!(value(buffer, fields, SAMPLES_PER_PIXEL, Some(1))) as uint;
+  let bits = match try!(values(buffer, fields, BITS_PER_SAMPLE)) {
+    Some(bits) => bits,
+    None => vec!(1)
+  };
+  if bits.len() == 0 {
+    return Err(BadField(BITS_PER_SAMPLE));
+  }
+  let depth = *bits.get(0) as uint;
+  for &sample_depth in bits.iter() {
+    if sample_depth as uint != depth {
+      return Err(UnsupportedBitDepth(sample_depth as uint));
+    }
+  }
+  match try!(values(buffer, fields, SAMPLE_FORMAT)) {
+    Some(formats) => {
+      for &format in formats.iter() {
+        if format != 1 {
+          return Err(UnsupportedSampleFormat(format));
+        }
+      }
+    },
+    None => {}
+  }
+
+  let photometric = try!(value(buffer, fields, PHOTOMETRIC, None)) as u16;
+  let color_samples = if photometric == PHOTOMETRIC_RGB { 3 } else { 1 };
+  let depth_supported = if photometric == WHITE_IS_ZERO || photometric == BLACK_IS_ZERO || photometric == PALETTE {
+    depth == 1 || depth == 2 || depth == 4 || depth == 8 || depth == 16
+  }
+  else if photometric == PHOTOMETRIC_RGB {
+    depth == 8 || depth == 16
+  }
+  else {
+    return Err(UnsupportedPhotometric(photometric));
+  };
+  if !depth_supported {
+    return Err(UnsupportedBitDepth(depth));
+  }
+  if samples < color_samples {
+    return Err(BadField(SAMPLES_PER_PIXEL));
+  }
+
+  let planar = try!(value(buffer, fields, PLANAR_CONFIGURATION, Some(1)));
+  if planar != 1 && samples > 1 {
+    return Err(UnsupportedPlanar(planar));
+  }
+  let compression = try!(value(buffer, fields, COMPRESSION, Some(1)));
+  let predictor = try!(value(buffer, fields, PREDICTOR, Some(1)));
+  if predictor != 1 && (predictor != 2 || (depth != 8 && depth != 16)) {
+    return Err(UnsupportedPredictor(predictor));
+  }
+
+  // Alpha is only the first extra sample, and only when it's said to be
+  let alpha = if samples > color_samples {
+    match try!(values(buffer, fields, EXTRA_SAMPLES)) {
+      Some(extra) => if extra.len() > 0 { *extra.get(0) } else { 0 },
+      None => 0
+    }
+  }
+  else {
+    0
+  };
+  let has_alpha = alpha == ASSOCIATED_ALPHA || alpha == UNASSOCIATED_ALPHA;
+
+  // Strips are tiles as wide as the image
+  let (offsets, counts, chunk_width, chunk_height) = if fields.contains_key(&TILE_OFFSETS) {
+    let offsets = try!(values(buffer, fields, TILE_OFFSETS)).unwrap();
+    let counts = match try!(values(buffer, fields, TILE_BYTE_COUNTS)) {
+      Some(counts) => counts,
+      None => return Err(MissingTag(TILE_BYTE_COUNTS))
+    };
+    let tile_width = try!(value(buffer, fields, TILE_WIDTH, None)) as uint;
+    let tile_height = try!(value(buffer, fields, TILE_LENGTH, None)) as uint;
+    (offsets, counts, tile_width, tile_height)
+  }
+  else {
+    let offsets = match try!(values(buffer, fields, STRIP_OFFSETS)) {
+      Some(offsets) => offsets,
+      None => return Err(MissingTag(STRIP_OFFSETS))
+    };
+    let counts = match try!(values(buffer, fields, STRIP_BYTE_COUNTS)) {
+      Some(counts) => counts,
+      None => return Err(MissingTag(STRIP_BYTE_COUNTS))
+    };
+    let rows_per_strip = try!(value(buffer, fields, ROWS_PER_STRIP, Some(0xFFFFFFFF))) as uint;
+    (offsets, counts, width, cmp::min(rows_per_strip, height))
+  };
+  if chunk_width == 0 || chunk_height == 0 {
+    return Err(InvalidDimensions(chunk_width, chunk_height));
+  }
+
+  let across = (width + chunk_width - 1) / chunk_width;
+  let down = (height + chunk_height - 1) / chunk_height;
+  if offsets.len() < across * down || counts.len() < across * down {
+    return Err(BadField(if chunk_width == width { STRIP_OFFSETS } else { TILE_OFFSETS }));
+  }
+
+  // Checked so huge dimensions fail instead of wrapping around
+  let image_row = row_bytes(width, samples, depth);
+  let chunk_row = row_bytes(chunk_width, samples, depth);
+  if image_row.checked_mul(&height).is_none() || chunk_row.checked_mul(&chunk_height).is_none()
+    || width.checked_mul(&height).and_then(|pixels| pixels.checked_mul(&4)).is_none() {
+    return Err(InvalidDimensions(width, height));
+  }
+
+  // Strips and tiles are decoded a band of rows at a time, and the raster only
+  // grows once a band's data is there, rather than from the header's size
+  let mut raster: Vec<u8> = Vec::new();
+  for band in range(0, down) {
+    let y = band * chunk_height;
+    let rows = cmp::min(chunk_height, height - y);
+
+    let mut chunks: Vec<Vec<u8>> = Vec::new();
+    for i in range(band * across, (band + 1) * across) {
+      let compressed = try!(buffer.bytes(*offsets.get(i) as uint, *counts.get(i) as uint));
+      let mut chunk = try!(decompress(compressed, compression, chunk_row * rows));
+      if chunk.len() < chunk_row * rows {
+        return Err(Truncated);
+      }
+      if predictor == 2 {
+        undo_predictor(chunk.mut_slice_to(chunk_row * rows), chunk_row, samples, depth, buffer.big_endian);
+      }
+      chunks.push(chunk);
+    }
+
+    for row in range(0, rows) {
+      let target = raster.len();
+      raster.grow(image_row, &0u8);
+      for (column, chunk) in chunks.iter().enumerate() {
+        let x = column * chunk_width;
+        let start = target + row_bytes(x, samples, depth);
+        let length = row_bytes(cmp::min(chunk_width, width - x), samples, depth);
+        raster.mut_slice(start, start + length).copy_from(chunk.slice(row * chunk_row, row * chunk_row + length));
+      }
+    }
+  }
+
+  let entries = 1u << depth;
+  let color_map = if photometric == PALETTE {
+    match try!(values(buffer, fields, COLOR_MAP)) {
+      Some(map) => {
+        if map.len() < entries * 3 {
+          return Err(BadField(COLOR_MAP));
+        }
+        map
+      },
+      None => return Err(MissingTag(COLOR_MAP))
+    }
+  }
+  else {
+    Vec::new()
+  };
+
+  let color_type = if has_alpha { RGBA8 } else if color_samples == 3 || photometric == PALETTE { RGB8 } else { GRAYSCALE8 };
+  let max = if depth == 16 { 65535 } else { (1u32 << depth) - 1 };
+
+  let mut data: Vec<u8> = Vec::with_capacity(width * height * 4);
+  for y in range(0, height) {
+    let row = raster.slice(y * image_row, (y + 1) * image_row);
+    for x in range(0, width) {
+      let base = x * samples;
+      let (r, g, b) = if photometric == PHOTOMETRIC_RGB {
+        (scale(read_sample(row, base, depth, buffer.big_endian), max),
+         scale(read_sample(row, base + 1, depth, buffer.big_endian), max),
+         scale(read_sample(row, base + 2, depth, buffer.big_endian), max))
+      }
+      else if photometric == PALETTE {
+        let index = read_sample(row, base, depth, buffer.big_endian) as uint;
+        (scale(*color_map.get(index), 65535),
+         scale(*color_map.get(entries + index), 65535),
+         scale(*color_map.get(entries * 2 + index), 65535))
+      }
+      else {
+        let level = scale(read_sample(row, base, depth, buffer.big_endian), max);
+        let level = if photometric == WHITE_IS_ZERO { 255 - level } else { level };
+        (level, level, level)
+      };
+
+      match color_type {
+        GRAYSCALE8  => data.push(r),
+        RGB8        => data.push_all(&[r, g, b]),
+        RGBA8       => {
+          let a = scale(read_sample(row, base + color_samples, depth, buffer.big_endian), max);
+          if alpha == ASSOCIATED_ALPHA && a > 0 {
+            let divide = |c: u8| cmp::min(255, (c as uint * 255 + a as uint / 2) / a as uint) as u8;
+            data.push_all(&[divide(r), divide(g), divide(b), a]);
+          }
+          else {
+            data.push_all(&[r, g, b, a]);
+          }
+        }
+      }
+    }
+  }
+
+  let mut image = Image{width: width, height: height, color_type: color_type, data: data, colorimetry: None, resolution: None};
+
+  // Resolution is in pixels per inch unless the unit says centimeters, or that there's no unit
+  let x_resolution = try!(rational(buffer, fields, X_RESOLUTION));
+  let y_resolution = try!(rational(buffer, fields, Y_RESOLUTION));
+  let unit = try!(value(buffer, fields, RESOLUTION_UNIT, Some(2)));
+  match (x_resolution, y_resolution) {
+    (Some(horizontal), Some(vertical)) => {
+      if unit == 2 {
+        image.resolution = Some(Resolution::from_dpi(horizontal, vertical));
+      }
+      else if unit == 3 {
+        image.resolution = Some(Resolution{horizontal: horizontal * 100.0, vertical: vertical * 100.0});
+      }
+    },
+    _ => {}
+  }
+
+  Ok(image)
+}
+
+// Sample at an index in a row, which may be packed into part of a byte or take two
+fn read_sample(row: &[u8], index: uint, depth: uint, big_endian: bool) -> u32 {
+  match depth {
+    8   => row[index] as u32,
+    16  => read_u16(row.slice_from(index * 2), big_endian) as u32,
+    _   => {
+      let bit = index * depth;
+      ((row[bit / 8] >> (8 - depth - bit % 8)) as u32) & ((1 << depth) - 1)
+    }
+  }
+}
+
+// Scales a sample from 0 to max to 0 to 255, rounding to the nearest
+fn scale(sample: u32, max: u32) -> u8 {
+  ((sample * 255 + max / 2) / max) as u8
+}
+
+fn decompress(data: &[u8], compression: u32, size: uint) -> Result<Vec<u8>, TiffError> {
+  match compression {
+    1             => Ok(Vec::from_slice(data)),
+    5             => lzw_decode(data, size),
+    8 | 32946     => {
+      // Adobe's Deflate, and the code used before it was registered
+      match zlib::inflate(data) {
+        Ok(inflated) => Ok(inflated),
+        Err(e)       => Err(BadCompression(e))
+      }
+    },
+    32773         => Ok(unpack_bits(data, size)),
+    _             => Err(UnsupportedCompression(compression))
+  }
+}
+
+// Expands PackBits runs until there are size bytes or the data runs out
+fn unpack_bits(data: &[u8], size: uint) -> Vec<u8> {
+  // A run of 128 bytes takes 2, so the data can't expand any further than 64 times
+  let mut output: Vec<u8> = Vec::with_capacity(cmp::min(size, data.len() * 64));
+  let mut i = 0u;
+  while i < data.len() && output.len() < size {
+    let header = data[i] as i8;
+    i += 1;
+    if header >= 0 {
+      let end = cmp::min(i + header as uint + 1, data.len());
+      output.push_all(data.slice(i, end));
+      i = end;
+    }
+    else if header != -128 && i < data.len() {
+      output.grow((1 - header as int) as uint, &data[i]);
+      i += 1;
+    }
+  }
+  output
+}
+
+// Adds back the sample to the left of every sample, row by row
+fn undo_predictor(chunk: &mut [u8], row_bytes: uint, samples: uint, depth: uint, big_endian: bool) {
+  for row in chunk.mut_chunks(row_bytes) {
+    if depth == 8 {
+      for i in range(samples, row.len()) {
+        row[i] = row[i] + row[i - samples];
+      }
+    }
+    else {
+      for i in range(samples, row.len() / 2) {
+        let sum = read_u16(row.slice_from(i * 2), big_endian) + read_u16(row.slice_from((i - samples) * 2), big_endian);
+        let (high, low) = ((sum >> 8) as u8, sum as u8);
+        if big_endian {
+          row[i * 2] = high;
+          row[i * 2 + 1] = low;
+        }
+        else {
+          row[i * 2] = low;
+          row[i * 2 + 1] = high;
+        }
+      }
+    }
+  }
+}
+
+// Decodes LZW codes into at most size bytes, stopping early at the end code
+// or the end of the data
+fn lzw_decode(data: &[u8], size: uint) -> Result<Vec<u8>, TiffError> {
+
+  // Each entry is an earlier entry plus one more byte
+  let mut prefixes: Vec<u16> = Vec::from_elem(MAX_CODES, 0u16);
+  let mut suffixes: Vec<u8> = Vec::from_elem(MAX_CODES, 0u8);
+  let mut lengths: Vec<u16> = Vec::from_elem(MAX_CODES, 0u16);
+  for code in range(0u, 256) {
+    *suffixes.get_mut(code) = code as u8;
+    *lengths.get_mut(code) = 1;
+  }
+
+  // Codes can stand for long entries, so the output grows as they decode
+  let mut output: Vec<u8> = Vec::new();
+  let mut width = 9u;
+  let mut next = FIRST_CODE;
+  let mut previous: Option<uint> = None;
+  let mut bits = 0u32;
+  let mut available = 0u;
+  let mut position = 0u;
+
+  while output.len() < size {
+    while available < width && position < data.len() {
+      bits = (bits << 8) | data[position] as u32;
+      available += 8;
+      position += 1;
+    }
+    if available < width {
+      break;
+    }
+    let code = ((bits >> (available - width)) & ((1 << width) - 1)) as uint;
+    available -= width;
+
+    if code == CLEAR_CODE {
+      width = 9;
+      next = FIRST_CODE;
+      previous = None;
+      continue;
+    }
+    if code == END_CODE {
+      break;
+    }
+
+    let prefix = match previous {
+      Some(prefix) => prefix,
+      None => {
+        if code > 255 {
+          return Err(BadCode);
+        }
+        output.push(code as u8);
+        previous = Some(code);
+        continue;
+      }
+    };
+
+    // A code one past the table is the previous entry followed by its own first byte
+    let start = output.len();
+    if code < next {
+      write_entry(&mut output, code, prefixes.as_slice(), suffixes.as_slice(), lengths.as_slice());
+    }
+    else if code == next {
+      write_entry(&mut output, prefix, prefixes.as_slice(), suffixes.as_slice(), lengths.as_slice());
+      let first = *output.get(start);
+      output.push(first);
+    }
+    else {
+      return Err(BadCode);
+    }
+
+    if next < MAX_CODES {
+      *prefixes.get_mut(next) = prefix as u16;
+      *suffixes.get_mut(next) = *output.get(start);
+      *lengths.get_mut(next) = *lengths.get(prefix) + 1;
+      next += 1;
+      if next == (1 << width) - 1 && width < 12 {
+        width += 1;
+      }
+    }
+    previous = Some(code);
+  }
+
+  output.truncate(size);
+  Ok(output)
+}
+
+// Appends the bytes of a table entry, found by walking back through its prefixes
+fn write_entry(output: &mut Vec<u8>, code: uint, prefixes: &[u16], suffixes: &[u8], lengths: &[u16]) {
+  let start = output.len();
+  let length = lengths[code] as uint;
+  output.grow(length, &0u8);
+  let mut code = code;
+  for i in range(0, length).rev() {
+    *output.get_mut(start + i) = suffixes[code];
+    code = prefixes[code] as uint;
+  }
+}
+
+
+// Compression for written strips
+#[deriving(Show, PartialEq, Clone)]
+pub enum TiffCompression {
+  Uncompressed,
+  Lzw,
+}
+
+// Settings for how pages are written
+#[deriving(Show, Clone)]
+pub struct TiffOptions {
+  pub compression: TiffCompression,
+}
+
+impl TiffOptions {
+
+  // Uncompressed, which every baseline reader handles
+  pub fn new() -> TiffOptions {
+    TiffOptions{compression: Uncompressed}
+  }
+
+}
+
+#[allow(dead_code)]
+pub fn write_tiff(pages: &[Image], filename: &str) -> Result<(), TiffError> {
+  write_tiff_with(pages, filename, &TiffOptions::new())
+}
+
+#[allow(dead_code)]
+pub fn write_tiff_with(pages: &[Image], filename: &str, options: &TiffOptions) -> Result<(), TiffError> {
+  let path = Path::new(filename);
+  let mut file = try!(io(File::create(&path)));
+  encode_tiff_with(pages, &mut file, options)
+}
+
+// Encodes a TIFF image into a new in-memory buffer
+#[allow(dead_code)]
+pub fn to_bytes(pages: &[Image]) -> Result<Vec<u8>, TiffError> {
+  to_bytes_with(pages, &TiffOptions::new())
+}
+
+#[allow(dead_code)]
+pub fn to_bytes_with(pages: &[Image], options: &TiffOptions) -> Result<Vec<u8>, TiffError> {
+  let mut output: Vec<u8> = Vec::new();
+  try!(encode_pages(pages, &mut output, options));
+  Ok(output)
+}
+
+// Encodes a TIFF image to any writer
+#[allow(dead_code)]
+pub fn encode_tiff<W: Writer>(pages: &[Image], file: &mut W) -> Result<(), TiffError> {
+  encode_tiff_with(pages, file, &TiffOptions::new())
+}
+
+// Encodes every image as a page with 8 bits per sample, GRAYSCALE8 as gray,
+// RGB8 as RGB and RGBA8 as RGB with alpha
+#[allow(dead_code)]
+pub fn encode_tiff_with<W: Writer>(pages: &[Image], file: &mut W, options: &TiffOptions) -> Result<(), TiffError> {
+  // Offsets point backwards and forwards, so the file is put together in memory first
+  let mut output: Vec<u8> = Vec::new();
+  try!(encode_pages(pages, &mut output, options));
+  io(file.write(output.as_slice()))
+}
+
+fn push_u16(output: &mut Vec<u8>, value: u16) {
+  output.push_all(&[value as u8, (value >> 8) as u8]);
+}
+
+fn push_u32(output: &mut Vec<u8>, value: u32) {
+  output.push_all(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]);
+}
+
+fn put_u32(output: &mut Vec<u8>, at: uint, value: u32) {
+  for i in range(0u, 4) {
+    *output.get_mut(at + i) = (value >> (i * 8)) as u8;
+  }
+}
+
+// Values are even offsets in the file
+fn pad(output: &mut Vec<u8>) {
+  if output.len() % 2 == 1 {
+    output.push(0);
+  }
+}
+
+// Rational close to a resolution
+fn resolution_rational(resolution: f64) -> Vec<u32> {
+  vec!((resolution * 100.0).round() as u32, 100)
+}
+
+fn encode_pages(pages: &[Image], output: &mut Vec<u8>, options: &TiffOptions) -> Result<(), TiffError> {
+
+  if pages.len() == 0 {
+    return Err(NoPages);
+  }
+
+  output.push_all("II".as_bytes());
+  push_u16(output, 42);
+  // Where the offset of the next IFD goes
+  let mut link = output.len();
+  push_u32(output, 0);
+
+  for image in pages.iter() {
+    if image.width == 0 || image.height == 0 || image.width > 0xFFFFFFFF || image.height > 0xFFFFFFFF {
+      return Err(InvalidDimensions(image.width, image.height));
+    }
+
+    let samples = match image.color_type {
+      GRAYSCALE8  => 1u,
+      RGB8        => 3,
+      RGBA8       => 4
+    };
+    let row_bytes = image.width * samples;
+    let rows_per_strip = cmp::max(1, STRIP_SIZE / row_bytes);
+
+    let mut offsets: Vec<u32> = Vec::new();
+    let mut counts: Vec<u32> = Vec::new();
+    for strip in image.data.as_slice().chunks(rows_per_strip * row_bytes) {
+      let compressed = match options.compression {
+        Uncompressed  => Vec::from_slice(strip),
+        Lzw           => lzw_encode(strip)
+      };
+      offsets.push(output.len() as u32);
+      counts.push(compressed.len() as u32);
+      output.push_all(compressed.as_slice());
+      pad(output);
+    }
+
+    // A unit of 1 says the resolution is only an aspect ratio
+    let (x_resolution, y_resolution, unit) = match image.resolution {
+      Some(ref resolution) => {
+        let (horizontal, vertical) = resolution.dpi();
+        (resolution_rational(horizontal), resolution_rational(vertical), 2u32)
+      },
+      None => (vec!(1, 1), vec!(1, 1), 1)
+    };
+
+    let mut entries: Vec<(u16, u16, Vec<u32>)> = vec!(
+      (IMAGE_WIDTH, LONG, vec!(image.width as u32)),
+      (IMAGE_LENGTH, LONG, vec!(image.height as u32)),
+      (BITS_PER_SAMPLE, SHORT, Vec::from_elem(samples, 8u32)),
+      (COMPRESSION, SHORT, vec!(if options.compression == Lzw { 5 } else { 1 })),
+      (PHOTOMETRIC, SHORT, vec!(if samples == 1 { BLACK_IS_ZERO as u32 } else { PHOTOMETRIC_RGB as u32 })),
+      (STRIP_OFFSETS, LONG, offsets),
+      (SAMPLES_PER_PIXEL, SHORT, vec!(samples as u32)),
+      (ROWS_PER_STRIP, LONG, vec!(rows_per_strip as u32)),
+      (STRIP_BYTE_COUNTS, LONG, counts),
+      (X_RESOLUTION, RATIONAL, x_resolution),
+      (Y_RESOLUTION, RATIONAL, y_resolution),
+      (PLANAR_CONFIGURATION, SHORT, vec!(1)),
+      (RESOLUTION_UNIT, SHORT, vec!(unit)));
+    if samples == 4 {
+      entries.push((EXTRA_SAMPLES, SHORT, vec!(UNASSOCIATED_ALPHA)));
+    }
+
+    let ifd = output.len();
+    put_u32(output, link, ifd as u32);
+    link = write_ifd(output, entries.as_slice());
+  }
+  Ok(())
+}
+
+// Writes an IFD of entries sorted by tag, with the values that don't fit in
+// their entry after it. Returns where the offset of the next IFD goes
+fn write_ifd(output: &mut Vec<u8>, entries: &[(u16, u16, Vec<u32>)]) -> uint {
+  let values_offset = output.len() + 2 + entries.len() * 12 + 4;
+  let mut values_data: Vec<u8> = Vec::new();
+
+  push_u16(output, entries.len() as u16);
+  for &(tag, kind, ref values) in entries.iter() {
+    let mut bytes: Vec<u8> = Vec::new();
+    for &value in values.iter() {
+      if kind == SHORT {
+        push_u16(&mut bytes, value as u16);
+      }
+      else {
+        push_u32(&mut bytes, value);
+      }
+    }
+
+    push_u16(output, tag);
+    push_u16(output, kind);
+    push_u32(output, (if kind == RATIONAL { values.len() / 2 } else { values.len() }) as u32);
+    if bytes.len() <= 4 {
+      bytes.grow(4 - bytes.len(), &0u8);
+      output.push_all(bytes.as_slice());
+    }
+    else {
+      push_u32(output, (values_offset + values_data.len()) as u32);
+      values_data.push_all(bytes.as_slice());
+      pad(&mut values_data);
+    }
+  }
+
+  let link = output.len();
+  push_u32(output, 0);
+  output.push_all(values_data.as_slice());
+  link
+}
+
+// Compresses a strip, starting with a clear code and clearing the table
+// before codes would need 13 bits
+fn lzw_encode(data: &[u8]) -> Vec<u8> {
+
+  let mut writer = CodeWriter{output: Vec::new(), bits: 0, count: 0};
+
+  // Entries are found by the code of their prefix and their last byte
+  let mut table: HashMap<u32, uint> = HashMap::new();
+  let mut width = 9u;
+  let mut next = FIRST_CODE;
+  writer.write(CLEAR_CODE, width);
+
+  let mut current: Option<uint> = None;
+  for &byte in data.iter() {
+    let prefix = match current {
+      Some(prefix) => prefix,
+      None => {
+        current = Some(byte as uint);
+        continue;
+      }
+    };
+    let key = ((prefix as u32) << 8) | byte as u32;
+    match table.find_copy(&key) {
+      Some(code) => {
+        current = Some(code);
+        continue;
+      },
+      None => {}
+    }
+
+    writer.write(prefix, width);
+    table.insert(key, next);
+    next += 1;
+    // Readers fall one entry behind, so the last two codes are never added
+    if next == MAX_CODES - 2 {
+      writer.write(CLEAR_CODE, width);
+      table.clear();
+      width = 9;
+      next = FIRST_CODE;
+    }
+    else if next == 1 << width && width < 12 {
+      width += 1;
+    }
+    current = Some(byte as uint);
+  }
+
+  match current {
+    Some(code) => {
+      writer.write(code, width);
+      // Reading that code adds an entry, which can widen the end code
+      next += 1;
+      if next == MAX_CODES - 2 {
+        writer.write(CLEAR_CODE, width);
+        width = 9;
+      }
+      else if next == 1 << width && width < 12 {
+        width += 1;
+      }
+    },
+    None => {}
+  }
+  writer.write(END_CODE, width);
+  if writer.count > 0 {
+    writer.output.push((writer.bits << (8 - writer.count)) as u8);
+  }
+  writer.output
+}
+
+// Packs codes most significant bit first
+struct CodeWriter {
+  output: Vec<u8>,
+  bits: u32,
+  count: uint,
+}
+
+impl CodeWriter {
+
+  fn write(&mut self, code: uint, width: uint) {
+    self.bits = (self.bits << width) | code as u32;
+    self.count += width;
+    while self.count >= 8 {
+      self.output.push((self.bits >> (self.count - 8)) as u8);
+      self.count -= 8;
+    }
+  }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use image::*;
+  use zlib;
+
+  // File with one IFD after the pixel data, which starts at offset 8. Values
+  // are SHORT when the type is 3 and LONG otherwise
+  fn tiff_file(big_endian: bool, entries: &[(u16, u16, Vec<u32>)], data: &[u8]) -> Vec<u8> {
+    let push = |output: &mut Vec<u8>, value: u32, size: uint| {
+      for i in range(0, size) {
+        let shift = if big_endian { (size - 1 - i) * 8 } else { i * 8 };
+        output.push((value >> shift) as u8);
+      }
+    };
+
+    let mut output: Vec<u8> = Vec::from_slice(if big_endian { "MM".as_bytes() } else { "II".as_bytes() });
+    push(&mut output, 42, 2);
+    push(&mut output, 0, 4);
+    output.push_all(data);
+    if output.len() % 2 == 1 {
+      output.push(0);
+    }
+
+    let ifd = output.len();
+    let mut offset: Vec<u8> = Vec::new();
+    push(&mut offset, ifd as u32, 4);
+    output.mut_slice(4, 8).copy_from(offset.as_slice());
+
+    let mut values_data: Vec<u8> = Vec::new();
+    let values_offset = ifd + 2 + entries.len() * 12 + 4;
+    push(&mut output, entries.len() as u32, 2);
+    for &(tag, kind, ref values) in entries.iter() {
+      let size = if kind == 3 { 2 } else { 4 };
+      let mut bytes: Vec<u8> = Vec::new();
+      for &value in values.iter() {
+        push(&mut bytes, value, size);
+      }
+      push(&mut output, tag as u32, 2);
+      push(&mut output, kind as u32, 2);
+      push(&mut output, (if kind == 5 { values.len() / 2 } else { values.len() }) as u32, 4);
+      if bytes.len() <= 4 {
+        bytes.grow(4 - bytes.len(), &0u8);
+        output.push_all(bytes.as_slice());
+      }
+      else {
+        push(&mut output, (values_offset + values_data.len()) as u32, 4);
+        values_data.push_all(bytes.as_slice());
+      }
+    }
+    push(&mut output, 0, 4);
+    output.push_all(values_data.as_slice());
+    output
+  }
+
+  fn test_image(width: uint, height: uint, color_type: ColorType) -> Image {
+    let mut image = Image::new(width, height, color_type);
+    let channels = image.data.len() / (width * height);
+    for (i, sample) in image.data.mut_iter().enumerate() {
+      let pixel = i / channels;
+      *sample = if pixel % 9 < 4 { 90 } else { ((pixel * 13 + i % channels * 70) % 256) as u8 };
+    }
+    image
+  }
+
+  #[test]
+  fn test_packbits_strips() {
+    // Two rows in the first strip, one in the second after a no-op header
+    let data = [0xFDu8, 1, 0x03, 2, 3, 4, 5, 0x80, 0xFD, 9];
+    let entries: Vec<(u16, u16, Vec<u32>)> = vec!(
+      (256, 3, vec!(4)), (257, 3, vec!(3)), (258, 3, vec!(8)), (259, 3, vec!(32773)), (262, 3, vec!(1)),
+      (273, 4, vec!(8, 15)), (277, 3, vec!(1)), (278, 3, vec!(2)), (279, 4, vec!(7, 3)));
+    let pages = from_bytes(tiff_file(false, entries.as_slice(), data.as_slice()).as_slice()).unwrap();
+    assert_eq!(pages.len(), 1);
+    assert_eq!(pages.get(0).color_type as uint, GRAYSCALE8 as uint);
+    assert_eq!(pages.get(0).data, vec!(1, 1, 1, 1, 2, 3, 4, 5, 9, 9, 9, 9));
+  }
+
+  #[test]
+  fn test_tiles() {
+    // 16-bit RGB in two 16x16 tiles, Deflate compressed with the horizontal predictor
+    let (width, height) = (20u, 3u);
+    let value = |x: uint, y: uint, c: uint| ((x * 3000 + y * 500 + c * 7000) % 65536) as u16;
+
+    let mut data: Vec<u8> = Vec::new();
+    let mut offsets: Vec<u32> = Vec::new();
+    let mut counts: Vec<u32> = Vec::new();
+    for tile in range(0u, 2) {
+      let mut raw: Vec<u8> = Vec::new();
+      for y in range(0u, 16) {
+        for x in range(0u, 16) {
+          for c in range(0u, 3) {
+            let image_x = tile * 16 + x;
+            let sample = if image_x < width && y < height { value(image_x, y, c) } else { 0 };
+            let left = if x > 0 && image_x - 1 < width && y < height { value(image_x - 1, y, c) } else { 0 };
+            let difference = sample - left;
+            raw.push_all(&[(difference >> 8) as u8, difference as u8]);
+          }
+        }
+      }
+      let compressed = zlib::deflate(raw.as_slice(), 6);
+      offsets.push(8 + data.len() as u32);
+      counts.push(compressed.len() as u32);
+      data.push_all(compressed.as_slice());
+    }
+
+    let entries: Vec<(u16, u16, Vec<u32>)> = vec!(
+      (256, 3, vec!(width as u32)), (257, 3, vec!(height as u32)), (258, 3, vec!(16, 16, 16)), (259, 3, vec!(8)),
+      (262, 3, vec!(2)), (277, 3, vec!(3)), (317, 3, vec!(2)), (322, 3, vec!(16)), (323, 3, vec!(16)),
+      (324, 4, offsets), (325, 4, counts));
+    let pages = from_bytes(tiff_file(true, entries.as_slice(), data.as_slice()).as_slice()).unwrap();
+    let image = pages.get(0);
+    assert_eq!(image.color_type as uint, RGB8 as uint);
+    assert_eq!((image.width, image.height), (width, height));
+    for y in range(0, height) {
+      for x in range(0, width) {
+        for c in range(0u, 3) {
+          let expected = ((value(x, y, c) as u32 * 255 + 32767) / 65535) as u8;
+          assert_eq!(*image.data.get((y * width + x) * 3 + c), expected);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn test_gray_and_palette() {
+    // Bilevel with white as 0, rows padded to a byte
+    let entries: Vec<(u16, u16, Vec<u32>)> = vec!(
+      (256, 3, vec!(10)), (257, 3, vec!(2)), (258, 3, vec!(1)), (262, 3, vec!(0)),
+      (273, 4, vec!(8)), (279, 4, vec!(4)));
+    let pages = from_bytes(tiff_file(false, entries.as_slice(), &[0b10000000, 0b01000000, 0xFF, 0xC0]).as_slice()).unwrap();
+    assert_eq!(pages.get(0).data, vec!(0, 255, 255, 255, 255, 255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0));
+
+    // Gray with premultiplied alpha
+    let entries: Vec<(u16, u16, Vec<u32>)> = vec!(
+      (256, 3, vec!(2)), (257, 3, vec!(1)), (258, 3, vec!(8, 8)), (262, 3, vec!(1)), (273, 4, vec!(8)),
+      (277, 3, vec!(2)), (279, 4, vec!(4)), (338, 3, vec!(1)));
+    let pages = from_bytes(tiff_file(true, entries.as_slice(), &[50, 100, 200, 255]).as_slice()).unwrap();
+    assert_eq!(pages.get(0).color_type as uint, RGBA8 as uint);
+    assert_eq!(pages.get(0).data, vec!(128, 128, 128, 100, 200, 200, 200, 255));
+
+    // An extra sample without a meaning is dropped
+    let entries: Vec<(u16, u16, Vec<u32>)> = vec!(
+      (256, 3, vec!(1)), (257, 3, vec!(1)), (258, 3, vec!(8, 8, 8, 8)), (262, 3, vec!(2)), (273, 4, vec!(8)),
+      (277, 3, vec!(4)), (279, 4, vec!(4)), (338, 3, vec!(0)));
+    let pages = from_bytes(tiff_file(false, entries.as_slice(), &[1, 2, 3, 4]).as_slice()).unwrap();
+    assert_eq!(pages.get(0).color_type as uint, RGB8 as uint);
+    assert_eq!(pages.get(0).data, vec!(1, 2, 3));
+
+    // 4-bit palette, with red, green and blue maps of 16 entries
+    let mut color_map: Vec<u32> = Vec::from_elem(48, 0u32);
+    *color_map.get_mut(1) = 65535;
+    *color_map.get_mut(16 + 2) = 65535;
+    *color_map.get_mut(32 + 2) = 32768;
+    let entries: Vec<(u16, u16, Vec<u32>)> = vec!(
+      (256, 3, vec!(3)), (257, 3, vec!(1)), (258, 3, vec!(4)), (262, 3, vec!(3)), (273, 4, vec!(8)),
+      (279, 4, vec!(2)), (320, 3, color_map));
+    let pages = from_bytes(tiff_file(false, entries.as_slice(), &[0x12, 0x00]).as_slice()).unwrap();
+    assert_eq!(pages.get(0).color_type as uint, RGB8 as uint);
+    assert_eq!(pages.get(0).data, vec!(255, 0, 0, 0, 255, 128, 0, 0, 0));
+  }
+
+  #[test]
+  fn test_lzw() {
+    let data = [7u8, 7, 7, 8, 8, 7, 7, 6, 6];
+    let encoded = super::lzw_encode(data.as_slice());
+    assert_eq!(encoded, vec!(128, 1, 224, 64, 128, 68, 8, 12, 6, 128, 128));
+    assert_eq!(super::lzw_decode(encoded.as_slice(), 100).unwrap().as_slice(), data.as_slice());
+
+    // Enough codes to fill the table and clear it several times
+    let mut data: Vec<u8> = Vec::new();
+    let mut state = 12345u32;
+    for _ in range(0u, 100000) {
+      state = state * 1103515245 + 12345;
+      data.push(((state >> 16) % 16) as u8);
+    }
+    let encoded = super::lzw_encode(data.as_slice());
+    assert_eq!(super::lzw_decode(encoded.as_slice(), data.len()).unwrap(), data);
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let mut pages = vec!(test_image(37, 300, GRAYSCALE8), test_image(30, 20, RGB8), test_image(5, 7, RGBA8));
+    pages.get_mut(1).resolution = Some(Resolution::from_dpi(300.0, 150.0));
+
+    let raw = to_bytes(pages.as_slice()).unwrap();
+    let compressed = to_bytes_with(pages.as_slice(), &TiffOptions{compression: Lzw}).unwrap();
+    assert!(compressed.len() < raw.len());
+
+    for bytes in [raw, compressed].iter() {
+      let decoded = from_bytes(bytes.as_slice()).unwrap();
+      assert_eq!(decoded.len(), 3);
+      for (page, image) in decoded.iter().zip(pages.iter()) {
+        assert_eq!(page.color_type as uint, image.color_type as uint);
+        assert_eq!((page.width, page.height), (image.width, image.height));
+        assert_eq!(page.data, image.data);
+      }
+      assert!(decoded.get(0).resolution.is_none());
+      let (horizontal, vertical) = decoded.get(1).resolution.clone().unwrap().dpi();
+      assert!((horizontal - 300.0).abs() < 0.01 && (vertical - 150.0).abs() < 0.01);
+    }
+  }
+
+  #[test]
+  fn test_errors() {
+    match from_bytes("II\x2B\x00\x08\x00\x00\x00".as_bytes()) {
+      Err(BadSignature) => {},
+      Err(e)  => fail!("Expected a bad signature, got {}", e),
+      Ok(_)   => fail!("Decoded a BigTIFF")
+    }
+
+    let entries: Vec<(u16, u16, Vec<u32>)> = vec!((256, 3, vec!(1)), (257, 3, vec!(1)), (262, 3, vec!(1)), (279, 4, vec!(1)));
+    match from_bytes(tiff_file(false, entries.as_slice(), &[0]).as_slice()) {
+      Err(MissingTag(273)) => {},
+      Err(e)  => fail!("Expected missing strip offsets, got {}", e),
+      Ok(_)   => fail!("Decoded an image without strips")
+    }
+
+    let entries: Vec<(u16, u16, Vec<u32>)> = vec!((256, 3, vec!(1)), (257, 3, vec!(1)), (259, 3, vec!(2)), (262, 3, vec!(1)), (273, 4, vec!(8)), (279, 4, vec!(1)));
+    match from_bytes(tiff_file(false, entries.as_slice(), &[0]).as_slice()) {
+      Err(UnsupportedCompression(2)) => {},
+      Err(e)  => fail!("Expected an unsupported compression, got {}", e),
+      Ok(_)   => fail!("Decoded CCITT compression")
+    }
+
+    let entries: Vec<(u16, u16, Vec<u32>)> = vec!((256, 3, vec!(4)), (257, 3, vec!(4)), (262, 3, vec!(1)), (273, 4, vec!(8)), (279, 4, vec!(1000)));
+    match from_bytes(tiff_file(false, entries.as_slice(), &[0, 0, 0, 0]).as_slice()) {
+      Err(Truncated) => {},
+      Err(e)  => fail!("Expected a truncated file, got {}", e),
+      Ok(_)   => fail!("Decoded a strip past the end of the file")
+    }
+
+    // 100000x100000 with a 4 byte strip, uncompressed and LZW
+    for &compression in [1u32, 5].iter() {
+      let entries: Vec<(u16, u16, Vec<u32>)> = vec!(
+        (256, 4, vec!(100000)), (257, 4, vec!(100000)), (258, 3, vec!(8)), (259, 3, vec!(compression)), (262, 3, vec!(1)),
+        (273, 4, vec!(8)), (279, 4, vec!(4)));
+      match from_bytes(tiff_file(false, entries.as_slice(), &[0x80, 0, 0, 0]).as_slice()) {
+        Err(Truncated) => {},
+        Err(e)  => fail!("Expected a truncated file, got {}", e),
+        Ok(_)   => fail!("Decoded a strip far smaller than the image")
+      }
+    }
+
+    // 16-bit RGB rows too long to count for every row
+    let entries: Vec<(u16, u16, Vec<u32>)> = vec!(
+      (256, 4, vec!(0xFFFFFFFF)), (257, 4, vec!(0xFFFFFFFF)), (258, 3, vec!(16, 16, 16)), (262, 3, vec!(2)),
+      (273, 4, vec!(8)), (277, 3, vec!(3)), (279, 4, vec!(4)));
+    match from_bytes(tiff_file(false, entries.as_slice(), &[0, 0, 0, 0]).as_slice()) {
+      Err(InvalidDimensions(0xFFFFFFFF, 0xFFFFFFFF)) => {},
+      Err(e)  => fail!("Expected invalid dimensions, got {}", e),
+      Ok(_)   => fail!("Decoded an image too large to hold")
+    }
+
+    // The IFD's next offset pointing at itself
+    let entries: Vec<(u16, u16, Vec<u32>)> = vec!((256, 3, vec!(1)), (257, 3, vec!(1)), (262, 3, vec!(1)), (273, 4, vec!(8)), (279, 4, vec!(1)));
+    let mut bytes = tiff_file(false, entries.as_slice(), &[0]);
+    let next = bytes.len() - 4;
+    bytes.mut_slice_from(next).copy_from(&[10, 0, 0, 0]);
+    match from_bytes(bytes.as_slice()) {
+      Err(IfdLoop) => {},
+      Err(e)  => fail!("Expected an IFD loop, got {}", e),
+      Ok(_)   => fail!("Decoded an IFD loop")
+    }
+
+    match to_bytes(&[]) {
+      Err(NoPages) => {},
+      Err(e)  => fail!("Expected no pages, got {}", e),
+      Ok(_)   => fail!("Encoded a file without pages")
+    }
+  }
+}
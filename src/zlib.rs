@@ -0,0 +1,987 @@
+// zlib and DEFLATE compression, for the formats that store deflated data
+
+use std::cmp;
+
+// Base lengths and extra bits for length symbols 257 to 285
+static LENGTH_BASE: [u16, ..29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+static LENGTH_EXTRA: [u8, ..29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+
+// Base distances and extra bits for distance symbols 0 to 29
+static DISTANCE_BASE: [u16, ..30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+static DISTANCE_EXTRA: [u8, ..30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+// Order the code length code lengths are stored in a dynamic block header
+static CODE_LENGTH_ORDER: [uint, ..19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+static WINDOW_SIZE: uint = 32768;
+static MIN_MATCH: uint = 3;
+static MAX_MATCH: uint = 258;
+static HASH_SIZE: uint = 32768;
+static BLOCK_TOKENS: uint = 16384;    // Literals and matches per block before new codes are built
+
+// For levels 0 to 9, how many earlier matches are tried and the length that ends the search early
+static MAX_CHAIN: [uint, ..10] = [0, 4, 8, 16, 32, 64, 128, 256, 1024, 4096];
+static NICE_LENGTH: [uint, ..10] = [0, 8, 16, 32, 64, 128, 128, 258, 258, 258];
+
+
+/* NOTES:
+ * A zlib stream is a 2 byte header, DEFLATE blocks, then the Adler-32 of the uncompressed data, big endian
+ * DEFLATE packs bits least significant first, but Huffman codes are stored most significant bit first
+ * Blocks are stored (type 0), fixed Huffman codes (type 1) or dynamic Huffman codes (type 2)
+ * The encoder writes whichever of the three is smallest for each block
+ * Level 0 only writes stored blocks, higher levels search further back for matches
+ */
+
+
+// Everything that can go wrong while inflating a stream
+#[deriving(Show, PartialEq, Clone)]
+pub enum ZlibError {
+  BadHeader,            // Not a deflate stream, or it needs a preset dictionary
+  BadBlockType,         // Block type 3 is reserved
+  BadStoredLength,      // Stored block length doesn't match its complement
+  BadCodeLengths,       // Huffman code lengths are oversubscribed, repeat past the end, or have no end of block
+  BadCode,              // Bits don't match any symbol of the current code
+  BadDistance,          // Match reaches back before the start of the output
+  BadChecksum,          // Adler-32 of the output doesn't match the stream
+  UnexpectedEnd,        // Data ended before the final block
+}
+
+
+// Reads bits least significant first
+struct BitReader<'a> {
+  data: &'a [u8],
+  position: uint,   // Next byte to load
+  bits: u32,        // Loaded bits not yet used
+  count: uint,      // Number of loaded bits
+}
+
+impl<'a> BitReader<'a> {
+
+  fn read(&mut self, count: uint) -> Result<u32, ZlibError> {
+    while self.count < count {
+      if self.position >= self.data.len() {
+        return Err(UnexpectedEnd);
+      }
+      self.bits |= (self.data[self.position] as u32) << self.count;
+      self.position += 1;
+      self.count += 8;
+    }
+    let value = self.bits & ((1u32 << count) - 1);
+    self.bits >>= count;
+    self.count -= count;
+    Ok(value)
+  }
+
+  // Skips to the next byte boundary. Fewer than 8 bits are ever loaded past
+  // what's been read, so they all belong to the current byte.
+  fn align(&mut self) {
+    self.bits = 0;
+    self.count = 0;
+  }
+
+}
+
+
+// Canonical Huffman code for decoding, kept as the number of codes of each
+// length and the symbols in code order
+struct Huffman {
+  counts: Vec<u16>,
+  symbols: Vec<u16>,
+}
+
+impl Huffman {
+
+  fn new(lengths: &[u8]) -> Result<Huffman, ZlibError> {
+
+    let mut counts: Vec<u16> = Vec::from_elem(16, 0u16);
+    for &length in lengths.iter() {
+      *counts.get_mut(length as uint) += 1;
+    }
+
+    // Codes may be incomplete, a single distance code is common, but never oversubscribed
+    let mut left: int = 1;
+    for length in range(1u, 16) {
+      left <<= 1;
+      left -= *counts.get(length) as int;
+      if left < 0 {
+        return Err(BadCodeLengths);
+      }
+    }
+
+    let mut offsets: Vec<u16> = Vec::from_elem(16, 0u16);
+    for length in range(1u, 15) {
+      *offsets.get_mut(length + 1) = *offsets.get(length) + *counts.get(length);
+    }
+
+    let mut symbols: Vec<u16> = Vec::from_elem(lengths.len(), 0u16);
+    for (symbol, &length) in lengths.iter().enumerate() {
+      if length != 0 {
+        *symbols.get_mut(*offsets.get(length as uint) as uint) = symbol as u16;
+        *offsets.get_mut(length as uint) += 1;
+      }
+    }
+
+    Ok(Huffman{counts: counts, symbols: symbols})
+  }
+
+  // Reads one code a bit at a time. Codes of each length are consecutive, so
+  // the code is found once it falls below the first code of the next length.
+  fn decode(&self, reader: &mut BitReader) -> Result<uint, ZlibError> {
+    let mut code: int = 0;
+    let mut first: int = 0;
+    let mut index: int = 0;
+    for length in range(1u, 16) {
+      code |= try!(reader.read(1)) as int;
+      let count = *self.counts.get(length) as int;
+      if code - first < count {
+        return Ok(*self.symbols.get((index + code - first) as uint) as uint);
+      }
+      index += count;
+      first += count;
+      first <<= 1;
+      code <<= 1;
+    }
+    Err(BadCode)
+  }
+
+}
+
+
+// Inflates a zlib stream and checks its Adler-32
+#[allow(dead_code)]
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, ZlibError> {
+
+  if data.len() < 2 {
+    return Err(UnexpectedEnd);
+  }
+
+  let method = data[0] as uint;
+  let flags = data[1] as uint;
+  if method & 0x0F != 8 || method >> 4 > 7 || (method * 256 + flags) % 31 != 0 || flags & 0x20 != 0 {
+    return Err(BadHeader);
+  }
+
+  let (output, used) = try!(inflate_raw(data.slice_from(2)));
+
+  let end = 2 + used;
+  if end + 4 > data.len() {
+    return Err(UnexpectedEnd);
+  }
+  let checksum = ((data[end] as u32) << 24) | ((data[end + 1] as u32) << 16) | ((data[end + 2] as u32) << 8) | data[end + 3] as u32;
+  if adler32(output.as_slice()) != checksum {
+    return Err(BadChecksum);
+  }
+
+  Ok(output)
+}
+
+// Inflates bare DEFLATE blocks, returning the output and the number of bytes used
+#[allow(dead_code)]
+pub fn inflate_raw(data: &[u8]) -> Result<(Vec<u8>, uint), ZlibError> {
+
+  let mut reader = BitReader{data: data, position: 0, bits: 0, count: 0};
+  let mut output: Vec<u8> = Vec::new();
+
+  loop {
+    let last = try!(reader.read(1)) == 1;
+    match try!(reader.read(2)) {
+      0 => {
+        try!(inflate_stored(&mut reader, &mut output));
+      },
+      1 => {
+        let (literal_lengths, distance_lengths) = fixed_lengths();
+        let literals = try!(Huffman::new(literal_lengths.as_slice()));
+        let distances = try!(Huffman::new(distance_lengths.as_slice()));
+        try!(inflate_block(&mut reader, &mut output, &literals, &distances));
+      },
+      2 => {
+        let (literals, distances) = try!(read_dynamic_codes(&mut reader));
+        try!(inflate_block(&mut reader, &mut output, &literals, &distances));
+      },
+      _ => return Err(BadBlockType)
+    }
+    if last {
+      break;
+    }
+  }
+
+  Ok((output, reader.position))
+}
+
+fn inflate_stored(reader: &mut BitReader, output: &mut Vec<u8>) -> Result<(), ZlibError> {
+
+  reader.align();
+  let length = try!(reader.read(16));
+  let complement = try!(reader.read(16));
+  if length != !complement & 0xFFFF {
+    return Err(BadStoredLength);
+  }
+
+  let start = reader.position;
+  let end = start + length as uint;
+  if end > reader.data.len() {
+    return Err(UnexpectedEnd);
+  }
+  output.push_all(reader.data.slice(start, end));
+  reader.position = end;
+  Ok(())
+}
+
+fn inflate_block(reader: &mut BitReader, output: &mut Vec<u8>, literals: &Huffman, distances: &Huffman) -> Result<(), ZlibError> {
+  loop {
+    let symbol = try!(literals.decode(reader));
+
+    if symbol < 256 {
+      output.push(symbol as u8);
+    }
+    else if symbol == 256 {
+      return Ok(());
+    }
+    else {
+      let index = symbol - 257;
+      if index >= 29 {
+        return Err(BadCode);
+      }
+      let length = LENGTH_BASE[index] as uint + try!(reader.read(LENGTH_EXTRA[index] as uint)) as uint;
+
+      let index = try!(distances.decode(reader));
+      if index >= 30 {
+        return Err(BadCode);
+      }
+      let distance = DISTANCE_BASE[index] as uint + try!(reader.read(DISTANCE_EXTRA[index] as uint)) as uint;
+      if distance > output.len() {
+        return Err(BadDistance);
+      }
+
+      // Copied a byte at a time since a match can overlap its own output
+      let start = output.len() - distance;
+      for i in range(0, length) {
+        let byte = *output.get(start + i);
+        output.push(byte);
+      }
+    }
+  }
+}
+
+// Reads the code lengths at the start of a dynamic block, which are
+// themselves Huffman coded with runs of repeats and zeros
+fn read_dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), ZlibError> {
+
+  let literal_count = try!(reader.read(5)) as uint + 257;
+  let distance_count = try!(reader.read(5)) as uint + 1;
+  let code_length_count = try!(reader.read(4)) as uint + 4;
+  if literal_count > 286 || distance_count > 30 {
+    return Err(BadCodeLengths);
+  }
+
+  let mut code_length_lengths: Vec<u8> = Vec::from_elem(19, 0u8);
+  for i in range(0, code_length_count) {
+    *code_length_lengths.get_mut(CODE_LENGTH_ORDER[i]) = try!(reader.read(3)) as u8;
+  }
+  let code_lengths = try!(Huffman::new(code_length_lengths.as_slice()));
+
+  let total = literal_count + distance_count;
+  let mut lengths: Vec<u8> = Vec::with_capacity(total);
+  while lengths.len() < total {
+    let symbol = try!(code_lengths.decode(reader));
+
+    if symbol < 16 {
+      lengths.push(symbol as u8);
+      continue;
+    }
+
+    let (value, repeat) = if symbol == 16 {
+      if lengths.len() == 0 {
+        return Err(BadCodeLengths);
+      }
+      (*lengths.last().unwrap(), 3 + try!(reader.read(2)) as uint)
+    }
+    else if symbol == 17 {
+      (0u8, 3 + try!(reader.read(3)) as uint)
+    }
+    else {
+      (0u8, 11 + try!(reader.read(7)) as uint)
+    };
+
+    if lengths.len() + repeat > total {
+      return Err(BadCodeLengths);
+    }
+    for _ in range(0, repeat) {
+      lengths.push(value);
+    }
+  }
+
+  if *lengths.get(256) == 0 {
+    return Err(BadCodeLengths);
+  }
+
+  let literals = try!(Huffman::new(lengths.slice(0, literal_count)));
+  let distances = try!(Huffman::new(lengths.slice_from(literal_count)));
+  Ok((literals, distances))
+}
+
+// Code lengths of the fixed Huffman codes, for literals/lengths and distances
+fn fixed_lengths() -> (Vec<u8>, Vec<u8>) {
+  let mut literals: Vec<u8> = Vec::with_capacity(288);
+  for symbol in range(0u, 288) {
+    literals.push(if symbol < 144 { 8 } else if symbol < 256 { 9 } else if symbol < 280 { 7 } else { 8 });
+  }
+  (literals, Vec::from_elem(30, 5u8))
+}
+
+
+// Writes bits least significant first
+struct BitWriter {
+  output: Vec<u8>,
+  bits: u32,
+  count: uint,
+}
+
+impl BitWriter {
+
+  fn new() -> BitWriter {
+    BitWriter{output: Vec::new(), bits: 0, count: 0}
+  }
+
+  fn write(&mut self, value: u32, count: uint) {
+    self.bits |= value << self.count;
+    self.count += count;
+    while self.count >= 8 {
+      self.output.push(self.bits as u8);
+      self.bits >>= 8;
+      self.count -= 8;
+    }
+  }
+
+  // Huffman codes go most significant bit first, so they're reversed before writing
+  fn write_code(&mut self, code: u16, length: u8) {
+    let mut reversed: u32 = 0;
+    for i in range(0, length as uint) {
+      reversed |= ((code as u32 >> i) & 1) << (length as uint - 1 - i);
+    }
+    self.write(reversed, length as uint);
+  }
+
+  // Pads with zeros to the next byte boundary
+  fn align(&mut self) {
+    if self.count > 0 {
+      self.output.push(self.bits as u8);
+      self.bits = 0;
+      self.count = 0;
+    }
+  }
+
+  fn finish(mut self) -> Vec<u8> {
+    self.align();
+    self.output
+  }
+
+}
+
+// A literal byte, or a (length, distance) match of earlier output
+enum Token {
+  Literal(u8),
+  Match(uint, uint),
+}
+
+
+// Deflates data into a zlib stream. Level 0 stores the data uncompressed,
+// 1 is fastest and 9 searches hardest for matches.
+#[allow(dead_code)]
+pub fn deflate(data: &[u8], level: u8) -> Vec<u8> {
+
+  let level = cmp::min(level, 9);
+
+  // 32K window deflate, with the level hint and a check value making the header a multiple of 31
+  let method = 0x78u;
+  let mut flags = (if level < 2 { 0u } else if level < 6 { 1 } else if level == 6 { 2 } else { 3 }) << 6;
+  let check = (method * 256 + flags) % 31;
+  if check != 0 {
+    flags += 31 - check;
+  }
+
+  let mut output: Vec<u8> = vec!(method as u8, flags as u8);
+  output.push_all(deflate_raw(data, level).as_slice());
+
+  let checksum = adler32(data);
+  output.push((checksum >> 24) as u8);
+  output.push((checksum >> 16) as u8);
+  output.push((checksum >> 8) as u8);
+  output.push(checksum as u8);
+  output
+}
+
+// Deflates data into bare DEFLATE blocks
+#[allow(dead_code)]
+pub fn deflate_raw(data: &[u8], level: u8) -> Vec<u8> {
+
+  let level = cmp::min(level, 9) as uint;
+  let mut writer = BitWriter::new();
+
+  if level == 0 {
+    let mut start = 0;
+    loop {
+      let end = cmp::min(start + 65535, data.len());
+      let last = end == data.len();
+      write_stored(&mut writer, data.slice(start, end), last);
+      if last {
+        break;
+      }
+      start = end;
+    }
+    return writer.finish();
+  }
+
+  let tokens = find_matches(data, MAX_CHAIN[level], NICE_LENGTH[level]);
+
+  let mut start_token = 0;
+  let mut start_byte = 0;
+  loop {
+    let end_token = cmp::min(start_token + BLOCK_TOKENS, tokens.len());
+    let block = tokens.slice(start_token, end_token);
+
+    let mut bytes = 0;
+    for token in block.iter() {
+      bytes += match *token {
+        Literal(_)        => 1,
+        Match(length, _)  => length
+      };
+    }
+
+    let last = end_token == tokens.len();
+    write_block(&mut writer, block, data.slice(start_byte, start_byte + bytes), last);
+    if last {
+      break;
+    }
+    start_token = end_token;
+    start_byte += bytes;
+  }
+
+  writer.finish()
+}
+
+fn write_stored(writer: &mut BitWriter, data: &[u8], last: bool) {
+  writer.write(last as u32, 1);
+  writer.write(0, 2);
+  writer.align();
+  let length = data.len() as u16;
+  writer.output.push(length as u8);
+  writer.output.push((length >> 8) as u8);
+  writer.output.push(!length as u8);
+  writer.output.push((!length >> 8) as u8);
+  writer.output.push_all(data);
+}
+
+// Greedy LZ77 over hash chains of every 3 byte sequence seen so far
+fn find_matches(data: &[u8], max_chain: uint, nice_length: uint) -> Vec<Token> {
+
+  let mut tokens: Vec<Token> = Vec::new();
+  let mut head: Vec<int> = Vec::from_elem(HASH_SIZE, -1i);
+  let mut previous: Vec<int> = Vec::from_elem(data.len(), -1i);
+
+  let hash = |i: uint| -> uint {
+    (((data[i] as uint) << 10) ^ ((data[i + 1] as uint) << 5) ^ data[i + 2] as uint) & (HASH_SIZE - 1)
+  };
+
+  let mut i = 0;
+  while i < data.len() {
+
+    let mut best_length = 0;
+    let mut best_distance = 0;
+
+    if i + MIN_MATCH <= data.len() {
+      let max_length = cmp::min(MAX_MATCH, data.len() - i);
+      let mut candidate = *head.get(hash(i));
+      let mut chain = max_chain;
+
+      while candidate >= 0 && chain > 0 {
+        let start = candidate as uint;
+        if i - start > WINDOW_SIZE {
+          break;
+        }
+
+        let mut length = 0;
+        while length < max_length && data[start + length] == data[i + length] {
+          length += 1;
+        }
+        if length > best_length {
+          best_length = length;
+          best_distance = i - start;
+          if length >= nice_length {
+            break;
+          }
+        }
+
+        candidate = *previous.get(start);
+        chain -= 1;
+      }
+    }
+
+    let advance = if best_length >= MIN_MATCH {
+      tokens.push(Match(best_length, best_distance));
+      best_length
+    }
+    else {
+      tokens.push(Literal(data[i]));
+      1
+    };
+
+    for position in range(i, i + advance) {
+      if position + MIN_MATCH <= data.len() {
+        let h = hash(position);
+        *previous.get_mut(position) = *head.get(h);
+        *head.get_mut(h) = position as int;
+      }
+    }
+    i += advance;
+  }
+
+  tokens
+}
+
+fn length_symbol(length: uint) -> uint {
+  let mut index = 28;
+  while LENGTH_BASE[index] as uint > length {
+    index -= 1;
+  }
+  index
+}
+
+fn distance_symbol(distance: uint) -> uint {
+  let mut index = 29;
+  while DISTANCE_BASE[index] as uint > distance {
+    index -= 1;
+  }
+  index
+}
+
+// Writes one block of tokens with whichever of dynamic, fixed or stored
+// encoding comes out smallest
+fn write_block(writer: &mut BitWriter, tokens: &[Token], data: &[u8], last: bool) {
+
+  let mut literal_frequencies: Vec<u32> = Vec::from_elem(286, 0u32);
+  let mut distance_frequencies: Vec<u32> = Vec::from_elem(30, 0u32);
+  for token in tokens.iter() {
+    match *token {
+      Literal(byte) => {
+        *literal_frequencies.get_mut(byte as uint) += 1;
+      },
+      Match(length, distance) => {
+        *literal_frequencies.get_mut(257 + length_symbol(length)) += 1;
+        *distance_frequencies.get_mut(distance_symbol(distance)) += 1;
+      }
+    }
+  }
+  *literal_frequencies.get_mut(256) = 1;
+
+  // At least one distance code keeps decoders that reject empty codes happy
+  if distance_frequencies.iter().all(|&f| f == 0) {
+    *distance_frequencies.get_mut(0) = 1;
+  }
+
+  let literal_lengths = huffman_lengths(literal_frequencies.as_slice(), 15);
+  let distance_lengths = huffman_lengths(distance_frequencies.as_slice(), 15);
+  let header = DynamicHeader::new(literal_lengths.as_slice(), distance_lengths.as_slice());
+  let (fixed_literals, fixed_distances) = fixed_lengths();
+
+  let dynamic_bits = 3 + header.bits() + token_bits(literal_frequencies.as_slice(), distance_frequencies.as_slice(), literal_lengths.as_slice(), distance_lengths.as_slice());
+  let fixed_bits = 3 + token_bits(literal_frequencies.as_slice(), distance_frequencies.as_slice(), fixed_literals.as_slice(), fixed_distances.as_slice());
+  let stored_bits = 3 + 7 + 32 + data.len() * 8;
+
+  if data.len() <= 65535 && stored_bits <= dynamic_bits && stored_bits <= fixed_bits {
+    write_stored(writer, data, last);
+  }
+  else if fixed_bits <= dynamic_bits {
+    writer.write(last as u32, 1);
+    writer.write(1, 2);
+    write_tokens(writer, tokens, fixed_literals.as_slice(), fixed_distances.as_slice());
+  }
+  else {
+    writer.write(last as u32, 1);
+    writer.write(2, 2);
+    header.write(writer);
+    write_tokens(writer, tokens, literal_lengths.as_slice(), distance_lengths.as_slice());
+  }
+}
+
+// Size in bits of the tokens counted in the frequencies, end of block included
+fn token_bits(literal_frequencies: &[u32], distance_frequencies: &[u32], literal_lengths: &[u8], distance_lengths: &[u8]) -> uint {
+  let mut bits = 0;
+  for (symbol, &frequency) in literal_frequencies.iter().enumerate() {
+    let extra = if symbol > 256 { LENGTH_EXTRA[symbol - 257] as uint } else { 0 };
+    bits += frequency as uint * (literal_lengths[symbol] as uint + extra);
+  }
+  for (symbol, &frequency) in distance_frequencies.iter().enumerate() {
+    bits += frequency as uint * (distance_lengths[symbol] as uint + DISTANCE_EXTRA[symbol] as uint);
+  }
+  bits
+}
+
+fn write_tokens(writer: &mut BitWriter, tokens: &[Token], literal_lengths: &[u8], distance_lengths: &[u8]) {
+
+  let literal_codes = canonical_codes(literal_lengths);
+  let distance_codes = canonical_codes(distance_lengths);
+
+  for token in tokens.iter() {
+    match *token {
+      Literal(byte) => {
+        writer.write_code(*literal_codes.get(byte as uint), literal_lengths[byte as uint]);
+      },
+      Match(length, distance) => {
+        let index = length_symbol(length);
+        writer.write_code(*literal_codes.get(257 + index), literal_lengths[257 + index]);
+        writer.write((length - LENGTH_BASE[index] as uint) as u32, LENGTH_EXTRA[index] as uint);
+
+        let index = distance_symbol(distance);
+        writer.write_code(*distance_codes.get(index), distance_lengths[index]);
+        writer.write((distance - DISTANCE_BASE[index] as uint) as u32, DISTANCE_EXTRA[index] as uint);
+      }
+    }
+  }
+
+  writer.write_code(*literal_codes.get(256), literal_lengths[256]);
+}
+
+
+// The code lengths of a dynamic block, run length coded with symbols 16 to 18
+// and then Huffman coded themselves
+struct DynamicHeader {
+  literal_count: uint,
+  distance_count: uint,
+  code_length_count: uint,
+  code_length_lengths: Vec<u8>,
+  symbols: Vec<(uint, u32)>,      // Code length symbol and the value of its extra bits
+}
+
+impl DynamicHeader {
+
+  fn new(literal_lengths: &[u8], distance_lengths: &[u8]) -> DynamicHeader {
+
+    let mut literal_count = literal_lengths.len();
+    while literal_count > 257 && literal_lengths[literal_count - 1] == 0 {
+      literal_count -= 1;
+    }
+    let mut distance_count = distance_lengths.len();
+    while distance_count > 1 && distance_lengths[distance_count - 1] == 0 {
+      distance_count -= 1;
+    }
+
+    let mut lengths: Vec<u8> = Vec::from_slice(literal_lengths.slice(0, literal_count));
+    lengths.push_all(distance_lengths.slice(0, distance_count));
+
+    let mut symbols: Vec<(uint, u32)> = Vec::new();
+    let mut i = 0;
+    while i < lengths.len() {
+      let value = *lengths.get(i);
+      let mut run = 1;
+      while i + run < lengths.len() && *lengths.get(i + run) == value {
+        run += 1;
+      }
+
+      if value == 0 && run >= 3 {
+        let repeat = cmp::min(run, 138);
+        if repeat >= 11 {
+          symbols.push((18, (repeat - 11) as u32));
+        }
+        else {
+          symbols.push((17, (repeat - 3) as u32));
+        }
+        i += repeat;
+      }
+      else if value != 0 && run >= 4 {
+        let repeat = cmp::min(run - 1, 6);
+        symbols.push((value as uint, 0));
+        symbols.push((16, (repeat - 3) as u32));
+        i += 1 + repeat;
+      }
+      else {
+        symbols.push((value as uint, 0));
+        i += 1;
+      }
+    }
+
+    let mut frequencies: Vec<u32> = Vec::from_elem(19, 0u32);
+    for &(symbol, _) in symbols.iter() {
+      *frequencies.get_mut(symbol) += 1;
+    }
+    let code_length_lengths = huffman_lengths(frequencies.as_slice(), 7);
+
+    let mut code_length_count = 19;
+    while code_length_count > 4 && *code_length_lengths.get(CODE_LENGTH_ORDER[code_length_count - 1]) == 0 {
+      code_length_count -= 1;
+    }
+
+    DynamicHeader {
+      literal_count: literal_count,
+      distance_count: distance_count,
+      code_length_count: code_length_count,
+      code_length_lengths: code_length_lengths,
+      symbols: symbols,
+    }
+  }
+
+  fn bits(&self) -> uint {
+    let mut bits = 5 + 5 + 4 + 3 * self.code_length_count;
+    for &(symbol, _) in self.symbols.iter() {
+      bits += *self.code_length_lengths.get(symbol) as uint + extra_bits(symbol);
+    }
+    bits
+  }
+
+  fn write(&self, writer: &mut BitWriter) {
+    writer.write((self.literal_count - 257) as u32, 5);
+    writer.write((self.distance_count - 1) as u32, 5);
+    writer.write((self.code_length_count - 4) as u32, 4);
+    for i in range(0, self.code_length_count) {
+      writer.write(*self.code_length_lengths.get(CODE_LENGTH_ORDER[i]) as u32, 3);
+    }
+
+    let codes = canonical_codes(self.code_length_lengths.as_slice());
+    for &(symbol, extra) in self.symbols.iter() {
+      writer.write_code(*codes.get(symbol), *self.code_length_lengths.get(symbol));
+      writer.write(extra, extra_bits(symbol));
+    }
+  }
+
+}
+
+// Extra bits after a code length symbol
+fn extra_bits(symbol: uint) -> uint {
+  match symbol {
+    16 => 2,
+    17 => 3,
+    18 => 7,
+    _  => 0
+  }
+}
+
+// Canonical codes for a set of code lengths: shorter codes first, and in
+// symbol order within each length
+#[allow(dead_code)]
+pub fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+
+  let mut counts: Vec<u32> = Vec::from_elem(17, 0u32);
+  for &length in lengths.iter() {
+    if length > 0 {
+      *counts.get_mut(length as uint) += 1;
+    }
+  }
+
+  let mut next: Vec<u32> = Vec::from_elem(17, 0u32);
+  let mut code: u32 = 0;
+  for length in range(1u, 17) {
+    code = (code + *counts.get(length - 1)) << 1;
+    *next.get_mut(length) = code;
+  }
+
+  let mut codes: Vec<u16> = Vec::from_elem(lengths.len(), 0u16);
+  for (symbol, &length) in lengths.iter().enumerate() {
+    if length > 0 {
+      *codes.get_mut(symbol) = *next.get(length as uint) as u16;
+      *next.get_mut(length as uint) += 1;
+    }
+  }
+  codes
+}
+
+// Huffman code lengths for symbol frequencies, none longer than max_bits.
+// Unused symbols get length 0. When the tree is too deep the frequencies
+// are flattened and it's built again.
+#[allow(dead_code)]
+pub fn huffman_lengths(frequencies: &[u32], max_bits: uint) -> Vec<u8> {
+  let mut weights: Vec<u32> = Vec::from_slice(frequencies);
+  loop {
+    let lengths = tree_lengths(weights.as_slice());
+    if lengths.iter().all(|&length| length as uint <= max_bits) {
+      return lengths;
+    }
+    for weight in weights.mut_iter() {
+      if *weight > 0 {
+        *weight = (*weight + 1) / 2;
+      }
+    }
+  }
+}
+
+// Depth of every leaf of a Huffman tree built by joining the two lightest nodes
+fn tree_lengths(weights: &[u32]) -> Vec<u8> {
+
+  let mut lengths: Vec<u8> = Vec::from_elem(weights.len(), 0u8);
+
+  // Leaves come first, then internal nodes as they're made
+  let mut parents: Vec<uint> = Vec::new();
+  let mut leaves: Vec<uint> = Vec::new();
+  let mut active: Vec<(u64, uint)> = Vec::new();
+  for (symbol, &weight) in weights.iter().enumerate() {
+    if weight > 0 {
+      active.push((weight as u64, parents.len()));
+      parents.push(0);
+      leaves.push(symbol);
+    }
+  }
+
+  if leaves.len() == 0 {
+    return lengths;
+  }
+  if leaves.len() == 1 {
+    *lengths.get_mut(*leaves.get(0)) = 1;
+    return lengths;
+  }
+
+  while active.len() > 1 {
+    // Heaviest first, so the two lightest are at the end
+    active.as_mut_slice().sort_by(|a, b| b.cmp(a));
+    let (first_weight, first) = active.pop().unwrap();
+    let (second_weight, second) = active.pop().unwrap();
+
+    let node = parents.len();
+    parents.push(node);
+    *parents.get_mut(first) = node;
+    *parents.get_mut(second) = node;
+    active.push((first_weight + second_weight, node));
+  }
+
+  let root = parents.len() - 1;
+  for (leaf, &symbol) in leaves.iter().enumerate() {
+    let mut node = leaf;
+    let mut depth = 0u;
+    while node != root {
+      node = *parents.get(node);
+      depth += 1;
+    }
+    *lengths.get_mut(symbol) = cmp::min(depth, 255) as u8;
+  }
+  lengths
+}
+
+
+// Adler-32 checksum, as stored at the end of a zlib stream
+#[allow(dead_code)]
+pub fn adler32(data: &[u8]) -> u32 {
+  let mut a: u32 = 1;
+  let mut b: u32 = 0;
+  // 5552 bytes is the most that can be summed before the sums overflow
+  for chunk in data.chunks(5552) {
+    for &byte in chunk.iter() {
+      a += byte as u32;
+      b += a;
+    }
+    a %= 65521;
+    b %= 65521;
+  }
+  (b << 16) | a
+}
+
+// CRC-32 of data, as used by PNG and gzip
+#[allow(dead_code)]
+pub fn crc32(data: &[u8]) -> u32 {
+  update_crc32(0, data)
+}
+
+// Continues a CRC-32 over more data, starting from the CRC of everything before it
+#[allow(dead_code)]
+pub fn update_crc32(crc: u32, data: &[u8]) -> u32 {
+
+  let mut table = [0u32, ..256];
+  for n in range(0u, 256) {
+    let mut c = n as u32;
+    for _ in range(0u, 8) {
+      c = if c & 1 == 1 { 0xEDB88320 ^ (c >> 1) } else { c >> 1 };
+    }
+    table[n] = c;
+  }
+
+  let mut c = crc ^ 0xFFFFFFFF;
+  for &byte in data.iter() {
+    c = table[((c ^ byte as u32) & 0xFF) as uint] ^ (c >> 8);
+  }
+  c ^ 0xFFFFFFFF
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Bytes with some repetition, so every level finds matches
+  fn test_data(length: uint) -> Vec<u8> {
+    let mut data: Vec<u8> = Vec::with_capacity(length);
+    let mut seed: u32 = 12345;
+    for i in range(0, length) {
+      seed = seed * 1103515245 + 12345;
+      if (seed >> 16) % 4 == 0 {
+        data.push((seed >> 24) as u8);
+      }
+      else {
+        data.push((i % 37) as u8);
+      }
+    }
+    data
+  }
+
+  #[test]
+  fn test_inflate_known_streams() {
+    let hello = [0x78u8, 0x9c, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00, 0x06, 0x2c, 0x02, 0x15];
+    assert_eq!(inflate(&hello), Ok(Vec::from_slice("hello".as_bytes())));
+
+    let repeated = [0x78u8, 0xda, 0x4b, 0x4c, 0x4a, 0x4e, 0xc4, 0x86, 0x00, 0x72, 0xe0, 0x09, 0x31];
+    assert_eq!(inflate(&repeated), Ok(Vec::from_slice("abcabcabcabcabcabcabcabc".as_bytes())));
+  }
+
+  #[test]
+  fn test_inflate_errors() {
+    let hello = [0x78u8, 0x9c, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00, 0x06, 0x2c, 0x02, 0x16];
+    assert_eq!(inflate(&hello), Err(BadChecksum));
+    assert_eq!(inflate(hello.slice(0, 6)), Err(UnexpectedEnd));
+    assert_eq!(inflate(&[0x78u8, 0x9d]), Err(BadHeader));
+    assert_eq!(inflate_raw(&[0x07u8]), Err(BadBlockType));
+  }
+
+  #[test]
+  fn test_checksums() {
+    assert_eq!(crc32("hello".as_bytes()), 0x3610a686);
+    assert_eq!(update_crc32(crc32("he".as_bytes()), "llo".as_bytes()), 0x3610a686);
+    assert_eq!(adler32("hello".as_bytes()), 0x062c0215);
+    assert_eq!(adler32(&[]), 1);
+  }
+
+  #[test]
+  fn test_round_trip_every_level() {
+    let data = test_data(100000);
+    for level in range(0u8, 10) {
+      let compressed = deflate(data.as_slice(), level);
+      assert_eq!(inflate(compressed.as_slice()), Ok(data.clone()));
+      if level > 0 {
+        assert!(compressed.len() < data.len());
+      }
+    }
+  }
+
+  #[test]
+  fn test_round_trip_edge_cases() {
+    let empty: Vec<u8> = Vec::new();
+    let single = vec!(42u8);
+    let run = Vec::from_elem(70000, 7u8);
+    for data in [empty, single, run].iter() {
+      for &level in [0u8, 1, 6, 9].iter() {
+        assert_eq!(inflate(deflate(data.as_slice(), level).as_slice()), Ok(data.clone()));
+      }
+    }
+  }
+
+  #[test]
+  fn test_huffman_lengths_are_limited() {
+    // Fibonacci frequencies make the deepest possible tree
+    let mut frequencies: Vec<u32> = vec!(1, 1);
+    for i in range(2u, 30) {
+      let next = *frequencies.get(i - 1) + *frequencies.get(i - 2);
+      frequencies.push(next);
+    }
+    let lengths = huffman_lengths(frequencies.as_slice(), 15);
+    assert!(lengths.iter().all(|&length| length > 0 && length <= 15));
+
+    // Still a complete prefix code
+    let kraft = lengths.iter().fold(0u, |sum, &length| sum + (1u << (15 - length as uint)));
+    assert_eq!(kraft, 1 << 15);
+  }
+}